@@ -0,0 +1,285 @@
+use anyhow::{bail, Result};
+use ksm2usc_score_import::importer::{self, ImportOptions, Progress, Severity};
+use ksm2usc_score_import::{config, history, importer_funcs, report, Summary};
+use serde::Serialize;
+use std::path::Path;
+use std::sync::Arc;
+
+// Switches that take no value. Every other --option takes one, the handoff to a running window
+// relies on that to tell paths from option values.
+pub const DRY_RUN: &str = "--dry-run";
+pub const TRY_ANYWAY: &str = "--try-anyway";
+pub const ALL_OR_NOTHING: &str = "--all-or-nothing";
+pub const NO_BACKUP: &str = "--no-backup";
+pub const JSON: &str = "--json";
+pub const IMPROVEMENTS_ONLY: &str = "--improvements-only";
+pub const ACROSS_GAUGES: &str = "--across-gauges";
+pub const CLEARS_ONLY: &str = "--clears-only";
+pub const NONSTANDARD_OPTIONS: &str = "--nonstandard-options";
+
+pub const FLAGS: &[&str] = &[
+    DRY_RUN,
+    TRY_ANYWAY,
+    ALL_OR_NOTHING,
+    NO_BACKUP,
+    JSON,
+    IMPROVEMENTS_ONLY,
+    ACROSS_GAUGES,
+    CLEARS_ONLY,
+    NONSTANDARD_OPTIONS,
+];
+
+pub fn flag(name: &str) -> bool {
+    debug_assert!(FLAGS.contains(&name), "{} is not in FLAGS", name);
+    std::env::args().any(|a| a == name)
+}
+
+// Exit codes for scripts: everything imported, some scores failed, or the import errored out or
+// never ran.
+const EXIT_OK: i32 = 0;
+const EXIT_FAILURES: i32 = 1;
+pub const EXIT_ERRORED: i32 = 2;
+
+// The --json output of an import that stopped before it had a summary.
+#[derive(Serialize)]
+struct JsonError<'a> {
+    error: &'a str,
+}
+
+pub fn run(
+    ksm_path: &Path,
+    db_path: &Path,
+    label: &str,
+    options: ImportOptions,
+    json: bool,
+) -> i32 {
+    let dry_run = options.dry_run;
+    let mut last_summary = None;
+    let result = import(ksm_path, db_path, options, json, &mut last_summary);
+    let error = result.as_ref().err().map(|e| format!("{:?}", e));
+    if let Some(e) = &error {
+        eprintln!("Error: {}", e);
+    }
+    let summary = match result.ok().or(last_summary) {
+        Some(summary) => summary,
+        None => {
+            if json {
+                print_json(serde_json::to_string_pretty(&JsonError {
+                    error: error.as_deref().unwrap_or_default(),
+                }))
+            }
+            return EXIT_ERRORED;
+        }
+    };
+    let run = history::Run::new(label, ksm_path, db_path, &summary, error.is_some());
+    if !dry_run {
+        match history::record(&run, &summary) {
+            Ok(()) if !json => println!("Recorded in the history as \"{}\"", run.label),
+            Ok(()) => {}
+            Err(e) => eprintln!("Failed to record import history: {:?}", e),
+        }
+    }
+    let header = report::Header {
+        error: error.clone(),
+        ..run.header()
+    };
+    if json {
+        print_json(report::json(&summary, Some(&header)))
+    }
+    exit_code(error.is_some(), &summary)
+}
+
+fn print_json<E: std::fmt::Debug>(json: Result<String, E>) {
+    match json {
+        Ok(s) => println!("{}", s),
+        Err(e) => eprintln!("Error: {:?}", e),
+    }
+}
+
+// An errored run keeps its partial summary for the output, but never passes for a success.
+fn exit_code(errored: bool, summary: &Summary) -> i32 {
+    if errored {
+        EXIT_ERRORED
+    } else if summary.fail_messages.is_empty() && summary.spilled_failures == 0 {
+        EXIT_OK
+    } else {
+        EXIT_FAILURES
+    }
+}
+
+fn import(
+    ksm_path: &Path,
+    db_path: &Path,
+    mut options: ImportOptions,
+    json: bool,
+    last_summary: &mut Option<Summary>,
+) -> Result<Summary> {
+    // With --json, stdout only gets the summary.
+    let log = |line: String| {
+        if json {
+            eprintln!("{}", line)
+        } else {
+            println!("{}", line)
+        }
+    };
+    options.songs_root = config::load().usc_songs_root;
+    options.earlier_rows = Arc::new(history::rows_written_to(db_path));
+    options
+        .validate()
+        .iter()
+        .filter(|c| c.severity == Severity::Warning)
+        .for_each(|c| log(format!("Warning: {}", c.message)));
+    if !options.dry_run && importer::db_locked(db_path) {
+        bail!("maps.db is locked, close USC and retry");
+    }
+    let source = importer::open_source(ksm_path, &options.cancel)?;
+    let preflight = importer::validate_paths(
+        source.as_ref(),
+        ksm_path,
+        db_path,
+        options.policy,
+        options.limits,
+    )?;
+    if !importer::is_supported_version(preflight.db_version) && !options.best_effort_schema {
+        bail!(
+            "maps.db is version {}, supported versions are {}. Pass --try-anyway to write only \
+            the Scores columns this tool recognizes",
+            preflight.db_version,
+            importer::supported_versions()
+        );
+    }
+    preflight
+        .schema_audit
+        .iter()
+        .flat_map(|a| a.lines())
+        .for_each(&log);
+    if preflight.users.len() > 1 {
+        log(format!(
+            "maps.db has scores from {} players, importing as {}",
+            preflight.users.len(),
+            importer::UserIdentity::of(&options)
+        ));
+    }
+    // Without a prompt, triggers always get the safe mode.
+    if !preflight.score_triggers.is_empty() {
+        log(format!(
+            "Triggers on Scores ({}), importing in safe mode",
+            preflight.score_triggers.join(", ")
+        ));
+        options.safe_mode = true;
+    }
+
+    let dry_run = options.dry_run;
+    let started = importer_funcs::now();
+    let summary = importer::import_scores(ksm_path, db_path, options, |p| match p {
+        Progress::Started => log("Starting".to_string()),
+        Progress::Advanced(p, file) => log(format!("{:5.1}% {}", p * 100.0, file)),
+        Progress::Optimizing => log("Optimizing database".to_string()),
+        Progress::Locked => log("maps.db is locked, stopping".to_string()),
+        Progress::Finished(summary) => {
+            if !json {
+                print_summary(summary, dry_run)
+            }
+        }
+        Progress::Cancelled(summary) => {
+            if !json {
+                print_summary(summary, dry_run)
+            }
+            *last_summary = Some(summary.clone());
+        }
+        Progress::Errored(_, summary) => {
+            if !json {
+                summary.iter().for_each(|s| print_summary(s, dry_run));
+            }
+            *last_summary = summary.clone();
+        }
+    })?;
+    if !dry_run {
+        let mut config = config::load();
+        config.last_import = Some(started);
+        if let Err(e) = config::save(&config) {
+            eprintln!("Failed to save settings: {:?}", e);
+        }
+    }
+    Ok(summary)
+}
+
+fn print_summary(summary: &Summary, dry_run: bool) {
+    if dry_run {
+        println!("Dry run — nothing was written");
+    }
+    summary
+        .warnings
+        .iter()
+        .for_each(|w| println!("Warning: {}", w));
+    summary
+        .fail_messages
+        .iter()
+        .for_each(|e| println!("{}", e.message));
+    println!(
+        "Score files processed: {} of {}",
+        summary.files_processed, summary.files_found
+    );
+    if summary.files_unmodified > 0 {
+        println!(
+            "Score files not modified since the cutoff: {}",
+            summary.files_unmodified
+        );
+    }
+    println!("Scores found: {}", summary.scores_found);
+    println!("Scores imported: {}", summary.scores_imported);
+    if summary.scores_with_nonstandard_options > 0 {
+        println!(
+            "Scores with options USC can't store, imported as plain plays: {}",
+            summary.scores_with_nonstandard_options
+        );
+    }
+    println!("Already in the database: {}", summary.scores_skipped);
+    if summary.scores_upgraded > 0 {
+        println!(
+            "Earlier imports given a better badge: {}",
+            summary.scores_upgraded
+        );
+    }
+    for (rule, count) in &summary.skipped {
+        println!("Skipped for {}: {}", rule.label(), count);
+    }
+    println!(
+        "Failed imports: {}",
+        summary.fail_messages.len() as u32 + summary.spilled_failures
+    );
+    if let Some(path) = &summary.spill_path {
+        println!(
+            "Failures were written to \"{}\"",
+            path.to_str().unwrap_or_default()
+        );
+    }
+    if let Some(path) = &summary.backup_path {
+        println!("Backup saved to \"{}\"", path.to_str().unwrap_or_default());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ksm2usc_score_import::{Failure, FailureKind};
+    use std::path::PathBuf;
+
+    #[test]
+    fn an_errored_run_never_exits_with_success() {
+        let clean = Summary::default();
+        let failed = Summary {
+            fail_messages: vec![Failure {
+                path: PathBuf::from("a.ksc"),
+                kind: FailureKind::Parse,
+                reason: String::new(),
+                message: String::new(),
+            }],
+            ..Default::default()
+        };
+        assert_eq!(exit_code(false, &clean), EXIT_OK);
+        assert_eq!(exit_code(false, &failed), EXIT_FAILURES);
+        assert_eq!(exit_code(true, &clean), EXIT_ERRORED);
+        assert_eq!(exit_code(true, &failed), EXIT_ERRORED);
+    }
+}
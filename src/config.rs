@@ -0,0 +1,168 @@
+use anyhow::{anyhow, Result};
+use std::backtrace::Backtrace;
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::path::PathBuf;
+use toml::Value;
+
+#[derive(Debug, Clone)]
+pub struct Config {
+    pub show_intro: bool,
+    pub ui_scale: f64,
+    pub high_contrast: bool,
+    pub usc_songs_root: Option<PathBuf>,
+    // Saved when an import starts, only offered again while they still exist.
+    pub last_ksm_path: Option<PathBuf>,
+    pub last_db_path: Option<PathBuf>,
+    // When the last finished import started, for only importing files modified since.
+    pub last_import: Option<i64>,
+    // "en" or "ja", unset follows the system.
+    pub language: Option<String>,
+    // Overrides the digit grouping of the system locale, a space for "12 345".
+    pub thousands_separator: Option<char>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            show_intro: true,
+            ui_scale: 1.0,
+            high_contrast: false,
+            usc_songs_root: None,
+            last_ksm_path: None,
+            last_db_path: None,
+            last_import: None,
+            language: None,
+            thousands_separator: None,
+        }
+    }
+}
+
+fn config_path() -> Option<PathBuf> {
+    dirs::config_dir().map(|d| d.join("ksm2usc.toml"))
+}
+
+pub fn log_path() -> Option<PathBuf> {
+    dirs::config_dir().map(|d| d.join("ksm2usc.log"))
+}
+
+pub fn lock_path() -> Option<PathBuf> {
+    dirs::config_dir().map(|d| d.join("ksm2usc.lock"))
+}
+
+pub fn spill_path() -> Option<PathBuf> {
+    dirs::config_dir().map(|d| d.join("ksm2usc-failures.txt"))
+}
+
+pub fn dirty_shutdown_path() -> Option<PathBuf> {
+    dirs::config_dir().map(|d| d.join("ksm2usc-dirty-shutdown.txt"))
+}
+
+pub fn history_path() -> Option<PathBuf> {
+    dirs::config_dir().map(|d| d.join("ksm2usc-history.txt"))
+}
+
+// One folder per recorded run, named by its id, with what the history view needs to act on it.
+pub fn runs_dir() -> Option<PathBuf> {
+    dirs::config_dir().map(|d| d.join("ksm2usc-runs"))
+}
+
+pub fn install_panic_log() {
+    let default_hook = std::panic::take_hook();
+    std::panic::set_hook(Box::new(move |info| {
+        if let Some(path) = log_path() {
+            let backtrace = Backtrace::force_capture();
+            let _ = OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(path)
+                .and_then(|mut f| writeln!(f, "{}\n{}", info, backtrace));
+        }
+        default_hook(info);
+    }));
+}
+
+pub fn load() -> Config {
+    let value = config_path()
+        .and_then(|p| std::fs::read_to_string(p).ok())
+        .and_then(|s| s.parse::<Value>().ok());
+
+    match value {
+        Some(value) => Config {
+            show_intro: value
+                .get("show_intro")
+                .and_then(Value::as_bool)
+                .unwrap_or(true),
+            ui_scale: value
+                .get("ui_scale")
+                .and_then(Value::as_float)
+                .map(crate::style::clamp_scale)
+                .unwrap_or(1.0),
+            high_contrast: value
+                .get("high_contrast")
+                .and_then(Value::as_bool)
+                .unwrap_or(false),
+            usc_songs_root: value
+                .get("usc_songs_root")
+                .and_then(Value::as_str)
+                .map(PathBuf::from),
+            last_ksm_path: value
+                .get("last_ksm_path")
+                .and_then(Value::as_str)
+                .map(PathBuf::from)
+                .filter(|p| p.exists()),
+            last_db_path: value
+                .get("last_db_path")
+                .and_then(Value::as_str)
+                .map(PathBuf::from)
+                .filter(|p| p.is_file()),
+            last_import: value.get("last_import").and_then(Value::as_integer),
+            language: value
+                .get("language")
+                .and_then(Value::as_str)
+                .map(str::to_string),
+            thousands_separator: value
+                .get("thousands_separator")
+                .and_then(Value::as_str)
+                .and_then(|s| s.chars().next()),
+        },
+        None => Config::default(),
+    }
+}
+
+pub fn save(config: &Config) -> Result<()> {
+    let path = config_path().ok_or_else(|| anyhow!("No config directory on this platform"))?;
+    let mut table = toml::value::Table::new();
+    table.insert("show_intro".into(), Value::Boolean(config.show_intro));
+    table.insert("ui_scale".into(), Value::Float(config.ui_scale));
+    table.insert("high_contrast".into(), Value::Boolean(config.high_contrast));
+    if let Some(last_import) = config.last_import {
+        table.insert("last_import".into(), Value::Integer(last_import));
+    }
+    if let Some(language) = &config.language {
+        table.insert("language".into(), Value::String(language.clone()));
+    }
+    if let Some(separator) = config.thousands_separator {
+        table.insert(
+            "thousands_separator".into(),
+            Value::String(separator.to_string()),
+        );
+    }
+
+    let paths = [
+        ("usc_songs_root", &config.usc_songs_root),
+        ("last_ksm_path", &config.last_ksm_path),
+        ("last_db_path", &config.last_db_path),
+    ];
+    for (key, path) in paths.iter() {
+        if let Some(path) = path.as_ref().and_then(|p| p.to_str()) {
+            table.insert(key.to_string(), Value::String(path.to_string()));
+        }
+    }
+
+    if let Some(parent) = path.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+    std::fs::write(path, Value::Table(table).to_string())?;
+    Ok(())
+}
@@ -0,0 +1,152 @@
+use rusqlite::{Connection, OpenFlags};
+use std::collections::HashSet;
+use std::convert::TryInto;
+use std::path::{Path, PathBuf};
+
+pub struct KsmInstall {
+    pub path: PathBuf,
+    pub has_scores: bool,
+}
+
+// Folders people usually unpack games to, each joined with the given folder names.
+fn folder_candidates(names: &[&str]) -> Vec<PathBuf> {
+    let mut dirs: Vec<PathBuf> = std::env::current_dir().into_iter().collect();
+    dirs.extend(
+        std::env::current_exe()
+            .ok()
+            .and_then(|exe| exe.parent().map(Path::to_path_buf)),
+    );
+    let mut bases: Vec<PathBuf> = [
+        dirs::home_dir(),
+        dirs::desktop_dir(),
+        dirs::document_dir(),
+        dirs::download_dir(),
+        dirs::data_dir(),
+        dirs::data_local_dir(),
+    ]
+    .iter()
+    .flatten()
+    .cloned()
+    .collect();
+    if cfg!(windows) {
+        bases.extend(
+            [
+                "C:\\",
+                "D:\\",
+                "C:\\Program Files",
+                "C:\\Program Files (x86)",
+                "C:\\Games",
+            ]
+            .iter()
+            .map(PathBuf::from),
+        );
+    }
+    bases.iter().for_each(|base| {
+        dirs.extend(names.iter().map(|name| base.join(name)));
+    });
+    dirs
+}
+
+// Case-insensitive filesystems reach the same folder through several of the candidates.
+fn dedup_paths(paths: impl Iterator<Item = PathBuf>) -> Vec<PathBuf> {
+    let mut seen = HashSet::new();
+    paths
+        .filter(|p| seen.insert(p.canonicalize().unwrap_or_else(|_| p.clone())))
+        .collect()
+}
+
+fn is_usc_database(path: &Path) -> bool {
+    Connection::open_with_flags(path, OpenFlags::SQLITE_OPEN_READ_ONLY)
+        .and_then(|db| db.query_row("SELECT version FROM `Database`", [], |r| r.get::<_, u32>(0)))
+        .is_ok()
+}
+
+// Portable installs keep maps.db next to usc-game.exe.
+pub fn usc_databases() -> Vec<PathBuf> {
+    let names = ["USC", "usc", "unnamed-sdvx-clone", "unnamed-sdvx-clone/bin"];
+    dedup_paths(
+        folder_candidates(&names)
+            .into_iter()
+            .map(|dir| dir.join("maps.db"))
+            .filter(|p| p.is_file() && is_usc_database(p)),
+    )
+}
+
+fn is_ksm_install(dir: &Path) -> bool {
+    dir.join("kshootmania.exe").is_file()
+        || dir.join("score").is_dir()
+        || (dir.join("config.ini").is_file() && dir.join("songs").is_dir())
+}
+
+pub fn ksm_installs() -> Vec<KsmInstall> {
+    let names = ["kshootmania", "KShootMania", "K-Shoot MANIA", "ksm", "KSM"];
+    let mut dirs = folder_candidates(&names);
+    if cfg!(windows) {
+        // A recently opened score or songs folder points at the install it lives in.
+        recent_folders().into_iter().for_each(|dir| {
+            dirs.extend(dir.parent().map(Path::to_path_buf));
+            dirs.push(dir);
+        });
+    }
+    dedup_paths(dirs.into_iter().filter(|d| is_ksm_install(d)))
+        .into_iter()
+        .map(|path| KsmInstall {
+            has_scores: path.join("score").is_dir(),
+            path,
+        })
+        .collect()
+}
+
+fn recent_folders() -> Vec<PathBuf> {
+    let recent = match dirs::data_dir() {
+        Some(dir) => dir.join("Microsoft").join("Windows").join("Recent"),
+        None => return Vec::new(),
+    };
+    std::fs::read_dir(recent)
+        .into_iter()
+        .flatten()
+        .flatten()
+        .filter(|entry| {
+            let name = entry.file_name().to_string_lossy().to_lowercase();
+            name.ends_with(".lnk") && (name.contains("kshoot") || name.contains("ksm"))
+        })
+        .filter_map(|entry| lnk_target(&std::fs::read(entry.path()).ok()?))
+        .map(|target| match target.is_file() {
+            true => target.parent().map(Path::to_path_buf).unwrap_or(target),
+            false => target,
+        })
+        .collect()
+}
+
+fn le_u32(data: &[u8], at: usize) -> Option<usize> {
+    Some(u32::from_le_bytes(data.get(at..at + 4)?.try_into().ok()?) as usize)
+}
+
+// Reads the local path out of a shell link's LinkInfo block, preferring the unicode copy.
+fn lnk_target(data: &[u8]) -> Option<PathBuf> {
+    let flags = le_u32(data, 0x14)?;
+    let mut pos = 0x4c;
+    if flags & 0x1 != 0 {
+        pos += 2 + u16::from_le_bytes(data.get(pos..pos + 2)?.try_into().ok()?) as usize;
+    }
+    if flags & 0x2 == 0 {
+        return None;
+    }
+    let info = data.get(pos..)?;
+    if le_u32(info, 4)? >= 0x24 {
+        let wide: Vec<u16> = info
+            .get(le_u32(info, 0x1c)?..)?
+            .chunks_exact(2)
+            .map(|c| u16::from_le_bytes([c[0], c[1]]))
+            .take_while(|&c| c != 0)
+            .collect();
+        return Some(PathBuf::from(String::from_utf16(&wide).ok()?));
+    }
+    let ansi: Vec<u8> = info
+        .get(le_u32(info, 0x10)?..)?
+        .iter()
+        .copied()
+        .take_while(|&b| b != 0)
+        .collect();
+    Some(PathBuf::from(String::from_utf8_lossy(&ansi).into_owned()))
+}
@@ -0,0 +1,149 @@
+use crate::{checksum, report::Header, ImportedScore, Summary};
+use anyhow::Result;
+use serde::Serialize;
+use std::path::Path;
+
+// The score dump community stat sites take for an upload, so a site never needs maps.db:
+//
+//   {"format": "ksm-score-dump", "version": 1, "scores": [{"chart_hash": "<sha1 of the .ksh>",
+//    "score": 9500000, "lamp": "HARD_CLEAR", "timestamp": 1600000000, "gauge": 87.5}, ...]}
+//
+// Scores go in the order they were imported, the gauge is a percentage like KSM writes it.
+const FORMAT: &str = "ksm-score-dump";
+const VERSION: u32 = 1;
+
+#[derive(Serialize)]
+struct Dump<'a> {
+    format: &'static str,
+    version: u32,
+    scores: Vec<DumpScore<'a>>,
+}
+
+#[derive(Debug, PartialEq, Serialize)]
+struct DumpScore<'a> {
+    chart_hash: &'a str,
+    score: u32,
+    lamp: Lamp,
+    timestamp: i64,
+    gauge: f64,
+}
+
+impl<'a> DumpScore<'a> {
+    fn of(score: &'a ImportedScore) -> Self {
+        Self {
+            chart_hash: &score.chart_hash,
+            score: score.score,
+            lamp: Lamp::of_badge(score.badge),
+            timestamp: score.timestamp,
+            gauge: (score.gauge * 10_000.0).round() / 100.0,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "SCREAMING_SNAKE_CASE")]
+pub enum Lamp {
+    Failed,
+    Clear,
+    HardClear,
+    FullCombo,
+    Perfect,
+}
+
+impl Lamp {
+    // KSM badges are 0 or 1 failed, 2 clear, 3 hard clear, 4 full combo, 5 perfect.
+    pub fn of_badge(badge: u32) -> Self {
+        match badge {
+            2 => Lamp::Clear,
+            3 => Lamp::HardClear,
+            4 => Lamp::FullCombo,
+            5 => Lamp::Perfect,
+            _ => Lamp::Failed,
+        }
+    }
+}
+
+// Works the same for a dry run, whose scores are the ones it would have imported.
+pub fn json(summary: &Summary) -> Result<String> {
+    Ok(serde_json::to_string_pretty(&Dump {
+        format: FORMAT,
+        version: VERSION,
+        scores: summary.imported_scores.iter().map(DumpScore::of).collect(),
+    })?)
+}
+
+pub fn write(summary: &Summary, path: &Path) -> Result<()> {
+    std::fs::write(path, json(summary)?)?;
+    checksum::write_companion(path)?;
+    Ok(())
+}
+
+pub fn file_name(header: Option<&Header>) -> String {
+    match header.filter(|h| !h.label.is_empty()) {
+        Some(header) => format!("ksm2usc-scores-{}.json", header.label),
+        None => "ksm2usc-scores.json".to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn imported(badge: u32, gauge: f64) -> ImportedScore {
+        ImportedScore {
+            chart_hash: "1e292d6aaec314f026490eca7a66c0c48954d8f3".to_string(),
+            score: 9_500_000,
+            badge,
+            gauge,
+            timestamp: 1_600_000_000,
+        }
+    }
+
+    #[test]
+    fn badges_become_lamps() {
+        let lamps: Vec<Lamp> = (0..=6).map(Lamp::of_badge).collect();
+        assert_eq!(
+            lamps,
+            vec![
+                Lamp::Failed,
+                Lamp::Failed,
+                Lamp::Clear,
+                Lamp::HardClear,
+                Lamp::FullCombo,
+                Lamp::Perfect,
+                Lamp::Failed,
+            ]
+        );
+    }
+
+    #[test]
+    fn the_dump_has_the_shape_sites_expect() {
+        let summary = Summary {
+            imported_scores: vec![imported(3, 0.875), imported(0, 0.3333)],
+            ..Default::default()
+        };
+        let expected = r#"{
+  "format": "ksm-score-dump",
+  "version": 1,
+  "scores": [
+    {
+      "chart_hash": "1e292d6aaec314f026490eca7a66c0c48954d8f3",
+      "score": 9500000,
+      "lamp": "HARD_CLEAR",
+      "timestamp": 1600000000,
+      "gauge": 87.5
+    },
+    {
+      "chart_hash": "1e292d6aaec314f026490eca7a66c0c48954d8f3",
+      "score": 9500000,
+      "lamp": "FAILED",
+      "timestamp": 1600000000,
+      "gauge": 33.33
+    }
+  ]
+}"#;
+        assert_eq!(json(&summary).unwrap(), expected);
+        let empty = json(&Summary::default()).unwrap();
+        assert!(empty.contains("\"scores\": []"), "{}", empty);
+    }
+}
@@ -0,0 +1,883 @@
+use crate::checksum::{self, Check};
+use crate::{report, InsertedRow, Summary};
+use anyhow::{anyhow, bail, Context, Result};
+use rusqlite::{params, Connection, OptionalExtension};
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+const REPORT_FILE: &str = "report.json";
+const FAILED_FILE: &str = "failed.txt";
+const ROWS_FILE: &str = "rows.txt";
+// Rows of earlier runs this run gave a better badge, with that badge.
+const UPGRADED_FILE: &str = "upgraded.txt";
+// Written once a run was undone, its rows are gone from the database.
+const UNDONE_FILE: &str = "undone";
+
+// Tells apart runs one process records within the same second, a retry straight after a run.
+static RUNS_STARTED: AtomicU32 = AtomicU32::new(0);
+
+#[derive(Debug, Clone)]
+pub struct Run {
+    pub finished: i64,
+    pub ksm_path: PathBuf,
+    pub db_path: PathBuf,
+    pub imported: u32,
+    pub failed: u32,
+    pub crashed: bool,
+    pub label: String,
+    // Names the run's folder in config::runs_dir. Runs recorded before those folders existed
+    // have none and can only be listed.
+    pub id: String,
+}
+
+impl Run {
+    pub fn new(
+        label: &str,
+        ksm_path: &Path,
+        db_path: &Path,
+        summary: &Summary,
+        crashed: bool,
+    ) -> Self {
+        let finished = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs() as i64)
+            .unwrap_or_default();
+        Self {
+            label: sanitize_label(label, ksm_path),
+            finished,
+            ksm_path: ksm_path.to_path_buf(),
+            db_path: db_path.to_path_buf(),
+            imported: summary.scores_imported,
+            failed: summary.fail_messages.len() as u32 + summary.spilled_failures,
+            crashed,
+            id: format!(
+                "{}-{}-{}",
+                finished,
+                std::process::id(),
+                RUNS_STARTED.fetch_add(1, Ordering::Relaxed)
+            ),
+        }
+    }
+
+    fn encode(&self) -> String {
+        format!(
+            "{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}",
+            self.finished,
+            self.imported,
+            self.failed,
+            self.crashed as u8,
+            self.ksm_path.to_string_lossy(),
+            self.db_path.to_string_lossy(),
+            self.label,
+            self.id
+        )
+    }
+
+    fn decode(line: &str) -> Option<Self> {
+        let mut fields = line.splitn(8, '\t');
+        Some(Self {
+            finished: fields.next()?.parse().ok()?,
+            imported: fields.next()?.parse().ok()?,
+            failed: fields.next()?.parse().ok()?,
+            crashed: fields.next()? == "1",
+            ksm_path: PathBuf::from(fields.next()?),
+            db_path: PathBuf::from(fields.next()?),
+            label: fields.next().unwrap_or_default().to_string(),
+            id: fields.next().unwrap_or_default().to_string(),
+        })
+    }
+
+    pub fn dir(&self) -> Option<PathBuf> {
+        let runs = crate::config::runs_dir()?;
+        self.dir_in(&runs)
+    }
+
+    fn dir_in(&self, runs: &Path) -> Option<PathBuf> {
+        Some(runs.join(&self.id)).filter(|_| !self.id.is_empty())
+    }
+
+    pub fn header(&self) -> report::Header {
+        report::Header {
+            label: self.label.clone(),
+            finished: self.finished,
+            error: None,
+            archive: Some(&self.ksm_path)
+                .filter(|p| p.extension().is_some_and(|e| e.eq_ignore_ascii_case("zip")))
+                .cloned(),
+        }
+    }
+}
+
+// What a recorded run left behind in its folder.
+#[derive(Debug, Default, Clone)]
+pub struct RunFiles {
+    pub report: Option<PathBuf>,
+    // Score files with a failure, the spilled ones of a low memory run aren't known.
+    pub failed: Vec<PathBuf>,
+    pub rows: Vec<InsertedRow>,
+    pub upgraded: Vec<InsertedRow>,
+    pub undone: bool,
+    // Files that don't match their .sha256, they're loaded as if they were missing.
+    pub damaged: Vec<&'static str>,
+}
+
+impl RunFiles {
+    pub fn of(run: &Run) -> Self {
+        run.dir().map(|dir| Self::load(&dir)).unwrap_or_default()
+    }
+
+    fn load(dir: &Path) -> Self {
+        let mut damaged = Vec::new();
+        // A file that doesn't match its checksum is left out rather than acted on.
+        let mut read = |name: &'static str| {
+            let path = dir.join(name);
+            if checksum::verify(&path) == Check::Damaged {
+                damaged.push(name);
+                return None;
+            }
+            std::fs::read_to_string(path).ok()
+        };
+        let report = read(REPORT_FILE).map(|_| dir.join(REPORT_FILE));
+        let failed = read(FAILED_FILE)
+            .unwrap_or_default()
+            .lines()
+            .filter(|l| !l.is_empty())
+            .map(PathBuf::from)
+            .collect();
+        let mut rows = |name| {
+            read(name)
+                .unwrap_or_default()
+                .lines()
+                .filter_map(decode_row)
+                .collect()
+        };
+        let (rows, upgraded) = (rows(ROWS_FILE), rows(UPGRADED_FILE));
+        Self {
+            report,
+            failed,
+            rows,
+            upgraded,
+            undone: dir.join(UNDONE_FILE).exists(),
+            damaged,
+        }
+    }
+}
+
+fn encode_row(row: &InsertedRow) -> String {
+    let badge = row.badge.map(|b| format!("\t{}", b)).unwrap_or_default();
+    format!(
+        "{}\t{}\t{}\t{}{}",
+        row.rowid, row.chart_hash, row.score, row.timestamp, badge
+    )
+}
+
+fn decode_row(line: &str) -> Option<InsertedRow> {
+    let mut fields = line.split('\t');
+    Some(InsertedRow {
+        rowid: fields.next()?.parse().ok()?,
+        chart_hash: fields.next()?.to_string(),
+        score: fields.next()?.parse().ok()?,
+        timestamp: fields.next()?.parse().ok()?,
+        badge: fields.next().and_then(|b| b.parse().ok()),
+    })
+}
+
+// Labels default to the name of the imported folder or archive, and lose the control characters
+// that would break the index or a file name.
+fn sanitize_label(label: &str, ksm_path: &Path) -> String {
+    let label = match label.trim() {
+        "" => ksm_path
+            .file_stem()
+            .map(|n| n.to_string_lossy().to_string())
+            .unwrap_or_default(),
+        label => label.to_string(),
+    };
+    label
+        .chars()
+        .map(|c| {
+            if c.is_control() || "/\\:*?\"<>|".contains(c) {
+                '_'
+            } else {
+                c
+            }
+        })
+        .collect()
+}
+
+pub fn record(run: &Run, summary: &Summary) -> Result<()> {
+    let index = crate::config::history_path()
+        .ok_or_else(|| anyhow!("No config directory on this platform"))?;
+    let runs =
+        crate::config::runs_dir().ok_or_else(|| anyhow!("No config directory on this platform"))?;
+    record_in(&index, &runs, run, summary)
+}
+
+fn record_in(index: &Path, runs: &Path, run: &Run, summary: &Summary) -> Result<()> {
+    if let Some(dir) = run.dir_in(runs) {
+        write_run_files(&dir, run, summary)
+            .with_context(|| format!("Saving the details of run {}", run.id))?;
+    }
+    if let Some(parent) = index.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+    let mut file = OpenOptions::new().create(true).append(true).open(index)?;
+    writeln!(file, "{}", run.encode())?;
+    Ok(())
+}
+
+fn write_run_files(dir: &Path, run: &Run, summary: &Summary) -> Result<()> {
+    if let Some(runs) = dir.parent() {
+        std::fs::create_dir_all(runs)?;
+    }
+    // Never write over the files of another run, it couldn't be undone any more.
+    std::fs::create_dir(dir).with_context(|| format!("Run folder {} is taken", dir.display()))?;
+    report::write(summary, Some(&run.header()), &dir.join(REPORT_FILE))?;
+    let failed: BTreeSet<&Path> = summary
+        .fail_messages
+        .iter()
+        .map(|f| f.path.as_path())
+        .collect();
+    let failed: String = failed
+        .iter()
+        .map(|p| format!("{}\n", p.to_string_lossy()))
+        .collect();
+    let write = |name, contents: String| -> Result<()> {
+        std::fs::write(dir.join(name), contents)?;
+        checksum::write_companion(&dir.join(name))
+    };
+    write(FAILED_FILE, failed)?;
+    let rows =
+        |rows: &[InsertedRow]| -> String { rows.iter().map(|r| encode_row(r) + "\n").collect() };
+    write(ROWS_FILE, rows(&summary.inserted_rows))?;
+    if !summary.upgraded_rows.is_empty() {
+        write(UPGRADED_FILE, rows(&summary.upgraded_rows))?;
+    }
+    Ok(())
+}
+
+// Newest first, lines from a damaged or newer index are left out.
+pub fn load() -> Vec<Run> {
+    crate::config::history_path()
+        .map(|p| load_from(&p))
+        .unwrap_or_default()
+}
+
+fn load_from(index: &Path) -> Vec<Run> {
+    let mut runs: Vec<Run> = std::fs::read_to_string(index)
+        .unwrap_or_default()
+        .lines()
+        .filter_map(Run::decode)
+        .collect();
+    runs.reverse();
+    runs
+}
+
+// The rows runs that weren't undone wrote into `db_path`, with the best badge any later run gave
+// them. An import may only upgrade these.
+pub fn rows_written_to(db_path: &Path) -> Vec<InsertedRow> {
+    match (crate::config::history_path(), crate::config::runs_dir()) {
+        (Some(index), Some(runs)) => rows_written_in(&index, &runs, db_path),
+        _ => Vec::new(),
+    }
+}
+
+fn rows_written_in(index: &Path, runs: &Path, db_path: &Path) -> Vec<InsertedRow> {
+    let mut rows: BTreeMap<i64, InsertedRow> = BTreeMap::new();
+    // Oldest first, a rowid reused after an undo belongs to the later run.
+    for run in load_from(index)
+        .iter()
+        .rev()
+        .filter(|r| r.db_path == db_path)
+    {
+        let files = match run.dir_in(runs) {
+            Some(dir) => RunFiles::load(&dir),
+            None => continue,
+        };
+        if files.undone {
+            continue;
+        }
+        for row in files.rows {
+            rows.insert(row.rowid, row);
+        }
+        for upgraded in files.upgraded {
+            if let Some(row) = rows.get_mut(&upgraded.rowid) {
+                if same_row(row, &upgraded) {
+                    row.badge = row.badge.max(upgraded.badge);
+                }
+            }
+        }
+    }
+    rows.into_values().collect()
+}
+
+fn same_row(a: &InsertedRow, b: &InsertedRow) -> bool {
+    a.chart_hash == b.chart_hash && a.score == b.score && a.timestamp == b.timestamp
+}
+
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct Undone {
+    pub removed: u32,
+    // Already deleted, by USC or an earlier undo.
+    pub missing: u32,
+    // Rows that no longer hold the score the run wrote, they're left alone.
+    pub modified: Vec<i64>,
+    // Picked runs that were undone before, their rows weren't looked at again.
+    pub runs_undone_before: u32,
+}
+
+// Deletes the rows a run wrote in one transaction. A row is only deleted while it still holds
+// the chart, score and timestamp that were written, rowids get reused once rows are deleted.
+pub fn undo_rows(db: &Connection, rows: &[InsertedRow]) -> Result<Undone> {
+    db.execute_batch("BEGIN IMMEDIATE")?;
+    let undone = delete_rows(db, rows);
+    match &undone {
+        Ok(_) => db.execute_batch("COMMIT")?,
+        Err(_) => {
+            let _ = db.execute_batch("ROLLBACK");
+        }
+    }
+    undone
+}
+
+fn delete_rows(db: &Connection, rows: &[InsertedRow]) -> Result<Undone> {
+    let mut undone = Undone::default();
+    let mut select =
+        db.prepare("SELECT chart_hash, score, timestamp FROM Scores WHERE rowid = ?")?;
+    let mut delete = db.prepare("DELETE FROM Scores WHERE rowid = ?")?;
+    for row in rows {
+        let stored: Option<(String, u32, i64)> = select
+            .query_row(params![row.rowid], |r| {
+                Ok((r.get(0)?, r.get(1)?, r.get(2)?))
+            })
+            .optional()?;
+        match stored {
+            None => undone.missing += 1,
+            Some((hash, score, timestamp))
+                if hash == row.chart_hash && score == row.score && timestamp == row.timestamp =>
+            {
+                delete.execute(params![row.rowid])?;
+                undone.removed += 1;
+            }
+            Some(_) => undone.modified.push(row.rowid),
+        }
+    }
+    Ok(undone)
+}
+
+pub fn undo(run: &Run) -> Result<Undone> {
+    if run.id.is_empty() {
+        bail!("This run was recorded before runs could be undone");
+    }
+    undo_runs(std::slice::from_ref(&run.id))
+}
+
+// Undoes the runs with these ids in one transaction, so either all of their rows are removed or
+// none are. The runs have to share a database.
+pub fn undo_runs(ids: &[String]) -> Result<Undone> {
+    let index = crate::config::history_path()
+        .ok_or_else(|| anyhow!("No config directory on this platform"))?;
+    let runs =
+        crate::config::runs_dir().ok_or_else(|| anyhow!("No config directory on this platform"))?;
+    undo_runs_in(&index, &runs, ids)
+}
+
+fn undo_runs_in(index: &Path, runs: &Path, ids: &[String]) -> Result<Undone> {
+    let history = load_from(index);
+    let picked = ids
+        .iter()
+        .map(|id| {
+            history
+                .iter()
+                .find(|r| !r.id.is_empty() && &r.id == id)
+                .ok_or_else(|| anyhow!("Run {} isn't in the history", id))
+        })
+        .collect::<Result<Vec<_>>>()?;
+    let db_path = match picked.first() {
+        Some(run) => &run.db_path,
+        None => bail!("No runs were picked to undo"),
+    };
+    if picked.iter().any(|r| &r.db_path != db_path) {
+        bail!("The runs imported into more than one database, undo them one database at a time");
+    }
+    if !db_path.exists() {
+        bail!("{} no longer exists", db_path.display());
+    }
+    let mut runs_undone_before = 0;
+    let mut rows = Vec::new();
+    let mut dirs = Vec::new();
+    for run in picked {
+        let dir = run.dir_in(runs).context("Run without a folder")?;
+        let files = RunFiles::load(&dir);
+        if files.undone {
+            runs_undone_before += 1;
+            continue;
+        }
+        if files.damaged.contains(&ROWS_FILE) {
+            bail!(
+                "The list of rows run \"{}\" wrote is damaged, nothing was removed",
+                run.label
+            );
+        }
+        rows.extend(files.rows);
+        dirs.push(dir);
+    }
+    if dirs.is_empty() {
+        bail!(match ids.len() {
+            1 => "This run was undone already",
+            _ => "These runs were undone already",
+        });
+    }
+    let db = Connection::open(db_path)?;
+    let undone = undo_rows(&db, &rows)?;
+    for dir in dirs {
+        std::fs::write(dir.join(UNDONE_FILE), "")?;
+    }
+    Ok(Undone {
+        runs_undone_before,
+        ..undone
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{memory_db, TempDb};
+    use crate::{Failure, FailureKind};
+
+    fn insert_score(db: &Connection, hash: &str, score: u32, timestamp: i64) -> InsertedRow {
+        db.execute(
+            "INSERT INTO Scores(score, chart_hash, timestamp) VALUES(?, ?, ?)",
+            params![score, hash, timestamp],
+        )
+        .unwrap();
+        InsertedRow {
+            rowid: db.last_insert_rowid(),
+            chart_hash: hash.to_string(),
+            score,
+            timestamp,
+            badge: Some(2),
+        }
+    }
+
+    fn scores(db: &Connection) -> i64 {
+        db.query_row("SELECT COUNT(*) FROM Scores", [], |r| r.get(0))
+            .unwrap()
+    }
+
+    #[test]
+    fn undo_removes_only_unchanged_rows() {
+        let db = memory_db();
+        let kept = insert_score(&db, "other", 9_000_000, 10);
+        let rows = vec![
+            insert_score(&db, "a", 9_100_000, 20),
+            insert_score(&db, "b", 9_200_000, 30),
+            insert_score(&db, "c", 9_300_000, 40),
+        ];
+        db.execute(
+            "UPDATE Scores SET score = 1 WHERE rowid = ?",
+            [rows[1].rowid],
+        )
+        .unwrap();
+        db.execute("DELETE FROM Scores WHERE rowid = ?", [rows[2].rowid])
+            .unwrap();
+        let undone = undo_rows(&db, &rows).unwrap();
+        assert_eq!(
+            undone,
+            Undone {
+                removed: 1,
+                missing: 1,
+                modified: vec![rows[1].rowid],
+                runs_undone_before: 0,
+            }
+        );
+        assert_eq!(scores(&db), 2);
+        let left: Vec<i64> = db
+            .prepare("SELECT rowid FROM Scores ORDER BY rowid")
+            .unwrap()
+            .query_map([], |r| r.get(0))
+            .unwrap()
+            .map(|r| r.unwrap())
+            .collect();
+        assert_eq!(left, vec![kept.rowid, rows[1].rowid]);
+    }
+
+    #[test]
+    fn undoing_twice_removes_nothing_more() {
+        let db = memory_db();
+        let rows = vec![insert_score(&db, "a", 9_100_000, 20)];
+        assert_eq!(undo_rows(&db, &rows).unwrap().removed, 1);
+        let again = undo_rows(&db, &rows).unwrap();
+        assert_eq!((again.removed, again.missing), (0, 1));
+    }
+
+    // Records a run into `db` that wrote `rows`, and returns its id.
+    fn record_rows(index: &Path, runs: &Path, db: &Path, id: &str, rows: &[InsertedRow]) -> String {
+        let summary = Summary {
+            inserted_rows: rows.to_vec(),
+            ..Default::default()
+        };
+        let mut run = Run::new("friend-B", Path::new("/games/KSM"), db, &summary, false);
+        run.id = id.to_string();
+        record_in(index, runs, &run, &summary).unwrap();
+        run.id
+    }
+
+    #[test]
+    fn picked_runs_are_undone_together() {
+        let dir = tempfile::tempdir().unwrap();
+        let (index, runs) = (dir.path().join("history.txt"), dir.path().join("runs"));
+        let db = TempDb::new();
+        let conn = db.open();
+        let first = [
+            insert_score(&conn, "a", 9_100_000, 20),
+            insert_score(&conn, "b", 9_200_000, 30),
+        ];
+        let second = [
+            insert_score(&conn, "c", 9_300_000, 40),
+            insert_score(&conn, "d", 9_400_000, 50),
+        ];
+        let undone_before = [insert_score(&conn, "e", 9_500_000, 60)];
+        let ids = vec![
+            record_rows(&index, &runs, &db.path, "1", &first),
+            record_rows(&index, &runs, &db.path, "2", &second),
+            record_rows(&index, &runs, &db.path, "3", &undone_before),
+        ];
+        std::fs::write(runs.join("3").join(UNDONE_FILE), "").unwrap();
+        // Played over in USC since the import.
+        conn.execute(
+            "UPDATE Scores SET score = 1 WHERE rowid = ?",
+            [second[0].rowid],
+        )
+        .unwrap();
+
+        let undone = undo_runs_in(&index, &runs, &ids).unwrap();
+        assert_eq!(
+            undone,
+            Undone {
+                removed: 3,
+                missing: 0,
+                modified: vec![second[0].rowid],
+                runs_undone_before: 1,
+            }
+        );
+        assert_eq!(db.scores(), 2);
+        assert!(["1", "2"]
+            .iter()
+            .all(|id| RunFiles::load(&runs.join(id)).undone));
+
+        let again = undo_runs_in(&index, &runs, &ids).unwrap_err();
+        assert_eq!(again.to_string(), "These runs were undone already");
+        assert_eq!(db.scores(), 2);
+    }
+
+    #[test]
+    fn a_run_that_cant_be_undone_keeps_the_others_rows() {
+        let dir = tempfile::tempdir().unwrap();
+        let (index, runs) = (dir.path().join("history.txt"), dir.path().join("runs"));
+        let (db, other) = (TempDb::new(), TempDb::new());
+        let conn = db.open();
+        let first = [insert_score(&conn, "a", 9_100_000, 20)];
+        let second = [insert_score(&conn, "b", 9_200_000, 30)];
+        let ids = vec![
+            record_rows(&index, &runs, &db.path, "1", &first),
+            record_rows(&index, &runs, &db.path, "2", &second),
+            record_rows(&index, &runs, &other.path, "3", &[]),
+        ];
+
+        let err = undo_runs_in(&index, &runs, &ids).unwrap_err();
+        assert!(
+            err.to_string().contains("more than one database"),
+            "{}",
+            err
+        );
+        let rows = runs.join("2").join(ROWS_FILE);
+        std::fs::write(&rows, "").unwrap();
+        let err = undo_runs_in(&index, &runs, &ids[..2]).unwrap_err();
+        assert!(err.to_string().contains("damaged"), "{}", err);
+        let err = undo_runs_in(&index, &runs, &["4".to_string()]).unwrap_err();
+        assert!(err.to_string().contains("isn't in the history"), "{}", err);
+
+        assert_eq!(db.scores(), 2);
+        assert!(!RunFiles::load(&runs.join("1")).undone);
+    }
+
+    #[test]
+    fn a_recorded_run_keeps_its_failures_and_rows() {
+        let dir = tempfile::tempdir().unwrap();
+        let (index, runs) = (dir.path().join("history.txt"), dir.path().join("runs"));
+        let failure = |path: &str| Failure {
+            path: PathBuf::from(path),
+            kind: FailureKind::Parse,
+            reason: String::new(),
+            message: String::new(),
+        };
+        let summary = Summary {
+            scores_imported: 1,
+            fail_messages: vec![failure("b.ksc"), failure("a.ksc"), failure("b.ksc")],
+            inserted_rows: vec![InsertedRow {
+                rowid: 7,
+                chart_hash: "hash".to_string(),
+                score: 9_500_000,
+                timestamp: 1_600_000_000,
+                badge: Some(3),
+            }],
+            ..Default::default()
+        };
+        let run = Run::new(
+            "",
+            Path::new("/games/KSM"),
+            Path::new("maps.db"),
+            &summary,
+            false,
+        );
+        record_in(&index, &runs, &run, &summary).unwrap();
+
+        let loaded = load_from(&index);
+        assert_eq!(loaded.len(), 1);
+        assert_eq!(loaded[0].id, run.id);
+        assert_eq!(loaded[0].label, "KSM");
+        assert_eq!((loaded[0].imported, loaded[0].failed), (1, 3));
+        let files = RunFiles::load(&loaded[0].dir_in(&runs).unwrap());
+        assert!(files.report.is_some());
+        assert_eq!(
+            files.failed,
+            vec![PathBuf::from("a.ksc"), PathBuf::from("b.ksc")]
+        );
+        assert_eq!(files.rows, summary.inserted_rows);
+        assert!(!files.undone);
+    }
+
+    #[test]
+    fn damaged_run_files_are_left_out_when_loading() {
+        let dir = tempfile::tempdir().unwrap();
+        let (index, runs) = (dir.path().join("history.txt"), dir.path().join("runs"));
+        let summary = Summary {
+            fail_messages: vec![Failure {
+                path: PathBuf::from("a.ksc"),
+                kind: FailureKind::Parse,
+                reason: String::new(),
+                message: String::new(),
+            }],
+            inserted_rows: vec![InsertedRow {
+                rowid: 7,
+                chart_hash: "hash".to_string(),
+                score: 9_500_000,
+                timestamp: 1_600_000_000,
+                badge: Some(3),
+            }],
+            ..Default::default()
+        };
+        let run = Run::new(
+            "",
+            Path::new("/games/KSM"),
+            Path::new("maps.db"),
+            &summary,
+            false,
+        );
+        record_in(&index, &runs, &run, &summary).unwrap();
+        let dir = load_from(&index)[0].dir_in(&runs).unwrap();
+        report::write(&summary, Some(&run.header()), &dir.join(REPORT_FILE)).unwrap();
+
+        let files = RunFiles::load(&dir);
+        assert!(files.damaged.is_empty());
+        assert!(files.report.is_some());
+        assert_eq!(files.rows, summary.inserted_rows);
+
+        // Cut short, the way a sync that gave up halfway leaves it.
+        let rows = std::fs::read(dir.join(ROWS_FILE)).unwrap();
+        std::fs::write(dir.join(ROWS_FILE), &rows[..rows.len() / 2]).unwrap();
+        let mut report = std::fs::read(dir.join(REPORT_FILE)).unwrap();
+        report[10] ^= 0x01;
+        std::fs::write(dir.join(REPORT_FILE), report).unwrap();
+        let files = RunFiles::load(&dir);
+        assert_eq!(files.damaged, vec![REPORT_FILE, ROWS_FILE]);
+        assert!(files.report.is_none());
+        assert!(files.rows.is_empty());
+        assert_eq!(files.failed, vec![PathBuf::from("a.ksc")]);
+
+        // Runs recorded before checksums were written are trusted.
+        std::fs::remove_file(checksum::companion_path(&dir.join(ROWS_FILE))).unwrap();
+        let files = RunFiles::load(&dir);
+        assert_eq!(files.damaged, vec![REPORT_FILE]);
+    }
+
+    #[test]
+    fn runs_recorded_in_the_same_second_keep_their_own_rows() {
+        let dir = tempfile::tempdir().unwrap();
+        let (index, runs) = (dir.path().join("history.txt"), dir.path().join("runs"));
+        let summary = |rowid| Summary {
+            inserted_rows: vec![InsertedRow {
+                rowid,
+                chart_hash: "hash".to_string(),
+                score: 9_500_000,
+                timestamp: 1_600_000_000,
+                badge: Some(2),
+            }],
+            ..Default::default()
+        };
+        let ksm = Path::new("/games/KSM");
+        let (first, retry) = (summary(1), summary(2));
+        let first_run = Run::new("", ksm, Path::new("maps.db"), &first, false);
+        let mut retry_run = Run::new("", ksm, Path::new("maps.db"), &retry, false);
+        retry_run.finished = first_run.finished;
+        assert_ne!(first_run.id, retry_run.id);
+        record_in(&index, &runs, &first_run, &first).unwrap();
+        record_in(&index, &runs, &retry_run, &retry).unwrap();
+
+        let rows = |run: &Run| RunFiles::load(&run.dir_in(&runs).unwrap()).rows;
+        assert_eq!(rows(&first_run), first.inserted_rows);
+        assert_eq!(rows(&retry_run), retry.inserted_rows);
+
+        // A folder that's taken anyway is an error, not overwritten.
+        assert!(record_in(&index, &runs, &first_run, &retry).is_err());
+        assert_eq!(rows(&first_run), first.inserted_rows);
+        assert_eq!(load_from(&index).len(), 2);
+    }
+
+    #[test]
+    fn runs_recorded_before_run_folders_still_load() {
+        let dir = tempfile::tempdir().unwrap();
+        let index = dir.path().join("history.txt");
+        std::fs::write(&index, "1600000000\t3\t0\t0\t/games/KSM\tmaps.db\tfriend\n").unwrap();
+        let loaded = load_from(&index);
+        assert_eq!(loaded[0].label, "friend");
+        assert!(loaded[0].id.is_empty());
+        assert!(loaded[0].dir_in(dir.path()).is_none());
+    }
+
+    #[test]
+    fn hostile_labels_are_made_safe_for_the_index_and_file_names() {
+        let run = Run::new(
+            " ../friend\tB:\n*best*? ",
+            Path::new("/games/KSM"),
+            Path::new("maps.db"),
+            &Summary::default(),
+            false,
+        );
+        assert_eq!(run.label, ".._friend_B___best__");
+        assert_eq!(
+            report::file_name(Some(&run.header())),
+            "ksm2usc-report-.._friend_B___best__.txt"
+        );
+        let loaded = Run::decode(&run.encode()).unwrap();
+        assert_eq!(loaded.label, run.label);
+        assert_eq!(loaded.id, run.id);
+    }
+
+    #[test]
+    fn empty_labels_default_to_the_folder_or_archive_name() {
+        let label = |label: &str, ksm: &str| {
+            Run::new(
+                label,
+                Path::new(ksm),
+                Path::new("maps.db"),
+                &Summary::default(),
+                false,
+            )
+            .label
+        };
+        assert_eq!(label("", "/games/KSM"), "KSM");
+        assert_eq!(label("   ", "/backups/friend-B.zip"), "friend-B");
+        assert_eq!(label("cab", "/games/KSM"), "cab");
+    }
+
+    #[test]
+    fn the_saved_report_names_the_run() {
+        let dir = tempfile::tempdir().unwrap();
+        let (index, runs) = (dir.path().join("history.txt"), dir.path().join("runs"));
+        let summary = Summary::default();
+        let run = Run::new(
+            "friend-B",
+            Path::new("/games/KSM"),
+            Path::new("maps.db"),
+            &summary,
+            false,
+        );
+        record_in(&index, &runs, &run, &summary).unwrap();
+        let dir = load_from(&index)[0].dir_in(&runs).unwrap();
+        let path = dir.join("report.txt");
+        report::write(&summary, Some(&run.header()), &path).unwrap();
+        let report = std::fs::read_to_string(&path).unwrap();
+        assert!(report.starts_with("Run: friend-B\nFinished: "));
+        assert!(RunFiles::load(&dir).report.is_some());
+
+        let run = Run::new(
+            "",
+            Path::new("/friends/b.zip"),
+            Path::new("maps.db"),
+            &summary,
+            false,
+        );
+        let header = run.header();
+        let report = report::json(&summary, Some(&header)).unwrap();
+        assert!(
+            report.contains("\"archive\": \"/friends/b.zip\""),
+            "{}",
+            report
+        );
+        report::write(&summary, Some(&header), &path).unwrap();
+        let report = std::fs::read_to_string(path).unwrap();
+        assert!(report.starts_with("Run: b\nRead from the archive \"/friends/b.zip\"\n"));
+    }
+
+    #[test]
+    fn rows_written_to_a_database_carry_their_best_badge() {
+        let dir = tempfile::tempdir().unwrap();
+        let index = dir.path().join("history.txt");
+        let runs = dir.path().join("runs");
+        let row = |rowid, badge| InsertedRow {
+            rowid,
+            chart_hash: "hash".to_string(),
+            score: 9_500_000,
+            timestamp: 1_600_000_000,
+            badge: Some(badge),
+        };
+        let record = |id: &str, db: &str, summary: Summary| {
+            let mut run = Run::new("", Path::new("/games/KSM"), Path::new(db), &summary, false);
+            run.id = id.to_string();
+            record_in(&index, &runs, &run, &summary).unwrap();
+        };
+        record(
+            "1",
+            "maps.db",
+            Summary {
+                inserted_rows: vec![row(1, 2), row(2, 2)],
+                ..Default::default()
+            },
+        );
+        record(
+            "2",
+            "maps.db",
+            Summary {
+                upgraded_rows: vec![row(1, 4)],
+                ..Default::default()
+            },
+        );
+        record(
+            "3",
+            "other/maps.db",
+            Summary {
+                inserted_rows: vec![row(3, 2)],
+                ..Default::default()
+            },
+        );
+        record(
+            "4",
+            "maps.db",
+            Summary {
+                inserted_rows: vec![row(4, 2)],
+                ..Default::default()
+            },
+        );
+        std::fs::write(runs.join("4").join(UNDONE_FILE), "").unwrap();
+
+        assert_eq!(
+            rows_written_in(&index, &runs, Path::new("maps.db")),
+            vec![row(1, 4), row(2, 2)]
+        );
+    }
+}
@@ -0,0 +1,90 @@
+// UI copy by locale, every key has a text in each language.
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum Locale {
+    #[default]
+    English,
+    Japanese,
+}
+
+impl Locale {
+    // The language set in the config, otherwise the one of the environment. Windows doesn't set
+    // LANG, there the config decides.
+    pub fn detect(configured: Option<&str>) -> Self {
+        let tag = configured
+            .map(str::to_string)
+            .or_else(|| system_locale("LC_MESSAGES"));
+        Self::from_tag(tag.as_deref())
+    }
+
+    // "ja", "ja_JP.UTF-8" and the like.
+    fn from_tag(tag: Option<&str>) -> Self {
+        match tag {
+            Some(tag) if tag.to_ascii_lowercase().starts_with("ja") => Locale::Japanese,
+            _ => Locale::English,
+        }
+    }
+}
+
+// The locale POSIX systems use for `category`, such as "ja_JP.UTF-8".
+pub fn system_locale(category: &str) -> Option<String> {
+    ["LC_ALL", category, "LANG"]
+        .iter()
+        .find_map(|var| std::env::var(var).ok().filter(|v| !v.is_empty()))
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Key {
+    IntroReads,
+    IntroWrites,
+    IntroPaths,
+    DontShowAgain,
+}
+
+pub fn text(locale: Locale, key: Key) -> &'static str {
+    match (locale, key) {
+        (Locale::English, Key::IntroReads) => {
+            "This tool reads the score files in your KSM \"score\" folder and the matching charts \
+            in its \"songs\" folder, which are hashed so USC can recognise them."
+        }
+        (Locale::English, Key::IntroWrites) => {
+            "It adds rows to the Scores table of the selected maps.db, after saving a backup copy \
+            next to it. Charts, replays and scores already in the database are left as they are. \
+            With \"Optimize database after import\" on, the whole file is rebuilt afterwards."
+        }
+        (Locale::English, Key::IntroPaths) => {
+            "KSM Path is the K-Shoot Mania folder that contains \"score\" and \"songs\", Archive… \
+            picks a .zip of a score folder instead. USC maps.db Path is the maps.db file in your \
+            USC folder."
+        }
+        (Locale::English, Key::DontShowAgain) => "Don't show this again",
+        (Locale::Japanese, Key::IntroReads) => {
+            "このツールは KSM の「score」フォルダにあるスコアファイルと、「songs」フォルダにある\
+            対応する譜面を読み込みます。譜面は USC が認識できるようにハッシュ化されます。"
+        }
+        (Locale::Japanese, Key::IntroWrites) => {
+            "選択した maps.db の横にバックアップを保存してから、その Scores テーブルに行を追加します。\
+            譜面、リプレイ、既存のスコアはそのまま残ります。「Optimize database after import」を\
+            有効にすると、インポート後にファイル全体が再構築されます。"
+        }
+        (Locale::Japanese, Key::IntroPaths) => {
+            "KSM Path には「score」と「songs」を含む K-Shoot Mania のフォルダを指定します。\
+            Archive… ではスコアフォルダの .zip を選べます。USC maps.db Path には USC フォルダ内の \
+            maps.db ファイルを指定します。"
+        }
+        (Locale::Japanese, Key::DontShowAgain) => "次回から表示しない",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn japanese_tags_pick_japanese() {
+        assert_eq!(Locale::from_tag(Some("ja_JP.UTF-8")), Locale::Japanese);
+        assert_eq!(Locale::from_tag(Some("JA")), Locale::Japanese);
+        assert_eq!(Locale::from_tag(Some("en_US.UTF-8")), Locale::English);
+        assert_eq!(Locale::from_tag(None), Locale::English);
+    }
+}
@@ -1,25 +1,627 @@
-use crate::{importer_funcs::ImportFn, Summary};
-use anyhow::{ensure, Result};
+use crate::{
+    checksum,
+    importer_funcs::{
+        self, ChartMissing, EarlierRows, HashCache, ImportFn, InsertKind, InsertOptions, Inserted,
+        LevelRange, TimestampAdjustment, TimestampPolicy, Upgrade,
+    },
+    schema,
+    source::{self, FsSource, MemorySource, ScoreFiles, Source},
+    watchdog::{self, STALL_TIMEOUT},
+    Failure, FailureKind, ImportedScore, InsertedRow, Summary,
+};
+use anyhow::{anyhow, bail, ensure, Context, Result};
 use iced_futures::futures;
-use rusqlite::Connection;
-use std::io::{BufRead, BufReader};
+use rusqlite::{Connection, ErrorCode, OpenFlags};
+use serde::Serialize;
+use std::any::Any;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::ffi::OsStr;
+use std::fs::OpenOptions;
+use std::io::{BufRead, BufReader, Write};
+use std::panic::AssertUnwindSafe;
 use std::path::PathBuf;
 use std::rc::Rc;
 use std::str::FromStr;
-use std::{cell::RefCell, path::Path};
-use walkdir::DirEntry;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use std::{
+    cell::{Cell, OnceCell, RefCell},
+    path::Path,
+};
+
+#[derive(Debug, Clone)]
+pub struct ImportOptions {
+    pub safe_mode: bool,
+    pub optimize: bool,
+    pub low_memory: bool,
+    pub policy: Policy,
+    pub songs_root: Option<PathBuf>,
+    pub heartbeat_file: Option<PathBuf>,
+    // Where a low memory import writes its failures, config::spill_path unless set.
+    pub spill_path: Option<PathBuf>,
+    // Checked between score files, a cancelled import ends with Progress::Cancelled.
+    pub cancel: Arc<AtomicBool>,
+    // While set, no new score file is started. The current one always finishes first.
+    pub pause: Arc<AtomicBool>,
+    pub limits: FileLimits,
+    pub user_name: String,
+    pub user_id: String,
+    pub skip_duplicates: bool,
+    // Skips scores that don't beat the chart's best in USC. Hard and effective gauge bests are
+    // compared separately unless compare_across_gauges is set.
+    pub improvements_only: bool,
+    pub compare_across_gauges: bool,
+    pub dry_run: bool,
+    // Import into an unknown database version through the Scores columns it turns out to have.
+    pub best_effort_schema: bool,
+    // Runs the whole import in one transaction, committed only once the last file is in.
+    pub all_or_nothing: bool,
+    // Databases too large to copy on every import can go without the backup.
+    pub skip_backup: bool,
+    // When not empty only these score files are imported, to retry the ones a run failed on.
+    pub only_files: Vec<PathBuf>,
+    // Rows earlier runs wrote into this maps.db, see history::rows_written_to. A score skipped as
+    // already imported or as no improvement upgrades its row when it has a better badge.
+    pub earlier_rows: Arc<Vec<InsertedRow>>,
+}
+
+// Scores already in the database are skipped unless asked otherwise, so a second run over the
+// same folder adds nothing.
+impl Default for ImportOptions {
+    fn default() -> Self {
+        Self {
+            safe_mode: false,
+            optimize: false,
+            low_memory: false,
+            policy: Policy::default(),
+            songs_root: None,
+            heartbeat_file: None,
+            spill_path: None,
+            cancel: Arc::default(),
+            pause: Arc::default(),
+            limits: FileLimits::default(),
+            user_name: String::new(),
+            user_id: String::new(),
+            skip_duplicates: true,
+            improvements_only: false,
+            compare_across_gauges: false,
+            dry_run: false,
+            best_effort_schema: false,
+            all_or_nothing: false,
+            skip_backup: false,
+            only_files: Vec::new(),
+            earlier_rows: Arc::default(),
+        }
+    }
+}
+
+// Phases add up to nearly all of `total`, which is the time the import ran without the pauses
+// for a lock.
+#[derive(Debug, Default, Clone, Serialize)]
+pub struct Timings {
+    pub scanning: Duration,
+    pub backing_up: Duration,
+    pub reading: Duration,
+    pub charts: Duration,
+    pub database: Duration,
+    pub committing: Duration,
+    pub optimizing: Duration,
+    pub total: Duration,
+}
+
+impl Timings {
+    pub fn phases(&self) -> [(&'static str, Duration); 7] {
+        [
+            ("scanning", self.scanning),
+            ("backing up", self.backing_up),
+            ("reading", self.reading),
+            ("charts", self.charts),
+            ("database", self.database),
+            ("committing", self.committing),
+            ("optimizing", self.optimizing),
+        ]
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Severity {
+    Warning,
+    Error,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct OptionConflict {
+    pub severity: Severity,
+    pub message: &'static str,
+}
+
+type OptionRule = (fn(&ImportOptions) -> bool, Severity, &'static str);
+
+const OPTION_RULES: &[OptionRule] = &[
+    (
+        |o| o.dry_run && o.optimize,
+        Severity::Warning,
+        "A dry run leaves the database as it was, the optimize step is skipped",
+    ),
+    (
+        |o| o.skip_duplicates && matches!(o.policy.timestamps, TimestampPolicy::Before(_)),
+        Severity::Warning,
+        "Backdated times depend on how many files are imported, re-importing a changed folder \
+        can still write duplicates",
+    ),
+    (
+        |o| schema::user_id(&o.user_id).is_err(),
+        Severity::Error,
+        "The user id has to be a whole number, like the ids USC writes",
+    ),
+    (
+        |o| !o.user_id.is_empty() && o.user_name.is_empty(),
+        Severity::Warning,
+        "A user id without a player name shows up as an unnamed player in USC",
+    ),
+];
+
+impl ImportOptions {
+    pub fn validate(&self) -> Vec<OptionConflict> {
+        OPTION_RULES
+            .iter()
+            .filter(|(applies, _, _)| applies(self))
+            .map(|&(_, severity, message)| OptionConflict { severity, message })
+            .collect()
+    }
+}
+
+// Keeps a damaged or misnamed file from stalling the import with millions of failures.
+#[derive(Debug, Clone, Copy)]
+pub struct FileLimits {
+    pub max_size: u64,
+    pub max_lines: usize,
+    pub max_failures: usize,
+}
+
+impl Default for FileLimits {
+    fn default() -> Self {
+        Self {
+            max_size: 4 << 20,
+            max_lines: 100_000,
+            max_failures: 100,
+        }
+    }
+}
+
+// Archives are read into memory up front, so open the source once per import. Setting `cancel`
+// also gives up on a folder read that is stuck.
+pub fn open_source(ksm_path: &Path, cancel: &Arc<AtomicBool>) -> Result<Arc<dyn Source>> {
+    if source::is_archive(ksm_path) {
+        Ok(Arc::new(MemorySource::from_zip(ksm_path)?))
+    } else {
+        Ok(Arc::new(FsSource {
+            ksm_path: ksm_path.to_path_buf(),
+            cancel: cancel.clone(),
+        }))
+    }
+}
+
+// Runs the whole import on the calling thread, for callers that don't drive an iced Subscription.
+pub fn import_scores(
+    ksm_path: &Path,
+    db_path: &Path,
+    options: ImportOptions,
+    progress: impl FnMut(&Progress),
+) -> Result<Summary> {
+    let source = open_source(ksm_path, &options.cancel)?;
+    import_from(source, db_path, options, progress)
+}
+
+// import_scores over any source, e.g. score files a caller already holds in memory.
+pub fn import_from(
+    source: Arc<dyn Source>,
+    db_path: &Path,
+    options: ImportOptions,
+    mut progress: impl FnMut(&Progress),
+) -> Result<Summary> {
+    if let Some(conflict) = options
+        .validate()
+        .into_iter()
+        .find(|c| c.severity == Severity::Error)
+    {
+        bail!(conflict.message);
+    }
+    // Nobody is there to press Retry, a lock cancels the import.
+    let cancel = options.cancel.clone();
+    let mut locked = false;
+    let mut state = State::Ready {
+        source,
+        db: db_path.to_path_buf(),
+        options,
+    };
+    while let Some((p, next)) = futures::executor::block_on(run_importer(state)) {
+        progress(&p);
+        match p {
+            Progress::Finished(summary) => return Ok(summary),
+            Progress::Locked => {
+                locked = true;
+                cancel.store(true, Ordering::Relaxed);
+                state = next;
+            }
+            Progress::Cancelled(_) if locked => bail!(LOCKED_MESSAGE),
+            Progress::Cancelled(_) => bail!("Import cancelled"),
+            Progress::Errored(e, _) => return Err(anyhow!(e)),
+            _ => state = next,
+        }
+    }
+    bail!("Import ended without a result")
+}
 
-pub fn import(ksm_path: &Path, db_path: &Path) -> Result<iced::Subscription<Progress>> {
+// iced keeps a subscription running as long as its hash is unchanged, every import needs its
+// own run_id to start a new stream. `source` is read from `ksm_path`.
+pub fn import_source(
+    ksm_path: &Path,
+    source: Arc<dyn Source>,
+    db_path: &Path,
+    options: ImportOptions,
+    run_id: u64,
+) -> Result<iced::Subscription<Progress>> {
     Ok(iced::Subscription::from_recipe(Importer {
-        db_path: db_path.to_path_buf(),
         ksm_path: ksm_path.to_path_buf(),
+        db_path: db_path.to_path_buf(),
+        source,
+        options,
+        run_id,
     }))
 }
 
-pub fn validate_paths(ksm_path: &Path, db_path: &Path) -> Result<()> {
+#[derive(Debug, Default, Clone)]
+pub struct Preflight {
+    pub score_triggers: Vec<String>,
+    pub duplicate_charts: u32,
+    pub scores_previewed: u32,
+    pub skipped: BTreeMap<SkipRule, u32>,
+    pub junk_files: u32,
+    pub case_variants: Vec<Vec<PathBuf>>,
+    pub db_size: u64,
+    pub db_version: u32,
+    // Only for a version no importer knows.
+    pub schema_audit: Option<schema::Audit>,
+    pub users: Vec<UserIdentity>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UserIdentity {
+    pub name: String,
+    pub id: String,
+}
+
+impl UserIdentity {
+    // USC's own local scores carry user id 0, an empty id writes exactly that.
+    pub fn of(options: &ImportOptions) -> Self {
+        Self {
+            name: options.user_name.clone(),
+            id: if options.user_id.is_empty() {
+                "0".to_string()
+            } else {
+                options.user_id.clone()
+            },
+        }
+    }
+
+    pub fn apply(&self, options: &mut ImportOptions) {
+        options.user_name = self.name.clone();
+        options.user_id = if self.id == "0" {
+            String::new()
+        } else {
+            self.id.clone()
+        };
+    }
+}
+
+impl std::fmt::Display for UserIdentity {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let name = if self.name.is_empty() {
+            "Unnamed"
+        } else {
+            &self.name
+        };
+        write!(f, "{} (user id {})", name, self.id)
+    }
+}
+
+// The players that already have scores, the one with the most first.
+fn users(db: &Connection) -> Result<Vec<UserIdentity>> {
+    let users = db
+        .prepare(
+            "SELECT IFNULL(user_name, ''), IFNULL(CAST(user_id AS TEXT), '0') FROM Scores \
+            GROUP BY 1, 2 ORDER BY COUNT(*) DESC",
+        )?
+        .query_map([], |r| {
+            Ok(UserIdentity {
+                name: r.get(0)?,
+                id: r.get(1)?,
+            })
+        })?
+        .collect::<rusqlite::Result<_>>()?;
+    Ok(users)
+}
+
+pub fn db_users(db_path: &Path) -> Result<Vec<UserIdentity>> {
+    users(&Connection::open_with_flags(
+        db_path,
+        OpenFlags::SQLITE_OPEN_READ_ONLY,
+    )?)
+}
+
+// Databases past this size can exhaust the address space of a 32-bit build.
+pub const LARGE_DB_SIZE: u64 = 2 << 30;
+
+pub fn address_space_limited() -> bool {
+    cfg!(target_pointer_width = "32")
+}
+
+fn database_size(db_path: &Path) -> u64 {
+    std::fs::metadata(db_path)
+        .map(|m| m.len())
+        .unwrap_or_default()
+}
+
+// Score files are imported and reported by pack, then by path. The pack is the third folder from
+// the end of score/<profile>/<pack>/<song>/<difficulty>.ksc, so one pack's scores of every
+// profile stay together. Failures are listed by kind first, then in this order.
+fn report_order(path: &Path) -> (Option<&OsStr>, &Path) {
+    (path.iter().rev().nth(2), path)
+}
+
+// Removes the files not modified since the cutoff and returns how many there were. A file
+// whose date can't be read is kept, opening it is cheaper than losing its scores.
+fn drop_unmodified(source: &dyn Source, files: &mut Vec<PathBuf>, cutoff: Option<i64>) -> u32 {
+    let cutoff = match cutoff {
+        Some(cutoff) => cutoff,
+        None => return 0,
+    };
+    let before = files.len();
+    files.retain(|f| {
+        source
+            .modified(f)
+            .ok()
+            .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+            .is_none_or(|d| d.as_secs() as i64 > cutoff)
+    });
+    (before - files.len()) as u32
+}
+
+pub fn validate_paths(
+    source: &dyn Source,
+    ksm_path: &Path,
+    db_path: &Path,
+    policy: Policy,
+    limits: FileLimits,
+) -> Result<Preflight> {
     ensure!(ksm_path.exists(), "KSM path invalid: {:?}", ksm_path);
     ensure!(db_path.exists(), "maps.db path invalid: {:?}", db_path);
-    Ok(())
+
+    let db = Connection::open_with_flags(db_path, OpenFlags::SQLITE_OPEN_READ_ONLY)?;
+    let score_triggers = db
+        .prepare("SELECT name FROM sqlite_master WHERE type = 'trigger' AND tbl_name = 'Scores'")?
+        .query_map([], |r| r.get(0))?
+        .collect::<rusqlite::Result<Vec<String>>>()?;
+    let db_version = db
+        .query_row("SELECT version FROM `Database`", [], |r| r.get(0))
+        .unwrap_or_default();
+    let duplicate_charts = db
+        .query_row(
+            "SELECT COUNT(*) FROM (SELECT hash FROM Charts GROUP BY hash HAVING COUNT(*) > 1)",
+            [],
+            |r| r.get(0),
+        )
+        .unwrap_or_default();
+
+    let mut scores_previewed = 0;
+    let mut skipped = BTreeMap::new();
+    let ScoreFiles {
+        mut files,
+        junk_files,
+    } = source.score_files(&mut Vec::new())?;
+    drop_unmodified(source, &mut files, policy.modified_after);
+    // The same limits as the import, a damaged file would otherwise hold up the preview.
+    files
+        .iter()
+        .filter(|f| source.size(f).is_ok_and(|size| size <= limits.max_size))
+        .filter_map(|f| source.read_score(f).ok())
+        .for_each(|file| {
+            let scores = score_lines(&file)
+                .take(limits.max_lines)
+                .filter_map(|line| {
+                    scores_previewed += 1;
+                    let score = KsmScore::parse(&line, policy.nonstandard_options);
+                    let rule = SkipRule::check(&line, policy).or_else(|| {
+                        score
+                            .as_ref()
+                            .ok()
+                            .and_then(|s| SkipRule::check_score(s, policy))
+                    });
+                    match rule {
+                        Some(rule) => {
+                            *skipped.entry(rule).or_insert(0) += 1;
+                            None
+                        }
+                        None => score.ok(),
+                    }
+                })
+                .map(|mut s| {
+                    if s.is_legacy_scale() {
+                        s.convert_legacy_scale();
+                    }
+                    s
+                })
+                .collect();
+            keep_best(scores, policy, &mut skipped);
+        });
+
+    Ok(Preflight {
+        score_triggers,
+        duplicate_charts,
+        scores_previewed,
+        skipped,
+        junk_files,
+        case_variants: case_variants(&ksm_path.join("songs")),
+        db_size: database_size(db_path),
+        db_version,
+        schema_audit: Some(db_version)
+            .filter(|v| !is_supported_version(*v))
+            .and_then(|_| schema::audit_db(&db).ok()),
+        users: users(&db).unwrap_or_default(),
+    })
+}
+
+// Groups pack folders, song folders and charts that differ from a sibling only by letter case.
+// A case-insensitive filesystem cannot hold both, so there they never show up.
+fn case_variant_groups(paths: impl Iterator<Item = PathBuf>) -> Vec<Vec<PathBuf>> {
+    let mut groups: BTreeMap<(PathBuf, String), Vec<PathBuf>> = BTreeMap::new();
+    for path in paths {
+        if let (Some(parent), Some(name)) = (path.parent(), path.file_name()) {
+            groups
+                .entry((parent.to_path_buf(), name.to_string_lossy().to_lowercase()))
+                .or_default()
+                .push(path);
+        }
+    }
+    groups.into_values().filter(|g| g.len() > 1).collect()
+}
+
+fn case_variants(songs_path: &Path) -> Vec<Vec<PathBuf>> {
+    case_variant_groups(
+        walkdir::WalkDir::new(songs_path)
+            .min_depth(1)
+            .max_depth(3)
+            .into_iter()
+            .filter_map(|e| e.ok())
+            .map(|e| e.into_path()),
+    )
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum GaugeType {
+    Normal,
+    Hard,
+    // KSM's light gauge, USC calls it permissive.
+    Permissive,
+}
+
+impl GaugeType {
+    // The Scores gauge_type value.
+    pub fn value(self) -> i64 {
+        match self {
+            GaugeType::Normal => 0,
+            GaugeType::Hard => 1,
+            GaugeType::Permissive => 2,
+        }
+    }
+}
+
+struct Settings {
+    gauge_type: GaugeType,
+    mirror: bool,
+    random: bool,
+    nonstandard: bool,
+}
+
+// The settings before the '='. Strictly only the gauge and the turn (mirror/random) setting may
+// differ from a plain play, relaxed any option USC has no column for is let through as well.
+// Autoplayed buttons or lasers ("off") are never the player's score.
+fn parse_settings(score_line: &str, relaxed: bool) -> Result<Settings, SkipRule> {
+    let settings: Vec<&str> = score_line
+        .split('=')
+        .next()
+        .unwrap_or_default()
+        .split(',')
+        .collect();
+    let (gauge, turn, option, rest) = match settings.as_slice() {
+        [gauge, turn, option, "on", "on", "on", rest @ ..] => (*gauge, *turn, *option, rest),
+        _ => return Err(SkipRule::NonstandardSettings),
+    };
+    let gauge_type = match gauge {
+        "normal" => GaugeType::Normal,
+        "hard" => GaugeType::Hard,
+        "easy" | "light" => GaugeType::Permissive,
+        _ => return Err(SkipRule::UnknownGauge),
+    };
+    let (mirror, random) = match turn {
+        "normal" => (false, false),
+        "mirror" => (true, false),
+        "random" => (false, true),
+        "mir-ran" => (true, true),
+        _ => return Err(SkipRule::NonstandardSettings),
+    };
+    let nonstandard = option != "normal" || !rest.is_empty();
+    if nonstandard && !relaxed {
+        return Err(SkipRule::NonstandardSettings);
+    }
+    Ok(Settings {
+        gauge_type,
+        mirror,
+        random,
+        nonstandard,
+    })
+}
+
+#[derive(Debug, Default, Clone, Copy)]
+pub struct Policy {
+    pub skip_legacy: bool,
+    pub best_only: bool,
+    pub clears_only: bool,
+    pub min_score: u32,
+    pub levels: LevelRange,
+    pub nonstandard_options: bool,
+    // Seconds since the epoch, score files last modified at or before it are left unopened.
+    pub modified_after: Option<i64>,
+    pub timestamps: TimestampPolicy,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize)]
+pub enum SkipRule {
+    NonstandardSettings,
+    LegacyScale,
+    NotBest,
+    NotImprovement,
+    Failed,
+    BelowMinScore,
+    OutsideLevelRange,
+    UnknownGauge,
+    // A gauge KSM knows that the database has no column for, easy on version 18.
+    UnsupportedGauge,
+}
+
+impl SkipRule {
+    pub fn label(self) -> &'static str {
+        match self {
+            SkipRule::NonstandardSettings => "nonstandard settings",
+            SkipRule::LegacyScale => "legacy score scale",
+            SkipRule::NotBest => "best-only",
+            SkipRule::NotImprovement => "existing USC score is higher",
+            SkipRule::Failed => "clears only",
+            SkipRule::BelowMinScore => "below the minimum score",
+            SkipRule::OutsideLevelRange => "outside the level range",
+            SkipRule::UnknownGauge => "unknown gauge type",
+            SkipRule::UnsupportedGauge => "gauge type this maps.db can't store",
+        }
+    }
+
+    fn check_score(score: &KsmScore, policy: Policy) -> Option<SkipRule> {
+        if policy.skip_legacy && score.is_legacy_scale() {
+            Some(SkipRule::LegacyScale)
+        // Badges 0 and 1 are failed plays on either gauge.
+        } else if policy.clears_only && score.badge <= 1 {
+            Some(SkipRule::Failed)
+        } else if score.converted_score() < policy.min_score {
+            Some(SkipRule::BelowMinScore)
+        } else {
+            None
+        }
+    }
+
+    fn check(score_line: &str, policy: Policy) -> Option<SkipRule> {
+        parse_settings(score_line, policy.nonstandard_options).err()
+    }
 }
 
 pub struct KsmScore {
@@ -29,228 +631,1618 @@ pub struct KsmScore {
     pub miss: u32,
     pub gauge: f64,
     pub badge: u32,
-    pub hard: bool,
+    pub gauge_type: GaugeType,
+    pub mirror: bool,
+    pub random: bool,
+    // Played with an option USC can't store, only let through when asked for.
+    pub nonstandard: bool,
+    // Trailing stats fields added by KSM versions newer than this parser, kept verbatim.
+    pub extra: Vec<String>,
 }
 
-impl FromStr for KsmScore {
-    type Err = anyhow::Error;
+// USC lamps show the best clear regardless of which play had the best score, so keep the best
+// clear as well when it is a different play than the top score.
+// Ties go to the higher gauge, then to the later play.
+fn best_of(scores: &[KsmScore]) -> Vec<usize> {
+    let best_by = |key: fn(&KsmScore) -> (u32, u32)| {
+        (0..scores.len()).max_by(|&a, &b| {
+            key(&scores[a])
+                .cmp(&key(&scores[b]))
+                .then(scores[a].gauge.total_cmp(&scores[b].gauge))
+        })
+    };
+    let top_score = best_by(|s| (s.score, s.badge));
+    let top_clear = best_by(|s| (s.badge, s.score));
+    let mut keep: Vec<usize> = top_score.into_iter().chain(top_clear).collect();
+    keep.sort_unstable();
+    keep.dedup();
+    keep
+}
 
-    fn from_str(score_line: &str) -> Result<Self, Self::Err> {
+fn keep_best(
+    scores: Vec<KsmScore>,
+    policy: Policy,
+    skipped: &mut BTreeMap<SkipRule, u32>,
+) -> Vec<KsmScore> {
+    if !policy.best_only {
+        return scores;
+    }
+    let keep = best_of(&scores);
+    let dropped = (scores.len() - keep.len()) as u32;
+    if dropped > 0 {
+        *skipped.entry(SkipRule::NotBest).or_insert(0) += dropped;
+    }
+    scores
+        .into_iter()
+        .enumerate()
+        .filter(|(i, _)| keep.contains(i))
+        .map(|(_, s)| s)
+        .collect()
+}
+
+const LEGACY_SCALE_MAX: u32 = 100;
+const LEGACY_SCALE_FACTOR: u32 = 100_000;
+
+const MAX_SCORE: u32 = 10_000_000;
+const NOMINAL_NOTES: u32 = 1000;
+
+// KSM keeps no judgement counts, only the score and a badge: 0 or 1 failed, 2 clear, 3 hard
+// clear, 4 full combo, 5 perfect. USC draws its lamp from the counts, so they're made up to
+// agree with the badge. A perfect has no nears or misses, a full combo no misses and the nears
+// its lost points are worth, anything else at least one miss. Points go by a chart of
+// NOMINAL_NOTES notes where a miss costs a note's share of the score and a near half of it.
+pub fn judgements(badge: u32, score: u32) -> (u32, u32, u32) {
+    let note = MAX_SCORE / NOMINAL_NOTES;
+    let lost = MAX_SCORE.saturating_sub(score);
+    let (near, miss) = match badge {
+        5 => (0, 0),
+        4 => (lost.div_ceil(note / 2).clamp(1, NOMINAL_NOTES), 0),
+        _ => {
+            let miss = (lost / note).clamp(1, NOMINAL_NOTES);
+            let near = lost.saturating_sub(miss * note).div_ceil(note / 2);
+            (near.min(NOMINAL_NOTES - miss), miss)
+        }
+    };
+    (NOMINAL_NOTES.saturating_sub(near + miss), near, miss)
+}
+
+impl KsmScore {
+    pub fn parse(score_line: &str, nonstandard_options: bool) -> Result<Self> {
+        let Settings {
+            gauge_type,
+            mirror,
+            random,
+            nonstandard,
+        } = parse_settings(score_line, nonstandard_options).map_err(UnsupportedEntry)?;
+
+        let mut stats: Vec<&str> = match score_line.split_once('=') {
+            Some((_, stats)) => stats.split(',').collect(),
+            None => bail!("No '=' before the score in \"{}\"", score_line),
+        };
         ensure!(
-            score_line.starts_with("hard,normal,normal,on,on,on")
-                || score_line.starts_with("normal,normal,normal,on,on,on"),
-            "Unsupported score entry"
+            stats.len() >= MIN_STAT_FIELDS,
+            "Expected at least {} fields after '=', found {} in \"{}\"",
+            MIN_STAT_FIELDS,
+            stats.len(),
+            score_line
         );
-
-        let (settings, stats): (Vec<&str>, Vec<&str>) = {
-            let mut parts = score_line.split('=');
-            (
-                parts.next().unwrap().split(',').collect(),
-                parts.next().unwrap().split(',').collect(),
-            )
+        // KSM writes the gauge with one decimal, "98,5" with a decimal comma, which splits into
+        // one field more than a plain line has. Read as one field that's the gauge again.
+        let single_digit = |f: &str| f.len() == 1 && f.bytes().all(|b| b.is_ascii_digit());
+        let gauge_field = if stats.len() == KNOWN_STAT_FIELDS + 1
+            && stats[3].bytes().all(|b| b.is_ascii_digit())
+            && single_digit(stats[4])
+        {
+            let fraction = stats.remove(4);
+            format!("{},{}", stats[3], fraction)
+        } else {
+            stats[3].to_string()
         };
-        let hard = settings[0] == "hard";
-        let score: u32 = stats[0].parse()?;
-        let gauge: f64 = stats[3].parse::<f64>()? / 100.0;
-        let badge: u32 = stats[1].parse()?;
-        let miss = if badge > 1 { 0 } else { 1 };
-        Ok(Self {
+        let score: u32 = stats[0]
+            .parse()
+            .with_context(|| format!("Invalid score \"{}\" in \"{}\"", stats[0], score_line))?;
+        let gauge = importer_funcs::parse_gauge(&gauge_field)
+            .with_context(|| format!("In \"{}\"", score_line))?;
+        let badge: u32 = stats[1]
+            .parse()
+            .with_context(|| format!("Invalid badge \"{}\" in \"{}\"", stats[1], score_line))?;
+        let extra = stats
+            .iter()
+            .skip(KNOWN_STAT_FIELDS)
+            .map(|f| f.to_string())
+            .collect();
+        let score = Self {
             score,
             crit: 0,
             near: 0,
-            miss,
+            miss: 0,
             gauge,
             badge,
-            hard,
+            gauge_type,
+            mirror,
+            random,
+            nonstandard,
+            extra,
+        };
+        let (crit, near, miss) = judgements(score.badge, score.converted_score());
+        Ok(Self {
+            crit,
+            near,
+            miss,
+            ..score
         })
     }
+
+    // No clear is worth 100 points or less on the 10,000,000 scale, so a cleared score in that
+    // range is a percentage written by an old KSM build. Failed plays are left alone since a
+    // low real score is possible there.
+    pub fn is_legacy_scale(&self) -> bool {
+        self.score > 0 && self.score <= LEGACY_SCALE_MAX && self.badge > 1
+    }
+
+    fn convert_legacy_scale(&mut self) {
+        self.score = self.converted_score();
+    }
+
+    fn converted_score(&self) -> u32 {
+        if self.is_legacy_scale() {
+            self.score * LEGACY_SCALE_FACTOR
+        } else {
+            self.score
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct UnsupportedEntry(pub SkipRule);
+
+impl std::fmt::Display for UnsupportedEntry {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "Unsupported score entry ({})", self.0.label())
+    }
+}
+
+impl std::error::Error for UnsupportedEntry {}
+
+impl FromStr for KsmScore {
+    type Err = anyhow::Error;
+
+    fn from_str(score_line: &str) -> Result<Self, Self::Err> {
+        Self::parse(score_line, false)
+    }
 }
 
-fn enumerate_ksm_score_files(ksm_path: &Path) -> Result<Vec<DirEntry>> {
+pub(crate) fn enumerate_ksm_score_files(
+    ksm_path: &Path,
+    cancel: &AtomicBool,
+    warnings: &mut Vec<String>,
+) -> Result<ScoreFiles> {
     let mut score_paths = ksm_path.to_path_buf();
     score_paths.push("score");
-    ensure!(
-        score_paths.exists(),
-        "Path does not exist: {:?}",
-        score_paths.to_str(),
-    );
-
-    let dirs = walkdir::WalkDir::new(score_paths);
-    Ok(dirs
-        .into_iter()
-        .filter(|p| p.is_ok())
-        .filter(|p| p.as_ref().unwrap().path().extension().is_some())
-        .filter(|p| {
-            p.as_ref()
-                .unwrap()
-                .path()
-                .extension()
-                .unwrap()
-                .to_str()
-                .unwrap()
-                .to_ascii_lowercase()
-                .eq(&"ksc".to_string())
-        })
-        .map(|d| d.unwrap())
-        .collect())
+    let exists = {
+        let score_paths = score_paths.clone();
+        watchdog::run_with_timeout(STALL_TIMEOUT, cancel, "checking score folder", move || {
+            Ok(score_paths.exists())
+        })?
+    };
+    ensure!(exists, "Path does not exist: {:?}", score_paths.to_str());
+
+    enumerate_score_folder(score_paths, cancel, warnings)
 }
 
-pub struct Importer {
-    db_path: PathBuf,
-    ksm_path: PathBuf,
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum ScoreFileKind {
+    Score,
+    Junk,
 }
 
-async fn run_importer(state: State) -> Option<(Progress, State)> {
-    match state {
-        State::Ready { ksm, db } => {
-            let db_conn = Connection::open(db.as_path());
-            let score_files = enumerate_ksm_score_files(&ksm);
+const BACKUP_SUFFIXES: &[&str] = &[".bak", "~", ".old"];
 
-            match (db_conn, score_files) {
-                (Ok(db), Ok(ksm)) => Some((
-                    Progress::Started,
-                    State::Importing {
-                        db_version: db
-                            .query_row("SELECT version FROM `Database`", [], |r| r.get(0))
-                            .unwrap_or_default(),
-                        connection: db,
-                        summary: Summary {
-                            scores_found: ksm.len() as u32,
-                            ..Default::default()
-                        },
-                        score_files: ksm,
-                    },
-                )),
-                (Ok(_), Err(e)) => Some((Progress::Errored(format!("{:?}", e)), State::Finished)),
-                (Err(e), Ok(_)) => Some((Progress::Errored(format!("{:?}", e)), State::Finished)),
-                (Err(db_err), Err(ksm_err)) => Some((
-                    Progress::Errored(format!(
-                        "DB Error: '{:?}', KSM Path error: '{:?}'",
-                        db_err, ksm_err
-                    )),
-                    State::Finished,
-                )),
+// Zips made on macOS shadow every file with AppleDouble metadata in this folder.
+pub(crate) const MACOS_SHADOW_DIR: &str = "__MACOSX";
+
+// Backup copies like "song.bak.ksc" and hidden files like macOS "._song.ksc" still carry the
+// .ksc extension, and importing them would duplicate real scores or fail on binary metadata.
+pub(crate) fn classify_score_file(path: &Path) -> Option<ScoreFileKind> {
+    let name = path.file_name()?.to_string_lossy();
+    let stem_len = name.len().checked_sub(4)?;
+    if !name.is_char_boundary(stem_len) || !name[stem_len..].eq_ignore_ascii_case(".ksc") {
+        return None;
+    }
+    let stem = &name[..stem_len];
+    if name.starts_with('.')
+        || path.iter().any(|c| c == MACOS_SHADOW_DIR)
+        || BACKUP_SUFFIXES
+            .iter()
+            .any(|suffix| stem.to_ascii_lowercase().ends_with(suffix))
+    {
+        Some(ScoreFileKind::Junk)
+    } else {
+        Some(ScoreFileKind::Score)
+    }
+}
+
+// A .ksc that links to another one in the folder is the same score file, it is only imported
+// under the path that isn't a link.
+fn enumerate_score_folder(
+    root: PathBuf,
+    cancel: &AtomicBool,
+    warnings: &mut Vec<String>,
+) -> Result<ScoreFiles> {
+    let entries = watchdog::collect_with_timeout(
+        STALL_TIMEOUT,
+        cancel,
+        "enumerating score files",
+        move |tx| {
+            let canonical_root = root.canonicalize().unwrap_or_else(|_| root.clone());
+            walkdir::WalkDir::new(&root)
+                .into_iter()
+                .filter_map(|entry| entry.ok())
+                .filter_map(|entry| classify_score_file(entry.path()).map(|k| (entry, k)))
+                .map(|(entry, kind)| {
+                    // Walking doesn't follow links, so only the root and the entry itself can be
+                    // one.
+                    let canonical = if entry.path_is_symlink() {
+                        entry.path().canonicalize().ok()
+                    } else {
+                        entry
+                            .path()
+                            .strip_prefix(&root)
+                            .ok()
+                            .map(|rel| canonical_root.join(rel))
+                    };
+                    let link = entry.path_is_symlink();
+                    (entry.into_path(), canonical, link, kind)
+                })
+                .try_for_each(|entry| tx.send(entry))
+                .ok();
+        },
+    )?;
+
+    let mut junk_files = 0;
+    let mut targets: HashMap<PathBuf, PathBuf> = HashMap::new();
+    let mut files = Vec::new();
+    let mut links = Vec::new();
+    for (path, canonical, link, kind) in entries {
+        if kind == ScoreFileKind::Junk {
+            junk_files += 1;
+            continue;
+        }
+        match canonical {
+            Some(canonical) if link => links.push((path, canonical)),
+            Some(canonical) => {
+                targets.insert(canonical, path.clone());
+                files.push(path);
             }
+            // A dangling link, reading it reports the failure.
+            None => files.push(path),
         }
-        State::Importing {
-            mut score_files,
-            mut summary,
-            connection,
-            db_version,
-        } => {
-            if score_files.is_empty() {
-                return Some((Progress::Finished(summary), State::Finished));
+    }
+    for (path, canonical) in links {
+        match targets.get(&canonical) {
+            Some(original) => warnings.push(format!(
+                "Skipped \"{}\", it links to \"{}\" which is imported already",
+                path.to_str().unwrap_or_default(),
+                original.to_str().unwrap_or_default()
+            )),
+            None => {
+                targets.insert(canonical, path.clone());
+                files.push(path);
             }
+        }
+    }
+    Ok(ScoreFiles { files, junk_files })
+}
 
-            let insert_func: Option<ImportFn> = match db_version {
-                19 => Some(crate::importer_funcs::version_19),
-                _ => None,
-            };
-            if insert_func.is_none() {
-                return Some((
-                    Progress::Errored(format!("Unsupported DB version: {}", db_version)),
-                    State::Finished,
-                ));
-            }
+fn chart_display_name(score_path: &Path) -> String {
+    let song = score_path
+        .parent()
+        .and_then(|p| p.file_name())
+        .and_then(|n| n.to_str())
+        .unwrap_or_default();
+    let difficulty = score_path
+        .file_stem()
+        .and_then(|n| n.to_str())
+        .unwrap_or_default();
+    format!("{} [{}]", song, difficulty)
+}
 
-            let insert_func = insert_func.unwrap();
+#[derive(Debug)]
+enum ChartName {
+    Canonical(String),
+    Resolved(PathBuf),
+}
 
-            let current_file_path = score_files.pop().unwrap().path().to_path_buf();
+// Identical charts can live in several packs, so the name shown for a hash must not depend
+// on which score file happened to be enumerated first.
+#[derive(Debug, Default)]
+struct ChartNames(HashMap<String, ChartName>);
 
-            match std::fs::File::open(&current_file_path) {
-                Ok(current_file) => {
-                    let scores_imported = &mut summary.scores_imported;
-                    let fail_messages = Rc::new(RefCell::new(&mut summary.fail_messages));
-                    BufReader::new(current_file)
-                        .lines()
-                        .filter(|l| l.is_ok())
-                        .map(|l| KsmScore::from_str(&l.unwrap()))
-                        .filter(|s| match s {
-                            Ok(_) => true,
-                            Err(e) => {
-                                fail_messages.borrow_mut().push(format!(
-                                    "Score parse failed in \"{}\": {:?}",
-                                    current_file_path.to_str().unwrap_or_default(),
-                                    e
-                                ));
-                                false
-                            }
-                        })
-                        .map(|s| s.unwrap())
-                        .filter(|s| match insert_func(&s, &connection, &current_file_path) {
-                            Ok(_) => true,
-                            Err(e) => {
-                                fail_messages
-                                    .borrow_mut()
-                                    .push(format!("Score insert failed: {:?}", e));
-                                false
-                            }
-                        })
-                        .for_each(|_| *scores_imported += 1);
+impl ChartNames {
+    fn note(&mut self, db: &Connection, inserted: &Inserted) {
+        match self.0.get_mut(&inserted.chart_hash) {
+            Some(ChartName::Canonical(_)) => {}
+            Some(ChartName::Resolved(path)) => {
+                if inserted.chart_path.to_string_lossy() < path.to_string_lossy() {
+                    *path = inserted.chart_path.clone();
                 }
-                Err(e) => summary.fail_messages.push(format!(
-                    "Failed to open \"{}\": {:?}",
-                    current_file_path.to_str().unwrap_or_default(),
-                    e
-                )),
             }
+            None => {
+                let name = match importer_funcs::canonical_chart_path(db, &inserted.chart_hash) {
+                    Ok(Some(path)) => ChartName::Canonical(chart_display_name(Path::new(&path))),
+                    _ => ChartName::Resolved(inserted.chart_path.clone()),
+                };
+                self.0.insert(inserted.chart_hash.clone(), name);
+            }
+        }
+    }
 
-            let progress = 1.0 - (score_files.len() as f32 / summary.scores_found as f32);
-            Some((
-                Progress::Advanced(
-                    progress,
-                    current_file_path.to_str().unwrap_or_default().to_string(),
-                ),
-                State::Importing {
-                    db_version,
-                    score_files,
-                    summary,
-                    connection,
-                },
-            ))
+    fn label(&self, hash: &str) -> String {
+        match self.0.get(hash) {
+            Some(ChartName::Canonical(name)) => name.clone(),
+            Some(ChartName::Resolved(path)) => chart_display_name(path),
+            None => hash.to_string(),
+        }
+    }
+
+    // Names the charts of the buckets and puts the failures in report order, once the import ends.
+    fn finish_summary(&self, summary: &mut Summary) {
+        summary
+            .fail_messages
+            .sort_by(|a, b| (a.kind, report_order(&a.path)).cmp(&(b.kind, report_order(&b.path))));
+        for bucket in [
+            &mut summary.new_charts,
+            &mut summary.improvements,
+            &mut summary.additional_plays,
+        ] {
+            bucket.iter_mut().for_each(|hash| *hash = self.label(hash));
         }
-        State::Finished => None,
     }
 }
 
-impl<H, I> iced_native::subscription::Recipe<H, I> for Importer
-where
-    H: std::hash::Hasher,
-{
-    type Output = Progress;
+struct ImportContext<'a> {
+    insert_func: ImportFn,
+    safe_mode: bool,
+    policy: Policy,
+    limits: FileLimits,
+    connection: &'a Connection,
+    options: InsertOptions<'a>,
+    // Set when maps.db stayed locked past BUSY_TIMEOUT, the import stops after the file.
+    locked: &'a Cell<bool>,
+}
 
-    fn hash(&self, state: &mut H) {
-        use std::hash::Hash;
+fn insert_in_savepoint(
+    ctx: &ImportContext,
+    score: &KsmScore,
+    score_path: &Path,
+) -> Result<Inserted> {
+    let db = ctx.connection;
+    db.execute_batch("SAVEPOINT score_insert")?;
+    match (ctx.insert_func)(score, db, score_path, &ctx.options) {
+        Ok(inserted) => {
+            db.execute_batch("RELEASE score_insert")?;
+            Ok(inserted)
+        }
+        // A trigger's RAISE(ROLLBACK) already ended the savepoint along with the transaction,
+        // rolling back to it then fails. The insert error is the one worth reporting.
+        Err(e) => {
+            let _ = db.execute_batch("ROLLBACK TO score_insert; RELEASE score_insert");
+            Err(e)
+        }
+    }
+}
 
-        std::any::TypeId::of::<Self>().hash(state);
-        self.db_path.hash(state);
+// Lines of a score file without what KSM doesn't write itself but copies and editors add: a
+// UTF-8 BOM, Windows line endings, trailing whitespace and blank lines.
+fn score_lines(file: &[u8]) -> impl Iterator<Item = String> + '_ {
+    let file = file.strip_prefix(b"\xEF\xBB\xBF").unwrap_or(file);
+    BufReader::new(file)
+        .lines()
+        .map_while(Result::ok)
+        .map(|line| line.trim().to_string())
+        .filter(|line| !line.is_empty())
+}
+
+const MIN_STAT_FIELDS: usize = 4;
+const KNOWN_STAT_FIELDS: usize = 6;
+
+// A file cut off mid-write ends in a partial line, which is dropped so it doesn't count as a
+// failed score. KSM ends every line it writes with a newline, a last line without one that is
+// too short to parse was cut. A cut late enough to keep the fields the importer reads still
+// imports. Returns the byte offset where that line starts.
+fn truncated_tail(buf: &[u8]) -> Option<usize> {
+    let end = buf.iter().rposition(|b| !b" \t".contains(b))? + 1;
+    if b"\r\n".contains(&buf[end - 1]) {
+        return None;
     }
+    let start = buf[..end]
+        .iter()
+        .rposition(|&b| b == b'\n')
+        .map_or(0, |i| i + 1);
+    let line = String::from_utf8_lossy(&buf[start..end]);
+    let missing_fields = line
+        .split_once('=')
+        .is_none_or(|(_, stats)| stats.split(',').count() < MIN_STAT_FIELDS);
+    missing_fields.then_some(start)
+}
 
-    fn stream(
-        self: Box<Self>,
-        _input: iced_futures::BoxStream<I>,
-    ) -> iced_futures::BoxStream<Self::Output> {
-        Box::pin(futures::stream::unfold(
-            State::Ready {
-                ksm: self.ksm_path,
-                db: self.db_path,
-            },
-            run_importer,
-        ))
+// What a file's transaction adds to the summary, put back when the transaction is rolled back.
+#[derive(Debug, Default, Clone)]
+struct FileCounts {
+    scores_imported: u32,
+    scores_upgraded: u32,
+    scores_skipped: u32,
+    skipped: BTreeMap<SkipRule, u32>,
+    new_charts: usize,
+    improvements: usize,
+    additional_plays: usize,
+    charts_matched_via_db: u32,
+    timestamps_adjusted: u32,
+    inserted_rows: usize,
+    upgraded_rows: usize,
+    imported_scores: usize,
+}
+
+impl FileCounts {
+    fn of(summary: &Summary) -> Self {
+        Self {
+            scores_imported: summary.scores_imported,
+            scores_upgraded: summary.scores_upgraded,
+            scores_skipped: summary.scores_skipped,
+            skipped: summary.skipped.clone(),
+            new_charts: summary.new_charts.len(),
+            improvements: summary.improvements.len(),
+            additional_plays: summary.additional_plays.len(),
+            charts_matched_via_db: summary.charts_matched_via_db,
+            timestamps_adjusted: summary.timestamps_adjusted,
+            inserted_rows: summary.inserted_rows.len(),
+            upgraded_rows: summary.upgraded_rows.len(),
+            imported_scores: summary.imported_scores.len(),
+        }
+    }
+
+    fn restore(self, summary: &mut Summary) {
+        summary.scores_imported = self.scores_imported;
+        summary.scores_upgraded = self.scores_upgraded;
+        summary.scores_skipped = self.scores_skipped;
+        summary.skipped = self.skipped;
+        summary.new_charts.truncate(self.new_charts);
+        summary.improvements.truncate(self.improvements);
+        summary.additional_plays.truncate(self.additional_plays);
+        summary.charts_matched_via_db = self.charts_matched_via_db;
+        summary.timestamps_adjusted = self.timestamps_adjusted;
+        summary.inserted_rows.truncate(self.inserted_rows);
+        summary.upgraded_rows.truncate(self.upgraded_rows);
+        summary.imported_scores.truncate(self.imported_scores);
     }
 }
 
-#[derive(Debug)]
-enum State {
-    Ready {
-        ksm: PathBuf,
+// What reading a file adds to the summary besides its transaction, taken back when a lock has
+// the file read again.
+#[derive(Debug, Clone)]
+struct FileReads {
+    files_processed: u32,
+    scores_found: u32,
+    scores_skipped: u32,
+    legacy_converted: u32,
+    scores_with_extra_fields: u32,
+    scores_with_nonstandard_options: u32,
+    timestamps_backdated: u32,
+    skipped: BTreeMap<SkipRule, u32>,
+    warnings: usize,
+    fail_messages: usize,
+}
+
+impl FileReads {
+    fn of(summary: &Summary) -> Self {
+        Self {
+            files_processed: summary.files_processed,
+            scores_found: summary.scores_found,
+            scores_skipped: summary.scores_skipped,
+            legacy_converted: summary.legacy_converted,
+            scores_with_extra_fields: summary.scores_with_extra_fields,
+            scores_with_nonstandard_options: summary.scores_with_nonstandard_options,
+            timestamps_backdated: summary.timestamps_backdated,
+            skipped: summary.skipped.clone(),
+            warnings: summary.warnings.len(),
+            fail_messages: summary.fail_messages.len(),
+        }
+    }
+
+    fn restore(self, summary: &mut Summary) {
+        summary.files_processed = self.files_processed;
+        summary.scores_found = self.scores_found;
+        summary.scores_skipped = self.scores_skipped;
+        summary.legacy_converted = self.legacy_converted;
+        summary.scores_with_extra_fields = self.scores_with_extra_fields;
+        summary.scores_with_nonstandard_options = self.scores_with_nonstandard_options;
+        summary.timestamps_backdated = self.timestamps_backdated;
+        summary.skipped = self.skipped;
+        summary.warnings.truncate(self.warnings);
+        summary.fail_messages.truncate(self.fail_messages);
+    }
+}
+
+fn import_file(
+    ctx: &ImportContext,
+    current_file_path: &Path,
+    summary: &mut Summary,
+    chart_names: &mut ChartNames,
+) {
+    let started = Instant::now();
+    let limits = ctx.limits;
+    summary.files_processed += 1;
+    if let Ok(size) = ctx.options.source.size(current_file_path) {
+        if size > limits.max_size {
+            summary.warnings.push(format!(
+                "Skipped \"{}\", at {:.1} MB it is too large to be a score file",
+                current_file_path.to_str().unwrap_or_default(),
+                size as f64 / (1024.0 * 1024.0)
+            ));
+            return;
+        }
+    }
+    match ctx.options.source.read_score(current_file_path) {
+        Ok(current_file) => {
+            let truncated_at = truncated_tail(&current_file);
+            let body = &current_file[..truncated_at.unwrap_or(current_file.len())];
+            let line_count = body.split(|&b| b == b'\n').count()
+                - usize::from(body.is_empty() || body.ends_with(b"\n"));
+            let parse_failures = Cell::new(0);
+            // Only read once a score of the file fails, every score in it is for the same chart.
+            let chart_label = OnceCell::new();
+            let failed_in = || match chart_label
+                .get_or_init(|| importer_funcs::chart_label(current_file_path, ctx.options.source))
+            {
+                Some(label) => format!(
+                    "{} in \"{}\"",
+                    label,
+                    current_file_path.to_str().unwrap_or_default()
+                ),
+                None => format!("\"{}\"", current_file_path.to_str().unwrap_or_default()),
+            };
+            let before = FileCounts::of(summary);
+            let mut rolled_back = false;
+            let scores_found = &mut summary.scores_found;
+            let scores_imported = &mut summary.scores_imported;
+            let scores_skipped = &mut summary.scores_skipped;
+            let scores_upgraded = &mut summary.scores_upgraded;
+            let new_charts = &mut summary.new_charts;
+            let improvements = &mut summary.improvements;
+            let additional_plays = &mut summary.additional_plays;
+            let charts_matched_via_db = &mut summary.charts_matched_via_db;
+            let timestamps_adjusted = &mut summary.timestamps_adjusted;
+            let skipped = RefCell::new(&mut summary.skipped);
+            let mut legacy_converted = 0;
+            let mut extra_fields = 0;
+            let mut nonstandard = 0;
+            let mut adjustment = None;
+            let mut backdated = None;
+            let mut rows = Vec::new();
+            let mut imported = Vec::new();
+            let mut upgrades: Vec<Upgrade> = Vec::new();
+            let fail_messages = Rc::new(RefCell::new(&mut summary.fail_messages));
+            // Checked before a line is counted, the line after the last allowed failure is
+            // never read.
+            let scores = score_lines(body)
+                .take(limits.max_lines)
+                .take_while(|_| parse_failures.get() < limits.max_failures)
+                .inspect(|_| *scores_found += 1)
+                .filter(|l| match SkipRule::check(l, ctx.policy) {
+                    Some(rule) => {
+                        *skipped.borrow_mut().entry(rule).or_insert(0) += 1;
+                        false
+                    }
+                    None => true,
+                })
+                .map(|l| KsmScore::parse(&l, ctx.policy.nonstandard_options))
+                .filter(|s| match s {
+                    Ok(_) => true,
+                    Err(e) => match e.downcast_ref::<UnsupportedEntry>() {
+                        // Unsupported options are expected, they're skips rather than failures.
+                        Some(UnsupportedEntry(rule)) => {
+                            *skipped.borrow_mut().entry(*rule).or_insert(0) += 1;
+                            false
+                        }
+                        None => {
+                            parse_failures.set(parse_failures.get() + 1);
+                            fail_messages.borrow_mut().push(Failure {
+                                path: current_file_path.to_path_buf(),
+                                kind: FailureKind::Parse,
+                                reason: e.to_string(),
+                                message: format!("Score parse failed for {}: {:?}", failed_in(), e),
+                            });
+                            false
+                        }
+                    },
+                })
+                .map(|s| s.unwrap())
+                .filter_map(|mut s| {
+                    if let Some(rule) = SkipRule::check_score(&s, ctx.policy) {
+                        *skipped.borrow_mut().entry(rule).or_insert(0) += 1;
+                        return None;
+                    }
+                    if s.is_legacy_scale() {
+                        s.convert_legacy_scale();
+                        legacy_converted += 1;
+                    }
+                    if !s.extra.is_empty() {
+                        extra_fields += 1;
+                    }
+                    if s.nonstandard {
+                        nonstandard += 1;
+                    }
+                    Some(s)
+                })
+                .collect();
+            let parsed = Instant::now();
+            let mut commit_time = Duration::ZERO;
+            // One transaction per file saves a sync per score. A trigger can roll back the whole
+            // transaction, so in safe mode every score still commits on its own. Inside the
+            // transaction of an all or nothing import, a savepoint lets a lock take the file back.
+            let nested = !ctx.connection.is_autocommit();
+            let batched = !ctx.safe_mode
+                && ctx
+                    .connection
+                    .execute_batch(if nested {
+                        "SAVEPOINT file_import"
+                    } else {
+                        "BEGIN"
+                    })
+                    .is_ok();
+            let scores = keep_best(scores, ctx.policy, &mut skipped.borrow_mut());
+            scores
+                .into_iter()
+                .take_while(|_| !ctx.locked.get())
+                .filter_map(|s| {
+                    let inserted = if ctx.safe_mode {
+                        insert_in_savepoint(ctx, &s, current_file_path)
+                    } else {
+                        (ctx.insert_func)(&s, ctx.connection, current_file_path, &ctx.options)
+                    };
+                    match inserted {
+                        Ok(inserted) => Some((s, inserted)),
+                        Err(e) if e.downcast_ref().is_some_and(is_locked) => {
+                            ctx.locked.set(true);
+                            None
+                        }
+                        Err(e) => {
+                            fail_messages.borrow_mut().push(Failure {
+                                path: current_file_path.to_path_buf(),
+                                kind: match e.downcast_ref::<ChartMissing>() {
+                                    Some(_) => FailureKind::MissingChart,
+                                    None => FailureKind::Insert,
+                                },
+                                reason: e.to_string(),
+                                message: format!(
+                                    "Score insert failed for {} on {}: {:?}",
+                                    s.score,
+                                    failed_in(),
+                                    e
+                                ),
+                            });
+                            None
+                        }
+                    }
+                })
+                .for_each(|(score, inserted)| {
+                    if let Some(upgrade) = inserted.upgraded {
+                        *scores_upgraded += 1;
+                        upgrades.push(upgrade);
+                        return;
+                    }
+                    if inserted.duplicate {
+                        *scores_skipped += 1;
+                        return;
+                    }
+                    if let Some(rule) = inserted.skipped {
+                        *skipped.borrow_mut().entry(rule).or_insert(0) += 1;
+                        return;
+                    }
+                    *scores_imported += 1;
+                    rows.extend(inserted.row.clone());
+                    imported.push(ImportedScore {
+                        chart_hash: inserted.chart_hash.clone(),
+                        score: score.score,
+                        badge: score.badge,
+                        gauge: score.gauge,
+                        timestamp: inserted.timestamp,
+                    });
+                    if inserted.chart_from_db {
+                        *charts_matched_via_db += 1;
+                    }
+                    if inserted.timestamp_adjusted.is_some() {
+                        *timestamps_adjusted += 1;
+                        adjustment = inserted.timestamp_adjusted;
+                    }
+                    if inserted.timestamp != inserted.original_timestamp {
+                        backdated = Some((inserted.original_timestamp, inserted.timestamp));
+                    }
+                    chart_names.note(ctx.connection, &inserted);
+                    match inserted.kind {
+                        InsertKind::NewChart => new_charts.push(inserted.chart_hash),
+                        InsertKind::Improvement => improvements.push(inserted.chart_hash),
+                        InsertKind::AdditionalPlay => additional_plays.push(inserted.chart_hash),
+                    }
+                });
+            if batched {
+                let (commit, rollback) = if nested {
+                    (
+                        "RELEASE file_import",
+                        "ROLLBACK TO file_import; RELEASE file_import",
+                    )
+                } else {
+                    ("COMMIT", "ROLLBACK")
+                };
+                let committing = Instant::now();
+                // A locked file is imported again once the lock is gone, so it isn't a failure.
+                let saved = match ctx.locked.get() {
+                    true => None,
+                    false => Some(ctx.connection.execute_batch(commit)),
+                };
+                if !matches!(saved, Some(Ok(()))) {
+                    let _ = ctx.connection.execute_batch(rollback);
+                    rolled_back = true;
+                }
+                commit_time = committing.elapsed();
+                match saved {
+                    Some(Err(e)) if is_locked(&e) => ctx.locked.set(true),
+                    Some(Err(e)) => fail_messages.borrow_mut().push(Failure {
+                        path: current_file_path.to_path_buf(),
+                        kind: FailureKind::Insert,
+                        reason: e.to_string(),
+                        message: format!(
+                            "Saving the scores from \"{}\" failed, none of them were imported: \
+                            {:?}",
+                            current_file_path.to_str().unwrap_or_default(),
+                            e
+                        ),
+                    }),
+                    _ => {}
+                }
+            }
+            if rolled_back {
+                before.restore(summary);
+                adjustment = None;
+                backdated = None;
+                // The badges are back to what they were, a retry upgrades the rows again.
+                let mut earlier = ctx.options.earlier_rows.borrow_mut();
+                for upgrade in upgrades {
+                    earlier
+                        .get_mut(&upgrade.row.chart_hash)
+                        .into_iter()
+                        .flatten()
+                        .filter(|r| r.rowid == upgrade.row.rowid)
+                        .for_each(|r| r.badge = Some(upgrade.previous_badge));
+                }
+            } else {
+                summary.inserted_rows.extend(rows);
+                summary.imported_scores.extend(imported);
+                summary
+                    .upgraded_rows
+                    .extend(upgrades.into_iter().map(|u| u.row));
+            }
+            let chart_time = ctx.options.chart_time.get();
+            summary.timings.reading += parsed - started;
+            summary.timings.charts += chart_time;
+            summary.timings.committing += commit_time;
+            summary.timings.database += parsed.elapsed().saturating_sub(chart_time + commit_time);
+
+            if parse_failures.get() >= limits.max_failures {
+                summary.warnings.push(format!(
+                    "Abandoned \"{}\" after {} failed score lines",
+                    current_file_path.to_str().unwrap_or_default(),
+                    parse_failures.get()
+                ));
+            } else if line_count > limits.max_lines {
+                summary.warnings.push(format!(
+                    "Only read the first {} of {} lines in \"{}\"",
+                    limits.max_lines,
+                    line_count,
+                    current_file_path.to_str().unwrap_or_default()
+                ));
+            }
+            if let Some(offset) = truncated_at {
+                summary.warnings.push(format!(
+                    "Ignored truncated last line at byte {} of \"{}\"",
+                    offset,
+                    current_file_path.to_str().unwrap_or_default()
+                ));
+            }
+            if legacy_converted > 0 {
+                summary.legacy_converted += legacy_converted;
+                summary.warnings.push(format!(
+                    "{} scores in \"{}\" converted from legacy scale",
+                    legacy_converted,
+                    current_file_path.to_str().unwrap_or_default()
+                ));
+            }
+            summary.scores_with_extra_fields += extra_fields;
+            summary.scores_with_nonstandard_options += nonstandard;
+            if let Some((original, written)) = backdated {
+                summary.timestamps_backdated += 1;
+                summary.warnings.push(format!(
+                    "Backdated \"{}\": modified {}, written as {}",
+                    current_file_path.to_str().unwrap_or_default(),
+                    importer_funcs::format_date(original),
+                    importer_funcs::format_date(written)
+                ));
+            }
+            if let Some(adjustment) = adjustment {
+                summary.warnings.push(format!(
+                    "Modification time of \"{}\" {}",
+                    current_file_path.to_str().unwrap_or_default(),
+                    match adjustment {
+                        TimestampAdjustment::BeforeEpoch => "is before 1970, written as 1970",
+                        TimestampAdjustment::InFuture => "is in the future, written as now",
+                    }
+                ));
+            }
+        }
+        // KSM may still be running and tidy up score files after they were enumerated.
+        Err(e)
+            if e.downcast_ref::<std::io::Error>()
+                .is_some_and(|e| e.kind() == std::io::ErrorKind::NotFound) =>
+        {
+            summary.warnings.push(format!(
+                "\"{}\" was removed after the score folder was scanned",
+                current_file_path.to_str().unwrap_or_default()
+            ))
+        }
+        Err(e) => summary.fail_messages.push(Failure {
+            path: current_file_path.to_path_buf(),
+            kind: FailureKind::Read,
+            reason: e.to_string(),
+            message: format!(
+                "Failed to open \"{}\": {:?}",
+                current_file_path.to_str().unwrap_or_default(),
+                e
+            ),
+        }),
+    }
+}
+
+fn panic_message(panic: &(dyn Any + Send)) -> String {
+    panic
+        .downcast_ref::<&str>()
+        .map(|s| s.to_string())
+        .or_else(|| panic.downcast_ref::<String>().cloned())
+        .unwrap_or_else(|| "unknown panic".to_string())
+}
+
+pub struct Importer {
+    ksm_path: PathBuf,
+    db_path: PathBuf,
+    source: Arc<dyn Source>,
+    options: ImportOptions,
+    run_id: u64,
+}
+
+const LOW_MEMORY_THRESHOLD: usize = 20_000;
+
+fn spill_failures(summary: &mut Summary) -> Result<()> {
+    if let Some(path) = &summary.spill_path {
+        let mut f = OpenOptions::new().create(true).append(true).open(path)?;
+        for failure in &summary.fail_messages {
+            writeln!(f, "{}", failure.message)?;
+        }
+        summary.spilled_failures += summary.fail_messages.len() as u32;
+        summary.fail_messages.clear();
+    }
+    Ok(())
+}
+
+// VACUUM can run for minutes on a large database, SQLite calls back into the heartbeat while it
+// works so it doesn't look wedged.
+fn optimize_database(
+    connection: &Connection,
+    db_path: &Path,
+    heartbeat_file: Option<PathBuf>,
+    heartbeat_counters: String,
+) -> Result<(u64, u64)> {
+    let before = std::fs::metadata(db_path)?.len();
+    if let Some(path) = heartbeat_file {
+        let mut last_heartbeat = Instant::now();
+        touch_heartbeat(&path, "optimizing", &heartbeat_counters);
+        connection.progress_handler(
+            HEARTBEAT_OPS,
+            Some(move || {
+                if last_heartbeat.elapsed() >= HEARTBEAT_INTERVAL {
+                    touch_heartbeat(&path, "optimizing", &heartbeat_counters);
+                    last_heartbeat = Instant::now();
+                }
+                false
+            }),
+        );
+    }
+    let optimized = connection.execute_batch("ANALYZE; VACUUM;");
+    connection.progress_handler(0, None::<fn() -> bool>);
+    optimized?;
+    let after = std::fs::metadata(db_path)?.len();
+    Ok((before, after))
+}
+
+const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(2);
+const PAUSE_POLL_INTERVAL: Duration = Duration::from_millis(200);
+// SQLite instructions between checks of the heartbeat while optimizing.
+const HEARTBEAT_OPS: i32 = 100_000;
+
+fn heartbeat_counters(summary: Option<&Summary>, files_left: usize) -> String {
+    summary
+        .map(|s| {
+            format!(
+                " files={}/{} imported={} failed={}",
+                s.files_found as usize - files_left,
+                s.files_found,
+                s.scores_imported,
+                s.fail_messages.len() as u32 + s.spilled_failures
+            )
+        })
+        .unwrap_or_default()
+}
+
+fn touch_heartbeat(path: &Path, status: &str, counters: &str) {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default();
+    let _ = std::fs::write(path, format!("{} {}{}\n", now, status, counters));
+}
+
+fn write_heartbeat(path: &Path, status: &str, summary: Option<&Summary>, files_left: usize) {
+    touch_heartbeat(path, status, &heartbeat_counters(summary, files_left));
+}
+
+async fn run_importer(state: State) -> Option<(Progress, State)> {
+    let heartbeat_file = match &state {
+        State::Ready { options, .. } | State::Importing { options, .. } => {
+            options.heartbeat_file.clone()
+        }
+        State::Optimizing { heartbeat_file, .. } => heartbeat_file.clone(),
+        State::Finished => None,
+    };
+    let next = advance(state).await;
+    if let (Some(path), Some((progress, _))) = (&heartbeat_file, &next) {
+        match progress {
+            Progress::Finished(summary) => write_heartbeat(path, "finished", Some(summary), 0),
+            Progress::Cancelled(summary) => write_heartbeat(path, "cancelled", Some(summary), 0),
+            Progress::Errored(e, summary) => write_heartbeat(
+                path,
+                &format!("errored: {}", e.replace('\n', " ")),
+                summary.as_ref(),
+                0,
+            ),
+            _ => {}
+        }
+    }
+    next
+}
+
+// Charts scores were written for that USC hasn't scanned yet, those only show up after a rescan.
+fn unindexed_charts(connection: &Connection, summary: &Summary) -> u32 {
+    let hashes: HashSet<&String> = summary
+        .new_charts
+        .iter()
+        .chain(&summary.improvements)
+        .chain(&summary.additional_plays)
+        .collect();
+    let mut stmt = match connection.prepare("SELECT EXISTS(SELECT 1 FROM Charts WHERE hash = ?)") {
+        Ok(stmt) => stmt,
+        Err(_) => return 0,
+    };
+    hashes
+        .into_iter()
+        .filter(|hash| !stmt.query_row([hash], |r| r.get(0)).unwrap_or(true))
+        .count() as u32
+}
+
+const SUPPORTED_VERSIONS: &[(u32, ImportFn, &[schema::Column])] = &[
+    (18, importer_funcs::version_18, schema::VERSION_18),
+    (19, importer_funcs::version_19, schema::VERSION_19),
+    (20, importer_funcs::version_20, schema::VERSION_20),
+    (21, importer_funcs::version_21, schema::VERSION_21),
+];
+
+fn supported_version(
+    db_version: u32,
+) -> Option<&'static (u32, ImportFn, &'static [schema::Column])> {
+    SUPPORTED_VERSIONS.iter().find(|(v, _, _)| *v == db_version)
+}
+
+pub fn is_supported_version(db_version: u32) -> bool {
+    supported_version(db_version).is_some()
+}
+
+pub fn supported_versions() -> String {
+    SUPPORTED_VERSIONS
+        .iter()
+        .map(|(v, _, _)| v.to_string())
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+fn check_columns(db: &Connection, db_version: u32, columns: &[schema::Column]) -> Result<()> {
+    if !columns.is_empty() {
+        let missing = schema::missing_columns(db, columns)?;
+        ensure!(
+            missing.is_empty(),
+            "Scores in this version {} database are missing {}",
+            db_version,
+            missing.join(", ")
+        );
+        let unfilled = schema::unfilled_columns(db, columns)?;
+        ensure!(
+            unfilled.is_empty(),
+            "Scores in this version {} database need {}, which the importer can't fill",
+            db_version,
+            unfilled.join(", ")
+        );
+    }
+    Ok(())
+}
+
+// Written next to the original before anything is imported, an interrupted import can't be
+// undone otherwise. VACUUM INTO reads through the connection, so the copy is consistent even
+// with USC writing or changes still in a -wal or -journal file.
+fn back_up_database(connection: &Connection, db_path: &Path) -> Result<PathBuf> {
+    let secs = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+    let mut name = db_path.file_name().unwrap_or_default().to_os_string();
+    name.push(format!(".ksm-import-backup-{}", secs));
+    let backup_path = db_path.with_file_name(name);
+    let target = backup_path
+        .to_str()
+        .ok_or_else(|| anyhow!("{:?} is not valid UTF-8", backup_path))?;
+    connection.execute("VACUUM INTO ?", [target])?;
+    checksum::write_companion(&backup_path)?;
+    Ok(backup_path)
+}
+
+// USC holds a lock on maps.db while it writes to it, short locks are waited out.
+const BUSY_TIMEOUT: Duration = Duration::from_secs(5);
+const LOCKED_MESSAGE: &str = "maps.db is locked, close USC and retry";
+
+fn is_locked(e: &rusqlite::Error) -> bool {
+    matches!(
+        e,
+        rusqlite::Error::SqliteFailure(f, _)
+            if matches!(f.code, ErrorCode::DatabaseBusy | ErrorCode::DatabaseLocked)
+    )
+}
+
+// Takes and drops a write lock, without writing anything.
+pub fn db_locked(db_path: &Path) -> bool {
+    Connection::open_with_flags(db_path, OpenFlags::SQLITE_OPEN_READ_WRITE)
+        .and_then(|db| {
+            db.busy_timeout(Duration::from_millis(500))?;
+            db.execute_batch("BEGIN IMMEDIATE; ROLLBACK")
+        })
+        .is_err_and(|e| is_locked(&e))
+}
+
+fn roll_back(connection: &Connection, summary: &mut Summary) {
+    if connection.execute_batch("ROLLBACK").is_ok() {
+        FileCounts::default().restore(summary);
+        summary
+            .warnings
+            .push("The import didn't finish and was rolled back, nothing was written".to_string());
+    }
+}
+
+async fn advance(state: State) -> Option<(Progress, State)> {
+    match state {
+        State::Ready {
+            source,
+            db,
+            options,
+        } => {
+            // Only paused by a lock found on the way in, it's waiting for Retry.
+            let mut last_heartbeat: Option<Instant> = None;
+            while options.pause.load(Ordering::Relaxed) && !options.cancel.load(Ordering::Relaxed) {
+                if let Some(path) = &options.heartbeat_file {
+                    if last_heartbeat.is_none_or(|t| t.elapsed() >= HEARTBEAT_INTERVAL) {
+                        write_heartbeat(path, "paused", None, 0);
+                        last_heartbeat = Some(Instant::now());
+                    }
+                }
+                async_std::task::sleep(PAUSE_POLL_INTERVAL).await;
+            }
+            if options.cancel.load(Ordering::Relaxed) {
+                return Some((Progress::Cancelled(Summary::default()), State::Finished));
+            }
+            let began = Instant::now();
+            // A dry run must leave the file untouched, not even a journal gets created.
+            let flags = if options.dry_run {
+                OpenFlags::SQLITE_OPEN_READ_ONLY
+            } else {
+                OpenFlags::default()
+            };
+            let db_conn = Connection::open_with_flags(db.as_path(), flags).and_then(|connection| {
+                // Memory mapping a multi GB database doesn't fit next to the app in 32 bits.
+                if address_space_limited() {
+                    connection.execute_batch("PRAGMA mmap_size = 0;")?;
+                }
+                connection.busy_timeout(BUSY_TIMEOUT)?;
+                Ok(connection)
+            });
+            let mut warnings = Vec::new();
+            let started = Instant::now();
+            let mut files_unmodified = 0;
+            let score_files = source.score_files(&mut warnings).map(|mut f| {
+                files_unmodified =
+                    drop_unmodified(source.as_ref(), &mut f.files, options.policy.modified_after);
+                if !options.only_files.is_empty() {
+                    let only: HashSet<&PathBuf> = options.only_files.iter().collect();
+                    f.files.retain(|p| only.contains(p));
+                }
+                // Popped from the back, so reverse report order imports files in report order.
+                // Walk order depends on the filesystem, sorting keeps the summary and failure log
+                // identical between runs over the same folder.
+                f.files
+                    .sort_unstable_by(|a, b| report_order(b).cmp(&report_order(a)));
+                f.files
+            });
+            let scanning = started.elapsed();
+            let low_memory = options.low_memory
+                || score_files
+                    .as_ref()
+                    .is_ok_and(|f| f.len() > LOW_MEMORY_THRESHOLD);
+            let spill_path = if low_memory {
+                options
+                    .spill_path
+                    .clone()
+                    .or_else(crate::config::spill_path)
+                    .filter(|p| std::fs::File::create(p).is_ok())
+            } else {
+                None
+            };
+
+            match (db_conn, score_files) {
+                (Ok(connection), Ok(ksm)) => {
+                    let db_version =
+                        match connection
+                            .query_row("SELECT version FROM `Database`", [], |r| r.get(0))
+                        {
+                            // Nothing was read yet, Retry starts over.
+                            Err(e) if is_locked(&e) => {
+                                options.pause.store(true, Ordering::Relaxed);
+                                return Some((
+                                    Progress::Locked,
+                                    State::Ready {
+                                        source,
+                                        db,
+                                        options,
+                                    },
+                                ));
+                            }
+                            version => version.unwrap_or_default(),
+                        };
+                    let detected_columns =
+                        if !is_supported_version(db_version) && options.best_effort_schema {
+                            schema::detect_columns(&connection)
+                        } else {
+                            Ok(Vec::new())
+                        };
+                    let checked = detected_columns.and_then(|detected| {
+                        let columns = supported_version(db_version)
+                            .map_or(detected.as_slice(), |&(_, _, columns)| columns);
+                        check_columns(&connection, db_version, columns)?;
+                        Ok(detected)
+                    });
+                    let detected_columns = match checked {
+                        Ok(detected) => detected,
+                        Err(e) => {
+                            return Some((
+                                Progress::Errored(format!("{:?}", e), None),
+                                State::Finished,
+                            ))
+                        }
+                    };
+                    // Only once the checks passed, and not for a version the import stops at.
+                    let writable =
+                        supported_version(db_version).is_some() || !detected_columns.is_empty();
+                    let backing_up = Instant::now();
+                    let backup_path = if options.dry_run || options.skip_backup || !writable {
+                        None
+                    } else {
+                        match back_up_database(&connection, &db) {
+                            Ok(backup_path) => Some(backup_path),
+                            Err(e) => {
+                                return Some((
+                                    Progress::Errored(
+                                        format!(
+                                            "Could not back up maps.db, nothing was imported: {:?}",
+                                            e
+                                        ),
+                                        None,
+                                    ),
+                                    State::Finished,
+                                ))
+                            }
+                        }
+                    };
+                    let backing_up = backing_up.elapsed();
+                    if options.all_or_nothing {
+                        if let Err(e) = connection.execute_batch("BEGIN") {
+                            return Some((
+                                Progress::Errored(format!("{:?}", e), None),
+                                State::Finished,
+                            ));
+                        }
+                    }
+                    if !detected_columns.is_empty() {
+                        warnings.push(format!(
+                            "DB version {} is unknown, scores were written to the columns found \
+                            in its Scores table",
+                            db_version
+                        ));
+                        if let Ok(audit) = schema::audit_db(&connection) {
+                            warnings.extend(audit.lines());
+                        }
+                    }
+                    Some((
+                        Progress::Started,
+                        State::Importing {
+                            db_version,
+                            detected_columns,
+                            connection,
+                            song_roots: crate::importer_funcs::song_roots(
+                                &db,
+                                options.songs_root.as_deref(),
+                            ),
+                            earlier_rows: RefCell::new(importer_funcs::earlier_rows(
+                                &options.earlier_rows,
+                            )),
+                            options,
+                            source,
+                            chart_names: ChartNames::default(),
+                            hash_cache: RefCell::default(),
+                            last_heartbeat: None,
+                            db_path: db,
+                            summary: Summary {
+                                files_found: ksm.len() as u32,
+                                files_unmodified,
+                                warnings,
+                                spill_path,
+                                backup_path,
+                                timings: Timings {
+                                    scanning,
+                                    backing_up,
+                                    total: began.elapsed(),
+                                    ..Default::default()
+                                },
+                                ..Default::default()
+                            },
+                            score_files: ksm,
+                        },
+                    ))
+                }
+                (Ok(_), Err(e)) => {
+                    Some((Progress::Errored(format!("{:?}", e), None), State::Finished))
+                }
+                (Err(e), Ok(_)) => {
+                    Some((Progress::Errored(format!("{:?}", e), None), State::Finished))
+                }
+                (Err(db_err), Err(ksm_err)) => Some((
+                    Progress::Errored(
+                        format!("DB Error: '{:?}', KSM Path error: '{:?}'", db_err, ksm_err),
+                        None,
+                    ),
+                    State::Finished,
+                )),
+            }
+        }
+        State::Importing {
+            mut score_files,
+            mut summary,
+            connection,
+            db_path,
+            db_version,
+            detected_columns,
+            options,
+            source,
+            song_roots,
+            mut chart_names,
+            hash_cache,
+            earlier_rows,
+            mut last_heartbeat,
+        } => {
+            while options.pause.load(Ordering::Relaxed) && !options.cancel.load(Ordering::Relaxed) {
+                if let Some(path) = &options.heartbeat_file {
+                    if last_heartbeat.is_none_or(|t: Instant| t.elapsed() >= HEARTBEAT_INTERVAL) {
+                        write_heartbeat(path, "paused", Some(&summary), score_files.len());
+                        last_heartbeat = Some(Instant::now());
+                    }
+                }
+                async_std::task::sleep(PAUSE_POLL_INTERVAL).await;
+            }
+            let step = Instant::now();
+            if options.cancel.load(Ordering::Relaxed) {
+                chart_names.finish_summary(&mut summary);
+                summary.warnings.push(format!(
+                    "Cancelled after {} of {} score files",
+                    summary.files_processed, summary.files_found
+                ));
+                if options.all_or_nothing {
+                    roll_back(&connection, &mut summary);
+                }
+                summary.timings.total += step.elapsed();
+                return Some((Progress::Cancelled(summary), State::Finished));
+            }
+            if score_files.is_empty() {
+                if options.all_or_nothing {
+                    let committing = Instant::now();
+                    let committed = connection.execute_batch("COMMIT");
+                    summary.timings.committing += committing.elapsed();
+                    match committed {
+                        // The transaction stays open, Retry commits it again.
+                        Err(e) if is_locked(&e) => {
+                            options.pause.store(true, Ordering::Relaxed);
+                            return Some((
+                                Progress::Locked,
+                                State::Importing {
+                                    db_version,
+                                    detected_columns,
+                                    score_files,
+                                    summary,
+                                    connection,
+                                    db_path,
+                                    options,
+                                    source,
+                                    song_roots,
+                                    chart_names,
+                                    hash_cache,
+                                    earlier_rows,
+                                    last_heartbeat,
+                                },
+                            ));
+                        }
+                        Err(e) => {
+                            chart_names.finish_summary(&mut summary);
+                            roll_back(&connection, &mut summary);
+                            return Some((
+                                Progress::Errored(format!("{:?}", e), Some(summary)),
+                                State::Finished,
+                            ));
+                        }
+                        Ok(()) => {}
+                    }
+                }
+                summary.unindexed_charts = unindexed_charts(&connection, &summary);
+                chart_names.finish_summary(&mut summary);
+                summary.timings.total += step.elapsed();
+                if options.optimize && options.dry_run {
+                    summary
+                        .warnings
+                        .push("Skipped optimizing, a dry run doesn't change maps.db".to_string());
+                } else if options.optimize {
+                    return Some((
+                        Progress::Optimizing,
+                        State::Optimizing {
+                            summary,
+                            connection,
+                            db_path,
+                            heartbeat_file: options.heartbeat_file,
+                        },
+                    ));
+                }
+                return Some((Progress::Finished(summary), State::Finished));
+            }
+
+            let insert_func = match supported_version(db_version) {
+                Some(&(_, insert_func, _)) => insert_func,
+                None if !detected_columns.is_empty() => importer_funcs::version_detected,
+                None => {
+                    return Some((
+                        Progress::Errored(
+                            format!(
+                                "Unsupported DB version: {}, supported versions are {}",
+                                db_version,
+                                supported_versions()
+                            ),
+                            None,
+                        ),
+                        State::Finished,
+                    ))
+                }
+            };
+
+            let current_file_path = score_files.pop().unwrap();
+
+            let locked = Cell::new(false);
+            let before = FileCounts::of(&summary);
+            let reads = FileReads::of(&summary);
+            let imported = std::panic::catch_unwind(AssertUnwindSafe(|| {
+                let ctx = ImportContext {
+                    insert_func,
+                    safe_mode: options.safe_mode,
+                    policy: options.policy,
+                    limits: options.limits,
+                    connection: &connection,
+                    locked: &locked,
+                    options: InsertOptions {
+                        source: source.as_ref(),
+                        song_roots: &song_roots,
+                        timestamps: options.policy.timestamps,
+                        file_index: summary.files_found - score_files.len() as u32 - 1,
+                        file_count: summary.files_found,
+                        chart_time: &Cell::new(Duration::ZERO),
+                        hash_cache: &hash_cache,
+                        earlier_rows: &earlier_rows,
+                        user_name: &options.user_name,
+                        user_id: &options.user_id,
+                        skip_duplicates: options.skip_duplicates,
+                        improvements_only: options.improvements_only,
+                        compare_across_gauges: options.compare_across_gauges,
+                        levels: options.policy.levels,
+                        dry_run: options.dry_run,
+                        detected_columns: &detected_columns,
+                    },
+                };
+                import_file(&ctx, &current_file_path, &mut summary, &mut chart_names)
+            }));
+            if let Err(panic) = imported {
+                // Scores the file wrote before the panic can't be told apart from the rest of
+                // one big transaction, so that import ends.
+                if options.all_or_nothing {
+                    chart_names.finish_summary(&mut summary);
+                    roll_back(&connection, &mut summary);
+                    return Some((
+                        Progress::Errored(
+                            format!(
+                                "The importer crashed while processing \"{}\": {}",
+                                current_file_path.to_str().unwrap_or_default(),
+                                panic_message(panic.as_ref())
+                            ),
+                            Some(summary),
+                        ),
+                        State::Finished,
+                    ));
+                }
+                // Scores committed one by one in safe mode stay, the rest went with the rollback.
+                if !connection.is_autocommit() {
+                    let _ = connection.execute_batch("ROLLBACK");
+                    before.restore(&mut summary);
+                }
+                summary.fail_messages.push(Failure {
+                    path: current_file_path.clone(),
+                    kind: FailureKind::Crashed,
+                    reason: panic_message(panic.as_ref()),
+                    message: format!(
+                        "The importer crashed while processing \"{}\", none of its scores were \
+                        imported: {}",
+                        current_file_path.to_str().unwrap_or_default(),
+                        panic_message(panic.as_ref())
+                    ),
+                });
+            }
+            if locked.get() {
+                // The file's transaction was rolled back, it's read again once Retry resumes the
+                // import. Scores safe mode saved before the lock stay and come back as duplicates.
+                reads.restore(&mut summary);
+                score_files.push(current_file_path.clone());
+                options.pause.store(true, Ordering::Relaxed);
+            }
+            if let Err(e) = spill_failures(&mut summary) {
+                summary.spill_path = None;
+                summary.warnings.push(format!(
+                    "Could not write failures to disk, keeping them in memory: {:?}",
+                    e
+                ));
+            }
+
+            // Written from the import loop so a stalled read also stalls the heartbeat.
+            if let Some(path) = &options.heartbeat_file {
+                if last_heartbeat.is_none_or(|t: Instant| t.elapsed() >= HEARTBEAT_INTERVAL) {
+                    write_heartbeat(path, "running", Some(&summary), score_files.len());
+                    last_heartbeat = Some(Instant::now());
+                }
+            }
+
+            summary.timings.total += step.elapsed();
+            let progress = 1.0 - (score_files.len() as f32 / summary.files_found as f32);
+            // Shown while the next file is imported, so a stall names the file it's stuck on.
+            let next_file = score_files.last().unwrap_or(&current_file_path);
+            Some((
+                if locked.get() {
+                    Progress::Locked
+                } else {
+                    Progress::Advanced(progress, chart_display_name(next_file).into())
+                },
+                State::Importing {
+                    db_version,
+                    detected_columns,
+                    score_files,
+                    summary,
+                    connection,
+                    db_path,
+                    options,
+                    source,
+                    song_roots,
+                    chart_names,
+                    hash_cache,
+                    earlier_rows,
+                    last_heartbeat,
+                },
+            ))
+        }
+        State::Optimizing {
+            mut summary,
+            connection,
+            db_path,
+            heartbeat_file,
+        } => {
+            let started = Instant::now();
+            let counters = heartbeat_counters(Some(&summary), 0);
+            match optimize_database(&connection, &db_path, heartbeat_file, counters) {
+                Ok(sizes) => summary.optimized_size = Some(sizes),
+                Err(e) => summary.optimize_error = Some(format!("{:?}", e)),
+            }
+            summary.timings.optimizing = started.elapsed();
+            summary.timings.total += summary.timings.optimizing;
+            Some((Progress::Finished(summary), State::Finished))
+        }
+        State::Finished => None,
+    }
+}
+
+impl<H, I> iced_native::subscription::Recipe<H, I> for Importer
+where
+    H: std::hash::Hasher,
+{
+    type Output = Progress;
+
+    fn hash(&self, state: &mut H) {
+        use std::hash::Hash;
+
+        std::any::TypeId::of::<Self>().hash(state);
+        self.ksm_path.hash(state);
+        self.db_path.hash(state);
+        self.run_id.hash(state);
+    }
+
+    fn stream(
+        self: Box<Self>,
+        _input: iced_futures::BoxStream<I>,
+    ) -> iced_futures::BoxStream<Self::Output> {
+        Box::pin(futures::stream::unfold(
+            State::Ready {
+                source: self.source,
+                db: self.db_path,
+                options: self.options,
+            },
+            run_importer,
+        ))
+    }
+}
+
+#[derive(Debug)]
+#[allow(clippy::large_enum_variant)]
+enum State {
+    Ready {
+        source: Arc<dyn Source>,
         db: PathBuf,
+        options: ImportOptions,
     },
     Importing {
         db_version: u32,
-        score_files: Vec<DirEntry>,
+        detected_columns: Vec<schema::Column>,
+        score_files: Vec<PathBuf>,
+        summary: Summary,
+        connection: Connection,
+        db_path: PathBuf,
+        options: ImportOptions,
+        source: Arc<dyn Source>,
+        song_roots: Vec<PathBuf>,
+        chart_names: ChartNames,
+        hash_cache: RefCell<HashCache>,
+        earlier_rows: RefCell<EarlierRows>,
+        last_heartbeat: Option<Instant>,
+    },
+    Optimizing {
         summary: Summary,
         connection: Connection,
+        db_path: PathBuf,
+        heartbeat_file: Option<PathBuf>,
     },
     Finished,
 }
@@ -258,7 +2250,2504 @@ enum State {
 #[derive(Debug, Clone)]
 pub enum Progress {
     Started,
-    Advanced(f32, String),
+    // The song and difficulty of the file being imported, cloned with every update.
+    Advanced(f32, Arc<str>),
+    Optimizing,
+    // maps.db stayed locked past BUSY_TIMEOUT. The import is paused, clearing the pause retries.
+    Locked,
     Finished(Summary),
-    Errored(String),
+    Cancelled(Summary),
+    Errored(String, Option<Summary>),
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::source::{ChartSource, ScoreSource};
+    use crate::test_support::{self, line, Fixture, TempDb};
+
+    // Panics reading anything under "boom", and on the second score of anything under "midway",
+    // after the first one was inserted.
+    #[derive(Debug)]
+    struct PanickingSource(MemorySource);
+
+    static MIDWAY_CALLS: std::sync::atomic::AtomicU32 = std::sync::atomic::AtomicU32::new(0);
+
+    impl ScoreSource for PanickingSource {
+        fn score_files(&self, warnings: &mut Vec<String>) -> Result<ScoreFiles> {
+            self.0.score_files(warnings)
+        }
+
+        fn read_score(&self, path: &Path) -> Result<Vec<u8>> {
+            if path.iter().any(|c| c == "boom") {
+                panic!("backend exploded");
+            }
+            self.0.read_score(path)
+        }
+
+        fn modified(&self, path: &Path) -> Result<SystemTime> {
+            if path.iter().any(|c| c == "midway")
+                && MIDWAY_CALLS.fetch_add(1, Ordering::SeqCst) == 1
+            {
+                panic!("backend exploded midway");
+            }
+            self.0.modified(path)
+        }
+
+        fn size(&self, path: &Path) -> Result<u64> {
+            self.0.size(path)
+        }
+    }
+
+    impl ChartSource for PanickingSource {
+        fn chart_exists(&self, path: &Path) -> bool {
+            self.0.chart_exists(path)
+        }
+
+        fn read_chart(&self, path: &Path) -> Result<Vec<u8>> {
+            self.0.read_chart(path)
+        }
+
+        fn case_variants(&self, path: &Path) -> Vec<PathBuf> {
+            self.0.case_variants(path)
+        }
+    }
+
+    #[test]
+    fn imports_a_fixture_into_a_temporary_db() {
+        let db = TempDb::new();
+        let fixture = Fixture::new()
+            .score("pack/song", "ex", &[&line(9_500_000), &line(9_600_000)])
+            .file("score/player/pack/song/._ex.ksc", b"\0\x05\x16\x07");
+        let summary = test_support::import(fixture, &db, test_support::options());
+        assert_eq!(summary.scores_imported, 2);
+        assert_eq!(db.scores(), 2);
+        assert_eq!(
+            db.count("SELECT MIN(timestamp) FROM Scores"),
+            test_support::MODIFIED
+        );
+    }
+
+    #[test]
+    fn a_panicking_file_becomes_a_failure() {
+        let db = TempDb::new();
+        let fixture = Fixture::new()
+            .score("pack/boom", "ex", &[&line(9_000_000)])
+            .score("pack/fine", "ex", &[&line(9_100_000)]);
+        let progress = test_support::run_source(
+            Arc::new(PanickingSource(fixture.source)),
+            &db.path,
+            test_support::options(),
+        );
+        let summary = test_support::finished(&progress);
+        assert_eq!(summary.files_processed, 2);
+        assert_eq!(summary.scores_imported, 1);
+        assert_eq!(db.scores(), 1);
+        let crashes: Vec<_> = summary.failures_of(FailureKind::Crashed).collect();
+        assert_eq!(crashes.len(), 1);
+        assert_eq!(crashes[0].reason, "backend exploded");
+        assert!(crashes[0].path.iter().any(|c| c == "boom"));
+    }
+
+    #[test]
+    fn a_panic_ends_an_all_or_nothing_import() {
+        let db = TempDb::new();
+        let fixture = Fixture::new()
+            .score("pack/boom", "ex", &[&line(9_000_000)])
+            .score("pack/alpha", "ex", &[&line(9_100_000)]);
+        let options = ImportOptions {
+            all_or_nothing: true,
+            ..test_support::options()
+        };
+        let progress =
+            test_support::run_source(Arc::new(PanickingSource(fixture.source)), &db.path, options);
+        match progress.last() {
+            Some(Progress::Errored(e, Some(summary))) => {
+                assert!(e.contains("backend exploded"), "{}", e);
+                // alpha was written and rolled back with the rest, there is nothing to undo.
+                assert!(summary.inserted_rows.is_empty());
+            }
+            other => panic!("{:?}", other),
+        }
+        assert_eq!(db.scores(), 0);
+    }
+
+    fn failing_trigger(raise: &str) -> TempDb {
+        TempDb::with(|db| {
+            db.execute_batch(&format!(
+                "CREATE TRIGGER volforce BEFORE INSERT ON Scores WHEN NEW.score = 6660000 \
+                BEGIN SELECT RAISE({}, 'volforce table broke'); END;",
+                raise
+            ))
+            .unwrap()
+        })
+    }
+
+    #[test]
+    fn safe_mode_reports_a_trigger_failure_against_its_score() {
+        for raise in ["ABORT", "ROLLBACK"] {
+            let db = failing_trigger(raise);
+            let fixture = Fixture::new()
+                .score("pack/a", "ex", &[&line(9_000_000), &line(6_660_000)])
+                .score("pack/b", "ex", &[&line(9_100_000)]);
+            let options = ImportOptions {
+                safe_mode: true,
+                ..test_support::options()
+            };
+            let summary = test_support::import(fixture, &db, options);
+            assert_eq!(summary.scores_imported, 2, "{}", raise);
+            assert_eq!(db.scores(), 2, "{}", raise);
+            let failures: Vec<_> = summary.failures_of(FailureKind::Insert).collect();
+            assert_eq!(failures.len(), 1, "{}", raise);
+            assert!(failures[0].message.contains("6660000"), "{}", raise);
+            assert!(
+                failures[0].reason.contains("volforce table broke"),
+                "{}: {}",
+                raise,
+                failures[0].reason
+            );
+        }
+    }
+
+    #[test]
+    fn preflight_lists_score_triggers() {
+        let db = failing_trigger("ABORT");
+        let ksm = tempfile::tempdir().unwrap();
+        let source = MemorySource::default();
+        let preflight = validate_paths(
+            &source,
+            ksm.path(),
+            &db.path,
+            Policy::default(),
+            FileLimits::default(),
+        )
+        .unwrap();
+        assert_eq!(preflight.score_triggers, vec!["volforce".to_string()]);
+    }
+
+    #[test]
+    fn preflight_counts_charts_scanned_twice() {
+        let db = TempDb::with(|db| {
+            let charts = [
+                ("a/song/ex.ksh", "x"),
+                ("b/song/ex.ksh", "x"),
+                ("c/song/ex.ksh", "x"),
+                ("a/other/ex.ksh", "y"),
+                ("b/other/ex.ksh", "y"),
+                ("a/single/ex.ksh", "z"),
+            ];
+            for (path, hash) in charts {
+                db.execute("INSERT INTO Charts(path, hash) VALUES(?, ?)", [path, hash])
+                    .unwrap();
+            }
+        });
+        let ksm = tempfile::tempdir().unwrap();
+        let preflight = validate_paths(
+            &MemorySource::default(),
+            ksm.path(),
+            &db.path,
+            Policy::default(),
+            FileLimits::default(),
+        )
+        .unwrap();
+        assert_eq!(preflight.duplicate_charts, 2);
+    }
+
+    #[test]
+    fn a_dry_run_leaves_the_database_file_untouched() {
+        let db = TempDb::new();
+        let stat = || {
+            let metadata = std::fs::metadata(&db.path).unwrap();
+            (metadata.len(), metadata.modified().unwrap())
+        };
+        let before = (stat(), std::fs::read(&db.path).unwrap());
+        let options = ImportOptions {
+            dry_run: true,
+            optimize: true,
+            skip_backup: false,
+            ..test_support::options()
+        };
+        let summary = test_support::import(
+            Fixture::new()
+                .score("pack/dry", "ex", &[&line(9_000_000)])
+                .score("pack/dry", "ch", &[&line(9_100_000)]),
+            &db,
+            options,
+        );
+        assert_eq!(summary.scores_imported, 2);
+        assert_eq!((stat(), std::fs::read(&db.path).unwrap()), before);
+    }
+
+    #[test]
+    fn a_dry_run_skips_optimizing() {
+        let db = TempDb::new();
+        let options = ImportOptions {
+            dry_run: true,
+            optimize: true,
+            ..test_support::options()
+        };
+        assert!(options
+            .validate()
+            .iter()
+            .all(|c| c.severity == Severity::Warning));
+        let progress = test_support::run(
+            Fixture::new().score("pack/dry", "ex", &[&line(9_000_000)]),
+            &db,
+            options,
+        );
+        assert!(!progress.iter().any(|p| matches!(p, Progress::Optimizing)));
+        let summary = test_support::finished(&progress);
+        assert_eq!(summary.optimized_size, None);
+        assert!(summary
+            .warnings
+            .iter()
+            .any(|w| w.contains("Skipped optimizing")));
+        assert_eq!(db.scores(), 0);
+    }
+
+    fn score_folder(files: &[&str]) -> tempfile::TempDir {
+        let dir = tempfile::tempdir().unwrap();
+        for file in files {
+            let path = dir.path().join(file);
+            std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+            std::fs::write(path, line(9_000_000)).unwrap();
+        }
+        dir
+    }
+
+    fn enumerate(dir: &Path) -> (ScoreFiles, Vec<String>) {
+        let mut warnings = Vec::new();
+        let files =
+            enumerate_score_folder(dir.to_path_buf(), &AtomicBool::new(false), &mut warnings)
+                .unwrap();
+        (files, warnings)
+    }
+
+    #[test]
+    fn enumerating_counts_junk_and_keeps_scores() {
+        let dir = score_folder(&[
+            "p/pack/song/ex.ksc",
+            "p/pack/song/ex.bak.ksc",
+            "p/readme.txt",
+        ]);
+        let (files, warnings) = enumerate(dir.path());
+        assert_eq!(files.files, vec![dir.path().join("p/pack/song/ex.ksc")]);
+        assert_eq!(files.junk_files, 1);
+        assert!(warnings.is_empty());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn a_linked_score_file_is_imported_once_under_its_own_path() {
+        let dir = score_folder(&["p/pack/song/ex.ksc"]);
+        std::fs::create_dir_all(dir.path().join("p/pack/copy")).unwrap();
+        std::os::unix::fs::symlink(
+            dir.path().join("p/pack/song/ex.ksc"),
+            dir.path().join("p/pack/copy/ex.ksc"),
+        )
+        .unwrap();
+        let (files, warnings) = enumerate(dir.path());
+        assert_eq!(files.files, vec![dir.path().join("p/pack/song/ex.ksc")]);
+        assert_eq!(warnings.len(), 1);
+        assert!(warnings[0].contains("copy"));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn a_score_folder_behind_a_link_keeps_the_given_paths() {
+        let dir = score_folder(&["real/p/pack/song/ex.ksc"]);
+        let link = dir.path().join("score");
+        std::os::unix::fs::symlink(dir.path().join("real"), &link).unwrap();
+        let (files, warnings) = enumerate(&link);
+        assert_eq!(files.files, vec![link.join("p/pack/song/ex.ksc")]);
+        assert!(warnings.is_empty());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn a_link_to_a_score_outside_the_folder_is_imported() {
+        let dir = score_folder(&["elsewhere/ex.ksc", "score/p/pack/song/nov.ksc"]);
+        std::os::unix::fs::symlink(
+            dir.path().join("elsewhere/ex.ksc"),
+            dir.path().join("score/p/pack/song/ex.ksc"),
+        )
+        .unwrap();
+        let (mut files, warnings) = enumerate(&dir.path().join("score"));
+        files.files.sort();
+        assert_eq!(files.files.len(), 2);
+        assert!(warnings.is_empty());
+    }
+
+    #[test]
+    fn clamped_timestamps_are_counted_and_noted() {
+        let db = TempDb::new();
+        let mut fixture = Fixture::new()
+            .score("pack/old", "ex", &[&line(9_000_000)])
+            .score("pack/new", "ex", &[&line(9_100_000)])
+            .score("pack/fine", "ex", &[&line(9_200_000), &line(9_300_000)]);
+        let contents = format!("{}\n", line(9_000_000)).into_bytes();
+        fixture.source.insert(
+            Fixture::score_path("pack/old", "ex"),
+            contents.clone(),
+            UNIX_EPOCH - Duration::from_secs(3600),
+        );
+        fixture.source.insert(
+            Fixture::score_path("pack/new", "ex"),
+            contents,
+            SystemTime::now() + Duration::from_secs(10 * 24 * 60 * 60),
+        );
+        let summary = test_support::import(fixture, &db, test_support::options());
+        assert_eq!(summary.scores_imported, 4);
+        assert_eq!(summary.timestamps_adjusted, 2);
+        let notes: Vec<&String> = summary
+            .warnings
+            .iter()
+            .filter(|w| w.starts_with("Modification time of"))
+            .collect();
+        assert_eq!(notes.len(), 2);
+        assert!(notes.iter().any(|w| w.ends_with("written as 1970")));
+        assert!(notes.iter().any(|w| w.ends_with("written as now")));
+        assert_eq!(db.count("SELECT MIN(timestamp) FROM Scores"), 0);
+        assert!(db.count("SELECT MAX(timestamp) FROM Scores") <= now_secs());
+    }
+
+    fn now_secs() -> i64 {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs() as i64
+    }
+
+    #[test]
+    fn preflight_stops_at_the_line_limit() {
+        let db = TempDb::new();
+        let ksm = tempfile::tempdir().unwrap();
+        let lines: Vec<String> = (0..5).map(|i| line(9_000_000 + i)).collect();
+        let lines: Vec<&str> = lines.iter().map(String::as_str).collect();
+        let fixture =
+            Fixture::new()
+                .score("pack/long", "ex", &lines)
+                .score("pack/short", "ex", &lines[..1]);
+        let limits = FileLimits {
+            max_lines: 2,
+            ..Default::default()
+        };
+        let preflight = validate_paths(
+            &fixture.source,
+            ksm.path(),
+            &db.path,
+            Policy::default(),
+            limits,
+        )
+        .unwrap();
+        assert_eq!(preflight.scores_previewed, 3);
+    }
+
+    fn score(score: u32, badge: u32) -> KsmScore {
+        format!(
+            "normal,normal,normal,on,on,on={},{},0,87.5,1,2",
+            score, badge
+        )
+        .parse()
+        .unwrap()
+    }
+
+    #[test]
+    fn cleared_scores_of_100_or_less_are_legacy_scale() {
+        assert!(score(98, 2).is_legacy_scale());
+        assert!(score(100, 5).is_legacy_scale());
+        assert!(score(1, 3).is_legacy_scale());
+        // A failed play can score this low for real, and nothing scored is no percentage.
+        assert!(!score(98, 1).is_legacy_scale());
+        assert!(!score(98, 0).is_legacy_scale());
+        assert!(!score(0, 2).is_legacy_scale());
+        assert!(!score(101, 2).is_legacy_scale());
+        assert!(!score(9_876_543, 2).is_legacy_scale());
+    }
+
+    #[test]
+    fn legacy_scores_convert_to_the_10_million_scale() {
+        let mut legacy = score(98, 2);
+        legacy.convert_legacy_scale();
+        assert_eq!(legacy.score, 9_800_000);
+        assert!(!legacy.is_legacy_scale());
+        let mut perfect = score(100, 5);
+        perfect.convert_legacy_scale();
+        assert_eq!(perfect.score, MAX_SCORE);
+        let mut failed = score(98, 1);
+        failed.convert_legacy_scale();
+        assert_eq!(failed.score, 98);
+    }
+
+    #[test]
+    fn legacy_scores_are_counted_or_skipped() {
+        let fixture = || {
+            Fixture::new().score(
+                "pack/song",
+                "ex",
+                &[
+                    "normal,normal,normal,on,on,on=97,2,0,87.5,1,2",
+                    &line(9_500_000),
+                ],
+            )
+        };
+        let db = TempDb::new();
+        let summary = test_support::import(fixture(), &db, test_support::options());
+        assert_eq!(summary.legacy_converted, 1);
+        assert_eq!(db.count("SELECT MIN(score) FROM Scores"), 9_500_000);
+        assert_eq!(db.count("SELECT MAX(score) FROM Scores"), 9_700_000);
+
+        let db = TempDb::new();
+        let mut options = test_support::options();
+        options.policy.skip_legacy = true;
+        let summary = test_support::import(fixture(), &db, options);
+        assert_eq!(summary.legacy_converted, 0);
+        assert_eq!(summary.skipped.get(&SkipRule::LegacyScale), Some(&1));
+        assert_eq!(db.scores(), 1);
+    }
+
+    fn play(score: u32, badge: u32, gauge: f64) -> KsmScore {
+        format!(
+            "normal,normal,normal,on,on,on={},{},0,{},1,2",
+            score, badge, gauge
+        )
+        .parse()
+        .unwrap()
+    }
+
+    type Play = (u32, u32, f64);
+
+    fn kept(plays: &[Play]) -> Vec<(u32, u32, u64)> {
+        let scores: Vec<KsmScore> = plays.iter().map(|&(s, b, g)| play(s, b, g)).collect();
+        let mut kept: Vec<(u32, u32, u64)> = best_of(&scores)
+            .into_iter()
+            .map(|i| (plays[i].0, plays[i].1, plays[i].2.to_bits()))
+            .collect();
+        kept.sort_unstable();
+        kept
+    }
+
+    fn permutations(plays: &[Play]) -> Vec<Vec<Play>> {
+        if plays.len() <= 1 {
+            return vec![plays.to_vec()];
+        }
+        (0..plays.len())
+            .flat_map(|i| {
+                let mut rest = plays.to_vec();
+                let first = rest.remove(i);
+                permutations(&rest).into_iter().map(move |mut p| {
+                    p.insert(0, first);
+                    p
+                })
+            })
+            .collect()
+    }
+
+    #[test]
+    fn best_only_keeps_a_lower_scoring_hard_clear() {
+        let plays = [play(9_800_000, 2, 80.0), play(9_500_000, 3, 60.0)];
+        assert_eq!(best_of(&plays), vec![0, 1]);
+    }
+
+    #[test]
+    fn best_only_keeps_one_play_when_it_is_best_both_ways() {
+        let plays = [
+            play(9_000_000, 2, 70.0),
+            play(9_900_000, 4, 100.0),
+            play(9_500_000, 3, 60.0),
+        ];
+        assert_eq!(best_of(&plays), vec![1]);
+        assert_eq!(best_of(&plays[..1]), vec![0]);
+        assert!(best_of(&[]).is_empty());
+    }
+
+    #[test]
+    fn best_only_ties_go_to_the_gauge_then_the_later_play() {
+        let plays = [play(9_500_000, 2, 70.0), play(9_500_000, 2, 90.0)];
+        assert_eq!(best_of(&plays), vec![1]);
+        let plays = [play(9_500_000, 2, 90.0), play(9_500_000, 2, 70.0)];
+        assert_eq!(best_of(&plays), vec![0]);
+        let plays = [play(9_500_000, 2, 90.0), play(9_500_000, 2, 90.0)];
+        assert_eq!(best_of(&plays), vec![1]);
+    }
+
+    #[test]
+    fn best_only_picks_the_same_plays_in_any_order() {
+        let sets: [&[Play]; 3] = [
+            &[
+                (9_800_000, 2, 80.0),
+                (9_500_000, 3, 60.0),
+                (9_000_000, 1, 40.0),
+                (8_000_000, 3, 90.0),
+            ],
+            &[
+                (9_700_000, 4, 100.0),
+                (9_700_000, 2, 100.0),
+                (9_900_000, 0, 20.0),
+                (9_100_000, 5, 100.0),
+            ],
+            &[
+                (9_600_000, 3, 50.0),
+                (9_600_000, 3, 75.0),
+                (9_200_000, 2, 99.0),
+            ],
+        ];
+        for plays in sets.iter() {
+            let expected = kept(plays);
+            let top_score = plays.iter().map(|p| p.0).max().unwrap();
+            let top_badge = plays.iter().map(|p| p.1).max().unwrap();
+            assert!(expected.iter().any(|k| k.0 == top_score));
+            assert!(expected.iter().any(|k| k.1 == top_badge));
+            assert!(expected.len() <= 2);
+            for permutation in permutations(plays) {
+                assert_eq!(kept(&permutation), expected);
+            }
+        }
+    }
+
+    #[test]
+    fn best_only_counts_the_dropped_plays() {
+        let mut skipped = BTreeMap::new();
+        let policy = Policy {
+            best_only: true,
+            ..Default::default()
+        };
+        let plays = vec![
+            play(9_800_000, 2, 80.0),
+            play(9_500_000, 3, 60.0),
+            play(9_000_000, 2, 50.0),
+        ];
+        assert_eq!(keep_best(plays, policy, &mut skipped).len(), 2);
+        assert_eq!(skipped.get(&SkipRule::NotBest), Some(&1));
+    }
+
+    #[test]
+    fn backdated_scores_note_both_timestamps() {
+        let db = TempDb::new();
+        let fixture = Fixture::new()
+            .score("pack/a", "ex", &[&line(9_000_000)])
+            .score("pack/b", "ex", &[&line(9_100_000)]);
+        let cutoff = test_support::MODIFIED - 3600;
+        let mut options = test_support::options();
+        options.policy.timestamps = TimestampPolicy::Before(cutoff);
+        let summary = test_support::import(fixture, &db, options);
+        assert_eq!(summary.timestamps_backdated, 2);
+        assert_eq!(db.count("SELECT MAX(timestamp) FROM Scores"), cutoff - 60);
+        assert_eq!(db.count("SELECT MIN(timestamp) FROM Scores"), cutoff - 120);
+        let original = importer_funcs::format_date(test_support::MODIFIED);
+        let written = importer_funcs::format_date(cutoff - 60);
+        assert!(summary
+            .warnings
+            .iter()
+            .any(|w| w.starts_with("Backdated") && w.contains(&original) && w.ends_with(&written)));
+    }
+
+    #[test]
+    fn score_file_names_are_classified() {
+        let cases = [
+            ("score/p/pack/song/ex.ksc", Some(ScoreFileKind::Score)),
+            ("score/p/pack/song/EX.KSC", Some(ScoreFileKind::Score)),
+            ("score/p/pack/song.old/ex.ksc", Some(ScoreFileKind::Score)),
+            ("score/p/pack/song/ex.ksc.bak", None),
+            ("score/p/pack/song/ex.ksh", None),
+            ("score/p/pack/song/Thumbs.db", None),
+            ("score/p/pack/song/.ex.ksc.swp", None),
+            ("score/p/pack/song/ksc", None),
+            ("score/p/pack/song/.ksc", Some(ScoreFileKind::Junk)),
+            ("score/p/pack/song/ex.bak.ksc", Some(ScoreFileKind::Junk)),
+            ("score/p/pack/song/ex.BAK.ksc", Some(ScoreFileKind::Junk)),
+            ("score/p/pack/song/ex~.ksc", Some(ScoreFileKind::Junk)),
+            ("score/p/pack/song/ex.old.ksc", Some(ScoreFileKind::Junk)),
+            ("score/p/pack/song/._ex.ksc", Some(ScoreFileKind::Junk)),
+            (
+                "__MACOSX/score/p/pack/song/ex.ksc",
+                Some(ScoreFileKind::Junk),
+            ),
+            ("score/p/pack/sóng/ëx.ksc", Some(ScoreFileKind::Score)),
+            ("score/p/pack/song/é.ksc", Some(ScoreFileKind::Score)),
+        ];
+        for (path, kind) in cases.iter() {
+            assert_eq!(classify_score_file(Path::new(path)), *kind, "{}", path);
+        }
+    }
+
+    #[test]
+    fn preflight_counts_junk_files() {
+        let db = TempDb::new();
+        let ksm = tempfile::tempdir().unwrap();
+        std::fs::create_dir_all(ksm.path().join("score")).unwrap();
+        let fixture = Fixture::new()
+            .score("pack/song", "ex", &[&line(9_000_000)])
+            .file(
+                "score/player/pack/song/ex.bak.ksc",
+                line(8_000_000).as_bytes(),
+            )
+            .file("score/player/pack/song/._ex.ksc", b"\0\x05\x16\x07");
+        let preflight = validate_paths(
+            &fixture.source,
+            ksm.path(),
+            &db.path,
+            Policy::default(),
+            FileLimits::default(),
+        )
+        .unwrap();
+        assert_eq!(preflight.junk_files, 2);
+        assert_eq!(preflight.scores_previewed, 1);
+    }
+
+    const GAUGES: &[(&str, GaugeType)] = &[
+        ("normal", GaugeType::Normal),
+        ("hard", GaugeType::Hard),
+        ("easy", GaugeType::Permissive),
+        ("light", GaugeType::Permissive),
+    ];
+    const TURNS: &[(&str, bool, bool)] = &[
+        ("normal", false, false),
+        ("mirror", true, false),
+        ("random", false, true),
+        ("mir-ran", true, true),
+    ];
+
+    proptest::proptest! {
+        #[test]
+        fn parsing_any_line_never_panics(line in ".*", relaxed: bool) {
+            let _ = KsmScore::parse(&line, relaxed);
+        }
+
+        #[test]
+        fn parsing_score_like_lines_never_panics(
+            line in "[a-z-]{0,8}(,[a-z-]{0,8}){0,7}=[0-9,.-]{0,40}",
+            relaxed: bool,
+        ) {
+            let _ = KsmScore::parse(&line, relaxed);
+        }
+
+        #[test]
+        fn a_written_line_parses_back(
+            gauge in 0..GAUGES.len(),
+            turn in 0..TURNS.len(),
+            score in 0..=10_000_000u32,
+            badge in 0..=5u32,
+            gauge_tenths in 0..=1000u32,
+            plays: u32,
+            combo: u32,
+            extra in proptest::collection::vec("[a-z0-9.]{0,8}", 0..10),
+        ) {
+            let (gauge_name, gauge_type) = GAUGES[gauge];
+            let (turn_name, mirror, random) = TURNS[turn];
+            let mut line = format!(
+                "{},{},normal,on,on,on={},{},0,{:.1},{},{}",
+                gauge_name,
+                turn_name,
+                score,
+                badge,
+                f64::from(gauge_tenths) / 10.0,
+                plays,
+                combo
+            );
+            extra.iter().for_each(|f| line += &format!(",{}", f));
+            let parsed = KsmScore::parse(&line, false).unwrap();
+            proptest::prop_assert_eq!(parsed.gauge_type, gauge_type);
+            proptest::prop_assert_eq!((parsed.mirror, parsed.random), (mirror, random));
+            proptest::prop_assert_eq!((parsed.score, parsed.badge), (score, badge));
+            proptest::prop_assert!((parsed.gauge - f64::from(gauge_tenths) / 1000.0).abs() < 1e-9);
+            proptest::prop_assert_eq!(parsed.extra, extra);
+            proptest::prop_assert!(!parsed.nonstandard);
+            proptest::prop_assert_eq!(
+                parsed.crit + parsed.near + parsed.miss,
+                NOMINAL_NOTES
+            );
+        }
+    }
+
+    #[test]
+    fn an_unknown_gauge_is_a_skipped_entry() {
+        let err = match KsmScore::parse("blocky,normal,normal,on,on,on=9000000,2,0,87.5", false) {
+            Ok(_) => panic!("An unknown gauge was parsed"),
+            Err(e) => e,
+        };
+        match err.downcast_ref::<UnsupportedEntry>() {
+            Some(UnsupportedEntry(rule)) => assert_eq!(*rule, SkipRule::UnknownGauge),
+            None => panic!("Not a skipped entry: {:?}", err),
+        }
+        assert!(err.to_string().contains(SkipRule::UnknownGauge.label()));
+    }
+
+    #[test]
+    fn every_gauge_is_written_as_its_usc_gauge_type() {
+        let db = TempDb::new();
+        let lines: Vec<String> = ["normal", "hard", "easy", "light", "blocky"]
+            .iter()
+            .zip(9_000_000..)
+            .map(|(gauge, score)| {
+                format!("{},normal,normal,on,on,on={},2,0,87.5,1,2", gauge, score)
+            })
+            .collect();
+        let lines: Vec<&str> = lines.iter().map(String::as_str).collect();
+        let fixture = Fixture::new().score("pack/song", "ex", &lines);
+        let options = ImportOptions {
+            skip_duplicates: false,
+            ..test_support::options()
+        };
+        let summary = test_support::import(fixture, &db, options);
+        assert!(summary.fail_messages.is_empty());
+        assert_eq!(summary.skipped.get(&SkipRule::UnknownGauge), Some(&1));
+        let gauges: Vec<(u32, i64)> = db
+            .open()
+            .prepare("SELECT score, gauge_type FROM Scores ORDER BY score")
+            .unwrap()
+            .query_map([], |r| Ok((r.get(0)?, r.get(1)?)))
+            .unwrap()
+            .map(|r| r.unwrap())
+            .collect();
+        assert_eq!(
+            gauges,
+            vec![
+                (9_000_000, 0),
+                (9_000_001, 1),
+                (9_000_002, 2),
+                (9_000_003, 2)
+            ]
+        );
+    }
+
+    #[test]
+    fn a_garbage_gauge_fails_its_line_and_a_good_one_keeps_its_precision() {
+        let db = TempDb::new();
+        let lines = [
+            "normal,normal,normal,on,on,on=9000000,2,0,abc,1,2",
+            "normal,normal,normal,on,on,on=9100000,2,0,98.5,1,2",
+        ];
+        let summary = test_support::import(
+            Fixture::new().score("pack/song", "ex", &lines),
+            &db,
+            test_support::options(),
+        );
+        let failures: Vec<&Failure> = summary.failures_of(FailureKind::Parse).collect();
+        assert_eq!(failures.len(), 1);
+        assert!(failures[0].message.contains("\"abc\""));
+        let gauge: f64 = db
+            .open()
+            .query_row("SELECT gauge FROM Scores", [], |r| r.get(0))
+            .unwrap();
+        assert_eq!(gauge, 0.985);
+    }
+
+    #[test]
+    fn a_user_id_is_written_as_a_number() {
+        let db = TempDb::new();
+        let options = ImportOptions {
+            user_name: "me".to_string(),
+            user_id: "42".to_string(),
+            ..test_support::options()
+        };
+        let fixture = Fixture::new().score("pack/song", "ex", &[&line(9_000_000)]);
+        test_support::import(fixture, &db, options);
+        assert_eq!(db.count("SELECT user_id + 0 FROM Scores"), 42);
+    }
+
+    fn conflicts(options: ImportOptions) -> Vec<(Severity, &'static str)> {
+        options
+            .validate()
+            .into_iter()
+            .map(|c| (c.severity, c.message))
+            .collect()
+    }
+
+    #[test]
+    fn the_default_options_dont_conflict() {
+        assert_eq!(conflicts(ImportOptions::default()), vec![]);
+        // A dry run on its own is fine, skip_duplicates is on by default.
+        let dry_run = ImportOptions {
+            dry_run: true,
+            ..Default::default()
+        };
+        assert_eq!(conflicts(dry_run), vec![]);
+    }
+
+    #[test]
+    fn a_dry_run_that_optimizes_is_warned_about() {
+        let options = ImportOptions {
+            dry_run: true,
+            optimize: true,
+            ..Default::default()
+        };
+        assert_eq!(
+            conflicts(options),
+            vec![(Severity::Warning, OPTION_RULES[0].2)]
+        );
+    }
+
+    #[test]
+    fn backdating_while_skipping_duplicates_is_warned_about() {
+        let mut options = ImportOptions::default();
+        options.policy.timestamps = TimestampPolicy::Before(1_600_000_000);
+        assert_eq!(
+            conflicts(options),
+            vec![(Severity::Warning, OPTION_RULES[1].2)]
+        );
+        let mut options = ImportOptions {
+            skip_duplicates: false,
+            ..Default::default()
+        };
+        options.policy.timestamps = TimestampPolicy::Before(1_600_000_000);
+        assert_eq!(conflicts(options), vec![]);
+    }
+
+    #[test]
+    fn a_user_id_that_isnt_a_number_is_an_error() {
+        let options = ImportOptions {
+            user_name: "me".to_string(),
+            user_id: "12a".to_string(),
+            ..Default::default()
+        };
+        assert_eq!(
+            conflicts(options),
+            vec![(Severity::Error, OPTION_RULES[2].2)]
+        );
+    }
+
+    #[test]
+    fn a_user_id_without_a_name_is_warned_about() {
+        let options = ImportOptions {
+            user_id: "42".to_string(),
+            ..Default::default()
+        };
+        assert_eq!(
+            conflicts(options),
+            vec![(Severity::Warning, OPTION_RULES[3].2)]
+        );
+    }
+
+    #[test]
+    fn a_user_id_that_isnt_a_number_stops_the_import() {
+        let options = ImportOptions {
+            user_name: "me".to_string(),
+            user_id: "me".to_string(),
+            ..test_support::options()
+        };
+        assert!(options
+            .validate()
+            .iter()
+            .any(|c| c.severity == Severity::Error && c.message.contains("user id")));
+        let dir = tempfile::tempdir().unwrap();
+        let err = import_scores(dir.path(), &TempDb::new().path, options, |_| {}).unwrap_err();
+        assert!(err.to_string().contains("whole number"));
+    }
+
+    fn parse_error(line: &str) -> String {
+        match KsmScore::parse(line, false) {
+            Ok(_) => panic!("\"{}\" was parsed", line),
+            Err(e) => e.to_string(),
+        }
+    }
+
+    #[test]
+    fn malformed_lines_are_errors_naming_the_line() {
+        assert!(parse_error("").contains("Unsupported score entry"));
+        let settings_only = "normal,normal,normal,on,on,on";
+        assert_eq!(
+            parse_error(settings_only),
+            format!("No '=' before the score in \"{}\"", settings_only)
+        );
+        let short = "normal,normal,normal,on,on,on=9000000,2";
+        assert_eq!(
+            parse_error(short),
+            format!(
+                "Expected at least {} fields after '=', found 2 in \"{}\"",
+                MIN_STAT_FIELDS, short
+            )
+        );
+        assert!(parse_error("normal,normal,normal,on,on,on=").contains("found 1"));
+    }
+
+    #[test]
+    fn a_malformed_line_fails_and_the_rest_of_the_file_is_imported() {
+        let db = TempDb::new();
+        let lines = [
+            line(9_000_000),
+            "normal,normal,normal,on,on,on".to_string(),
+            "normal,normal,normal,on,on,on=9100000,2".to_string(),
+            line(9_200_000),
+        ];
+        let lines: Vec<&str> = lines.iter().map(String::as_str).collect();
+        let summary = test_support::import(
+            Fixture::new().score("pack/song", "ex", &lines),
+            &db,
+            ImportOptions {
+                skip_duplicates: false,
+                ..test_support::options()
+            },
+        );
+        assert_eq!(summary.failures_of(FailureKind::Parse).count(), 2);
+        assert_eq!(summary.scores_imported, 2);
+        assert_eq!(db.scores(), 2);
+    }
+
+    #[test]
+    fn score_lines_drop_the_bom_line_endings_and_blank_lines() {
+        let file = b"\xEF\xBB\xBFfirst\r\n\r\n  \t\r\nsecond  \r\n\n";
+        assert_eq!(
+            score_lines(file).collect::<Vec<_>>(),
+            vec!["first", "second"]
+        );
+    }
+
+    #[test]
+    fn a_windows_score_file_imports_cleanly() {
+        let db = TempDb::new();
+        let contents = format!(
+            "\u{FEFF}{}\r\n\r\n   \r\n{}  \r\n",
+            line(9_000_000),
+            line(9_100_000)
+        );
+        let score_path = Fixture::score_path("pack/song", "ex");
+        let fixture = Fixture::new()
+            .chart("pack/song", "ex", "title=song\nlevel=15\n--\n")
+            .file(score_path.to_str().unwrap(), contents.as_bytes());
+        let summary = test_support::import(
+            fixture,
+            &db,
+            ImportOptions {
+                skip_duplicates: false,
+                ..test_support::options()
+            },
+        );
+        assert!(summary.fail_messages.is_empty());
+        assert!(summary.warnings.is_empty(), "{:?}", summary.warnings);
+        assert_eq!(summary.scores_imported, 2);
+        let gauges: Vec<f64> = db
+            .open()
+            .prepare("SELECT gauge FROM Scores")
+            .unwrap()
+            .query_map([], |r| r.get(0))
+            .unwrap()
+            .map(|r| r.unwrap())
+            .collect();
+        assert_eq!(gauges, vec![0.875, 0.875]);
+    }
+
+    #[test]
+    fn a_low_memory_import_writes_failures_to_the_spill_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let spill = dir.path().join("failures.txt");
+        let db = TempDb::new();
+        let good = line(9_500_000);
+        let bad = "normal,normal,normal,on,on,on=garbage,2,0,87.5,1,2";
+        let fixture =
+            Fixture::new()
+                .score("pack/a", "ex", &[bad, &good])
+                .score("pack/b", "ex", &[bad]);
+        let summary = test_support::import(
+            fixture,
+            &db,
+            ImportOptions {
+                low_memory: true,
+                spill_path: Some(spill.clone()),
+                ..test_support::options()
+            },
+        );
+        assert_eq!(summary.spill_path.as_deref(), Some(spill.as_path()));
+        assert_eq!(summary.spilled_failures, 2);
+        assert!(summary.fail_messages.is_empty());
+        let spilled = std::fs::read_to_string(&spill).unwrap();
+        assert_eq!(
+            spilled.matches("Score parse failed").count(),
+            2,
+            "{}",
+            spilled
+        );
+        assert_eq!(db.scores(), 1);
+    }
+
+    #[test]
+    fn a_chart_edited_between_imports_is_hashed_again() {
+        let db = TempDb::new();
+        let fixture = |chart: &str, score| {
+            Fixture::new().chart("pack/song", "ex", chart).score_only(
+                "pack/song",
+                "ex",
+                &[&line(score)],
+            )
+        };
+        test_support::import(
+            fixture("title=a\n--\n", 9_000_000),
+            &db,
+            test_support::options(),
+        );
+        test_support::import(
+            fixture("title=b\n--\n", 9_100_000),
+            &db,
+            test_support::options(),
+        );
+        assert_eq!(db.count("SELECT COUNT(DISTINCT chart_hash) FROM Scores"), 2);
+    }
+
+    // Hands out the score files last to first, the way a differently ordered folder listing would.
+    #[derive(Debug)]
+    struct ReversedSource(MemorySource);
+
+    impl ScoreSource for ReversedSource {
+        fn score_files(&self, warnings: &mut Vec<String>) -> Result<ScoreFiles> {
+            let mut files = self.0.score_files(warnings)?;
+            files.files.reverse();
+            Ok(files)
+        }
+
+        fn read_score(&self, path: &Path) -> Result<Vec<u8>> {
+            self.0.read_score(path)
+        }
+
+        fn modified(&self, path: &Path) -> Result<SystemTime> {
+            self.0.modified(path)
+        }
+
+        fn size(&self, path: &Path) -> Result<u64> {
+            self.0.size(path)
+        }
+    }
+
+    impl ChartSource for ReversedSource {
+        fn chart_exists(&self, path: &Path) -> bool {
+            self.0.chart_exists(path)
+        }
+
+        fn read_chart(&self, path: &Path) -> Result<Vec<u8>> {
+            self.0.read_chart(path)
+        }
+
+        fn case_variants(&self, path: &Path) -> Vec<PathBuf> {
+            self.0.case_variants(path)
+        }
+    }
+
+    #[test]
+    fn identical_charts_in_two_folders_get_one_stable_name() {
+        let twins = || {
+            let chart = "title=twin\nlevel=15\n--\n";
+            Fixture::new()
+                .chart("pack/twin b", "ex", chart)
+                .score_only("pack/twin b", "ex", &[&line(9_000_000)])
+                .chart("pack/twin a", "ex", chart)
+                .score_only("pack/twin a", "ex", &[&line(9_100_000)])
+        };
+        let names = |summary: Summary| {
+            (
+                summary.new_charts,
+                summary.improvements,
+                summary.additional_plays,
+            )
+        };
+        let summaries = |reversed: bool, setup: fn(&Connection)| {
+            (0..2)
+                .map(|_| {
+                    let db = TempDb::with(setup);
+                    let source: Arc<dyn Source> = if reversed {
+                        Arc::new(ReversedSource(twins().source))
+                    } else {
+                        Arc::new(twins().source)
+                    };
+                    names(test_support::finished(&test_support::run_source(
+                        source,
+                        &db.path,
+                        test_support::options(),
+                    )))
+                })
+                .collect::<Vec<_>>()
+        };
+
+        // Neither folder is in Charts, the smallest path names both.
+        let expected = (
+            vec!["twin a [ex]".to_string()],
+            vec![],
+            vec!["twin a [ex]".to_string()],
+        );
+        assert_eq!(summaries(false, |_| {}), vec![expected.clone(); 2]);
+        assert_eq!(summaries(true, |_| {}), vec![expected; 2]);
+
+        // USC's row wins over the smaller path.
+        let indexed: fn(&Connection) = |db| {
+            let hash = sha1::Sha1::from("title=twin\nlevel=15\n--\n").digest();
+            db.execute(
+                "INSERT INTO Charts(path, hash) VALUES(?, ?)",
+                rusqlite::params!["songs/pack/twin b/ex.ksh", hash.to_string()],
+            )
+            .unwrap();
+        };
+        let expected = (
+            vec!["twin b [ex]".to_string()],
+            vec![],
+            vec!["twin b [ex]".to_string()],
+        );
+        assert_eq!(summaries(false, indexed), vec![expected.clone(); 2]);
+        assert_eq!(summaries(true, indexed), vec![expected; 2]);
+    }
+
+    // Writes a score file and its chart under a KSM folder on disk.
+    fn write_score(ksm: &Path, song: &str, lines: &[String]) {
+        let score = ksm.join(Fixture::score_path(song, "ex"));
+        std::fs::create_dir_all(score.parent().unwrap()).unwrap();
+        std::fs::write(
+            &score,
+            lines.iter().map(|l| format!("{}\n", l)).collect::<String>(),
+        )
+        .unwrap();
+        let chart = ksm.join(Fixture::chart_path(song, "ex"));
+        std::fs::create_dir_all(chart.parent().unwrap()).unwrap();
+        std::fs::write(&chart, format!("title={}\nlevel=15\n--\n", song)).unwrap();
+    }
+
+    #[test]
+    fn a_score_file_removed_after_the_scan_is_a_warning() {
+        let db = TempDb::new();
+        let ksm = tempfile::tempdir().unwrap();
+        write_score(ksm.path(), "pack/a", &[line(9_000_000)]);
+        write_score(ksm.path(), "pack/b", &[line(9_100_000)]);
+        let removed = ksm.path().join(Fixture::score_path("pack/b", "ex"));
+        let summary = import_scores(ksm.path(), &db.path, test_support::options(), |p| {
+            if let Progress::Started = p {
+                std::fs::remove_file(&removed).unwrap();
+            }
+        })
+        .unwrap();
+        assert_eq!(summary.files_found, 2);
+        assert_eq!(summary.scores_imported, 1);
+        assert!(
+            summary.fail_messages.is_empty(),
+            "{:?}",
+            summary.fail_messages
+        );
+        assert!(summary
+            .warnings
+            .iter()
+            .any(|w| w.contains("pack/b") && w.contains("was removed after the score folder")));
+    }
+
+    #[test]
+    fn files_changed_after_preflight_are_imported_as_they_are_now() {
+        let db = TempDb::new();
+        let ksm = tempfile::tempdir().unwrap();
+        write_score(ksm.path(), "pack/a", &[line(9_000_000)]);
+        write_score(ksm.path(), "pack/gone", &[line(9_100_000)]);
+        let source = open_source(ksm.path(), &Arc::default()).unwrap();
+        let preflight = validate_paths(
+            source.as_ref(),
+            ksm.path(),
+            &db.path,
+            Policy::default(),
+            FileLimits::default(),
+        )
+        .unwrap();
+        assert_eq!(preflight.scores_previewed, 2);
+
+        // KSM keeps running between the preview and the import.
+        write_score(ksm.path(), "pack/a", &[line(9_000_000), line(9_200_000)]);
+        std::fs::remove_file(ksm.path().join(Fixture::score_path("pack/gone", "ex"))).unwrap();
+        write_score(ksm.path(), "pack/new", &[line(9_300_000)]);
+        let summary = import_scores(ksm.path(), &db.path, test_support::options(), |_| {}).unwrap();
+        assert_eq!(summary.files_found, 2);
+        assert_eq!(summary.scores_imported, 3);
+        assert!(summary.warnings.iter().all(|w| !w.contains("gone")));
+        assert_eq!(
+            db.count("SELECT COUNT(*) FROM Scores WHERE score IN (9200000, 9300000)"),
+            2
+        );
+    }
+
+    #[test]
+    fn a_last_line_cut_off_is_only_a_warning() {
+        let good = format!("{}\n{}\n", line(9_000_000), line(9_100_000));
+        let cuts = [
+            "normal,normal,normal,on,on,on=95",
+            "normal,normal,normal,on,on,on=9500000,2,0",
+            "normal,normal,norm",
+        ];
+        for cut in cuts.iter() {
+            let db = TempDb::new();
+            let fixture = Fixture::new()
+                .chart("pack/cut", "ex", "title=cut\n--\n")
+                .file(
+                    "score/player/pack/cut/ex.ksc",
+                    format!("{}{}", good, cut).as_bytes(),
+                );
+            let summary = test_support::import(fixture, &db, test_support::options());
+            assert_eq!(summary.scores_imported, 2, "{}", cut);
+            assert!(summary.fail_messages.is_empty(), "{}", cut);
+            assert!(
+                summary.warnings.iter().any(|w| w.starts_with(&format!(
+                    "Ignored truncated last line at byte {} ",
+                    good.len()
+                ))),
+                "{}: {:?}",
+                cut,
+                summary.warnings
+            );
+        }
+    }
+
+    #[test]
+    fn a_short_last_line_with_its_newline_is_a_failure() {
+        let db = TempDb::new();
+        let fixture = Fixture::new().score(
+            "pack/short",
+            "ex",
+            &[&line(9_000_000), "normal,normal,normal,on,on,on"],
+        );
+        let summary = test_support::import(fixture, &db, test_support::options());
+        assert_eq!(summary.scores_imported, 1);
+        assert_eq!(summary.fail_messages.len(), 1);
+        assert!(summary.warnings.iter().all(|w| !w.contains("truncated")));
+
+        // A last line without a newline that still has the fields read is a score like any other.
+        for last in [
+            line(9_000_000),
+            "normal,normal,normal,on,on,on=9000000,2,0,87.5".to_string(),
+            "normal,normal,normal,on,on,on=9000000,2,0,87.5,1".to_string(),
+        ]
+        .iter()
+        {
+            let db = TempDb::new();
+            let fixture = Fixture::new()
+                .chart("pack/short", "ex", "title=short\n--\n")
+                .file("score/player/pack/short/ex.ksc", last.as_bytes());
+            let summary = test_support::import(fixture, &db, test_support::options());
+            assert_eq!(summary.scores_imported, 1, "{}", last);
+            assert!(summary.warnings.iter().all(|w| !w.contains("truncated")));
+        }
+    }
+
+    // Takes a while over every score file, like a slow network share.
+    #[derive(Debug)]
+    struct SlowSource(MemorySource, Duration);
+
+    impl ScoreSource for SlowSource {
+        fn score_files(&self, warnings: &mut Vec<String>) -> Result<ScoreFiles> {
+            self.0.score_files(warnings)
+        }
+
+        fn read_score(&self, path: &Path) -> Result<Vec<u8>> {
+            std::thread::sleep(self.1);
+            self.0.read_score(path)
+        }
+
+        fn modified(&self, path: &Path) -> Result<SystemTime> {
+            self.0.modified(path)
+        }
+
+        fn size(&self, path: &Path) -> Result<u64> {
+            self.0.size(path)
+        }
+    }
+
+    impl ChartSource for SlowSource {
+        fn chart_exists(&self, path: &Path) -> bool {
+            self.0.chart_exists(path)
+        }
+
+        fn read_chart(&self, path: &Path) -> Result<Vec<u8>> {
+            self.0.read_chart(path)
+        }
+
+        fn case_variants(&self, path: &Path) -> Vec<PathBuf> {
+            self.0.case_variants(path)
+        }
+    }
+
+    #[test]
+    fn the_heartbeat_follows_a_slow_import_to_its_end() {
+        let db = TempDb::new();
+        let dir = tempfile::tempdir().unwrap();
+        let heartbeat = dir.path().join("heartbeat.txt");
+        let fixture = ["a", "b", "c", "d"].iter().fold(Fixture::new(), |f, song| {
+            f.score(&format!("pack/{}", song), "ex", &[&line(9_000_000)])
+        });
+        let source = SlowSource(
+            fixture.source,
+            HEARTBEAT_INTERVAL / 2 + Duration::from_millis(100),
+        );
+        let mut beats = Vec::new();
+        let progress = test_support::run_watching(
+            Arc::new(source),
+            &db.path,
+            ImportOptions {
+                heartbeat_file: Some(heartbeat.clone()),
+                optimize: true,
+                ..test_support::options()
+            },
+            |p| {
+                if let Progress::Advanced(..) = p {
+                    beats.push(std::fs::read_to_string(&heartbeat).unwrap());
+                }
+            },
+        );
+        test_support::finished(&progress);
+        beats.dedup();
+        let counters: Vec<&str> = beats
+            .iter()
+            .map(|b| b.split_once(' ').unwrap().1.trim_end())
+            .collect();
+        assert_eq!(
+            counters,
+            [
+                "running files=1/4 imported=1 failed=0",
+                "running files=3/4 imported=3 failed=0",
+            ]
+        );
+        let last = std::fs::read_to_string(&heartbeat).unwrap();
+        assert!(
+            last.ends_with(" finished files=4/4 imported=4 failed=0\n"),
+            "{}",
+            last
+        );
+    }
+
+    #[test]
+    fn the_heartbeat_goes_on_while_a_lock_holds_up_the_start() {
+        let db = TempDb::new();
+        let dir = tempfile::tempdir().unwrap();
+        let heartbeat = dir.path().join("heartbeat.txt");
+        let options = ImportOptions {
+            heartbeat_file: Some(heartbeat.clone()),
+            ..test_support::options()
+        };
+        options.pause.store(true, Ordering::Relaxed);
+        let cancel = options.cancel.clone();
+        let watched = heartbeat.clone();
+        let watcher = std::thread::spawn(move || {
+            while std::fs::read_to_string(&watched).map_or(true, |b| !b.ends_with(" paused\n")) {
+                std::thread::sleep(Duration::from_millis(20));
+            }
+            cancel.store(true, Ordering::Relaxed);
+        });
+        let fixture = Fixture::new().score("pack/song", "ex", &[&line(9_000_000)]);
+        let progress = test_support::run(fixture, &db, options);
+        watcher.join().unwrap();
+        assert!(matches!(progress.last(), Some(Progress::Cancelled(_))));
+        let last = std::fs::read_to_string(&heartbeat).unwrap();
+        assert!(last.contains(" cancelled"), "{}", last);
+    }
+
+    fn with_badge(settings: &str, score: u32, badge: u32) -> String {
+        format!(
+            "{},normal,normal,on,on,on={},{},0,87.5,1,2",
+            settings, score, badge
+        )
+    }
+
+    type ResultColumns = (Option<i64>, Option<i64>, Option<i64>, Option<f64>);
+
+    fn result_columns(db: &TempDb) -> Vec<ResultColumns> {
+        let db = db.open();
+        let mut stmt = db
+            .prepare("SELECT gauge_type, near, miss, gauge FROM Scores ORDER BY rowid")
+            .unwrap();
+        stmt.query_map([], |r| Ok((r.get(0)?, r.get(1)?, r.get(2)?, r.get(3)?)))
+            .unwrap()
+            .map(|r| r.unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn improvements_only_imports_only_plays_that_beat_the_usc_best() {
+        // USC already has a 9,500,000 clear on the normal gauge.
+        let import = |line: &str, compare_across_gauges| {
+            let db = TempDb::with(|db| {
+                let hash = sha1::Sha1::from("title=pack/song\nlevel=15\n--\n").digest();
+                db.execute(
+                    "INSERT INTO Scores(score, chart_hash, timestamp, gauge_type) \
+                    VALUES(9500000, ?, 1, 0)",
+                    [hash.to_string()],
+                )
+                .unwrap();
+            });
+            let summary = test_support::import(
+                Fixture::new().score("pack/song", "ex", &[line]),
+                &db,
+                ImportOptions {
+                    improvements_only: true,
+                    compare_across_gauges,
+                    ..test_support::options()
+                },
+            );
+            let skipped = summary.skipped.get(&SkipRule::NotImprovement).copied();
+            (summary.scores_imported, skipped, db.scores())
+        };
+        let (skipped, imported) = ((0, Some(1), 1), (1, None, 2));
+
+        assert_eq!(import(&with_badge("normal", 9_400_000, 2), false), skipped);
+        assert_eq!(import(&with_badge("normal", 9_500_000, 2), false), skipped);
+        assert_eq!(import(&with_badge("normal", 9_600_000, 2), false), imported);
+        // Per gauge, a hard clear is held only to the hard gauge bests.
+        assert_eq!(import(&with_badge("hard", 9_400_000, 3), false), imported);
+        assert_eq!(import(&with_badge("hard", 9_400_000, 3), true), skipped);
+        assert_eq!(import(&with_badge("hard", 9_600_000, 3), true), imported);
+    }
+
+    #[test]
+    fn files_not_modified_since_the_cutoff_are_left_out() {
+        let db = TempDb::new();
+        let mut fixture = Fixture::new();
+        for (song, modified) in [
+            ("pack/older", test_support::MODIFIED - 100),
+            ("pack/at cutoff", test_support::MODIFIED),
+            ("pack/newer", test_support::MODIFIED + 1),
+        ]
+        .iter()
+        {
+            fixture = fixture.score(song, "ex", &[]);
+            fixture.source.insert(
+                Fixture::score_path(song, "ex"),
+                format!("{}\n", line(9_000_000)).into_bytes(),
+                test_support::modified_at(*modified),
+            );
+        }
+        let mut options = test_support::options();
+        options.policy.modified_after = Some(test_support::MODIFIED);
+        let summary = test_support::import(fixture, &db, options);
+        assert_eq!(summary.files_unmodified, 2);
+        assert_eq!(summary.files_processed, 1);
+        assert_eq!(summary.scores_imported, 1);
+        assert_eq!(db.scores(), 1);
+        assert_eq!(summary.new_charts, vec!["newer [ex]".to_string()]);
+    }
+
+    #[test]
+    fn scores_below_the_minimum_are_skipped() {
+        let db = TempDb::new();
+        let mut options = test_support::options();
+        options.policy.min_score = 9_000_000;
+        let summary = test_support::import(
+            Fixture::new().score(
+                "pack/song",
+                "ex",
+                &[&line(8_999_999), &line(9_000_000), &line(9_500_000)],
+            ),
+            &db,
+            options,
+        );
+        assert_eq!(summary.scores_imported, 2);
+        assert_eq!(summary.skipped.get(&SkipRule::BelowMinScore), Some(&1));
+        assert_eq!(db.count("SELECT MIN(score) FROM Scores"), 9_000_000);
+    }
+
+    #[test]
+    fn charts_outside_the_level_range_are_skipped() {
+        let fixture = || {
+            [12, 15, 18, 20]
+                .iter()
+                .fold(Fixture::new(), |fixture, level| {
+                    let song = format!("pack/level {}", level);
+                    fixture
+                        .chart(
+                            &song,
+                            "ex",
+                            &format!("title={}\nlevel={}\n--\n", song, level),
+                        )
+                        .score_only(&song, "ex", &[&line(9_000_000)])
+                })
+        };
+        let import = |levels: &str| {
+            let db = TempDb::new();
+            let mut options = test_support::options();
+            options.policy.levels = importer_funcs::parse_level_range(levels).unwrap();
+            let summary = test_support::import(fixture(), &db, options);
+            let outside = summary.skipped.get(&SkipRule::OutsideLevelRange).copied();
+            (summary.scores_imported, outside)
+        };
+        assert_eq!(import("15-18"), (2, Some(2)));
+        assert_eq!(import("18+"), (2, Some(2)));
+        assert_eq!(import("-12"), (1, Some(3)));
+        assert_eq!(import(""), (4, None));
+    }
+
+    #[test]
+    fn clears_only_skips_failed_plays() {
+        let import = |clears_only| {
+            let db = TempDb::new();
+            let mut options = test_support::options();
+            options.policy.clears_only = clears_only;
+            let summary = test_support::import(
+                Fixture::new().score(
+                    "pack/song",
+                    "ex",
+                    &[
+                        &with_badge("normal", 8_000_000, 0),
+                        &with_badge("hard", 8_500_000, 1),
+                        &with_badge("normal", 9_000_000, 2),
+                    ],
+                ),
+                &db,
+                options,
+            );
+            let failed = summary.skipped.get(&SkipRule::Failed).copied();
+            (summary.scores_imported, failed, db.scores())
+        };
+        assert_eq!(import(true), (1, Some(2), 1));
+        assert_eq!(import(false), (3, None, 3));
+    }
+
+    #[test]
+    fn a_better_badge_upgrades_the_row_an_earlier_import_wrote() {
+        let upgrade = |first: &str, second: &str, options: ImportOptions| {
+            let db = TempDb::new();
+            let fixture = |l: &str| Fixture::new().score("pack/song", "ex", &[l]);
+            let earlier = test_support::import(fixture(first), &db, test_support::options());
+            let summary = test_support::import(
+                fixture(second),
+                &db,
+                ImportOptions {
+                    earlier_rows: Arc::new(earlier.inserted_rows),
+                    ..options
+                },
+            );
+            (summary, db)
+        };
+
+        // The same play again, KSM gave it a full combo since. It's a duplicate on its own.
+        let (summary, db) = upgrade(
+            &with_badge("normal", 9_900_000, 2),
+            &with_badge("normal", 9_900_000, 4),
+            test_support::options(),
+        );
+        assert_eq!((summary.scores_upgraded, summary.scores_skipped), (1, 0));
+        assert_eq!(summary.upgraded_rows.len(), 1);
+        assert_eq!(summary.upgraded_rows[0].badge, Some(4));
+        assert_eq!(db.scores(), 1);
+        let fresh = TempDb::new();
+        test_support::import(
+            Fixture::new().score("pack/song", "ex", &[&with_badge("normal", 9_900_000, 4)]),
+            &fresh,
+            test_support::options(),
+        );
+        assert_eq!(result_columns(&db), result_columns(&fresh));
+
+        // A hard clear of the score imported as a clear is no improvement to improvements_only.
+        let (summary, db) = upgrade(
+            &with_badge("normal", 9_500_000, 2),
+            &with_badge("hard", 9_500_000, 3),
+            ImportOptions {
+                improvements_only: true,
+                compare_across_gauges: true,
+                ..test_support::options()
+            },
+        );
+        assert_eq!(summary.scores_upgraded, 1);
+        assert!(summary.skipped.is_empty(), "{:?}", summary.skipped);
+        assert_eq!(db.scores(), 1);
+        assert_eq!(
+            result_columns(&db)[0].0,
+            Some(crate::importer::GaugeType::Hard.value())
+        );
+        let report = crate::report::json(&summary, None).unwrap();
+        assert!(report.contains("\"scores_upgraded\": 1"), "{}", report);
+    }
+
+    #[test]
+    fn scores_that_dont_beat_the_badge_are_skipped_as_before() {
+        for (first, second) in [(4, 4), (4, 2)].iter() {
+            let db = TempDb::new();
+            let fixture = |badge| {
+                Fixture::new().score(
+                    "pack/song",
+                    "ex",
+                    &[&with_badge("normal", 9_900_000, badge)],
+                )
+            };
+            let earlier = test_support::import(fixture(*first), &db, test_support::options());
+            let before = result_columns(&db);
+            let summary = test_support::import(
+                fixture(*second),
+                &db,
+                ImportOptions {
+                    earlier_rows: Arc::new(earlier.inserted_rows),
+                    ..test_support::options()
+                },
+            );
+            assert_eq!((summary.scores_upgraded, summary.scores_skipped), (0, 1));
+            assert_eq!(result_columns(&db), before);
+        }
+    }
+
+    #[test]
+    fn rows_the_importer_didnt_write_are_never_upgraded() {
+        let fixture = |badge| {
+            Fixture::new().score(
+                "pack/song",
+                "ex",
+                &[&with_badge("normal", 9_900_000, badge)],
+            )
+        };
+
+        // Written by USC, or by a run that was undone: not among the earlier rows.
+        let db = TempDb::new();
+        test_support::import(fixture(2), &db, test_support::options());
+        let before = result_columns(&db);
+        let summary = test_support::import(fixture(4), &db, test_support::options());
+        assert_eq!((summary.scores_upgraded, summary.scores_skipped), (0, 1));
+        assert_eq!(result_columns(&db), before);
+
+        // Imported, but changed in the database since.
+        let db = TempDb::new();
+        let earlier = test_support::import(fixture(2), &db, test_support::options());
+        db.open()
+            .execute("UPDATE Scores SET timestamp = timestamp + 1", [])
+            .unwrap();
+        let before = result_columns(&db);
+        let summary = test_support::import(
+            fixture(4),
+            &db,
+            ImportOptions {
+                earlier_rows: Arc::new(earlier.inserted_rows.clone()),
+                skip_duplicates: false,
+                improvements_only: true,
+                ..test_support::options()
+            },
+        );
+        assert_eq!(summary.scores_upgraded, 0);
+        assert_eq!(result_columns(&db), before);
+
+        // Recorded before rows kept their badge.
+        let db = TempDb::new();
+        let mut earlier = test_support::import(fixture(2), &db, test_support::options());
+        earlier.inserted_rows[0].badge = None;
+        let summary = test_support::import(
+            fixture(4),
+            &db,
+            ImportOptions {
+                earlier_rows: Arc::new(earlier.inserted_rows),
+                ..test_support::options()
+            },
+        );
+        assert_eq!(summary.scores_upgraded, 0);
+    }
+
+    #[test]
+    fn database_sizes_past_4_gb_are_read_whole() {
+        let dir = tempfile::tempdir().unwrap();
+        let db = dir.path().join("maps.db");
+        for size in [u32::MAX as u64, 1 << 32, (1 << 32) + 1, 5 << 30].iter() {
+            // Sparse where the filesystem allows, nothing is written.
+            std::fs::File::create(&db).unwrap().set_len(*size).unwrap();
+            assert_eq!(database_size(&db), *size);
+            assert!(database_size(&db) >= LARGE_DB_SIZE);
+        }
+        std::fs::File::create(&db)
+            .unwrap()
+            .set_len(LARGE_DB_SIZE - 1)
+            .unwrap();
+        assert!(database_size(&db) < LARGE_DB_SIZE);
+        assert_eq!(database_size(&dir.path().join("missing.db")), 0);
+    }
+
+    #[test]
+    fn the_phases_add_up_to_the_time_the_import_took() {
+        let db = TempDb::new();
+        let fixture = (0..40).fold(Fixture::new(), |fixture, i| {
+            fixture.score(
+                &format!("pack/song{}", i),
+                "ex",
+                &[&line(9_000_000 + i), &line(8_000_000 + i)],
+            )
+        });
+        let source = SlowSource(fixture.source, Duration::from_millis(2));
+        let options = ImportOptions {
+            skip_backup: false,
+            optimize: true,
+            ..test_support::options()
+        };
+        let started = Instant::now();
+        let summary = test_support::finished(&test_support::run_source(
+            Arc::new(source),
+            &db.path,
+            options,
+        ));
+        let wall = started.elapsed();
+
+        let timings = &summary.timings;
+        let phases: Duration = timings.phases().iter().map(|(_, time)| *time).sum();
+        assert!(timings.total <= wall, "{:?} {:?}", timings, wall);
+        assert!(phases <= timings.total, "{:?}", timings);
+        // What's left is bookkeeping between the phases.
+        assert!(
+            timings.total - phases <= timings.total / 20 + Duration::from_millis(5),
+            "{:?} left {:?}",
+            timings,
+            timings.total - phases
+        );
+        assert!(
+            timings.reading >= Duration::from_millis(80),
+            "{:?}",
+            timings
+        );
+        for (phase, time) in timings.phases().iter() {
+            assert!(!time.is_zero(), "Nothing spent {}: {:?}", phase, timings);
+        }
+    }
+
+    // Imports the one file at `path` the way the import loop does, on a connection that turns
+    // every commit into a rollback.
+    fn import_file_with_failing_commit(
+        fixture: &Fixture,
+        db: &TempDb,
+        path: &Path,
+        policy: Policy,
+    ) -> Summary {
+        let connection = db.open();
+        connection.commit_hook(Some(|| true));
+        let song_roots = importer_funcs::song_roots(&db.path, None);
+        let (locked, chart_time) = (Cell::new(false), Cell::new(Duration::ZERO));
+        let (hash_cache, earlier_rows) = (RefCell::default(), RefCell::default());
+        let ctx = ImportContext {
+            insert_func: supported_version(schema::NEWEST_VERSION).unwrap().1,
+            safe_mode: false,
+            policy,
+            limits: FileLimits::default(),
+            connection: &connection,
+            locked: &locked,
+            options: InsertOptions {
+                source: &fixture.source,
+                song_roots: &song_roots,
+                timestamps: policy.timestamps,
+                file_index: 0,
+                file_count: 1,
+                chart_time: &chart_time,
+                hash_cache: &hash_cache,
+                earlier_rows: &earlier_rows,
+                user_name: "",
+                user_id: "",
+                skip_duplicates: true,
+                improvements_only: false,
+                compare_across_gauges: false,
+                levels: policy.levels,
+                dry_run: false,
+                detected_columns: &[],
+            },
+        };
+        let mut summary = Summary::default();
+        import_file(&ctx, path, &mut summary, &mut ChartNames::default());
+        summary
+    }
+
+    #[test]
+    fn a_failed_commit_takes_back_the_files_skips() {
+        let db = TempDb::new();
+        let fixture = Fixture::new().score(
+            "pack/song",
+            "ex",
+            &[&line(9_000_000), &line(9_600_000), &line(9_600_000)],
+        );
+        let policy = Policy {
+            min_score: 9_500_000,
+            ..Policy::default()
+        };
+        let path = Fixture::score_path("pack/song", "ex");
+        let summary = import_file_with_failing_commit(&fixture, &db, &path, policy);
+        assert_eq!(summary.scores_found, 3);
+        assert_eq!(
+            (summary.scores_imported, summary.scores_skipped),
+            (0, 0),
+            "{:?}",
+            summary
+        );
+        assert!(summary.skipped.is_empty(), "{:?}", summary.skipped);
+        assert_eq!(summary.fail_messages.len(), 1);
+        assert!(summary.fail_messages[0]
+            .message
+            .contains("none of them were imported"));
+        assert_eq!(db.scores(), 0);
+    }
+
+    #[test]
+    fn imports_of_other_folders_or_runs_hash_apart() {
+        use iced_native::subscription::Recipe;
+        use std::hash::Hasher;
+
+        let hash = |ksm: &str, db: &str, run_id| {
+            let importer = Importer {
+                ksm_path: PathBuf::from(ksm),
+                db_path: PathBuf::from(db),
+                source: Arc::new(MemorySource::default()),
+                options: ImportOptions::default(),
+                run_id,
+            };
+            let mut state = std::collections::hash_map::DefaultHasher::new();
+            Recipe::<_, ()>::hash(&importer, &mut state);
+            state.finish()
+        };
+        let first = hash("/games/KSM", "/usc/maps.db", 1);
+        assert_eq!(hash("/games/KSM", "/usc/maps.db", 1), first);
+        assert_ne!(hash("/backups/friend-B", "/usc/maps.db", 1), first);
+        assert_ne!(hash("/games/KSM", "/usc/other.db", 1), first);
+        assert_ne!(hash("/games/KSM", "/usc/maps.db", 2), first);
+    }
+
+    #[test]
+    fn imported_rows_are_kept_for_undo() {
+        let db = TempDb::new();
+        let fixture = Fixture::new()
+            .score("pack/a", "ex", &[&line(9_000_000), &line(9_100_000)])
+            .score("pack/b", "ex", &[&line(9_200_000)]);
+        let summary = test_support::import(fixture, &db, test_support::options());
+        assert_eq!(summary.inserted_rows.len(), 3);
+        let connection = db.open();
+        for row in &summary.inserted_rows {
+            let stored: (String, u32, i64) = connection
+                .query_row(
+                    "SELECT chart_hash, score, timestamp FROM Scores WHERE rowid = ?",
+                    [row.rowid],
+                    |r| Ok((r.get(0)?, r.get(1)?, r.get(2)?)),
+                )
+                .unwrap();
+            assert_eq!(stored, (row.chart_hash.clone(), row.score, row.timestamp));
+        }
+        let undone = crate::history::undo_rows(&connection, &summary.inserted_rows).unwrap();
+        assert_eq!(undone.removed, 3);
+        assert_eq!(db.scores(), 0);
+    }
+
+    #[test]
+    fn a_retry_only_imports_the_given_files() {
+        let db = TempDb::new();
+        let fixture = Fixture::new()
+            .score("pack/a", "ex", &[&line(9_000_000)])
+            .score("pack/b", "ex", &[&line(9_100_000)]);
+        let options = ImportOptions {
+            only_files: vec![Fixture::score_path("pack/b", "ex")],
+            ..test_support::options()
+        };
+        let summary = test_support::import(fixture, &db, options);
+        assert_eq!((summary.files_found, summary.scores_imported), (1, 1));
+        assert_eq!(db.count("SELECT score FROM Scores"), 9_100_000);
+    }
+
+    fn with_fields(extra: usize) -> String {
+        let fields: String = (0..extra).map(|i| format!(",x{}", i)).collect();
+        line(9_500_000) + &fields
+    }
+
+    #[test]
+    fn a_gauge_written_with_a_decimal_comma_is_read_whole() {
+        let score: KsmScore = "normal,normal,normal,on,on,on=9000000,2,0,98,5,1,2"
+            .parse()
+            .unwrap();
+        assert_eq!((score.score, score.badge), (9_000_000, 2));
+        assert_eq!(score.gauge, 0.985);
+        assert!(score.extra.is_empty(), "{:?}", score.extra);
+        let plain: KsmScore = "normal,normal,normal,on,on,on=9000000,2,0,98.5,1,2"
+            .parse()
+            .unwrap();
+        assert_eq!(plain.gauge, score.gauge);
+        // A field past the known ones is still kept when the gauge has its point.
+        let extra: KsmScore = "normal,normal,normal,on,on,on=9000000,2,0,98.5,1,2,5"
+            .parse()
+            .unwrap();
+        assert_eq!((extra.gauge, extra.extra), (0.985, vec!["5".to_string()]));
+    }
+
+    #[test]
+    fn unknown_trailing_fields_are_kept_verbatim() {
+        for extra in [0, 2, 10] {
+            let score: KsmScore = with_fields(extra).parse().unwrap();
+            assert_eq!(score.score, 9_500_000);
+            assert_eq!(score.badge, 2);
+            let expected: Vec<String> = (0..extra).map(|i| format!("x{}", i)).collect();
+            assert_eq!(score.extra, expected);
+        }
+        // Only the fields up to the gauge are required.
+        let short: KsmScore = "normal,normal,normal,on,on,on=9500000,2,0,87.5"
+            .parse()
+            .unwrap();
+        assert!(short.extra.is_empty());
+        assert!("normal,normal,normal,on,on,on=9500000,2,0"
+            .parse::<KsmScore>()
+            .is_err());
+    }
+
+    #[test]
+    fn scores_with_unknown_fields_are_counted() {
+        let db = TempDb::new();
+        let fixture = Fixture::new().score(
+            "pack/song",
+            "ex",
+            &[&with_fields(0), &with_fields(2), &with_fields(10)],
+        );
+        let mut options = test_support::options();
+        options.skip_duplicates = false;
+        let summary = test_support::import(fixture, &db, options);
+        assert_eq!(summary.scores_imported, 3);
+        assert_eq!(summary.scores_with_extra_fields, 2);
+    }
+
+    #[test]
+    fn judgements_agree_with_the_badge() {
+        assert_eq!(judgements(5, MAX_SCORE), (1000, 0, 0));
+        assert_eq!(judgements(4, 9_990_000), (998, 2, 0));
+        // A full combo always has a near, or USC would show it as a perfect.
+        assert_eq!(judgements(4, MAX_SCORE), (999, 1, 0));
+        assert_eq!(judgements(3, 9_500_000), (950, 0, 50));
+        assert_eq!(judgements(2, 9_987_500), (998, 1, 1));
+        // A clear always has a miss, or USC would show it as a full combo.
+        assert_eq!(judgements(2, MAX_SCORE), (999, 0, 1));
+    }
+
+    #[test]
+    fn judgements_never_add_up_to_more_notes_than_the_chart() {
+        // Found by a_written_line_parses_back, the nears pushed the total past 1000.
+        assert_eq!(judgements(0, 1), (0, 1, 999));
+        for badge in 0..=5 {
+            for score in [0, 1, 4_999, 5_000, 9_999, 1_234_567, 9_999_999, MAX_SCORE] {
+                let (crit, near, miss) = judgements(badge, score);
+                assert_eq!(crit + near + miss, NOMINAL_NOTES, "{} {}", badge, score);
+            }
+        }
+    }
+
+    fn import_with_limits(fixture: Fixture, limits: FileLimits) -> (Summary, TempDb) {
+        let db = TempDb::new();
+        let options = ImportOptions {
+            limits,
+            skip_duplicates: false,
+            ..test_support::options()
+        };
+        (test_support::import(fixture, &db, options), db)
+    }
+
+    #[test]
+    fn oversized_score_files_are_skipped_with_a_warning() {
+        let limits = FileLimits {
+            max_size: 1024,
+            ..Default::default()
+        };
+        let lines: Vec<String> = (0..100).map(|i| line(9_000_000 + i)).collect();
+        let lines: Vec<&str> = lines.iter().map(String::as_str).collect();
+        let fixture = Fixture::new().score("pack/huge", "ex", &lines).score(
+            "pack/fine",
+            "ex",
+            &[&line(9_500_000)],
+        );
+        let (summary, db) = import_with_limits(fixture, limits);
+        assert_eq!(summary.scores_imported, 1);
+        assert!(summary
+            .warnings
+            .iter()
+            .any(|w| w.contains("huge") && w.contains("too large")));
+        assert_eq!(db.scores(), 1);
+    }
+
+    #[test]
+    fn only_the_first_lines_of_a_long_file_are_read() {
+        let limits = FileLimits {
+            max_lines: 10,
+            ..Default::default()
+        };
+        let lines: Vec<String> = (0..25).map(|i| line(9_000_000 + i)).collect();
+        let lines: Vec<&str> = lines.iter().map(String::as_str).collect();
+        let (summary, _db) =
+            import_with_limits(Fixture::new().score("pack/long", "ex", &lines), limits);
+        assert_eq!(summary.scores_found, 10);
+        assert_eq!(summary.scores_imported, 10);
+        assert!(summary
+            .warnings
+            .iter()
+            .any(|w| w.starts_with("Only read the first 10 of 25 lines")));
+    }
+
+    #[test]
+    fn a_file_full_of_garbage_is_abandoned() {
+        let limits = FileLimits {
+            max_failures: 5,
+            ..Default::default()
+        };
+        let mut lines = vec!["normal,normal,normal,on,on,on=garbage,2,0,87.5,1,2"; 50];
+        let good = line(9_500_000);
+        lines.push(&good);
+        let (summary, db) =
+            import_with_limits(Fixture::new().score("pack/garbage", "ex", &lines), limits);
+        assert_eq!(summary.fail_messages.len(), 5);
+        // Only the lines that were looked at are found.
+        assert_eq!(summary.scores_found, 5);
+        assert!(summary
+            .warnings
+            .iter()
+            .any(|w| w.starts_with("Abandoned") && w.contains("after 5 failed score lines")));
+        assert_eq!(db.scores(), 0);
+    }
+
+    #[test]
+    fn an_unknown_version_is_audited_before_and_after_the_import() {
+        let db = TempDb::with(|db| {
+            db.execute_batch(
+                "UPDATE Database SET version = 25; ALTER TABLE Scores ADD COLUMN gauge_graph BLOB;",
+            )
+            .unwrap()
+        });
+        let fixture = Fixture::new().score("pack/song", "ex", &[&line(9_500_000)]);
+        let preflight = validate_paths(
+            &fixture.source,
+            Path::new("."),
+            &db.path,
+            Policy::default(),
+            FileLimits::default(),
+        )
+        .unwrap();
+        let audit = preflight.schema_audit.unwrap();
+        assert_eq!(audit.defaulted, vec!["gauge_graph"]);
+
+        let options = ImportOptions {
+            best_effort_schema: true,
+            ..test_support::options()
+        };
+        let summary = test_support::import(fixture, &db, options);
+        assert_eq!(summary.scores_imported, 1);
+        assert!(audit.lines().iter().all(|l| summary.warnings.contains(l)));
+    }
+
+    #[test]
+    fn importing_the_same_folder_twice_adds_nothing_the_second_time() {
+        let db = TempDb::new();
+        let fixture = || {
+            Fixture::new()
+                .score("pack/song", "ex", &[&line(9_500_000), &line(9_700_000)])
+                .score("pack/other", "nov", &[&line(8_000_000)])
+        };
+        let first = test_support::import(fixture(), &db, test_support::options());
+        assert_eq!(first.scores_imported, 3);
+        assert_eq!(db.scores(), 3);
+
+        let second = test_support::import(fixture(), &db, test_support::options());
+        assert_eq!(second.scores_imported, 0);
+        assert_eq!(second.scores_skipped, 3);
+        assert_eq!(db.scores(), 3);
+    }
+
+    #[test]
+    fn a_version_20_database_gets_the_combo_columns() {
+        let db = TempDb::with(|db| {
+            db.execute_batch("UPDATE Database SET version = 20")
+                .unwrap()
+        });
+        let full_combo = "normal,normal,normal,on,on,on=9900000,4,0,87.5,1,2";
+        let fixture = Fixture::new().score("pack/song", "ex", &[full_combo]);
+        let summary = test_support::import(fixture, &db, test_support::options());
+        assert_eq!(summary.scores_imported, 1);
+        let row: (i64, i64, i64, i64) = db
+            .open()
+            .query_row(
+                "SELECT early, late, combo, crit + near FROM Scores",
+                [],
+                |r| Ok((r.get(0)?, r.get(1)?, r.get(2)?, r.get(3)?)),
+            )
+            .unwrap();
+        assert_eq!((row.0, row.1), (0, 0));
+        assert_eq!(row.2, row.3);
+        assert!(row.2 > 0);
+    }
+
+    fn database_at(version: u32, change: &str) -> TempDb {
+        let sql = format!("UPDATE Database SET version = {}; {}", version, change);
+        TempDb::with(|db| db.execute_batch(&sql).unwrap())
+    }
+
+    #[test]
+    fn a_version_21_database_is_imported_into() {
+        let db = database_at(21, "ALTER TABLE Scores ADD COLUMN hit_stats BLOB;");
+        let fixture = Fixture::new().score("pack/song", "ex", &[&line(9_500_000)]);
+        let summary = test_support::import(fixture, &db, test_support::options());
+        assert_eq!(summary.scores_imported, 1);
+        assert!(summary.warnings.is_empty());
+        assert_eq!(
+            db.count("SELECT COUNT(*) FROM Scores WHERE combo = 0 AND hit_stats IS NULL"),
+            1
+        );
+    }
+
+    #[test]
+    fn a_required_column_the_importer_cant_fill_stops_the_import_up_front() {
+        // SQLite can't add a NOT NULL column without a default, Scores is made anew.
+        let db = database_at(
+            21,
+            "DROP TABLE Scores; \
+            CREATE TABLE Scores(score INTEGER, crit INTEGER, near INTEGER, early INTEGER, \
+            late INTEGER, combo INTEGER, miss INTEGER, gauge REAL, auto_flags INTEGER, \
+            replay TEXT, timestamp INTEGER, chart_hash TEXT, user_name TEXT, user_id TEXT, \
+            local_score INTEGER, window_perfect INTEGER, window_good INTEGER, \
+            window_hold INTEGER, window_miss INTEGER, window_slam INTEGER, gauge_type INTEGER, \
+            gauge_opt INTEGER, mirror INTEGER, random INTEGER, hit_stats BLOB NOT NULL);",
+        );
+        let fixture = Fixture::new().score("pack/song", "ex", &[&line(9_500_000)]);
+        match test_support::run(fixture, &db, test_support::options()).last() {
+            Some(Progress::Errored(e, None)) => {
+                assert!(e.contains("version 21") && e.contains("hit_stats"), "{}", e)
+            }
+            other => panic!("The import didn't stop: {:?}", other),
+        }
+        assert_eq!(db.scores(), 0);
+    }
+
+    #[test]
+    fn an_unknown_version_lists_the_supported_ones() {
+        let db = database_at(25, "");
+        let fixture = Fixture::new().score("pack/song", "ex", &[&line(9_500_000)]);
+        match test_support::run(fixture, &db, test_support::options()).last() {
+            Some(Progress::Errored(e, _)) => assert!(e.contains("18, 19, 20, 21"), "{}", e),
+            other => panic!("The import didn't stop: {:?}", other),
+        }
+    }
+
+    // Scores as version 18 had it, with the gauge, mirror and random settings in gameflags.
+    const VERSION_18_SCORES: &str = "DROP TABLE Scores; \
+        CREATE TABLE Scores(score INTEGER, crit INTEGER, near INTEGER, miss INTEGER, \
+        gauge REAL, gameflags INTEGER, replay TEXT, timestamp INTEGER, chart_hash TEXT, \
+        user_name TEXT, user_id TEXT, local_score INTEGER, window_perfect INTEGER, \
+        window_good INTEGER, window_hold INTEGER, window_miss INTEGER, window_slam INTEGER);";
+
+    #[test]
+    fn a_version_18_database_gets_its_own_columns() {
+        let db = database_at(18, VERSION_18_SCORES);
+        let hard = "hard,normal,normal,on,on,on=9500000,3,0,87.5,1,2";
+        let mir_ran = "hard,mir-ran,normal,on,on,on=9700000,3,0,87.5,1,2";
+        let fixture = Fixture::new().score("pack/song", "ex", &[hard, mir_ran, &line(9_000_000)]);
+        let options = ImportOptions {
+            skip_duplicates: false,
+            ..test_support::options()
+        };
+        let summary = test_support::import(fixture, &db, options);
+        assert_eq!(summary.scores_imported, 3);
+        assert!(summary.fail_messages.is_empty());
+        let rows: Vec<(i64, i64, f64)> = db
+            .open()
+            .prepare("SELECT score, gameflags, gauge FROM Scores ORDER BY score")
+            .unwrap()
+            .query_map([], |r| Ok((r.get(0)?, r.get(1)?, r.get(2)?)))
+            .unwrap()
+            .map(|r| r.unwrap())
+            .collect();
+        assert_eq!(
+            rows,
+            vec![
+                (9_000_000, 0, 0.875),
+                (9_500_000, 1, 0.875),
+                (9_700_000, 7, 0.875)
+            ]
+        );
+    }
+
+    #[test]
+    fn a_version_18_database_skips_permissive_gauges() {
+        let db = database_at(18, VERSION_18_SCORES);
+        let easy = "easy,normal,normal,on,on,on=9500000,2,0,87.5,1,2";
+        let blocky = "blocky,normal,normal,on,on,on=9600000,2,0,87.5,1,2";
+        let fixture = Fixture::new().score("pack/song", "ex", &[easy, blocky, &line(9_000_000)]);
+        let summary = test_support::import(fixture, &db, test_support::options());
+        assert_eq!(summary.scores_imported, 1);
+        assert_eq!(summary.skipped.get(&SkipRule::UnsupportedGauge), Some(&1));
+        assert_eq!(summary.skipped.get(&SkipRule::UnknownGauge), Some(&1));
+    }
+
+    #[test]
+    fn a_version_18_database_skips_duplicates_by_gameflags() {
+        let db = database_at(18, VERSION_18_SCORES);
+        let fixture = || Fixture::new().score("pack/song", "ex", &[&line(9_000_000)]);
+        test_support::import(fixture(), &db, test_support::options());
+        let again = test_support::import(fixture(), &db, test_support::options());
+        assert_eq!((again.scores_imported, again.scores_skipped), (0, 1));
+        assert_eq!(db.scores(), 1);
+
+        // The same score played mirrored isn't a duplicate of it.
+        let mirrored = "normal,mirror,normal,on,on,on=9000000,2,0,87.5,1,2";
+        let fixture = Fixture::new().score("pack/song", "ex", &[mirrored]);
+        let mirrored = test_support::import(fixture, &db, test_support::options());
+        assert_eq!((mirrored.scores_imported, mirrored.scores_skipped), (1, 0));
+        assert_eq!(db.count("SELECT MAX(gameflags) FROM Scores"), 2);
+    }
+
+    #[test]
+    fn a_file_that_panics_midway_leaves_no_counts_behind() {
+        let db = TempDb::new();
+        let fixture = Fixture::new()
+            .score("pack/midway", "ex", &[&line(9_000_000), &line(9_100_000)])
+            .score("pack/fine", "ex", &[&line(9_200_000)]);
+        let options = ImportOptions {
+            skip_duplicates: false,
+            ..test_support::options()
+        };
+        let progress =
+            test_support::run_source(Arc::new(PanickingSource(fixture.source)), &db.path, options);
+        let summary = test_support::finished(&progress);
+        assert_eq!(summary.failures_of(FailureKind::Crashed).count(), 1);
+        assert_eq!(db.scores(), 1);
+        assert_eq!(summary.scores_imported, 1);
+        assert_eq!(summary.new_charts.len(), 1);
+        assert_eq!(summary.inserted_rows.len(), 1);
+    }
+
+    // A reader keeps its shared lock, a commit can't get the database to itself until it's gone.
+    fn hold_read_lock(db: &TempDb) -> Connection {
+        let reader = db.open();
+        reader
+            .execute_batch("BEGIN; SELECT COUNT(*) FROM Scores;")
+            .unwrap();
+        reader
+    }
+
+    #[test]
+    fn a_locked_commit_takes_every_count_of_the_file_back() {
+        let db = TempDb::new();
+        let reader = hold_read_lock(&db);
+        let fixture =
+            Fixture::new().score("pack/song", "ex", &[&line(9_000_000), &line(9_100_000)]);
+        let mut options = test_support::options();
+        options.skip_duplicates = false;
+        let cancel = options.cancel.clone();
+        let progress =
+            test_support::run_watching(Arc::new(fixture.source), &db.path, options, |p| {
+                if let Progress::Locked = p {
+                    cancel.store(true, Ordering::Relaxed);
+                }
+            });
+        drop(reader);
+        assert!(progress.iter().any(|p| matches!(p, Progress::Locked)));
+        let summary = match progress.last() {
+            Some(Progress::Cancelled(summary)) => summary.clone(),
+            other => panic!("The import wasn't cancelled: {:?}", other),
+        };
+        assert_eq!(db.scores(), 0);
+        assert_eq!(summary.files_processed, 0);
+        assert_eq!(summary.scores_found, 0);
+        assert_eq!(summary.scores_imported, 0);
+        assert!(summary.new_charts.is_empty());
+        assert!(summary.additional_plays.is_empty());
+        assert!(summary.improvements.is_empty());
+        assert!(summary.inserted_rows.is_empty());
+        assert!(summary.fail_messages.is_empty());
+    }
+
+    #[test]
+    fn retrying_after_a_lock_imports_the_file_once() {
+        let db = TempDb::new();
+        let mut reader = Some(hold_read_lock(&db));
+        let fixture = Fixture::new()
+            .score("pack/song", "ex", &[&line(9_000_000), &line(9_100_000)])
+            .score("pack/other", "ex", &[&line(9_200_000)]);
+        let mut options = test_support::options();
+        options.skip_duplicates = false;
+        let pause = options.pause.clone();
+        let progress =
+            test_support::run_watching(Arc::new(fixture.source), &db.path, options, |p| {
+                if let Progress::Locked = p {
+                    // USC was closed, then Retry pressed.
+                    reader = None;
+                    pause.store(false, Ordering::Relaxed);
+                }
+            });
+        assert_eq!(
+            progress
+                .iter()
+                .filter(|p| matches!(p, Progress::Locked))
+                .count(),
+            1
+        );
+        let summary = test_support::finished(&progress);
+        assert_eq!(db.scores(), 3);
+        assert_eq!(summary.files_processed, 2);
+        assert_eq!(summary.scores_found, 3);
+        assert_eq!(summary.scores_imported, 3);
+        assert_eq!(summary.inserted_rows.len(), 3);
+        assert!(summary.fail_messages.is_empty());
+    }
+
+    #[test]
+    fn a_database_locked_on_the_way_in_is_retried() {
+        let db = TempDb::new();
+        // A writer in the middle of its commit keeps even readers out.
+        let mut writer = Some(db.open());
+        writer
+            .as_ref()
+            .unwrap()
+            .execute_batch("BEGIN EXCLUSIVE")
+            .unwrap();
+        let fixture = Fixture::new().score("pack/song", "ex", &[&line(9_000_000)]);
+        let options = test_support::options();
+        let pause = options.pause.clone();
+        let progress =
+            test_support::run_watching(Arc::new(fixture.source), &db.path, options, |p| {
+                if let Progress::Locked = p {
+                    writer = None;
+                    pause.store(false, Ordering::Relaxed);
+                }
+            });
+        assert!(matches!(progress.first(), Some(Progress::Locked)));
+        assert_eq!(test_support::finished(&progress).scores_imported, 1);
+        assert_eq!(db.scores(), 1);
+    }
+
+    #[test]
+    fn a_locked_all_or_nothing_import_leaves_the_scores_as_they_were() {
+        let db = TempDb::with(|db| {
+            db.execute_batch(
+                "INSERT INTO Scores(score, chart_hash, timestamp) VALUES(9000000, 'a', 1); \
+                INSERT INTO Scores(score, chart_hash, timestamp) VALUES(9100000, 'b', 2);",
+            )
+            .unwrap()
+        });
+        // Held until the import tries to commit, which then waits for Retry.
+        let reader = hold_read_lock(&db);
+        let fixture = Fixture::new()
+            .score("pack/song", "ex", &[&line(9_500_000)])
+            .score("pack/other", "ex", &[&line(9_600_000)]);
+        let options = ImportOptions {
+            all_or_nothing: true,
+            ..test_support::options()
+        };
+        let cancel = options.cancel.clone();
+        let progress =
+            test_support::run_watching(Arc::new(fixture.source), &db.path, options, |p| {
+                if let Progress::Locked = p {
+                    cancel.store(true, Ordering::Relaxed);
+                }
+            });
+        drop(reader);
+        match progress.last() {
+            Some(Progress::Cancelled(summary)) => {
+                assert_eq!(summary.scores_imported, 0);
+                assert!(summary.new_charts.is_empty());
+                assert!(summary.inserted_rows.is_empty());
+                assert!(summary.warnings.iter().any(|w| w.contains("rolled back")));
+            }
+            other => panic!("The import wasn't cancelled: {:?}", other),
+        }
+        assert_eq!(db.scores(), 2);
+    }
+
+    #[test]
+    fn a_cancelled_import_keeps_the_files_it_finished() {
+        let cancelled = |all_or_nothing| {
+            let db = TempDb::new();
+            let fixture = Fixture::new()
+                .score("pack/a", "ex", &[&line(9_100_000)])
+                .score("pack/b", "ex", &[&line(9_200_000)])
+                .score("pack/c", "ex", &[&line(9_300_000)]);
+            let options = ImportOptions {
+                all_or_nothing,
+                ..test_support::options()
+            };
+            let cancel = options.cancel.clone();
+            let progress =
+                test_support::run_watching(Arc::new(fixture.source), &db.path, options, |p| {
+                    if let Progress::Advanced(..) = p {
+                        cancel.store(true, Ordering::Relaxed);
+                    }
+                });
+            match progress.last() {
+                Some(Progress::Cancelled(summary)) => (summary.clone(), db),
+                other => panic!("The import wasn't cancelled: {:?}", other),
+            }
+        };
+
+        let (summary, db) = cancelled(false);
+        assert!(
+            (1..3).contains(&summary.files_processed),
+            "{}",
+            summary.files_processed
+        );
+        assert_eq!(summary.scores_imported, summary.files_processed);
+        assert_eq!(db.scores(), summary.scores_imported as i64);
+        assert_eq!(summary.inserted_rows.len(), db.scores() as usize);
+        assert!(summary
+            .warnings
+            .iter()
+            .any(|w| w.starts_with("Cancelled after")));
+
+        // Everything the run wrote goes, and maps.db is left as it was.
+        let (summary, db) = cancelled(true);
+        assert!(summary.files_processed >= 1);
+        assert_eq!(db.scores(), 0);
+        assert_eq!(summary.scores_imported, 0);
+        assert!(summary.inserted_rows.is_empty());
+        assert!(summary.new_charts.is_empty());
+        assert!(summary.warnings.iter().any(|w| w.contains("rolled back")));
+        let check: String = db
+            .open()
+            .query_row("PRAGMA integrity_check", [], |r| r.get(0))
+            .unwrap();
+        assert_eq!(check, "ok");
+    }
+
+    #[test]
+    fn a_headless_import_stops_at_a_lock() {
+        let db = TempDb::new();
+        let dir = tempfile::tempdir().unwrap();
+        for (path, contents) in [
+            (
+                "score/player/pack/song/ex.ksc",
+                format!("{}\n", line(9_000_000)),
+            ),
+            (
+                "songs/pack/song/ex.ksh",
+                "title=song\nlevel=15\n--\n".to_string(),
+            ),
+        ]
+        .iter()
+        {
+            let path = dir.path().join(path);
+            std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+            std::fs::write(path, contents).unwrap();
+        }
+        let _reader = hold_read_lock(&db);
+        let result = import_scores(dir.path(), &db.path, test_support::options(), |_| {});
+        assert_eq!(result.unwrap_err().to_string(), LOCKED_MESSAGE);
+    }
+
+    fn backups_next_to(db: &TempDb) -> Vec<PathBuf> {
+        std::fs::read_dir(db.path.parent().unwrap())
+            .unwrap()
+            .map(|e| e.unwrap().path())
+            .filter(|p| p.to_string_lossy().contains(".ksm-import-backup-"))
+            .filter(|p| p.extension().is_none_or(|e| e != "sha256"))
+            .collect()
+    }
+
+    #[test]
+    fn the_backup_holds_the_scores_still_in_the_wal() {
+        let db = TempDb::new();
+        // Kept open so its rows stay in maps.db-wal, a copy of maps.db alone wouldn't have them.
+        let writer = db.open();
+        writer
+            .execute_batch(
+                "PRAGMA journal_mode = WAL; PRAGMA wal_autocheckpoint = 0; \
+                INSERT INTO Scores(score, chart_hash, timestamp) VALUES(9000000, 'a', 1);",
+            )
+            .unwrap();
+        let fixture = Fixture::new().score("pack/song", "ex", &[&line(9_500_000)]);
+        let options = ImportOptions {
+            skip_backup: false,
+            ..test_support::options()
+        };
+        let summary = test_support::import(fixture, &db, options);
+        let backup = summary.backup_path.unwrap();
+        assert_eq!(backups_next_to(&db), vec![backup.clone()]);
+        assert_eq!(checksum::verify(&backup), checksum::Check::Intact);
+        let backup = Connection::open(backup).unwrap();
+        let scores: i64 = backup
+            .query_row("SELECT COUNT(*) FROM Scores", [], |r| r.get(0))
+            .unwrap();
+        assert_eq!(scores, 1);
+        assert_eq!(db.scores(), 2);
+        drop(writer);
+    }
+
+    #[test]
+    fn a_database_the_checks_reject_is_not_backed_up() {
+        let db = database_at(21, "ALTER TABLE Scores DROP COLUMN mirror;");
+        let fixture = Fixture::new().score("pack/song", "ex", &[&line(9_500_000)]);
+        let options = ImportOptions {
+            skip_backup: false,
+            ..test_support::options()
+        };
+        match test_support::run(fixture, &db, options).last() {
+            Some(Progress::Errored(e, None)) => assert!(e.contains("missing mirror"), "{}", e),
+            other => panic!("The import didn't stop: {:?}", other),
+        }
+        assert!(backups_next_to(&db).is_empty());
+    }
 }
@@ -1,22 +1,325 @@
-use crate::importer::KsmScore;
-use anyhow::{bail, Result};
-use async_std::sync::Mutex;
-use lazy_static::lazy_static;
-use rusqlite::{params, Connection};
+use crate::{
+    importer::{GaugeType, KsmScore, SkipRule},
+    schema::{self, Column},
+    source::Source,
+    InsertedRow,
+};
+use anyhow::{bail, ensure, Result};
+use rusqlite::{params, params_from_iter, Connection};
 use std::{
-    collections::HashMap,
+    cell::{Cell, RefCell},
+    collections::{BTreeMap, HashMap, HashSet},
     ffi::OsStr,
-    io::Read,
     path::{Component, Path, PathBuf},
-    sync::Arc,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
 };
 
-pub type ImportFn = fn(&KsmScore, &Connection, &Path) -> Result<()>;
+pub type ImportFn = fn(&KsmScore, &Connection, &Path, &InsertOptions) -> Result<Inserted>;
+
+pub struct InsertOptions<'a> {
+    pub source: &'a dyn Source,
+    pub song_roots: &'a [PathBuf],
+    pub timestamps: TimestampPolicy,
+    pub file_index: u32,
+    pub file_count: u32,
+    // Time spent finding and hashing charts, the rest of an insert is database work.
+    pub chart_time: &'a Cell<Duration>,
+    pub hash_cache: &'a RefCell<HashCache>,
+    pub earlier_rows: &'a RefCell<EarlierRows>,
+    pub user_name: &'a str,
+    pub user_id: &'a str,
+    pub skip_duplicates: bool,
+    pub improvements_only: bool,
+    pub compare_across_gauges: bool,
+    pub dry_run: bool,
+    pub levels: LevelRange,
+    // Read from the database when its version is unknown, see version_detected.
+    pub detected_columns: &'a [Column],
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum InsertKind {
+    NewChart,
+    Improvement,
+    AdditionalPlay,
+}
+
+#[derive(Debug, Clone)]
+pub struct Inserted {
+    pub kind: InsertKind,
+    pub chart_hash: String,
+    pub chart_path: PathBuf,
+    pub chart_from_db: bool,
+    pub timestamp_adjusted: Option<TimestampAdjustment>,
+    pub timestamp: i64,
+    pub original_timestamp: i64,
+    // Already in Scores and skipped, nothing was written.
+    pub duplicate: bool,
+    // Dropped once the chart was known, by the level range or improvements_only. Nothing was
+    // written.
+    pub skipped: Option<SkipRule>,
+    pub row: Option<InsertedRow>,
+    // Set instead of skipping the score when it gave a row of an earlier import a better badge.
+    pub upgraded: Option<Upgrade>,
+}
+
+// Rows earlier imports wrote into this maps.db by chart hash, the only ones a better badge may
+// change. Scores USC recorded itself are never touched.
+pub type EarlierRows = HashMap<String, Vec<InsertedRow>>;
+
+pub fn earlier_rows(rows: &[InsertedRow]) -> EarlierRows {
+    let mut by_chart = EarlierRows::new();
+    for row in rows {
+        by_chart
+            .entry(row.chart_hash.clone())
+            .or_default()
+            .push(row.clone());
+    }
+    by_chart
+}
+
+#[derive(Debug, Clone)]
+pub struct Upgrade {
+    // With the new badge.
+    pub row: InsertedRow,
+    pub previous_badge: u32,
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct LevelRange {
+    pub min: Option<u8>,
+    pub max: Option<u8>,
+}
+
+impl LevelRange {
+    pub fn is_all(self) -> bool {
+        self.min.is_none() && self.max.is_none()
+    }
+
+    pub fn contains(self, level: u8) -> bool {
+        self.min.is_none_or(|min| level >= min) && self.max.is_none_or(|max| level <= max)
+    }
+}
+
+// "15", "15-18", "15+" or "-12", empty for every level.
+pub fn parse_level_range(s: &str) -> Option<LevelRange> {
+    let level = |s: &str| match s.trim() {
+        "" => Some(None),
+        s => s.parse().ok().map(Some),
+    };
+    let s = s.trim();
+    let (min, max) = if let Some(min) = s.strip_suffix('+') {
+        (level(min)?, None)
+    } else if let Some((min, max)) = s.split_once('-') {
+        (level(min)?, level(max)?)
+    } else {
+        let exact = level(s)?;
+        (exact, exact)
+    };
+    Some(LevelRange { min, max }).filter(|r| match (r.min, r.max) {
+        (Some(min), Some(max)) => min <= max,
+        _ => true,
+    })
+}
+
+// Digit grouping is allowed, "8,000,000". Empty means no minimum.
+pub fn parse_score(s: &str) -> Option<u32> {
+    match s.trim() {
+        "" => Some(0),
+        s => s.replace(',', "").parse().ok(),
+    }
+}
+
+// The gauge percentage as a 0-1 fraction. KSM builds running with a decimal comma locale write
+// "98,5", it's read the same as "98.5". Values outside 0-100 are clamped.
+pub fn parse_gauge(field: &str) -> Result<f64> {
+    match field.trim().replace(',', ".").parse::<f64>() {
+        Ok(gauge) if gauge.is_finite() => Ok((gauge / 100.0).clamp(0.0, 1.0)),
+        _ => bail!("Invalid gauge \"{}\"", field),
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TimestampAdjustment {
+    BeforeEpoch,
+    InFuture,
+}
+
+const FUTURE_TOLERANCE: Duration = Duration::from_secs(24 * 60 * 60);
+
+pub fn clamp_timestamp(time: SystemTime, now: SystemTime) -> (i64, Option<TimestampAdjustment>) {
+    let secs = |t: SystemTime| {
+        t.duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs() as i64)
+            .unwrap_or(0)
+    };
+    if time < UNIX_EPOCH {
+        (0, Some(TimestampAdjustment::BeforeEpoch))
+    } else if time > now + FUTURE_TOLERANCE {
+        (secs(now), Some(TimestampAdjustment::InFuture))
+    } else {
+        (secs(time), None)
+    }
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum TimestampPolicy {
+    #[default]
+    Preserve,
+    Before(i64),
+}
+
+const BACKDATE_SPACING: i64 = 60;
+
+// Backdated files are spread a minute apart in import order so they keep a stable ordering
+// just before the cutoff. Timestamps already before the cutoff are kept.
+pub fn remap_timestamp(
+    policy: TimestampPolicy,
+    natural: i64,
+    file_index: u32,
+    file_count: u32,
+) -> i64 {
+    match policy {
+        TimestampPolicy::Preserve => natural,
+        TimestampPolicy::Before(cutoff) if natural < cutoff => natural,
+        TimestampPolicy::Before(cutoff) => {
+            let steps = i64::from(file_count.max(file_index + 1) - file_index);
+            (cutoff - steps * BACKDATE_SPACING).max(0)
+        }
+    }
+}
+
+// Civil date conversions from Howard Hinnant's days_from_civil/civil_from_days.
+pub fn parse_date(date: &str) -> Option<i64> {
+    let mut parts = date.trim().splitn(3, '-').map(|p| p.parse::<i64>().ok());
+    let (y, m, d) = (parts.next()??, parts.next()??, parts.next()??);
+    if !(1..=12).contains(&m) || !(1..=31).contains(&d) {
+        return None;
+    }
+    let y = if m <= 2 { y - 1 } else { y };
+    let era = y.div_euclid(400);
+    let yoe = y - era * 400;
+    let doy = (153 * ((m + 9) % 12) + 2) / 5 + d - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    Some((era * 146097 + doe - 719468) * 86400)
+}
+
+pub fn now() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs() as i64)
+        .unwrap_or(0)
+}
+
+// A date, optionally followed by a time of day: "2021-03-04" or "2021-03-04 18:30:00".
+pub fn parse_date_time(s: &str) -> Option<i64> {
+    let s = s.trim();
+    let (date, time) = s.split_once(' ').unwrap_or((s, ""));
+    let mut parts = time.trim().split(':').filter(|p| !p.is_empty());
+    let mut secs = 0;
+    for (unit, limit) in [(3600, 24), (60, 60), (1, 60)].iter() {
+        if let Some(part) = parts.next() {
+            secs += part
+                .parse::<i64>()
+                .ok()
+                .filter(|n| (0..*limit).contains(n))?
+                * unit;
+        }
+    }
+    if parts.next().is_some() {
+        return None;
+    }
+    Some(parse_date(date)? + secs)
+}
+
+// Sizes are u64 end to end, a database past 4 GB is common once replays pile up.
+pub fn format_size(bytes: u64) -> String {
+    format!("{:.1} MB", bytes as f64 / (1024.0 * 1024.0))
+}
+
+pub fn format_date_time(timestamp: i64) -> String {
+    let secs = timestamp.rem_euclid(86400);
+    format!(
+        "{} {:02}:{:02}:{:02}",
+        format_date(timestamp),
+        secs / 3600,
+        secs / 60 % 60,
+        secs % 60
+    )
+}
+
+pub fn format_date(timestamp: i64) -> String {
+    let z = timestamp.div_euclid(86400) + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z - era * 146097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let d = doy - (153 * mp + 2) / 5 + 1;
+    let m = if mp < 10 { mp + 3 } else { mp - 9 };
+    let y = yoe + era * 400 + if m <= 2 { 1 } else { 0 };
+    format!("{:04}-{:02}-{:02}", y, m, d)
+}
+
+fn classify_insert(score: &KsmScore, db: &Connection, hash: &str) -> Result<InsertKind> {
+    let best: Option<u32> = db
+        .prepare_cached("SELECT MAX(score) FROM Scores WHERE chart_hash = ?")?
+        .query_row(params![hash], |r| r.get(0))?;
+    Ok(match best {
+        None => InsertKind::NewChart,
+        Some(best) if score.score > best => InsertKind::Improvement,
+        Some(_) => InsertKind::AdditionalPlay,
+    })
+}
+
+fn best_existing(
+    db: &Connection,
+    columns: &[Column],
+    score: &KsmScore,
+    hash: &str,
+    across_gauges: bool,
+) -> Result<Option<u32>> {
+    if across_gauges {
+        return Ok(db
+            .prepare_cached("SELECT MAX(score) FROM Scores WHERE chart_hash = ?")?
+            .query_row(params![hash], |r| r.get(0))?);
+    }
+    let (gauge, value) = schema::same_gauge(columns, score);
+    Ok(db
+        .prepare_cached(&format!(
+            "SELECT MAX(score) FROM Scores WHERE chart_hash = ? AND {}",
+            gauge
+        ))?
+        .query_row(params![hash, value], |r| r.get(0))?)
+}
+
+fn score_exists(
+    db: &Connection,
+    columns: &[Column],
+    score: &KsmScore,
+    timestamp: i64,
+    hash: &str,
+) -> Result<bool> {
+    let (settings, value) = schema::same_settings(columns, score);
+    Ok(db
+        .prepare_cached(&format!(
+            "SELECT EXISTS(SELECT 1 FROM Scores WHERE chart_hash = ? AND score = ? \
+            AND timestamp = ? AND {})",
+            settings
+        ))?
+        .query_row(params![hash, score.score, timestamp, value], |r| r.get(0))?)
+}
 
-fn get_score_chart_path(score_path: &Path) -> Result<PathBuf> {
-    let mut res = score_path.with_extension("ksh");
+// score/<profile>/<pack>/<song>/<difficulty>.ksc to songs/<pack>/<song>/<difficulty>.ksh
+fn reconstruct_chart_path(score_path: &Path) -> Result<PathBuf> {
+    let res = score_path.with_extension("ksh");
     let depth = res.components().count();
-    res = res
+    ensure!(
+        depth >= 5,
+        "\"{}\" is not in a score/<profile>/<pack>/<song> folder",
+        score_path.to_str().unwrap_or_default()
+    );
+    Ok(res
         .components()
         .enumerate()
         .filter(|(i, _)| *i != depth - 4)
@@ -27,52 +330,818 @@ fn get_score_chart_path(score_path: &Path) -> Result<PathBuf> {
                 c
             }
         })
-        .collect();
+        .collect())
+}
+
+fn header_fields(chart: &str) -> impl Iterator<Item = (&str, &str)> {
+    chart
+        .trim_start_matches('\u{feff}')
+        .lines()
+        .take_while(|l| l.trim() != "--")
+        .filter_map(|l| l.split_once('='))
+}
+
+fn chart_level(chart: &[u8]) -> Option<u8> {
+    let header = String::from_utf8_lossy(chart);
+    let level = header_fields(&header).find(|(key, _)| *key == "level")?.1;
+    level.trim().parse().ok()
+}
+
+// "Title (EXH 18)" from the header of the chart a score file belongs to, the header ends at the
+// first "--" line.
+pub fn chart_label(score_path: &Path, source: &dyn Source) -> Option<String> {
+    let chart = source
+        .read_chart(&reconstruct_chart_path(score_path).ok()?)
+        .ok()?;
+    let header = String::from_utf8_lossy(&chart);
+    let (mut title, mut difficulty, mut level) = (None, None, None);
+    header_fields(&header).for_each(|(key, value)| match key {
+        "title" => title = Some(value.trim()),
+        "difficulty" => {
+            difficulty = Some(match value.trim() {
+                "light" => "NOV",
+                "challenge" => "ADV",
+                "extended" => "EXH",
+                "infinite" => "INF",
+                other => other,
+            })
+        }
+        "level" => level = Some(value.trim()),
+        _ => {}
+    });
+    let title = title.filter(|t| !t.is_empty())?;
+    Some(match (difficulty, level) {
+        (Some(d), Some(l)) => format!("{} ({} {})", title, d, l),
+        (Some(d), None) => format!("{} ({})", title, d),
+        (None, Some(l)) => format!("{} ({})", title, l),
+        (None, None) => title.to_string(),
+    })
+}
+
+#[derive(Debug)]
+pub struct ChartMissing(pub PathBuf);
 
-    if !res.exists() {
-        bail!(
+impl std::fmt::Display for ChartMissing {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(
+            f,
             "File does not exist: \"{}\"",
-            res.to_str().unwrap_or_default()
-        );
+            self.0.to_str().unwrap_or_default()
+        )
+    }
+}
+
+impl std::error::Error for ChartMissing {}
+
+fn get_score_chart_path(score_path: &Path, source: &dyn Source) -> Result<PathBuf> {
+    let res = reconstruct_chart_path(score_path)?;
+    if !source.chart_exists(&res) {
+        bail!(ChartMissing(res));
     }
 
     Ok(res)
 }
 
-lazy_static! {
-    static ref HASH_CACHE: Arc<Mutex<HashMap<String, String>>> =
-        Arc::new(Mutex::new(HashMap::new()));
+fn escape_like(s: &str) -> String {
+    s.replace('!', "!!").replace('%', "!%").replace('_', "!_")
+}
+
+fn chart_hash_from_db(
+    db: &Connection,
+    chart_path: &Path,
+    options: &InsertOptions,
+) -> Result<Option<String>> {
+    let name = |p: Option<&Path>| {
+        p.and_then(|p| p.file_name())
+            .and_then(|n| n.to_str())
+            .map(escape_like)
+            .unwrap_or_default()
+    };
+    let song = name(chart_path.parent());
+    let file = name(Some(chart_path));
+
+    let mut stmt = db.prepare(
+        "SELECT hash, path FROM Charts WHERE path = ?1 OR path LIKE ?2 ESCAPE '!' OR path LIKE ?3 ESCAPE '!' \
+        ORDER BY path = ?1 DESC, path",
+    )?;
+    let candidates = stmt
+        .query_map(
+            params![
+                chart_path.to_str().unwrap_or_default(),
+                format!("%/{}/{}", song, file),
+                format!("%\\{}\\{}", song, file),
+            ],
+            |r| Ok((r.get(0)?, r.get(1)?)),
+        )?
+        .collect::<rusqlite::Result<Vec<(String, String)>>>()?;
+
+    Ok(pick_chart_row(candidates, options))
+}
+
+// Charts can hold the same chart under several paths, prefer one that still exists so
+// lookups don't depend on row order.
+fn pick_chart_row(candidates: Vec<(String, String)>, options: &InsertOptions) -> Option<String> {
+    let existing = candidates
+        .iter()
+        .position(|(_, path)| resolve_stored_path(path, options).is_some())
+        .unwrap_or(0);
+    candidates.into_iter().nth(existing).map(|(hash, _)| hash)
+}
+
+// Charts.path is absolute for the default songs folder but relative when USC is pointed at a
+// custom one, so relative paths are tried against every known root.
+fn resolve_stored_path(stored: &str, options: &InsertOptions) -> Option<PathBuf> {
+    let stored = Path::new(stored);
+    if stored.is_absolute() {
+        return Some(stored.to_path_buf()).filter(|p| options.source.chart_exists(p));
+    }
+    options
+        .song_roots
+        .iter()
+        .map(|root| root.join(stored))
+        .find(|p| options.source.chart_exists(p))
+}
+
+fn configured_song_folder(usc_dir: &Path) -> Option<PathBuf> {
+    let cfg = std::fs::read_to_string(usc_dir.join("Main.cfg")).ok()?;
+    let folder = cfg.lines().find_map(|line| {
+        let (key, value) = line.split_once('=')?;
+        Some(value.trim().trim_matches('"')).filter(|_| key.trim() == "SongFolder")
+    })?;
+    Some(usc_dir.join(folder)).filter(|_| !folder.is_empty())
+}
+
+pub fn song_roots(db_path: &Path, manual_root: Option<&Path>) -> Vec<PathBuf> {
+    let usc_dir = db_path.parent().unwrap_or_else(|| Path::new(""));
+    let mut roots: Vec<PathBuf> = manual_root.map(Path::to_path_buf).into_iter().collect();
+    roots.extend(configured_song_folder(usc_dir));
+    roots.push(usc_dir.to_path_buf());
+    let mut seen = HashSet::new();
+    roots.retain(|root| seen.insert(root.clone()));
+    roots
+}
+
+fn resolve_chart_hash(
+    db: &Connection,
+    score_path: &Path,
+    options: &InsertOptions,
+) -> Result<(String, PathBuf, bool)> {
+    match get_score_chart_path(score_path, options.source) {
+        Ok(chart_path) => {
+            let hash = hash_file(&chart_path, options)?;
+            if chart_indexed(db, &hash)? {
+                return Ok((hash, chart_path, false));
+            }
+            // Merged downloads can leave PACK/Song next to pack/song, prefer the copy USC indexed.
+            for variant in options.source.case_variants(&chart_path) {
+                if let Ok(variant_hash) = hash_file(&variant, options) {
+                    if chart_indexed(db, &variant_hash)? {
+                        return Ok((variant_hash, variant, false));
+                    }
+                }
+            }
+            Ok((hash, chart_path, false))
+        }
+        Err(e) => {
+            let chart_path = reconstruct_chart_path(score_path)?;
+            match chart_hash_from_db(db, &chart_path, options)? {
+                Some(hash) => Ok((hash, chart_path, true)),
+                None => Err(e),
+            }
+        }
+    }
+}
+
+fn chart_indexed(db: &Connection, hash: &str) -> Result<bool> {
+    Ok(db.query_row(
+        "SELECT EXISTS(SELECT 1 FROM Charts WHERE hash = ?)",
+        params![hash],
+        |r| r.get(0),
+    )?)
+}
+
+pub fn canonical_chart_path(db: &Connection, hash: &str) -> Result<Option<String>> {
+    Ok(db
+        .prepare("SELECT path FROM Charts WHERE hash = ? ORDER BY path LIMIT 1")?
+        .query_map(params![hash], |r| r.get(0))?
+        .next()
+        .transpose()?)
 }
 
-fn hash_file(path: &Path) -> Result<String> {
-    let mut cache = HASH_CACHE.try_lock().unwrap();
+const HASH_CACHE_LIMIT: usize = 4096;
+
+// Chart hashes read during one import, the level is kept with each so the level range never
+// reads a chart twice. A new import starts empty, charts edited in between are read again.
+#[derive(Debug)]
+pub struct HashCache {
+    capacity: usize,
+    entries: HashMap<PathBuf, CachedChart>,
+    // Last use of every entry, the oldest is evicted first once the cache is full.
+    by_use: BTreeMap<u64, PathBuf>,
+    tick: u64,
+}
 
-    let key = path.to_str().unwrap_or_default().to_string();
-    if cache.contains_key(&key) {
-        println!("Cache hit");
-        return Ok(cache.get(&key).unwrap().clone());
+#[derive(Debug)]
+struct CachedChart {
+    hash: String,
+    level: Option<u8>,
+    used: u64,
+}
+
+impl Default for HashCache {
+    fn default() -> Self {
+        Self::with_capacity(HASH_CACHE_LIMIT)
     }
+}
 
-    let mut f = std::fs::File::open(path)?;
+impl HashCache {
+    fn with_capacity(capacity: usize) -> Self {
+        HashCache {
+            capacity: capacity.max(1),
+            entries: HashMap::new(),
+            by_use: BTreeMap::new(),
+            tick: 0,
+        }
+    }
+
+    fn get(&mut self, path: &Path) -> Option<(&str, Option<u8>)> {
+        self.tick += 1;
+        let entry = self.entries.get_mut(path)?;
+        let path = self.by_use.remove(&entry.used)?;
+        entry.used = self.tick;
+        self.by_use.insert(self.tick, path);
+        Some((&entry.hash, entry.level))
+    }
+
+    fn insert(&mut self, path: &Path, hash: String, level: Option<u8>) {
+        self.tick += 1;
+        if let Some(old) = self.entries.remove(path) {
+            self.by_use.remove(&old.used);
+        }
+        while self.entries.len() >= self.capacity {
+            match self.by_use.pop_first() {
+                Some((_, oldest)) => self.entries.remove(&oldest),
+                None => break,
+            };
+        }
+        self.by_use.insert(self.tick, path.to_path_buf());
+        self.entries.insert(
+            path.to_path_buf(),
+            CachedChart {
+                hash,
+                level,
+                used: self.tick,
+            },
+        );
+    }
+}
+
+fn cached_level(path: &Path, options: &InsertOptions) -> Option<u8> {
+    options.hash_cache.borrow_mut().get(path)?.1
+}
+
+fn hash_file(path: &Path, options: &InsertOptions) -> Result<String> {
+    if let Some((hash, _)) = options.hash_cache.borrow_mut().get(path) {
+        return Ok(hash.to_string());
+    }
+
+    let buf = options.source.read_chart(path)?;
     let mut hasher = sha1::Sha1::new();
-    let mut buf = Vec::new();
-    f.read_to_end(&mut buf)?;
     hasher.update(buf.as_slice());
     let res = hasher.digest().to_string();
-    cache.insert(key, res.clone());
+    options
+        .hash_cache
+        .borrow_mut()
+        .insert(path, res.clone(), chart_level(&buf));
     Ok(res)
 }
 
-pub fn version_19(score: &KsmScore, db: &Connection, score_path: &Path) -> Result<()> {
-    let chart_path = get_score_chart_path(score_path)?;
-    let lwt = std::fs::metadata(&score_path)?.modified()?;
-    let lwt = lwt.duration_since(std::time::UNIX_EPOCH).unwrap().as_secs() as i64;
-    let hash = hash_file(&chart_path)?;
-    let gauge_type = if score.hard { 1 } else { 0 };
-    db.execute(
-        "INSERT INTO 
-        Scores(score,crit,near,miss,gauge,auto_flags,replay,timestamp,chart_hash,user_name,user_id,local_score,window_perfect,window_good,window_hold,window_miss,window_slam,gauge_type,gauge_opt,mirror,random) 
-        VALUES(?,?,?,?,?,?,?,?,?,?,?,?,?,?,?,?,?,?,?,?,?)", params![score.score, score.crit, score.near, score.miss, score.gauge as f32, 0, "", lwt, hash, "", 0, true, 46, 92, 138, 250, 84, gauge_type, 0, false, false]
-    )?;
-    Ok(())
+fn insert_with_schema(
+    columns: &[Column],
+    score: &KsmScore,
+    db: &Connection,
+    score_path: &Path,
+    options: &InsertOptions,
+) -> Result<Inserted> {
+    let lwt = options.source.modified(score_path)?;
+    let (original_timestamp, timestamp_adjusted) = clamp_timestamp(lwt, SystemTime::now());
+    let lwt = remap_timestamp(
+        options.timestamps,
+        original_timestamp,
+        options.file_index,
+        options.file_count,
+    );
+    let started = Instant::now();
+    let (hash, chart_path, chart_from_db) = resolve_chart_hash(db, score_path, options)?;
+    options
+        .chart_time
+        .set(options.chart_time.get() + started.elapsed());
+    let mut skipped = None;
+    if score.gauge_type == GaugeType::Permissive && schema::has_game_flags(columns) {
+        skipped = Some(SkipRule::UnsupportedGauge);
+    }
+    // Charts found only through the database have no header to read, they pass the level range.
+    if skipped.is_none()
+        && !options.levels.is_all()
+        && cached_level(&chart_path, options).is_some_and(|level| !options.levels.contains(level))
+    {
+        skipped = Some(SkipRule::OutsideLevelRange);
+    }
+    let duplicate = skipped.is_none()
+        && options.skip_duplicates
+        && score_exists(db, columns, score, lwt, &hash)?;
+    if skipped.is_none()
+        && !duplicate
+        && options.improvements_only
+        && best_existing(db, columns, score, &hash, options.compare_across_gauges)?
+            .is_some_and(|best| score.score <= best)
+    {
+        skipped = Some(SkipRule::NotImprovement);
+    }
+    // The same score again with a better badge, e.g. a hard clear of a score that was imported
+    // as a clear, lights the better lamp on the row already there.
+    let upgraded = match (duplicate, skipped) {
+        (true, _) | (_, Some(SkipRule::NotImprovement)) => {
+            upgrade_badge(db, columns, score, &hash, options)?
+        }
+        _ => None,
+    };
+    let kind = classify_insert(score, db, &hash)?;
+    let meta = schema::Meta {
+        timestamp: lwt,
+        chart_hash: &hash,
+        user_name: options.user_name,
+        user_id: schema::user_id(options.user_id)?,
+    };
+    let mut row = None;
+    if !duplicate && skipped.is_none() && !options.dry_run {
+        // Every score of a run shares one statement, preparing the insert took longer than
+        // running it.
+        db.prepare_cached(&schema::insert_sql(columns))?
+            .execute(params_from_iter(schema::bind(columns, score, &meta)))?;
+        row = Some(InsertedRow {
+            rowid: db.last_insert_rowid(),
+            chart_hash: hash.clone(),
+            score: score.score,
+            timestamp: lwt,
+            badge: Some(score.badge),
+        });
+    }
+    Ok(Inserted {
+        kind,
+        chart_hash: hash,
+        chart_path,
+        chart_from_db,
+        timestamp_adjusted,
+        timestamp: lwt,
+        original_timestamp,
+        duplicate,
+        skipped,
+        row,
+        upgraded,
+    })
+}
+
+fn upgrade_badge(
+    db: &Connection,
+    columns: &[Column],
+    score: &KsmScore,
+    hash: &str,
+    options: &InsertOptions,
+) -> Result<Option<Upgrade>> {
+    let mut earlier = options.earlier_rows.borrow_mut();
+    let same_score: Vec<&mut InsertedRow> = match earlier.get_mut(hash) {
+        Some(rows) => rows.iter_mut().filter(|r| r.score == score.score).collect(),
+        None => return Ok(None),
+    };
+    // A row already showing this badge or a better one needs nothing.
+    if same_score
+        .iter()
+        .any(|r| r.badge.is_some_and(|b| b >= score.badge))
+    {
+        return Ok(None);
+    }
+    let row = match same_score.into_iter().find(|r| r.badge.is_some()) {
+        Some(row) => row,
+        None => return Ok(None),
+    };
+    if !options.dry_run {
+        let changed = db
+            .prepare_cached(&schema::upgrade_sql(columns))?
+            .execute(params_from_iter(schema::bind_upgrade(columns, score, row)))?;
+        // Changed since it was written, by USC or by hand, so it's left alone.
+        if changed == 0 {
+            return Ok(None);
+        }
+    }
+    let previous_badge = row.badge.unwrap_or_default();
+    row.badge = Some(score.badge);
+    Ok(Some(Upgrade {
+        row: row.clone(),
+        previous_badge,
+    }))
+}
+
+pub fn version_18(
+    score: &KsmScore,
+    db: &Connection,
+    score_path: &Path,
+    options: &InsertOptions,
+) -> Result<Inserted> {
+    insert_with_schema(schema::VERSION_18, score, db, score_path, options)
+}
+
+pub fn version_detected(
+    score: &KsmScore,
+    db: &Connection,
+    score_path: &Path,
+    options: &InsertOptions,
+) -> Result<Inserted> {
+    insert_with_schema(options.detected_columns, score, db, score_path, options)
+}
+
+pub fn version_19(
+    score: &KsmScore,
+    db: &Connection,
+    score_path: &Path,
+    options: &InsertOptions,
+) -> Result<Inserted> {
+    insert_with_schema(schema::VERSION_19, score, db, score_path, options)
+}
+
+pub fn version_20(
+    score: &KsmScore,
+    db: &Connection,
+    score_path: &Path,
+    options: &InsertOptions,
+) -> Result<Inserted> {
+    insert_with_schema(schema::VERSION_20, score, db, score_path, options)
+}
+
+pub fn version_21(
+    score: &KsmScore,
+    db: &Connection,
+    score_path: &Path,
+    options: &InsertOptions,
+) -> Result<Inserted> {
+    insert_with_schema(schema::VERSION_21, score, db, score_path, options)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::source::MemorySource;
+    use crate::test_support::memory_db;
+
+    // The state an import keeps across inserts.
+    #[derive(Default)]
+    struct Cells {
+        chart_time: Cell<Duration>,
+        hash_cache: RefCell<HashCache>,
+        earlier_rows: RefCell<EarlierRows>,
+    }
+
+    fn insert_options<'a>(source: &'a dyn Source, cells: &'a Cells) -> InsertOptions<'a> {
+        InsertOptions {
+            source,
+            song_roots: &[],
+            timestamps: TimestampPolicy::Preserve,
+            file_index: 0,
+            file_count: 1,
+            chart_time: &cells.chart_time,
+            hash_cache: &cells.hash_cache,
+            earlier_rows: &cells.earlier_rows,
+            user_name: "",
+            user_id: "",
+            skip_duplicates: true,
+            improvements_only: false,
+            compare_across_gauges: false,
+            dry_run: false,
+            levels: LevelRange::default(),
+            detected_columns: &[],
+        }
+    }
+
+    fn add_chart(db: &Connection, path: &str, hash: &str) {
+        db.execute(
+            "INSERT INTO Charts(path, hash) VALUES(?, ?)",
+            params![path, hash],
+        )
+        .unwrap();
+    }
+
+    #[test]
+    fn duplicate_chart_rows_prefer_an_existing_path() {
+        let db = memory_db();
+        add_chart(&db, "/old/pack/song/ex.ksh", "stale");
+        add_chart(&db, "/usc/songs/pack/song/ex.ksh", "current");
+        let mut source = MemorySource::default();
+        source.insert("/usc/songs/pack/song/ex.ksh", b"chart".to_vec(), UNIX_EPOCH);
+        let cells = Cells::default();
+        let options = insert_options(&source, &cells);
+        let hash = chart_hash_from_db(&db, Path::new("ksm/songs/pack/song/ex.ksh"), &options);
+        assert_eq!(hash.unwrap().as_deref(), Some("current"));
+
+        // Neither exists, the first by path wins rather than whichever row was scanned first.
+        let source = MemorySource::default();
+        let options = insert_options(&source, &cells);
+        let hash = chart_hash_from_db(&db, Path::new("ksm/songs/pack/song/ex.ksh"), &options);
+        assert_eq!(hash.unwrap().as_deref(), Some("stale"));
+    }
+
+    #[test]
+    fn duplicate_chart_rows_name_the_chart_by_its_first_path() {
+        let db = memory_db();
+        add_chart(&db, "/usc/songs/pack b/song/ex.ksh", "same");
+        add_chart(&db, "/usc/songs/pack a/song/ex.ksh", "same");
+        assert_eq!(
+            canonical_chart_path(&db, "same").unwrap().as_deref(),
+            Some("/usc/songs/pack a/song/ex.ksh")
+        );
+        assert_eq!(canonical_chart_path(&db, "other").unwrap(), None);
+    }
+
+    #[test]
+    fn chart_paths_mirror_score_paths() {
+        assert_eq!(
+            reconstruct_chart_path(Path::new("score/player/pack/song/ex.ksc")).unwrap(),
+            Path::new("songs/pack/song/ex.ksh")
+        );
+        assert!(reconstruct_chart_path(Path::new("score/song/ex.ksc")).is_err());
+        assert!(reconstruct_chart_path(Path::new("ex.ksc")).is_err());
+    }
+
+    #[test]
+    fn timestamps_before_1970_or_ahead_of_now_are_clamped() {
+        let now = UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+        assert_eq!(
+            clamp_timestamp(UNIX_EPOCH - Duration::from_secs(60), now),
+            (0, Some(TimestampAdjustment::BeforeEpoch))
+        );
+        assert_eq!(
+            clamp_timestamp(now + FUTURE_TOLERANCE + Duration::from_secs(1), now),
+            (1_700_000_000, Some(TimestampAdjustment::InFuture))
+        );
+        // Clock skew within a day is left alone.
+        assert_eq!(
+            clamp_timestamp(now + FUTURE_TOLERANCE, now),
+            (1_700_086_400, None)
+        );
+        assert_eq!(
+            clamp_timestamp(UNIX_EPOCH + Duration::from_secs(1_600_000_000), now),
+            (1_600_000_000, None)
+        );
+        assert_eq!(clamp_timestamp(UNIX_EPOCH, now), (0, None));
+    }
+
+    #[test]
+    fn sizes_at_and_past_4_gb_are_formatted_whole() {
+        assert_eq!(format_size(u32::MAX as u64), "4096.0 MB");
+        assert_eq!(format_size(1 << 32), "4096.0 MB");
+        assert_eq!(format_size((1 << 32) + (512 << 10)), "4096.5 MB");
+        assert_eq!(format_size(5 << 30), "5120.0 MB");
+        assert_eq!(format_size(1 << 40), "1048576.0 MB");
+    }
+
+    #[test]
+    fn song_roots_put_the_manual_root_first() {
+        let usc = tempfile::tempdir().unwrap();
+        let db = usc.path().join("maps.db");
+        assert_eq!(song_roots(&db, None), vec![usc.path().to_path_buf()]);
+
+        std::fs::write(usc.path().join("Main.cfg"), "SongFolder = \"custom\"\n").unwrap();
+        assert_eq!(
+            song_roots(&db, Some(Path::new("/mnt/songs"))),
+            vec![
+                PathBuf::from("/mnt/songs"),
+                usc.path().join("custom"),
+                usc.path().to_path_buf()
+            ]
+        );
+
+        // An absolute SongFolder replaces the USC folder, a root given twice is tried once.
+        std::fs::write(usc.path().join("Main.cfg"), "SongFolder = \"/mnt/songs\"\n").unwrap();
+        assert_eq!(
+            song_roots(&db, Some(usc.path())),
+            vec![usc.path().to_path_buf(), PathBuf::from("/mnt/songs")]
+        );
+    }
+
+    #[test]
+    fn stored_paths_resolve_against_each_root() {
+        let mut source = MemorySource::default();
+        source.insert("/usc/songs/pack/song/ex.ksh", b"chart".to_vec(), UNIX_EPOCH);
+        source.insert(
+            "/mnt/songs/pack/other/ex.ksh",
+            b"chart".to_vec(),
+            UNIX_EPOCH,
+        );
+        let cells = Cells::default();
+        let roots = [PathBuf::from("/usc/songs"), PathBuf::from("/mnt/songs")];
+        let options = InsertOptions {
+            song_roots: &roots,
+            ..insert_options(&source, &cells)
+        };
+        let resolve = |stored| resolve_stored_path(stored, &options);
+        assert_eq!(
+            resolve("/usc/songs/pack/song/ex.ksh"),
+            Some(PathBuf::from("/usc/songs/pack/song/ex.ksh"))
+        );
+        // An absolute path is taken as it is, not joined to a root.
+        assert_eq!(resolve("/elsewhere/pack/song/ex.ksh"), None);
+        assert_eq!(
+            resolve("pack/song/ex.ksh"),
+            Some(PathBuf::from("/usc/songs/pack/song/ex.ksh"))
+        );
+        assert_eq!(
+            resolve("pack/other/ex.ksh"),
+            Some(PathBuf::from("/mnt/songs/pack/other/ex.ksh"))
+        );
+        assert_eq!(resolve("pack/missing/ex.ksh"), None);
+    }
+
+    #[test]
+    fn backdating_spreads_files_before_the_cutoff_in_order() {
+        let cutoff = 1_577_836_800;
+        let policy = TimestampPolicy::Before(cutoff);
+        assert_eq!(
+            remap_timestamp(TimestampPolicy::Preserve, cutoff + 5, 0, 3),
+            cutoff + 5
+        );
+        assert_eq!(remap_timestamp(policy, cutoff - 5, 0, 3), cutoff - 5);
+        let written: Vec<i64> = (0..3)
+            .map(|i| remap_timestamp(policy, cutoff + 1000, i, 3))
+            .collect();
+        assert_eq!(written, vec![cutoff - 180, cutoff - 120, cutoff - 60]);
+        // The mtime doesn't matter once it is past the cutoff, only the file order does.
+        assert_eq!(remap_timestamp(policy, cutoff, 1, 3), cutoff - 120);
+        assert_eq!(remap_timestamp(policy, i64::MAX, 1, 3), cutoff - 120);
+        assert_eq!(remap_timestamp(policy, cutoff, 5, 3), cutoff - 60);
+        assert_eq!(remap_timestamp(TimestampPolicy::Before(100), 200, 0, 10), 0);
+    }
+
+    // Folder and file names as KSM users have them: spaces, dots, unicode, never "." or "..".
+    const NAME: &str = "[a-zA-Z0-9 _ぁ-んー][a-zA-Z0-9 ._ぁ-んー]{0,10}";
+
+    proptest::proptest! {
+        #[test]
+        fn reconstructing_any_path_never_panics(
+            components in proptest::collection::vec("[^/\\x00]{1,12}", 0..8),
+        ) {
+            let _ = reconstruct_chart_path(Path::new(&components.join("/")));
+        }
+
+        #[test]
+        fn charts_are_found_in_the_mirrored_tree(
+            prefix in proptest::collection::vec(NAME, 0..3),
+            profile in NAME,
+            pack in NAME,
+            song in NAME,
+            difficulty in NAME,
+        ) {
+            let prefix: PathBuf = prefix.iter().collect();
+            let score_path = prefix
+                .join("score")
+                .join(&profile)
+                .join(&pack)
+                .join(&song)
+                .join(format!("{}.ksc", difficulty));
+            let chart_path = prefix
+                .join("songs")
+                .join(&pack)
+                .join(&song)
+                .join(format!("{}.ksh", difficulty));
+            proptest::prop_assert_eq!(
+                reconstruct_chart_path(&score_path).unwrap(),
+                chart_path.clone()
+            );
+
+            let mut source = MemorySource::default();
+            proptest::prop_assert!(get_score_chart_path(&score_path, &source).is_err());
+            source.insert(chart_path.clone(), b"chart".to_vec(), UNIX_EPOCH);
+            proptest::prop_assert_eq!(
+                get_score_chart_path(&score_path, &source).unwrap(),
+                chart_path
+            );
+        }
+    }
+
+    fn scores_table(db: &Connection) -> Vec<Vec<rusqlite::types::Value>> {
+        let mut stmt = db.prepare("SELECT * FROM Scores ORDER BY rowid").unwrap();
+        let columns = stmt.column_count();
+        stmt.query_map([], |r| (0..columns).map(|i| r.get(i)).collect())
+            .unwrap()
+            .map(|r| r.unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn the_cached_insert_writes_the_same_rows_as_a_fresh_one() {
+        use crate::test_support::{line, Fixture, MODIFIED};
+        let fixture = Fixture::new().score("pack/song", "ex", &[]);
+        let score_path = Fixture::score_path("pack/song", "ex");
+        let cells = Cells::default();
+        let options = InsertOptions {
+            skip_duplicates: false,
+            ..insert_options(&fixture.source, &cells)
+        };
+        let scores: Vec<KsmScore> = [9_000_000, 9_500_000, 9_000_000, 10_000_000]
+            .iter()
+            .map(|&s| KsmScore::parse(&line(s), false).unwrap())
+            .collect();
+
+        let cached = memory_db();
+        let inserted: Vec<Inserted> = scores
+            .iter()
+            .map(|s| version_19(s, &cached, &score_path, &options).unwrap())
+            .collect();
+        let fresh = memory_db();
+        for (score, inserted) in scores.iter().zip(&inserted) {
+            let meta = schema::Meta {
+                timestamp: MODIFIED,
+                chart_hash: &inserted.chart_hash,
+                user_name: "",
+                user_id: 0,
+            };
+            fresh
+                .execute(
+                    &schema::insert_sql(schema::VERSION_19),
+                    rusqlite::params_from_iter(schema::bind(schema::VERSION_19, score, &meta)),
+                )
+                .unwrap();
+        }
+        assert_eq!(scores_table(&cached).len(), 4);
+        assert_eq!(scores_table(&cached), scores_table(&fresh));
+    }
+
+    #[test]
+    fn the_hash_cache_evicts_the_least_recently_used_chart() {
+        let mut cache = HashCache::with_capacity(2);
+        cache.insert(Path::new("a"), "1".to_string(), Some(1));
+        cache.insert(Path::new("b"), "2".to_string(), None);
+        assert_eq!(cache.get(Path::new("a")), Some(("1", Some(1))));
+        cache.insert(Path::new("c"), "3".to_string(), None);
+        assert_eq!(cache.get(Path::new("b")), None);
+        assert_eq!(cache.get(Path::new("a")), Some(("1", Some(1))));
+        assert_eq!(cache.get(Path::new("c")), Some(("3", None)));
+
+        // Inserting a cached path again replaces it rather than taking a second slot.
+        cache.insert(Path::new("c"), "4".to_string(), None);
+        assert_eq!(cache.get(Path::new("a")), Some(("1", Some(1))));
+        assert_eq!(cache.get(Path::new("c")), Some(("4", None)));
+        assert_eq!(cache.entries.len(), 2);
+        assert_eq!(cache.by_use.len(), 2);
+    }
+
+    #[test]
+    fn level_ranges_are_read_from_the_command_line_forms() {
+        let range = |min, max| Some(LevelRange { min, max });
+        assert_eq!(parse_level_range("15"), range(Some(15), Some(15)));
+        assert_eq!(parse_level_range(" 15-18 "), range(Some(15), Some(18)));
+        assert_eq!(parse_level_range("15 - 18"), range(Some(15), Some(18)));
+        assert_eq!(parse_level_range("15+"), range(Some(15), None));
+        assert_eq!(parse_level_range("-12"), range(None, Some(12)));
+        assert_eq!(parse_level_range(""), range(None, None));
+        for garbage in ["18-15", "abc", "15-x", "300", "1.5"].iter() {
+            assert_eq!(parse_level_range(garbage), None, "{}", garbage);
+        }
+    }
+
+    #[test]
+    fn level_ranges_hold_their_ends() {
+        let range = parse_level_range("15-18").unwrap();
+        assert!(!range.is_all());
+        assert!([15, 16, 18].iter().all(|&l| range.contains(l)));
+        assert!(!range.contains(14) && !range.contains(19));
+        let from = parse_level_range("15+").unwrap();
+        assert!(from.contains(20) && !from.contains(14));
+        let up_to = parse_level_range("-12").unwrap();
+        assert!(up_to.contains(1) && !up_to.contains(13));
+        let all = parse_level_range("").unwrap();
+        assert!(all.is_all() && all.contains(1) && all.contains(20));
+    }
+
+    #[test]
+    fn minimum_scores_allow_digit_grouping() {
+        assert_eq!(parse_score("8000000"), Some(8_000_000));
+        assert_eq!(parse_score("8,000,000"), Some(8_000_000));
+        assert_eq!(parse_score(" 9,500,000 "), Some(9_500_000));
+        assert_eq!(parse_score(""), Some(0));
+        for garbage in ["8.000.000", "-1", "8m", "99999999999"].iter() {
+            assert_eq!(parse_score(garbage), None, "{}", garbage);
+        }
+    }
+
+    #[test]
+    fn gauges_are_read_as_a_clamped_fraction() {
+        assert_eq!(parse_gauge("0").unwrap(), 0.0);
+        assert_eq!(parse_gauge("100").unwrap(), 1.0);
+        assert_eq!(parse_gauge("103").unwrap(), 1.0);
+        assert_eq!(parse_gauge("98,5").unwrap(), 0.985);
+        assert_eq!(parse_gauge("98.5").unwrap(), parse_gauge("98,5").unwrap());
+        for garbage in ["abc", "NaN", "inf", ""].iter() {
+            let err = parse_gauge(garbage).unwrap_err().to_string();
+            assert!(err.contains(&format!("\"{}\"", garbage)), "{}", err);
+        }
+    }
 }
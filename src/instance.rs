@@ -0,0 +1,304 @@
+use crate::cli;
+use async_std::io::{prelude::WriteExt, ReadExt};
+use iced_futures::futures;
+use std::ffi::OsString;
+use std::io::{BufRead, BufReader, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+
+const CONNECT_TIMEOUT: Duration = Duration::from_millis(500);
+const READ_TIMEOUT: Duration = Duration::from_secs(5);
+// Sent by the running instance to every connection, so a port reused by another program since a
+// crash isn't mistaken for it.
+const GREETING: &str = "ksm2usc";
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Forwarded {
+    Ksm(PathBuf),
+    Db(PathBuf),
+}
+
+pub fn paths_from_args() -> Vec<Forwarded> {
+    paths_in(std::env::args_os().skip(1))
+}
+
+fn paths_in(mut args: impl Iterator<Item = OsString>) -> Vec<Forwarded> {
+    let mut paths = Vec::new();
+    while let Some(arg) = args.next() {
+        // Every --option other than a flag takes a value, skip both.
+        let arg_str = arg.to_string_lossy();
+        if arg_str.starts_with("--") {
+            if !cli::FLAGS.contains(&arg_str.as_ref()) {
+                args.next();
+            }
+            continue;
+        }
+        let p = PathBuf::from(arg);
+        if p.is_dir() || ksm2usc_score_import::source::is_archive(&p) {
+            paths.push(Forwarded::Ksm(p));
+        } else if p.is_file() {
+            paths.push(Forwarded::Db(p));
+        }
+    }
+    paths
+}
+
+fn encode(paths: &[Forwarded]) -> String {
+    paths
+        .iter()
+        .filter_map(|p| match p {
+            Forwarded::Ksm(p) => p.to_str().map(|p| format!("ksm {}\n", p)),
+            Forwarded::Db(p) => p.to_str().map(|p| format!("db {}\n", p)),
+        })
+        .collect()
+}
+
+fn decode(message: &str) -> Vec<Forwarded> {
+    message
+        .lines()
+        .filter_map(|line| match line.split_once(' ') {
+            Some(("ksm", path)) => Some(Forwarded::Ksm(PathBuf::from(path))),
+            Some(("db", path)) => Some(Forwarded::Db(PathBuf::from(path))),
+            _ => None,
+        })
+        .collect()
+}
+
+#[derive(Debug)]
+pub struct PrimaryGuard {
+    listener: Arc<TcpListener>,
+    lock_path: PathBuf,
+}
+
+impl Drop for PrimaryGuard {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.lock_path);
+    }
+}
+
+pub enum Acquired {
+    Primary(Option<PrimaryGuard>),
+    HandedOff,
+}
+
+fn greeting(pid: u32) -> String {
+    format!("{} {}\n", GREETING, pid)
+}
+
+// Connected only once the process named in the lock file answered on its port.
+fn running_instance(lock_path: &Path) -> Option<TcpStream> {
+    let contents = std::fs::read_to_string(lock_path).ok()?;
+    let mut fields = contents.split_whitespace();
+    let pid: u32 = fields.next()?.parse().ok()?;
+    let port: u16 = fields.next()?.parse().ok()?;
+    let addr = SocketAddr::from(([127, 0, 0, 1], port));
+    let stream = TcpStream::connect_timeout(&addr, CONNECT_TIMEOUT).ok()?;
+    stream.set_read_timeout(Some(CONNECT_TIMEOUT)).ok()?;
+    let mut answer = String::new();
+    BufReader::new(&stream).read_line(&mut answer).ok()?;
+    Some(stream).filter(|_| answer == greeting(pid))
+}
+
+pub fn acquire(forward: &[Forwarded]) -> Acquired {
+    match ksm2usc_score_import::config::lock_path() {
+        Some(path) => acquire_at(path, forward),
+        None => Acquired::Primary(None),
+    }
+}
+
+fn acquire_at(lock_path: PathBuf, forward: &[Forwarded]) -> Acquired {
+    // A lock whose owner doesn't answer was left behind by a crash.
+    if let Some(mut stream) = running_instance(&lock_path) {
+        if stream.write_all(encode(forward).as_bytes()).is_ok() {
+            return Acquired::HandedOff;
+        }
+    }
+
+    let guard = TcpListener::bind(("127.0.0.1", 0))
+        .and_then(|listener| {
+            let port = listener.local_addr()?.port();
+            if let Some(parent) = lock_path.parent() {
+                std::fs::create_dir_all(parent)?;
+            }
+            std::fs::write(&lock_path, format!("{} {}", std::process::id(), port))?;
+            Ok(PrimaryGuard {
+                listener: Arc::new(listener),
+                lock_path,
+            })
+        })
+        .ok();
+    Acquired::Primary(guard)
+}
+
+pub fn listen(guard: &PrimaryGuard) -> iced::Subscription<Vec<Forwarded>> {
+    iced::Subscription::from_recipe(Listener(guard.listener.clone()))
+}
+
+pub struct Listener(Arc<TcpListener>);
+
+async fn next_handoff(
+    listener: async_std::net::TcpListener,
+) -> Option<(Vec<Forwarded>, async_std::net::TcpListener)> {
+    loop {
+        let (mut stream, _) = listener.accept().await.ok()?;
+        if stream
+            .write_all(greeting(std::process::id()).as_bytes())
+            .await
+            .is_err()
+        {
+            continue;
+        }
+        let mut message = String::new();
+        let read = async_std::io::timeout(READ_TIMEOUT, stream.read_to_string(&mut message)).await;
+        if read.is_ok() {
+            return Some((decode(&message), listener));
+        }
+    }
+}
+
+impl<H, I> iced_native::subscription::Recipe<H, I> for Listener
+where
+    H: std::hash::Hasher,
+{
+    type Output = Vec<Forwarded>;
+
+    fn hash(&self, state: &mut H) {
+        use std::hash::Hash;
+
+        std::any::TypeId::of::<Self>().hash(state);
+    }
+
+    fn stream(
+        self: Box<Self>,
+        _input: iced_futures::BoxStream<I>,
+    ) -> iced_futures::BoxStream<Self::Output> {
+        match self.0.try_clone() {
+            Ok(listener) => Box::pin(futures::stream::unfold(
+                async_std::net::TcpListener::from(listener),
+                next_handoff,
+            )),
+            Err(_) => Box::pin(futures::stream::empty()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn flags_are_skipped_and_option_values_with_them() {
+        let dir = tempfile::tempdir().unwrap();
+        let db = dir.path().join("maps.db");
+        std::fs::write(&db, b"").unwrap();
+        let args = [
+            OsString::from(cli::DRY_RUN),
+            OsString::from("--user-name"),
+            dir.path().into(),
+            OsString::from(cli::JSON),
+            dir.path().into(),
+            db.clone().into(),
+        ];
+        assert_eq!(
+            paths_in(args.iter().cloned()),
+            vec![Forwarded::Ksm(dir.path().to_path_buf()), Forwarded::Db(db)]
+        );
+    }
+
+    #[test]
+    fn forwarded_paths_survive_the_handoff() {
+        let paths = vec![
+            Forwarded::Ksm(PathBuf::from("/games/KSM")),
+            Forwarded::Db(PathBuf::from("/games/USC/maps.db")),
+        ];
+        assert_eq!(decode(&encode(&paths)), paths);
+    }
+
+    fn primary(acquired: Acquired) -> PrimaryGuard {
+        match acquired {
+            Acquired::Primary(Some(guard)) => guard,
+            Acquired::Primary(None) => panic!("No lock was taken"),
+            Acquired::HandedOff => panic!("Handed off instead of taking the lock"),
+        }
+    }
+
+    fn lock_contents(path: &Path) -> (u32, u16) {
+        let contents = std::fs::read_to_string(path).unwrap();
+        let (pid, port) = contents.split_once(' ').unwrap();
+        (pid.parse().unwrap(), port.parse().unwrap())
+    }
+
+    #[test]
+    fn the_first_instance_takes_the_lock_and_frees_it_on_drop() {
+        let dir = tempfile::tempdir().unwrap();
+        let lock_path = dir.path().join("config").join("ksm2usc.lock");
+        let guard = primary(acquire_at(lock_path.clone(), &[]));
+        let port = guard.listener.local_addr().unwrap().port();
+        assert_eq!(lock_contents(&lock_path), (std::process::id(), port));
+        drop(guard);
+        assert!(!lock_path.exists());
+    }
+
+    #[test]
+    fn a_lock_nobody_answers_is_taken_over() {
+        let dir = tempfile::tempdir().unwrap();
+        let lock_path = dir.path().join("ksm2usc.lock");
+
+        // Nothing listens on the port any more.
+        let closed = TcpListener::bind(("127.0.0.1", 0)).unwrap();
+        let port = closed.local_addr().unwrap().port();
+        drop(closed);
+        std::fs::write(&lock_path, format!("4000000 {}", port)).unwrap();
+        let guard = primary(acquire_at(lock_path.clone(), &[]));
+        assert_eq!(lock_contents(&lock_path).0, std::process::id());
+        drop(guard);
+
+        // Another program got the port and accepts, but never greets.
+        let stranger = TcpListener::bind(("127.0.0.1", 0)).unwrap();
+        let port = stranger.local_addr().unwrap().port();
+        std::fs::write(&lock_path, format!("4000000 {}", port)).unwrap();
+        let guard = primary(acquire_at(lock_path.clone(), &[]));
+        assert_ne!(guard.listener.local_addr().unwrap().port(), port);
+        assert_eq!(lock_contents(&lock_path).0, std::process::id());
+        drop(guard);
+
+        // A greeting from a different process than the lock names is stale too.
+        let running = primary(acquire_at(dir.path().join("other.lock"), &[]));
+        let port = running.listener.local_addr().unwrap().port();
+        std::fs::write(&lock_path, format!("4000000 {}", port)).unwrap();
+        let listener = running.listener.try_clone().unwrap();
+        let answering = std::thread::spawn(move || {
+            futures::executor::block_on(next_handoff(async_std::net::TcpListener::from(listener)))
+        });
+        primary(acquire_at(lock_path, &[]));
+        drop(answering);
+    }
+
+    #[test]
+    fn a_second_instance_hands_its_paths_to_the_first() {
+        let dir = tempfile::tempdir().unwrap();
+        let lock_path = dir.path().join("ksm2usc.lock");
+        let guard = primary(acquire_at(lock_path.clone(), &[]));
+        let listener = guard.listener.try_clone().unwrap();
+        let received = std::thread::spawn(move || {
+            futures::executor::block_on(next_handoff(async_std::net::TcpListener::from(listener)))
+                .map(|(paths, _)| paths)
+        });
+        let paths = vec![
+            Forwarded::Ksm(PathBuf::from("/games/KSM")),
+            Forwarded::Db(PathBuf::from("/games/USC/maps.db")),
+        ];
+        assert!(matches!(
+            acquire_at(lock_path.clone(), &paths),
+            Acquired::HandedOff
+        ));
+        assert_eq!(received.join().unwrap(), Some(paths));
+        // The lock still belongs to the first instance.
+        assert_eq!(
+            lock_contents(&lock_path).1,
+            guard.listener.local_addr().unwrap().port()
+        );
+    }
+}
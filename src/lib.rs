@@ -0,0 +1,131 @@
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+
+pub mod checksum;
+pub mod config;
+pub mod detect;
+pub mod export;
+pub mod history;
+pub mod importer;
+pub mod importer_funcs;
+pub mod report;
+pub mod schema;
+pub mod source;
+pub mod style;
+#[cfg(test)]
+mod test_support;
+mod watchdog;
+
+pub use importer::{import_scores, ImportOptions, KsmScore, Progress};
+
+// Two runs over the same files give the same summary. The buckets and warnings follow the score
+// files by pack and then path, failures are sorted by kind and then the same way, and how long
+// the run took is left out of the serialized form, reports put it in their header.
+#[derive(Debug, Default, Clone, Serialize)]
+pub struct Summary {
+    pub files_found: u32,
+    pub files_processed: u32,
+    pub files_unmodified: u32,
+    pub scores_found: u32,
+    pub scores_imported: u32,
+    pub scores_skipped: u32,
+    // Rows of earlier imports whose score came back with a better badge, updated in place.
+    pub scores_upgraded: u32,
+    pub new_charts: Vec<String>,
+    pub improvements: Vec<String>,
+    pub additional_plays: Vec<String>,
+    pub charts_matched_via_db: u32,
+    pub timestamps_adjusted: u32,
+    pub legacy_converted: u32,
+    pub scores_with_extra_fields: u32,
+    pub scores_with_nonstandard_options: u32,
+    pub timestamps_backdated: u32,
+    pub unindexed_charts: u32,
+    pub skipped: BTreeMap<importer::SkipRule, u32>,
+    pub optimized_size: Option<(u64, u64)>,
+    pub optimize_error: Option<String>,
+    #[serde(skip)]
+    pub timings: importer::Timings,
+    pub warnings: Vec<String>,
+    pub spill_path: Option<PathBuf>,
+    pub backup_path: Option<PathBuf>,
+    pub spilled_failures: u32,
+    pub fail_messages: Vec<Failure>,
+    // Every row the import wrote, so the run can be undone. Too many to put in a report.
+    #[serde(skip)]
+    pub inserted_rows: Vec<InsertedRow>,
+    // The upgraded rows with their new badge.
+    #[serde(skip)]
+    pub upgraded_rows: Vec<InsertedRow>,
+    // Every score the run imported, or would have for a dry run, for export::write.
+    #[serde(skip)]
+    pub imported_scores: Vec<ImportedScore>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize)]
+pub enum FailureKind {
+    MissingChart,
+    Parse,
+    Insert,
+    Read,
+    Crashed,
+}
+
+impl FailureKind {
+    pub fn label(self) -> &'static str {
+        match self {
+            FailureKind::MissingChart => "Missing chart files",
+            FailureKind::Parse => "Parse errors",
+            FailureKind::Insert => "Insert errors",
+            FailureKind::Read => "Unreadable score files",
+            FailureKind::Crashed => "Score files that crashed the importer",
+        }
+    }
+}
+
+// `message` is the full line shown to the user, `reason` only the error so that repeats of the
+// same problem group together.
+#[derive(Debug, Clone, Serialize)]
+pub struct Failure {
+    pub path: PathBuf,
+    pub kind: FailureKind,
+    pub reason: String,
+    pub message: String,
+}
+
+// A Scores row written by an import, enough to find it again and to tell whether it was changed
+// since.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct InsertedRow {
+    pub rowid: i64,
+    pub chart_hash: String,
+    pub score: u32,
+    pub timestamp: i64,
+    // KSM's badge of the score, unknown for rows recorded before badges were kept.
+    pub badge: Option<u32>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ImportedScore {
+    pub chart_hash: String,
+    pub score: u32,
+    pub badge: u32,
+    // As a fraction, 0.875 for 87.5%.
+    pub gauge: f64,
+    pub timestamp: i64,
+}
+
+impl Summary {
+    pub fn failure_counts(&self) -> BTreeMap<FailureKind, u32> {
+        let mut counts = BTreeMap::new();
+        for failure in &self.fail_messages {
+            *counts.entry(failure.kind).or_insert(0) += 1;
+        }
+        counts
+    }
+
+    pub fn failures_of(&self, kind: FailureKind) -> impl Iterator<Item = &Failure> {
+        self.fail_messages.iter().filter(move |f| f.kind == kind)
+    }
+}
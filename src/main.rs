@@ -1,20 +1,25 @@
 use anyhow::Result;
 use iced::{
-    button, scrollable, Application, Button, Column, Command, Container, Length, Row, Scrollable,
-    Subscription, Text,
+    button, pick_list, scrollable, text_input, Application, Button, Checkbox, Column, Command,
+    Container, Length, PickList, Row, Scrollable, Subscription, Text, TextInput,
 };
-use importer::Progress;
+use importer::{Progress, Severity};
+use importer_funcs::InsertKind;
+use ksm2usc_score_import::{
+    checksum, config, detect, export, history, importer, importer_funcs, report, schema, source,
+    style, FailureKind, Summary,
+};
+use std::collections::{BTreeMap, BTreeSet};
 use std::path::PathBuf;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use style::Status;
 
-mod importer;
-mod importer_funcs;
-
-#[derive(Debug, Default, Clone)]
-pub struct Summary {
-    scores_found: u32,
-    scores_imported: u32,
-    fail_messages: Vec<String>,
-}
+mod cli;
+mod i18n;
+mod instance;
+mod table;
 
 #[derive(Debug, Default)]
 struct State {
@@ -22,23 +27,228 @@ struct State {
     db_path: Option<PathBuf>,
     progress: Option<importer::Progress>,
     summary: Option<Summary>,
+    preflight: Option<importer::Preflight>,
+    // The preview runs before the import starts, Import is disabled meanwhile.
+    checking: bool,
+    source: Option<Arc<dyn source::Source>>,
+    crash_message: Option<String>,
+    cancelled: bool,
+    options: importer::ImportOptions,
+    backdate: bool,
+    backdate_before: String,
+    backdate_input: text_input::State,
+    min_score: String,
+    min_score_input: text_input::State,
+    levels: String,
+    levels_input: text_input::State,
+    modified_filter: bool,
+    modified_after: String,
+    modified_after_input: text_input::State,
+    since_last_import_button: button::State,
+    import_started: i64,
+    label: String,
+    label_input: text_input::State,
+    // The finished run as recorded in the history, saved reports start with it.
+    report_header: Option<report::Header>,
+    user_name_input: text_input::State,
+    user_id_input: text_input::State,
+    // Players with scores in the picked maps.db, to import as one of them.
+    db_users: Vec<importer::UserIdentity>,
+    db_users_list: pick_list::State<importer::UserIdentity>,
+    create_db: bool,
+    config: config::Config,
+    locale: i18n::Locale,
+    thousands_separator: char,
+    show_intro: bool,
+    instance: Option<instance::PrimaryGuard>,
     ksm_button: button::State,
+    ksm_archive_button: button::State,
+    detect_ksm_button: button::State,
+    detected_ksm: Vec<PathBuf>,
+    detected_ksm_buttons: Vec<button::State>,
     db_button: button::State,
+    new_db_button: button::State,
+    detect_db_button: button::State,
+    detected_dbs: Vec<PathBuf>,
+    detected_db_buttons: Vec<button::State>,
+    songs_root_button: button::State,
+    scale_down_button: button::State,
+    scale_up_button: button::State,
     import_button: button::State,
     back_button: button::State,
+    cancel_button: button::State,
+    pause_button: button::State,
+    start_over_button: button::State,
+    save_report_button: button::State,
+    export_button: button::State,
+    verify_backup_button: button::State,
+    copy_errors_button: button::State,
+    copied_at: Option<Instant>,
+    // Counts Import presses, see importer::import_source.
+    run_id: u64,
+    new_charts_button: button::State,
+    improvements_button: button::State,
+    additional_plays_button: button::State,
+    expanded_bucket: Option<InsertKind>,
+    bucket_scroll: scrollable::State,
+    bucket_table: table::TableState,
+    expanded_failures: Option<FailureKind>,
+    failure_kind_buttons: Vec<button::State>,
+    failure_table: table::TableState,
     error_scroll: scrollable::State,
+    history: Vec<history::Run>,
+    show_history: bool,
+    selected_run: Option<usize>,
+    selected_run_files: history::RunFiles,
+    history_button: button::State,
+    forget_paths_button: button::State,
+    history_back_button: button::State,
+    export_run_button: button::State,
+    retry_run_button: button::State,
+    undo_run_button: button::State,
+    check_label_button: button::State,
+    undo_checked_button: button::State,
+    // Runs ticked in the history list, to be undone together.
+    checked_runs: BTreeSet<usize>,
+    run_buttons: Vec<button::State>,
+    history_scroll: scrollable::State,
+    closing: Option<Instant>,
+    file_hovered: bool,
+    exiting: bool,
 }
 
 #[derive(Debug, Clone)]
+#[allow(clippy::large_enum_variant)]
 enum Message {
     KsmButton,
+    KsmArchiveButton,
+    DetectKsm,
+    PickDetectedKsm(usize),
     DbButton,
+    NewDbButton,
+    SongsRootButton,
+    DetectDb,
+    PickDetectedDb(usize),
     BackButton,
     Start,
+    ShowHistory,
+    ForgetPaths,
+    HideHistory,
+    SelectRun(usize),
+    ExportRun,
+    RetryRun,
+    UndoRun,
+    CheckRun(usize, bool),
+    CheckLabel,
+    UndoCheckedRuns,
+    RunUndone(Result<history::Undone, String>),
+    ToggleBucket(InsertKind),
+    SortBucketByName,
+    SortBucketByCount,
+    ExpandBucketRow(usize),
+    ToggleFailures(FailureKind),
+    SortFailuresByName,
+    SortFailuresByCount,
+    ExpandFailureRow(usize),
+    HideIntro(bool),
+    ScaleDown,
+    ScaleUp,
+    HighContrast(bool),
+    Forwarded(Vec<instance::Forwarded>),
+    Optimize(bool),
+    LowMemory(bool),
+    SkipLegacy(bool),
+    BestOnly(bool),
+    Backdate(bool),
+    BackdateBefore(String),
+    MinScore(String),
+    Levels(String),
+    ModifiedFilter(bool),
+    ModifiedAfter(String),
+    SinceLastImport,
+    Label(String),
+    UserName(String),
+    UserId(String),
+    PickUser(importer::UserIdentity),
+    SkipDuplicates(bool),
+    ClearsOnly(bool),
+    NonstandardOptions(bool),
+    ImprovementsOnly(bool),
+    CompareAcrossGauges(bool),
+    DryRun(bool),
+    AllOrNothing(bool),
+    SkipBackup(bool),
+    CancelImport,
+    PauseImport(bool),
+    StartOver,
+    SaveReport,
+    CopyErrors,
+    CopiedTick,
+    ReportSaved(Result<(), String>),
+    ExportScores,
+    ScoresExported(Result<(), String>),
+    VerifyBackup,
+    BackupVerified(checksum::Check),
+    Checked(
+        PathBuf,
+        PathBuf,
+        Result<(Arc<dyn source::Source>, importer::Preflight), String>,
+    ),
     Progress(importer::Progress),
+    CloseRequested,
+    FileHovered(bool),
+    FileDropped(PathBuf),
+    ShutdownTick,
 }
 
+// How long a cancelled import gets to finish its current file when the window is closed.
+const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(5);
+const COPIED_NOTICE: Duration = Duration::from_secs(2);
+
 fn main() -> Result<()> {
+    config::install_panic_log();
+    let heartbeat_file = arg_value("--heartbeat-file");
+    let label = arg_value("--label")
+        .map(|l| l.to_string_lossy().to_string())
+        .unwrap_or_default();
+    if let (Some(ksm), Some(db)) = (arg_value("--ksm"), arg_value("--db")) {
+        let options = importer::ImportOptions {
+            user_name: arg_value("--user-name")
+                .map(|n| n.to_string_lossy().to_string())
+                .unwrap_or_default(),
+            user_id: arg_value("--user-id")
+                .map(|n| n.to_string_lossy().to_string())
+                .unwrap_or_default(),
+            dry_run: cli::flag(cli::DRY_RUN),
+            best_effort_schema: cli::flag(cli::TRY_ANYWAY),
+            all_or_nothing: cli::flag(cli::ALL_OR_NOTHING),
+            skip_backup: cli::flag(cli::NO_BACKUP),
+            improvements_only: cli::flag(cli::IMPROVEMENTS_ONLY),
+            compare_across_gauges: cli::flag(cli::ACROSS_GAUGES),
+            policy: importer::Policy {
+                clears_only: cli::flag(cli::CLEARS_ONLY),
+                nonstandard_options: cli::flag(cli::NONSTANDARD_OPTIONS),
+                min_score: parsed_arg("--min-score", importer_funcs::parse_score).unwrap_or(0),
+                levels: parsed_arg("--levels", importer_funcs::parse_level_range)
+                    .unwrap_or_default(),
+                // "last" is the start of the last import that finished, when there was one.
+                modified_after: parsed_arg("--modified-after", |s| match s {
+                    "last" => config::load().last_import,
+                    s => importer_funcs::parse_date_time(s),
+                }),
+                ..Default::default()
+            },
+            heartbeat_file,
+            ..Default::default()
+        };
+        let json = cli::flag(cli::JSON);
+        std::process::exit(cli::run(&ksm, &db, &label, options, json));
+    }
+    let forwarded = instance::paths_from_args();
+    let instance = match instance::acquire(&forwarded) {
+        instance::Acquired::Primary(guard) => guard,
+        instance::Acquired::HandedOff => return Ok(()),
+    };
     let settings = iced::Settings {
         window: iced::window::Settings {
             size: (800, 600),
@@ -51,12 +261,15 @@ fn main() -> Result<()> {
             icon: None,
         },
         antialiasing: true,
+        exit_on_close_request: false,
+        flags: (instance, forwarded, heartbeat_file, label),
         ..Default::default()
     };
     Ok(State::run(settings)?)
 }
 enum Stage {
     Paths,
+    History,
     Importing,
     Finished,
 }
@@ -66,20 +279,67 @@ impl Application for State {
 
     type Message = Message;
 
-    type Flags = ();
+    type Flags = (
+        Option<instance::PrimaryGuard>,
+        Vec<instance::Forwarded>,
+        Option<PathBuf>,
+        String,
+    );
 
-    fn new(_flags: Self::Flags) -> (Self, iced::Command<Self::Message>) {
-        (Self::default(), Command::none())
+    fn new(
+        (instance, forwarded, heartbeat_file, label): Self::Flags,
+    ) -> (Self, iced::Command<Self::Message>) {
+        let config = config::load();
+        let mut state = Self {
+            show_intro: config.show_intro,
+            ksm_path: config.last_ksm_path.clone(),
+            db_path: config.last_db_path.clone(),
+            backdate_before: "2020-01-01".to_string(),
+            label,
+            locale: i18n::Locale::detect(config.language.as_deref()),
+            thousands_separator: config.thousands_separator.unwrap_or_else(|| {
+                table::thousands_separator(&i18n::system_locale("LC_NUMERIC").unwrap_or_default())
+            }),
+            config,
+            instance,
+            options: importer::ImportOptions {
+                heartbeat_file,
+                ..Default::default()
+            },
+            ..Self::default()
+        };
+        state.prefill(forwarded);
+        state.load_db_users();
+        report_dirty_shutdown();
+        (state, Command::none())
     }
 
     fn title(&self) -> String {
         "KSM To USC Score Import Tool".to_string()
     }
 
+    fn should_exit(&self) -> bool {
+        self.exiting
+    }
+
+    fn scale_factor(&self) -> f64 {
+        self.config.ui_scale
+    }
+
     fn subscription(&self) -> Subscription<Self::Message> {
-        match (&self.ksm_path, &self.db_path, &self.progress) {
-            (Some(ksm_path), Some(db_path), Some(_progress)) => {
-                match importer::import(ksm_path, db_path) {
+        let handoff = match &self.instance {
+            Some(guard) => instance::listen(guard).map(Message::Forwarded),
+            None => Subscription::none(),
+        };
+        let import = match (&self.source, &self.ksm_path, &self.db_path, &self.progress) {
+            (Some(source), Some(ksm_path), Some(db_path), Some(_progress)) => {
+                match importer::import_source(
+                    ksm_path,
+                    source.clone(),
+                    db_path,
+                    self.options.clone(),
+                    self.run_id,
+                ) {
                     Ok(s) => s.map(Message::Progress),
                     Err(e) => {
                         rfd::MessageDialog::new()
@@ -93,49 +353,803 @@ impl Application for State {
                 }
             }
             _ => Subscription::none(),
-        }
+        };
+        let window = iced_native::subscription::events_with(|event, _status| match event {
+            iced_native::Event::Window(event) => match event {
+                iced_native::window::Event::CloseRequested => Some(Message::CloseRequested),
+                iced_native::window::Event::FileHovered(_) => Some(Message::FileHovered(true)),
+                iced_native::window::Event::FilesHoveredLeft => Some(Message::FileHovered(false)),
+                iced_native::window::Event::FileDropped(path) => Some(Message::FileDropped(path)),
+                _ => None,
+            },
+            _ => None,
+        });
+        let shutdown = match self.closing {
+            Some(_) => iced::time::every(Duration::from_millis(250)).map(|_| Message::ShutdownTick),
+            None => Subscription::none(),
+        };
+        let copied = match self.copied_at {
+            Some(_) => iced::time::every(Duration::from_millis(250)).map(|_| Message::CopiedTick),
+            None => Subscription::none(),
+        };
+        Subscription::batch(vec![handoff, import, window, shutdown, copied])
     }
     fn update(
         &mut self,
         message: Self::Message,
-        _clipboard: &mut iced::Clipboard,
+        clipboard: &mut iced::Clipboard,
     ) -> iced::Command<Self::Message> {
+        let db_path = self.db_path.clone();
         match message {
-            Message::Progress(p) => match p {
-                Progress::Finished(s) => {
-                    self.progress = None;
-                    self.summary = Some(s)
+            Message::Progress(p) => {
+                match p {
+                    Progress::Finished(s) => {
+                        self.progress = None;
+                        self.options.only_files.clear();
+                        self.record_run(&s, false);
+                        if !self.options.dry_run {
+                            self.config.last_import = Some(self.import_started);
+                            save_config(&self.config);
+                        }
+                        self.summary = Some(s)
+                    }
+                    Progress::Cancelled(s) => {
+                        self.progress = None;
+                        self.options.only_files.clear();
+                        self.cancelled = true;
+                        self.record_run(&s, true);
+                        self.summary = Some(s)
+                    }
+                    Progress::Errored(e, Some(s)) => {
+                        self.progress = None;
+                        self.options.only_files.clear();
+                        self.crash_message = Some(e);
+                        self.record_run(&s, true);
+                        self.summary = Some(s)
+                    }
+                    _ => self.progress = Some(p),
                 }
-                _ => self.progress = Some(p),
+                if self.closing.is_some() && !self.import_running() {
+                    self.exiting = true;
+                }
+            }
+            Message::CloseRequested => if !self.import_running() {
+                self.exiting = true;
+            } else if rfd::MessageDialog::new()
+                .set_title("Import in progress")
+                .set_description(
+                    "An import is still running. Stop it after the current score file and quit?",
+                )
+                .set_level(rfd::MessageLevel::Warning)
+                .set_buttons(rfd::MessageButtons::OkCancle)
+                .show()
+            {
+                self.options.cancel.store(true, Ordering::Relaxed);
+                self.closing = Some(Instant::now());
             },
-            Message::KsmButton => self.ksm_path = rfd::FileDialog::new().pick_folder(),
-            Message::DbButton => {
+            Message::FileHovered(hovered) => self.file_hovered = hovered,
+            Message::FileDropped(path) => {
+                self.file_hovered = false;
+                // Same as forwarded paths, a running import keeps its paths.
+                if self.progress.is_some() {
+                    return Command::none();
+                }
+                if (path.is_dir() && path.join("score").is_dir()) || source::is_archive(&path) {
+                    self.detected_ksm.clear();
+                    self.ksm_path = Some(path);
+                } else if path.is_file() && path.extension().is_some_and(|e| e == "db") {
+                    self.create_db = false;
+                    self.detected_dbs.clear();
+                    self.db_path = Some(path);
+                } else {
+                    rfd::MessageDialog::new()
+                        .set_title("Unrecognized path")
+                        .set_description(&format!(
+                            "\"{}\" is neither a KShootMania folder with a score folder \
+                            nor a .db file",
+                            path.to_str().unwrap_or_default()
+                        ))
+                        .set_level(rfd::MessageLevel::Warning)
+                        .set_buttons(rfd::MessageButtons::Ok)
+                        .show();
+                }
+            }
+            Message::CancelImport => self.options.cancel.store(true, Ordering::Relaxed),
+            Message::PauseImport(pause) => self.options.pause.store(pause, Ordering::Relaxed),
+            Message::ShutdownTick => {
+                if self
+                    .closing
+                    .is_some_and(|t| t.elapsed() >= SHUTDOWN_TIMEOUT)
+                {
+                    self.write_dirty_shutdown();
+                    self.exiting = true;
+                }
+            }
+            Message::KsmButton => {
+                self.detected_ksm.clear();
+                self.ksm_path = rfd::FileDialog::new().pick_folder()
+            }
+            Message::KsmArchiveButton => {
+                self.ksm_path = rfd::FileDialog::new()
+                    .add_filter("Score archive", &["zip"])
+                    .pick_file()
+            }
+            Message::DbButton => self.pick_db(),
+            Message::NewDbButton => {
                 self.db_path = rfd::FileDialog::new()
                     .add_filter("Database", &["db"])
-                    .pick_file()
+                    .set_file_name("maps.db")
+                    .save_file();
+                self.create_db = self.db_path.as_ref().is_some_and(|p| !p.exists());
+            }
+            Message::DetectDb => {
+                let mut found = detect::usc_databases();
+                self.detected_dbs.clear();
+                match found.len() {
+                    0 => self.pick_db(),
+                    1 => {
+                        self.create_db = false;
+                        self.db_path = found.pop();
+                    }
+                    n => {
+                        self.detected_db_buttons = vec![button::State::new(); n];
+                        self.detected_dbs = found;
+                    }
+                }
+            }
+            Message::DetectKsm => {
+                self.detected_ksm.clear();
+                let installs = detect::ksm_installs();
+                let mut found: Vec<PathBuf> = installs
+                    .iter()
+                    .filter(|i| i.has_scores)
+                    .map(|i| i.path.clone())
+                    .collect();
+                match found.len() {
+                    0 if !installs.is_empty() => {
+                        rfd::MessageDialog::new()
+                            .set_title("No scores yet")
+                            .set_description(&format!(
+                                "Found K-Shoot Mania at \"{}\", but it has no score folder. \
+                                KSM creates it the first time a play is saved.",
+                                installs[0].path.to_str().unwrap_or_default()
+                            ))
+                            .set_level(rfd::MessageLevel::Warning)
+                            .set_buttons(rfd::MessageButtons::Ok)
+                            .show();
+                    }
+                    0 => self.ksm_path = rfd::FileDialog::new().pick_folder(),
+                    1 => self.ksm_path = found.pop(),
+                    n => {
+                        self.detected_ksm_buttons = vec![button::State::new(); n];
+                        self.detected_ksm = found;
+                    }
+                }
+            }
+            Message::PickDetectedKsm(i) => {
+                self.ksm_path = Some(self.detected_ksm.swap_remove(i));
+                self.detected_ksm.clear();
+            }
+            Message::PickDetectedDb(i) => {
+                self.create_db = false;
+                self.db_path = Some(self.detected_dbs.swap_remove(i));
+                self.detected_dbs.clear();
+            }
+            Message::SongsRootButton => {
+                self.config.usc_songs_root = rfd::FileDialog::new().pick_folder();
+                save_config(&self.config);
             }
             Message::Start => {
+                self.options.songs_root = self.config.usc_songs_root.clone();
+                self.options.policy.timestamps = if self.backdate {
+                    match importer_funcs::parse_date(&self.backdate_before) {
+                        Some(cutoff) => importer_funcs::TimestampPolicy::Before(cutoff),
+                        None => {
+                            rfd::MessageDialog::new()
+                                .set_title("Invalid date")
+                                .set_description(&format!(
+                                    "\"{}\" is not a date, use YYYY-MM-DD",
+                                    self.backdate_before
+                                ))
+                                .set_level(rfd::MessageLevel::Error)
+                                .set_buttons(rfd::MessageButtons::Ok)
+                                .show();
+                            return Command::none();
+                        }
+                    }
+                } else {
+                    importer_funcs::TimestampPolicy::Preserve
+                };
+                let filters = (
+                    importer_funcs::parse_score(&self.min_score),
+                    importer_funcs::parse_level_range(&self.levels),
+                );
+                match filters {
+                    (Some(min_score), Some(levels)) => {
+                        self.options.policy.min_score = min_score;
+                        self.options.policy.levels = levels;
+                    }
+                    _ => {
+                        rfd::MessageDialog::new()
+                            .set_title("Invalid filter")
+                            .set_description(
+                                "The minimum score must be a number, and levels a level like \
+                                15, a range like 15-18, or 15+",
+                            )
+                            .set_level(rfd::MessageLevel::Error)
+                            .set_buttons(rfd::MessageButtons::Ok)
+                            .show();
+                        return Command::none();
+                    }
+                }
+                self.options.policy.modified_after = if self.modified_filter {
+                    match importer_funcs::parse_date_time(&self.modified_after) {
+                        Some(cutoff) => Some(cutoff),
+                        None => {
+                            rfd::MessageDialog::new()
+                                .set_title("Invalid date")
+                                .set_description(&format!(
+                                    "\"{}\" is not a date, use YYYY-MM-DD or YYYY-MM-DD HH:MM",
+                                    self.modified_after
+                                ))
+                                .set_level(rfd::MessageLevel::Error)
+                                .set_buttons(rfd::MessageButtons::Ok)
+                                .show();
+                            return Command::none();
+                        }
+                    }
+                } else {
+                    None
+                };
+                self.import_started = importer_funcs::now();
                 if let (Some(db), Some(ksm)) = (&self.db_path, &self.ksm_path) {
-                    match importer::validate_paths(ksm, db) {
-                        Ok(_) => self.progress = Some(importer::Progress::Started),
-                        Err(e) => {
+                    if self.options.dry_run && !db.exists() {
+                        rfd::MessageDialog::new()
+                            .set_title("Nothing to compare against")
+                            .set_description(
+                                "A dry run doesn't create the database, pick an existing maps.db",
+                            )
+                            .set_level(rfd::MessageLevel::Error)
+                            .set_buttons(rfd::MessageButtons::Ok)
+                            .show();
+                        return Command::none();
+                    }
+                    if self.create_db && !db.exists() {
+                        let confirmed = rfd::MessageDialog::new()
+                            .set_title("Create new maps.db")
+                            .set_description(&format!(
+                                "A new database (version {}) will be created at {:?}. \
+                                USC has not scanned your charts into it yet, so run a \
+                                song rescan in USC before the imported scores show up.",
+                                schema::NEWEST_VERSION,
+                                db
+                            ))
+                            .set_level(rfd::MessageLevel::Warning)
+                            .set_buttons(rfd::MessageButtons::OkCancle)
+                            .show();
+                        if !confirmed {
+                            return Command::none();
+                        }
+                        if let Err(e) = schema::create_database(db) {
                             rfd::MessageDialog::new()
-                                .set_title("Failed to start import")
+                                .set_title("Failed to create maps.db")
                                 .set_description(&format!("{:?}", e))
                                 .set_level(rfd::MessageLevel::Error)
                                 .set_buttons(rfd::MessageButtons::Ok)
                                 .show();
+                            return Command::none();
+                        }
+                    }
+                    while !self.options.dry_run && db.exists() && importer::db_locked(db) {
+                        let retry = rfd::MessageDialog::new()
+                            .set_title("maps.db is locked")
+                            .set_description(
+                                "USC is using maps.db. Close USC, then press OK to retry.",
+                            )
+                            .set_level(rfd::MessageLevel::Warning)
+                            .set_buttons(rfd::MessageButtons::OkCancle)
+                            .show();
+                        if !retry {
+                            return Command::none();
+                        }
+                    }
+                    // Reading every score file for the preview can take a while on a large
+                    // folder or a network share.
+                    let (ksm, db) = (ksm.clone(), db.clone());
+                    let policy = self.options.policy;
+                    let limits = self.options.limits;
+                    let cancel = self.options.cancel.clone();
+                    self.checking = true;
+                    return Command::perform(
+                        async move {
+                            let preflight =
+                                importer::open_source(&ksm, &cancel).and_then(|source| {
+                                    importer::validate_paths(
+                                        source.as_ref(),
+                                        &ksm,
+                                        &db,
+                                        policy,
+                                        limits,
+                                    )
+                                    .map(|preflight| (source, preflight))
+                                });
+                            (ksm, db, preflight.map_err(|e| format!("{:?}", e)))
+                        },
+                        |(ksm, db, preflight)| Message::Checked(ksm, db, preflight),
+                    );
+                }
+            }
+            // Paths changed while checking belong to a new press of Import.
+            Message::Checked(ksm, db, _)
+                if self.ksm_path.as_ref() != Some(&ksm) || self.db_path.as_ref() != Some(&db) =>
+            {
+                self.checking = false;
+            }
+            Message::Checked(ksm, db, preflight) => {
+                self.checking = false;
+                let (ksm, db) = (&ksm, &db);
+                match preflight {
+                    Ok((source, preflight)) => {
+                        self.options.best_effort_schema =
+                            !importer::is_supported_version(preflight.db_version);
+                        if self.options.best_effort_schema {
+                            let confirmed = rfd::MessageDialog::new()
+                                .set_title("Unknown database version")
+                                .set_description(&format!(
+                                    "maps.db is version {}, this tool knows versions {}. \
+                                Try anyway, writing scores only to the Scores columns \
+                                it recognizes?\n\n{}",
+                                    preflight.db_version,
+                                    importer::supported_versions(),
+                                    preflight
+                                        .schema_audit
+                                        .iter()
+                                        .flat_map(|a| a.lines())
+                                        .collect::<Vec<_>>()
+                                        .join("\n")
+                                ))
+                                .set_level(rfd::MessageLevel::Warning)
+                                .set_buttons(rfd::MessageButtons::YesNo)
+                                .show();
+                            if !confirmed {
+                                return Command::none();
+                            }
+                        }
+                        if !preflight.skipped.is_empty() {
+                            let confirmed = rfd::MessageDialog::new()
+                                .set_title("Some scores will be skipped")
+                                .set_description(&format!(
+                                    "Policy will skip {} of {} scores. Continue?",
+                                    format_skips(&preflight.skipped, self.thousands_separator),
+                                    table::format_count(
+                                        preflight.scores_previewed.into(),
+                                        self.thousands_separator,
+                                    )
+                                ))
+                                .set_level(rfd::MessageLevel::Warning)
+                                .set_buttons(rfd::MessageButtons::OkCancle)
+                                .show();
+                            if !confirmed {
+                                return Command::none();
+                            }
+                        }
+                        if preflight.users.len() > 1 {
+                            let confirmed = rfd::MessageDialog::new()
+                                .set_title("Import as")
+                                .set_description(&format!(
+                                    "maps.db has scores from {} players. The imported \
+                                scores will belong to {}. Continue?",
+                                    preflight.users.len(),
+                                    importer::UserIdentity::of(&self.options)
+                                ))
+                                .set_level(rfd::MessageLevel::Info)
+                                .set_buttons(rfd::MessageButtons::OkCancle)
+                                .show();
+                            if !confirmed {
+                                return Command::none();
+                            }
                         }
+                        if importer::address_space_limited()
+                            && preflight.db_size >= importer::LARGE_DB_SIZE
+                        {
+                            let confirmed = rfd::MessageDialog::new()
+                                .set_title("Large database")
+                                .set_description(&format!(
+                                    "maps.db is {}, this 32-bit build may run out of memory \
+                                importing into it. Use the 64-bit build if the import \
+                                fails. Continue?",
+                                    importer_funcs::format_size(preflight.db_size)
+                                ))
+                                .set_level(rfd::MessageLevel::Warning)
+                                .set_buttons(rfd::MessageButtons::OkCancle)
+                                .show();
+                            if !confirmed {
+                                return Command::none();
+                            }
+                        }
+                        self.options.safe_mode = !preflight.score_triggers.is_empty()
+                            && rfd::MessageDialog::new()
+                                .set_title("Triggers found on Scores")
+                                .set_description(&format!(
+                                    "maps.db has triggers on the Scores table ({}). \
+                                Import in safe mode, so a failing trigger only \
+                                rolls back the score that caused it?",
+                                    preflight.score_triggers.join(", ")
+                                ))
+                                .set_level(rfd::MessageLevel::Warning)
+                                .set_buttons(rfd::MessageButtons::YesNo)
+                                .show();
+                        self.preflight = Some(preflight);
+                        self.source = Some(source);
+                        self.config.last_ksm_path = Some(ksm.clone());
+                        self.config.last_db_path = Some(db.clone());
+                        save_config(&self.config);
+                        self.options.earlier_rows = Arc::new(history::rows_written_to(db));
+                        self.run_id += 1;
+                        self.options.cancel.store(false, Ordering::Relaxed);
+                        self.options.pause.store(false, Ordering::Relaxed);
+                        self.cancelled = false;
+                        self.progress = Some(importer::Progress::Started)
+                    }
+                    Err(e) => {
+                        rfd::MessageDialog::new()
+                            .set_title("Failed to start import")
+                            .set_description(&format!("{:?}", e))
+                            .set_level(rfd::MessageLevel::Error)
+                            .set_buttons(rfd::MessageButtons::Ok)
+                            .show();
                     }
                 }
             }
+            Message::Forwarded(forwarded) => self.prefill(forwarded),
             Message::BackButton => self.progress = None,
+            Message::CopyErrors => {
+                if let Some(summary) = &self.summary {
+                    clipboard.write(
+                        summary
+                            .fail_messages
+                            .iter()
+                            .map(|f| f.message.as_str())
+                            .collect::<Vec<_>>()
+                            .join("\n"),
+                    );
+                    self.copied_at = Some(Instant::now());
+                }
+            }
+            Message::CopiedTick => {
+                if self.copied_at.is_some_and(|t| t.elapsed() >= COPIED_NOTICE) {
+                    self.copied_at = None;
+                }
+            }
+            Message::SaveReport => {
+                let path = rfd::FileDialog::new()
+                    .add_filter("Text report", &["txt"])
+                    .add_filter("JSON report", &["json"])
+                    .set_file_name(&report::file_name(self.report_header.as_ref()))
+                    .save_file();
+                if let (Some(path), Some(summary)) = (path, self.summary.clone()) {
+                    let header = self.report_header.clone();
+                    return Command::perform(
+                        async move {
+                            report::write(&summary, header.as_ref(), &path)
+                                .map_err(|e| format!("{:?}", e))
+                        },
+                        Message::ReportSaved,
+                    );
+                }
+            }
+            Message::ReportSaved(Err(e)) => {
+                rfd::MessageDialog::new()
+                    .set_title("Failed to save the report")
+                    .set_description(&e)
+                    .set_level(rfd::MessageLevel::Error)
+                    .set_buttons(rfd::MessageButtons::Ok)
+                    .show();
+            }
+            Message::ReportSaved(Ok(())) => {}
+            Message::ExportScores => {
+                let path = rfd::FileDialog::new()
+                    .add_filter("Score dump", &["json"])
+                    .set_file_name(&export::file_name(self.report_header.as_ref()))
+                    .save_file();
+                if let (Some(path), Some(summary)) = (path, self.summary.clone()) {
+                    return Command::perform(
+                        async move { export::write(&summary, &path).map_err(|e| format!("{:?}", e)) },
+                        Message::ScoresExported,
+                    );
+                }
+            }
+            Message::ScoresExported(Err(e)) => {
+                rfd::MessageDialog::new()
+                    .set_title("Failed to export the scores")
+                    .set_description(&e)
+                    .set_level(rfd::MessageLevel::Error)
+                    .set_buttons(rfd::MessageButtons::Ok)
+                    .show();
+            }
+            Message::ScoresExported(Ok(())) => {}
+            Message::VerifyBackup => {
+                if let Some(path) = self.summary.as_ref().and_then(|s| s.backup_path.clone()) {
+                    return Command::perform(
+                        async move { checksum::verify(&path) },
+                        Message::BackupVerified,
+                    );
+                }
+            }
+            Message::BackupVerified(check) => {
+                let (level, description) = match check {
+                    checksum::Check::Intact => {
+                        (rfd::MessageLevel::Info, "The backup matches its checksum")
+                    }
+                    checksum::Check::Unchecked => (
+                        rfd::MessageLevel::Warning,
+                        "The backup has no .sha256 next to it to check against",
+                    ),
+                    checksum::Check::Damaged => (
+                        rfd::MessageLevel::Error,
+                        "The backup doesn't match its checksum, it was changed or cut short",
+                    ),
+                };
+                rfd::MessageDialog::new()
+                    .set_title("Verify backup")
+                    .set_description(description)
+                    .set_level(level)
+                    .set_buttons(rfd::MessageButtons::Ok)
+                    .show();
+            }
+            Message::StartOver => {
+                self.options.only_files.clear();
+                self.summary = None;
+                self.report_header = None;
+                self.progress = None;
+                self.source = None;
+                self.preflight = None;
+                self.crash_message = None;
+                self.cancelled = false;
+                self.expanded_bucket = None;
+                self.expanded_failures = None;
+                self.failure_table = table::TableState::default();
+            }
+            Message::ForgetPaths => {
+                self.config.last_ksm_path = None;
+                self.config.last_db_path = None;
+                save_config(&self.config);
+                self.ksm_path = None;
+                self.db_path = None;
+            }
+            Message::ShowHistory => {
+                self.history = history::load();
+                self.run_buttons = vec![button::State::new(); self.history.len()];
+                self.selected_run = None;
+                self.checked_runs.clear();
+                self.show_history = true;
+            }
+            Message::HideHistory => self.show_history = false,
+            Message::SelectRun(run) => {
+                self.selected_run = Some(run);
+                self.selected_run_files = history::RunFiles::of(&self.history[run]);
+            }
+            Message::ExportRun => {
+                let path = rfd::FileDialog::new()
+                    .add_filter("JSON report", &["json"])
+                    .set_file_name("ksm2usc-report.json")
+                    .save_file();
+                if let (Some(path), Some(report)) = (path, &self.selected_run_files.report) {
+                    let copied = std::fs::copy(report, &path)
+                        .map_err(anyhow::Error::from)
+                        .and_then(|_| checksum::write_companion(&path));
+                    if let Err(e) = copied {
+                        rfd::MessageDialog::new()
+                            .set_title("Failed to export the report")
+                            .set_description(&format!("{:?}", e))
+                            .set_level(rfd::MessageLevel::Error)
+                            .set_buttons(rfd::MessageButtons::Ok)
+                            .show();
+                    }
+                }
+            }
+            Message::RetryRun => {
+                if let Some(run) = self.selected_run.map(|i| self.history[i].clone()) {
+                    self.ksm_path = Some(run.ksm_path);
+                    self.db_path = Some(run.db_path);
+                    self.create_db = false;
+                    self.label = run.label;
+                    self.options.only_files = self.selected_run_files.failed.clone();
+                    self.show_history = false;
+                    return self.update(Message::Start, clipboard);
+                }
+            }
+            Message::UndoRun => {
+                if let Some(run) = self.selected_run.map(|i| self.history[i].clone()) {
+                    if warn_if_locked(&run.db_path) {
+                        return Command::none();
+                    }
+                    let confirmed = rfd::MessageDialog::new()
+                        .set_title("Undo import")
+                        .set_description(&format!(
+                            "Remove the {} scores this run imported from {:?}? Scores changed \
+                            since the import are kept.",
+                            self.selected_run_files.rows.len(),
+                            run.db_path
+                        ))
+                        .set_level(rfd::MessageLevel::Warning)
+                        .set_buttons(rfd::MessageButtons::OkCancle)
+                        .show();
+                    if confirmed {
+                        return Command::perform(
+                            async move { history::undo(&run).map_err(|e| format!("{:?}", e)) },
+                            Message::RunUndone,
+                        );
+                    }
+                }
+            }
+            Message::CheckRun(run, checked) => {
+                if checked {
+                    self.checked_runs.insert(run);
+                } else {
+                    self.checked_runs.remove(&run);
+                }
+            }
+            Message::CheckLabel => {
+                let history = &self.history;
+                if let Some(selected) = self.selected_run.map(|i| &history[i]) {
+                    let same_label = history.iter().enumerate().filter(|(_, r)| {
+                        !r.id.is_empty()
+                            && r.label == selected.label
+                            && r.db_path == selected.db_path
+                    });
+                    self.checked_runs.extend(same_label.map(|(i, _)| i));
+                }
+            }
+            Message::UndoCheckedRuns => {
+                let runs: Vec<&history::Run> = self
+                    .checked_runs
+                    .iter()
+                    .map(|&i| &self.history[i])
+                    .collect();
+                let db_path = match runs.first() {
+                    Some(run) => run.db_path.clone(),
+                    None => return Command::none(),
+                };
+                if warn_if_locked(&db_path) {
+                    return Command::none();
+                }
+                let rows: usize = runs
+                    .iter()
+                    .map(|r| history::RunFiles::of(r))
+                    .filter(|f| !f.undone)
+                    .map(|f| f.rows.len())
+                    .sum();
+                let confirmed = rfd::MessageDialog::new()
+                    .set_title("Undo imports")
+                    .set_description(&format!(
+                        "Remove the {} scores these {} runs imported from {:?}? Scores changed \
+                        since the imports are kept.",
+                        rows,
+                        runs.len(),
+                        db_path
+                    ))
+                    .set_level(rfd::MessageLevel::Warning)
+                    .set_buttons(rfd::MessageButtons::OkCancle)
+                    .show();
+                if confirmed {
+                    let ids: Vec<String> = runs.iter().map(|r| r.id.clone()).collect();
+                    return Command::perform(
+                        async move { history::undo_runs(&ids).map_err(|e| format!("{:?}", e)) },
+                        Message::RunUndone,
+                    );
+                }
+            }
+            Message::RunUndone(undone) => {
+                let (level, description) = match undone {
+                    Ok(undone) => (
+                        rfd::MessageLevel::Info,
+                        format!(
+                            "Removed {} scores. {} were deleted already, {} changed since the \
+                            import and were kept.{}",
+                            undone.removed,
+                            undone.missing,
+                            undone.modified.len(),
+                            match undone.runs_undone_before {
+                                0 => String::new(),
+                                runs => format!(" {} of the runs were undone before.", runs),
+                            }
+                        ),
+                    ),
+                    Err(e) => (rfd::MessageLevel::Error, e),
+                };
+                rfd::MessageDialog::new()
+                    .set_title("Undo import")
+                    .set_description(&description)
+                    .set_level(level)
+                    .set_buttons(rfd::MessageButtons::Ok)
+                    .show();
+                self.checked_runs.clear();
+                if let Some(run) = self.selected_run {
+                    self.selected_run_files = history::RunFiles::of(&self.history[run]);
+                }
+            }
+            Message::Optimize(optimize) => self.options.optimize = optimize,
+            Message::LowMemory(low_memory) => self.options.low_memory = low_memory,
+            Message::SkipLegacy(skip_legacy) => self.options.policy.skip_legacy = skip_legacy,
+            Message::BestOnly(best_only) => self.options.policy.best_only = best_only,
+            Message::ClearsOnly(clears_only) => self.options.policy.clears_only = clears_only,
+            Message::NonstandardOptions(allowed) => {
+                self.options.policy.nonstandard_options = allowed
+            }
+            Message::Backdate(backdate) => self.backdate = backdate,
+            Message::BackdateBefore(date) => self.backdate_before = date,
+            Message::MinScore(score) => self.min_score = score,
+            Message::Levels(levels) => self.levels = levels,
+            Message::ModifiedFilter(filter) => self.modified_filter = filter,
+            Message::ModifiedAfter(date) => self.modified_after = date,
+            Message::SinceLastImport => {
+                if let Some(last_import) = self.config.last_import {
+                    self.modified_filter = true;
+                    self.modified_after = importer_funcs::format_date_time(last_import);
+                }
+            }
+            Message::Label(label) => self.label = label,
+            Message::UserName(user_name) => self.options.user_name = user_name,
+            Message::UserId(user_id) => self.options.user_id = user_id,
+            Message::PickUser(user) => user.apply(&mut self.options),
+            Message::SkipDuplicates(skip) => self.options.skip_duplicates = skip,
+            Message::ImprovementsOnly(only) => self.options.improvements_only = only,
+            Message::CompareAcrossGauges(across) => self.options.compare_across_gauges = across,
+            Message::DryRun(dry_run) => self.options.dry_run = dry_run,
+            Message::AllOrNothing(all) => self.options.all_or_nothing = all,
+            Message::SkipBackup(skip) => self.options.skip_backup = skip,
+            Message::HideIntro(hide) => {
+                self.config.show_intro = !hide;
+                save_config(&self.config);
+            }
+            Message::ScaleDown | Message::ScaleUp => {
+                let step = if let Message::ScaleUp = message {
+                    style::SCALE_STEP
+                } else {
+                    -style::SCALE_STEP
+                };
+                self.config.ui_scale = style::clamp_scale(self.config.ui_scale + step);
+                save_config(&self.config);
+            }
+            Message::HighContrast(high_contrast) => {
+                self.config.high_contrast = high_contrast;
+                save_config(&self.config);
+            }
+            Message::SortBucketByName => self.bucket_table.sort_by_name(),
+            Message::SortBucketByCount => self.bucket_table.toggle_count_sort(),
+            Message::ExpandBucketRow(row) => self.bucket_table.toggle_row(row),
+            Message::ToggleBucket(kind) => {
+                self.bucket_table.expanded_row = None;
+                self.expanded_bucket = if self.expanded_bucket == Some(kind) {
+                    None
+                } else {
+                    Some(kind)
+                }
+            }
+            Message::SortFailuresByName => self.failure_table.sort_by_name(),
+            Message::SortFailuresByCount => self.failure_table.toggle_count_sort(),
+            Message::ExpandFailureRow(row) => self.failure_table.toggle_row(row),
+            Message::ToggleFailures(kind) => {
+                self.failure_table.expanded_row = None;
+                self.expanded_failures = if self.expanded_failures == Some(kind) {
+                    None
+                } else {
+                    Some(kind)
+                }
+            }
         };
 
+        if self.db_path != db_path {
+            self.load_db_users();
+        }
         Command::none()
     }
 
     fn view(&mut self) -> iced::Element<'_, Self::Message> {
+        let palette = style::Palette {
+            high_contrast: self.config.high_contrast,
+        };
         let stage = match (
             self.ksm_path.is_some(),
             self.db_path.is_some(),
@@ -147,113 +1161,995 @@ impl Application for State {
             (_, _, _, true) => Stage::Finished,
             (_, _, true, false) => Stage::Importing,
         };
+        let stage = match stage {
+            Stage::Paths if self.show_history => Stage::History,
+            stage => stage,
+        };
+
+        let conflicts = self.pending_options().validate();
+        let blocked = conflicts.iter().any(|c| c.severity == Severity::Error);
 
         let content = match stage {
-            Stage::Paths => Column::new()
-                .align_items(iced::Align::Center)
-                .spacing(20)
-                .push(
-                    Row::new()
-                        .align_items(iced::Align::Center)
-                        .spacing(10)
-                        .push(
-                            Text::new(
-                                self.ksm_path
-                                    .clone()
-                                    .unwrap_or_default()
-                                    .to_str()
-                                    .unwrap_or_default(),
+            Stage::Paths => {
+                let mut paths = Column::new().align_items(iced::Align::Center).spacing(20);
+                if self.show_intro {
+                    paths = paths.push(intro_panel(self.config.show_intro, self.locale));
+                }
+                paths
+                    .push(Text::new(if self.file_hovered {
+                        "Drop to use this path"
+                    } else {
+                        "Drag the KShootMania folder or maps.db onto this window, or pick them below"
+                    }))
+                    .push(
+                        Row::new()
+                            .align_items(iced::Align::Center)
+                            .spacing(10)
+                            .push(
+                                Text::new(
+                                    self.ksm_path
+                                        .clone()
+                                        .unwrap_or_default()
+                                        .to_str()
+                                        .unwrap_or_default(),
+                                )
+                                .width(Length::FillPortion(3))
+                                .horizontal_alignment(iced::HorizontalAlignment::Right)
+                                .vertical_alignment(iced::VerticalAlignment::Center),
                             )
-                            .width(Length::FillPortion(3))
-                            .horizontal_alignment(iced::HorizontalAlignment::Right)
-                            .vertical_alignment(iced::VerticalAlignment::Center),
-                        )
-                        .push(
-                            Button::new(
-                                &mut self.ksm_button,
-                                Text::new("KSM Path")
-                                    .horizontal_alignment(iced::HorizontalAlignment::Center),
+                            .push(
+                                Button::new(
+                                    &mut self.ksm_button,
+                                    Text::new("KSM Path")
+                                        .horizontal_alignment(iced::HorizontalAlignment::Center),
+                                )
+                                .on_press(Message::KsmButton)
+                                .width(Length::FillPortion(1)),
                             )
-                            .on_press(Message::KsmButton)
-                            .width(Length::FillPortion(1)),
-                        ),
-                )
-                .push(
+                            .push(
+                                Button::new(
+                                    &mut self.ksm_archive_button,
+                                    Text::new("Archive…")
+                                        .horizontal_alignment(iced::HorizontalAlignment::Center),
+                                )
+                                .on_press(Message::KsmArchiveButton),
+                            )
+                            .push(
+                                Button::new(
+                                    &mut self.detect_ksm_button,
+                                    Text::new("Detect")
+                                        .horizontal_alignment(iced::HorizontalAlignment::Center),
+                                )
+                                .on_press(Message::DetectKsm),
+                            ),
+                    )
+                    .push(detected_list(
+                        &self.detected_ksm,
+                        &mut self.detected_ksm_buttons,
+                        Message::PickDetectedKsm,
+                    ))
+                    .push(
+                        Row::new()
+                            .align_items(iced::Align::Center)
+                            .spacing(10)
+                            .push(
+                                Text::new(
+                                    self.db_path
+                                        .clone()
+                                        .unwrap_or_default()
+                                        .to_str()
+                                        .unwrap_or_default(),
+                                )
+                                .width(Length::FillPortion(3))
+                                .horizontal_alignment(iced::HorizontalAlignment::Right)
+                                .vertical_alignment(iced::VerticalAlignment::Center),
+                            )
+                            .push(
+                                Button::new(
+                                    &mut self.db_button,
+                                    Text::new("USC maps.db Path")
+                                        .horizontal_alignment(iced::HorizontalAlignment::Center),
+                                )
+                                .on_press(Message::DbButton)
+                                .width(Length::FillPortion(1)),
+                            )
+                            .push(
+                                Button::new(
+                                    &mut self.new_db_button,
+                                    Text::new("New…")
+                                        .horizontal_alignment(iced::HorizontalAlignment::Center),
+                                )
+                                .on_press(Message::NewDbButton),
+                            )
+                            .push(
+                                Button::new(
+                                    &mut self.detect_db_button,
+                                    Text::new("Detect")
+                                        .horizontal_alignment(iced::HorizontalAlignment::Center),
+                                )
+                                .on_press(Message::DetectDb),
+                            ),
+                    )
+                    .push(detected_list(
+                        &self.detected_dbs,
+                        &mut self.detected_db_buttons,
+                        Message::PickDetectedDb,
+                    ))
+                    .push(
+                        Row::new()
+                            .align_items(iced::Align::Center)
+                            .spacing(10)
+                            .push(
+                                Text::new(match &self.config.usc_songs_root {
+                                    Some(root) => root.to_str().unwrap_or_default().to_string(),
+                                    None => "Songs folder from USC's Main.cfg".to_string(),
+                                })
+                                .width(Length::FillPortion(3))
+                                .horizontal_alignment(iced::HorizontalAlignment::Right)
+                                .vertical_alignment(iced::VerticalAlignment::Center),
+                            )
+                            .push(
+                                Button::new(
+                                    &mut self.songs_root_button,
+                                    Text::new("USC Songs Path")
+                                        .horizontal_alignment(iced::HorizontalAlignment::Center),
+                                )
+                                .on_press(Message::SongsRootButton)
+                                .width(Length::FillPortion(1)),
+                            ),
+                    )
+                    .push(Checkbox::new(
+                        self.options.optimize,
+                        "Optimize database after import (ANALYZE and VACUUM)",
+                        Message::Optimize,
+                    ))
+                    .push(Checkbox::new(
+                        self.options.low_memory,
+                        "Low memory mode (failures are written to a file, not listed here)",
+                        Message::LowMemory,
+                    ))
+                    .push(Checkbox::new(
+                        self.options.policy.skip_legacy,
+                        "Skip percentage scores from old KSM versions instead of converting them",
+                        Message::SkipLegacy,
+                    ))
+                    .push(Checkbox::new(
+                        self.options.policy.best_only,
+                        "Only import the best score and the best clear of each chart",
+                        Message::BestOnly,
+                    ))
+                    .push(Checkbox::new(
+                        self.options.policy.clears_only,
+                        "Only import clears, skip failed plays",
+                        Message::ClearsOnly,
+                    ))
+                    .push(Checkbox::new(
+                        self.options.policy.nonstandard_options,
+                        "Import scores with non-standard options (not autoplay)",
+                        Message::NonstandardOptions,
+                    ))
+                    .push(Checkbox::new(
+                        self.options.skip_duplicates,
+                        "Skip scores that are already in the database",
+                        Message::SkipDuplicates,
+                    ))
+                    .push(Checkbox::new(
+                        self.options.improvements_only,
+                        "Only import scores that beat the chart's best score in USC",
+                        Message::ImprovementsOnly,
+                    ))
+                    .push(Checkbox::new(
+                        self.options.compare_across_gauges,
+                        "Compare against USC's best on any gauge, not only the same gauge",
+                        Message::CompareAcrossGauges,
+                    ))
+                    .push(Checkbox::new(
+                        self.options.dry_run,
+                        "Dry run (match charts and report, without writing to the database)",
+                        Message::DryRun,
+                    ))
+                    .push(Checkbox::new(
+                        self.options.all_or_nothing,
+                        "All or nothing (roll back every score if the import doesn't finish)",
+                        Message::AllOrNothing,
+                    ))
+                    .push(Checkbox::new(
+                        self.options.skip_backup,
+                        "Don't back up maps.db first (for very large databases)",
+                        Message::SkipBackup,
+                    ))
+                    .push(
+                        Row::new()
+                            .align_items(iced::Align::Center)
+                            .spacing(10)
+                            .push(Checkbox::new(
+                                self.backdate,
+                                "Date recently modified scores before",
+                                Message::Backdate,
+                            ))
+                            .push(
+                                TextInput::new(
+                                    &mut self.backdate_input,
+                                    "YYYY-MM-DD",
+                                    &self.backdate_before,
+                                    Message::BackdateBefore,
+                                )
+                                .padding(5)
+                                .width(Length::Units(120)),
+                            ),
+                    )
+                    .push(
+                        Row::new()
+                            .align_items(iced::Align::Center)
+                            .spacing(10)
+                            .push(Text::new("Minimum score"))
+                            .push(
+                                TextInput::new(
+                                    &mut self.min_score_input,
+                                    "Any",
+                                    &self.min_score,
+                                    Message::MinScore,
+                                )
+                                .padding(5)
+                                .width(Length::Units(120)),
+                            )
+                            .push(Text::new("Levels"))
+                            .push(
+                                TextInput::new(
+                                    &mut self.levels_input,
+                                    "All, or 15-18, 15+",
+                                    &self.levels,
+                                    Message::Levels,
+                                )
+                                .padding(5)
+                                .width(Length::Units(120)),
+                            ),
+                    )
+                    .push({
+                        let since_last_import = Button::new(
+                            &mut self.since_last_import_button,
+                            Text::new("Since last import"),
+                        );
+                        Row::new()
+                            .align_items(iced::Align::Center)
+                            .spacing(10)
+                            .push(Checkbox::new(
+                                self.modified_filter,
+                                "Only score files modified after",
+                                Message::ModifiedFilter,
+                            ))
+                            .push(
+                                TextInput::new(
+                                    &mut self.modified_after_input,
+                                    "YYYY-MM-DD HH:MM",
+                                    &self.modified_after,
+                                    Message::ModifiedAfter,
+                                )
+                                .padding(5)
+                                .width(Length::Units(160)),
+                            )
+                            .push(match self.config.last_import {
+                                Some(_) => since_last_import.on_press(Message::SinceLastImport),
+                                None => since_last_import,
+                            })
+                    })
+                    .push(
+                        Row::new()
+                            .align_items(iced::Align::Center)
+                            .spacing(10)
+                            .push(Text::new("Label for this import"))
+                            .push(
+                                TextInput::new(
+                                    &mut self.label_input,
+                                    "Name of the KSM folder",
+                                    &self.label,
+                                    Message::Label,
+                                )
+                                .padding(5)
+                                .width(Length::Units(240)),
+                            ),
+                    )
+                    .push(
+                        Row::new()
+                            .align_items(iced::Align::Center)
+                            .spacing(10)
+                            .push(Text::new("Player name on imported scores"))
+                            .push(
+                                TextInput::new(
+                                    &mut self.user_name_input,
+                                    "Left empty like USC's local scores",
+                                    &self.options.user_name,
+                                    Message::UserName,
+                                )
+                                .padding(5)
+                                .width(Length::Units(240)),
+                            )
+                            .push(Text::new("User id"))
+                            .push(
+                                TextInput::new(
+                                    &mut self.user_id_input,
+                                    "0",
+                                    &self.options.user_id,
+                                    Message::UserId,
+                                )
+                                .padding(5)
+                                .width(Length::Units(120)),
+                            ),
+                    )
+                    .push(if self.db_users.is_empty() {
+                        Row::new()
+                    } else {
+                        let current = importer::UserIdentity::of(&self.options);
+                        let selected = self.db_users.iter().find(|u| **u == current).cloned();
+                        Row::new()
+                            .align_items(iced::Align::Center)
+                            .spacing(10)
+                            .push(Text::new("Import as a player already in maps.db"))
+                            .push(PickList::new(
+                                &mut self.db_users_list,
+                                &self.db_users[..],
+                                selected,
+                                Message::PickUser,
+                            ))
+                    })
+                    .push(
+                        Row::new()
+                            .align_items(iced::Align::Center)
+                            .spacing(10)
+                            .push(Text::new(format!(
+                                "Interface size: {:.0}%",
+                                self.config.ui_scale * 100.0
+                            )))
+                            .push(
+                                Button::new(&mut self.scale_down_button, Text::new("−"))
+                                    .on_press(Message::ScaleDown),
+                            )
+                            .push(
+                                Button::new(&mut self.scale_up_button, Text::new("+"))
+                                    .on_press(Message::ScaleUp),
+                            )
+                            .push(Checkbox::new(
+                                self.config.high_contrast,
+                                "High contrast / colorblind-safe colors",
+                                Message::HighContrast,
+                            )),
+                    )
+                    .push(
+                        conflicts
+                            .iter()
+                            .fold(Column::new().spacing(5), |c, conflict| {
+                                let status = match conflict.severity {
+                                    Severity::Warning => Status::Warning,
+                                    Severity::Error => Status::Failure,
+                                };
+                                c.push(palette.status_text(status, conflict.message))
+                            }),
+                    )
+                    .push(
+                        Row::new()
+                            .spacing(10)
+                            .push({
+                                let import = Button::new(
+                                    &mut self.import_button,
+                                    Text::new(if self.checking { "Checking…" } else { "Import" })
+                                        .horizontal_alignment(iced::HorizontalAlignment::Center),
+                                );
+                                // Hard conflicts keep the button disabled until they're resolved.
+                                if blocked || self.checking {
+                                    import
+                                } else {
+                                    import.on_press(Message::Start)
+                                }
+                            })
+                            .push(
+                                Button::new(&mut self.history_button, Text::new("History"))
+                                    .on_press(Message::ShowHistory),
+                            )
+                            .push({
+                                let forget = Button::new(
+                                    &mut self.forget_paths_button,
+                                    Text::new("Forget paths"),
+                                );
+                                if self.config.last_ksm_path.is_some()
+                                    || self.config.last_db_path.is_some()
+                                {
+                                    forget.on_press(Message::ForgetPaths)
+                                } else {
+                                    forget
+                                }
+                            }),
+                    )
+            }
+
+            Stage::History => {
+                let selected = self.selected_run;
+                let history = &self.history;
+                let checked = &self.checked_runs;
+                let list = history
+                    .iter()
+                    .zip(self.run_buttons.iter_mut())
+                    .enumerate()
+                    .fold(
+                        Scrollable::new(&mut self.history_scroll).height(Length::FillPortion(1)),
+                        |list, (i, (run, state))| {
+                            let label = format!(
+                                "{}  {}  {} imported, {} failed",
+                                importer_funcs::format_date(run.finished),
+                                run.label,
+                                run.imported,
+                                run.failed
+                            );
+                            let label = if selected == Some(i) {
+                                format!("> {}", label)
+                            } else {
+                                label
+                            };
+                            let mut row = Row::new().spacing(5);
+                            // Runs recorded before run folders existed can't be undone.
+                            if !run.id.is_empty() {
+                                row = row.push(Checkbox::new(
+                                    checked.contains(&i),
+                                    "",
+                                    move |c| Message::CheckRun(i, c),
+                                ));
+                            }
+                            list.push(row.push(
+                                Button::new(state, Text::new(label))
+                                    .on_press(Message::SelectRun(i)),
+                            ))
+                        },
+                    );
+                let mut content = Column::new().spacing(5).push(Text::new("Previous imports"));
+                content = if history.is_empty() {
+                    content.push(Text::new("No imports have finished yet"))
+                } else {
+                    content.push(list)
+                };
+                if let Some(run) = selected.and_then(|i| history.get(i)) {
+                    content = content
+                        .push(Text::new(format!(
+                            "Finished: {}",
+                            importer_funcs::format_date(run.finished)
+                        )))
+                        .push(Text::new(format!("Label: {}", run.label)))
+                        .push(Text::new(format!(
+                            "KSM folder: {}",
+                            run.ksm_path.to_string_lossy()
+                        )))
+                        .push(Text::new(format!(
+                            "Database: {}",
+                            run.db_path.to_string_lossy()
+                        )))
+                        .push(Text::new(format!("Scores imported: {}", run.imported)))
+                        .push(Text::new(format!("Failed imports: {}", run.failed)));
+                    if run.crashed {
+                        content = content.push(
+                            palette
+                                .status_text(Status::Failure, "This run crashed or was cancelled"),
+                        );
+                    }
+                    let files = &self.selected_run_files;
+                    if files.undone {
+                        content = content
+                            .push(palette.status_text(Status::Warning, "This run was undone"));
+                    }
+                    if !run.db_path.exists() {
+                        content = content.push(
+                            palette.status_text(Status::Warning, "This database no longer exists"),
+                        );
+                    }
+                    if !files.damaged.is_empty() {
+                        content = content.push(palette.status_text(
+                            Status::Warning,
+                            format!(
+                                "These files don't match their checksum and were left out: {}",
+                                files.damaged.join(", ")
+                            ),
+                        ));
+                    }
+                    let export =
+                        Button::new(&mut self.export_run_button, Text::new("Export report"));
+                    let retry = Button::new(
+                        &mut self.retry_run_button,
+                        Text::new(format!("Retry {} failed files", files.failed.len())),
+                    );
+                    let undo = Button::new(&mut self.undo_run_button, Text::new("Undo"));
+                    content = content.push(
+                        Row::new()
+                            .spacing(10)
+                            .push(if files.report.is_some() {
+                                export.on_press(Message::ExportRun)
+                            } else {
+                                export
+                            })
+                            .push(
+                                if !files.failed.is_empty()
+                                    && run.db_path.exists()
+                                    && run.ksm_path.exists()
+                                {
+                                    retry.on_press(Message::RetryRun)
+                                } else {
+                                    retry
+                                },
+                            )
+                            .push(
+                                if !files.rows.is_empty() && !files.undone && run.db_path.exists() {
+                                    undo.on_press(Message::UndoRun)
+                                } else {
+                                    undo
+                                },
+                            )
+                            .push({
+                                let check_label = Button::new(
+                                    &mut self.check_label_button,
+                                    Text::new(format!("Tick runs labeled \"{}\"", run.label)),
+                                );
+                                if run.id.is_empty() {
+                                    check_label
+                                } else {
+                                    check_label.on_press(Message::CheckLabel)
+                                }
+                            }),
+                    );
+                }
+                let undo_checked = Button::new(
+                    &mut self.undo_checked_button,
+                    Text::new(format!("Undo {} ticked runs", checked.len())),
+                );
+                content.push(
                     Row::new()
-                        .align_items(iced::Align::Center)
                         .spacing(10)
                         .push(
-                            Text::new(
-                                self.db_path
-                                    .clone()
-                                    .unwrap_or_default()
-                                    .to_str()
-                                    .unwrap_or_default(),
-                            )
-                            .width(Length::FillPortion(3))
-                            .horizontal_alignment(iced::HorizontalAlignment::Right)
-                            .vertical_alignment(iced::VerticalAlignment::Center),
+                            Button::new(&mut self.history_back_button, Text::new("Back"))
+                                .on_press(Message::HideHistory),
                         )
-                        .push(
-                            Button::new(
-                                &mut self.db_button,
-                                Text::new("USC maps.db Path")
-                                    .horizontal_alignment(iced::HorizontalAlignment::Center),
-                            )
-                            .on_press(Message::DbButton)
-                            .width(Length::FillPortion(1)),
-                        ),
+                        .push(if checked.is_empty() {
+                            undo_checked
+                        } else {
+                            undo_checked.on_press(Message::UndoCheckedRuns)
+                        }),
                 )
-                .push(
-                    Button::new(
-                        &mut self.import_button,
-                        Text::new("Import").horizontal_alignment(iced::HorizontalAlignment::Center),
-                    )
-                    .on_press(Message::Start),
-                ),
+            }
 
             Stage::Importing => Column::new()
-                .push(Text::new("Importing"))
+                .push(Text::new(match self.progress {
+                    Some(importer::Progress::Locked) => "maps.db is locked",
+                    _ if self.options.pause.load(Ordering::Relaxed) => "Paused",
+                    _ => "Importing",
+                }))
                 .push(match self.progress.as_ref().unwrap() {
-                    importer::Progress::Advanced(p, _score_file) => {
-                        Column::new().push(iced::ProgressBar::new(0.0_f32..=1.0_f32, *p))
-                    }
+                    importer::Progress::Advanced(p, score_file) => Column::new()
+                        .spacing(5)
+                        .push(iced::ProgressBar::new(0.0_f32..=1.0_f32, *p))
+                        .push(Text::new(score_file.as_ref())),
 
                     importer::Progress::Started => Column::new().push(Text::new("Starting")),
-                    importer::Progress::Finished(_) => Column::new().push(Text::new("Finished")),
-                    importer::Progress::Errored(e) => {
-                        Column::new().push(Text::new(&format!("Error: {}", e)))
+                    importer::Progress::Optimizing => {
+                        Column::new().push(Text::new("Optimizing database, this can take a while"))
                     }
+                    importer::Progress::Locked => Column::new().push(palette.status_text(
+                        Status::Warning,
+                        "USC is using maps.db. Close USC, then press Retry to go on with the import.",
+                    )),
+                    importer::Progress::Finished(_) => Column::new().push(Text::new("Finished")),
+                    importer::Progress::Cancelled(_) => Column::new().push(Text::new("Cancelled")),
+                    importer::Progress::Errored(e, _) => Column::new()
+                        .push(palette.status_text(Status::Failure, format!("Error: {}", e))),
                 })
                 .push(match self.progress.as_ref().unwrap() {
-                    importer::Progress::Errored(_) => Row::new().push(
+                    importer::Progress::Errored(..) => Row::new().push(
                         Button::new(&mut self.back_button, Text::new("Back"))
                             .on_press(Message::BackButton),
                     ),
+                    importer::Progress::Started
+                    | importer::Progress::Advanced(..)
+                    | importer::Progress::Locked
+                        if self.options.cancel.load(Ordering::Relaxed) =>
+                    {
+                        Row::new().push(Text::new("Stopping after the current score file…"))
+                    }
+                    importer::Progress::Locked => {
+                        let retrying = !self.options.pause.load(Ordering::Relaxed);
+                        let retry = Button::new(&mut self.pause_button, Text::new("Retry"));
+                        Row::new()
+                            .spacing(10)
+                            .align_items(iced::Align::Center)
+                            .push(if retrying {
+                                retry
+                            } else {
+                                retry.on_press(Message::PauseImport(false))
+                            })
+                            .push(
+                                Button::new(&mut self.cancel_button, Text::new("Cancel"))
+                                    .on_press(Message::CancelImport),
+                            )
+                            .push(Text::new(if retrying { "Retrying…" } else { "" }))
+                    }
+                    importer::Progress::Started | importer::Progress::Advanced(..) => {
+                        let paused = self.options.pause.load(Ordering::Relaxed);
+                        Row::new()
+                            .spacing(10)
+                            .align_items(iced::Align::Center)
+                            .push(
+                                Button::new(
+                                    &mut self.pause_button,
+                                    Text::new(if paused { "Resume" } else { "Pause" }),
+                                )
+                                .on_press(Message::PauseImport(!paused)),
+                            )
+                            .push(
+                                Button::new(&mut self.cancel_button, Text::new("Cancel"))
+                                    .on_press(Message::CancelImport),
+                            )
+                            .push(Text::new(if paused {
+                                "Paused, the current score file was finished"
+                            } else {
+                                ""
+                            }))
+                    }
                     _ => Row::new(),
                 }),
             Stage::Finished => {
                 let summary = self.summary.as_ref().unwrap();
-                let error_view = summary
-                    .fail_messages
+                let expanded = self.expanded_bucket;
+                let mut error_view = summary
+                    .warnings
                     .iter()
-                    .fold(Scrollable::new(&mut self.error_scroll), |v, e| {
-                        v.push(Text::new(e))
+                    .fold(Scrollable::new(&mut self.error_scroll), |v, w| {
+                        v.push(palette.status_text(Status::Warning, format!("Warning: {}", w)))
                     });
-                Column::new()
+                // Repeats of the same failure collapse into one table row per reason, the
+                // individual messages are listed under the row that's opened.
+                let failure_counts = summary.failure_counts();
+                let expanded_failures = self.expanded_failures;
+                let mut reason_rows = Vec::new();
+                let mut open_reason = None;
+                if let Some(kind) = expanded_failures {
+                    let reasons: Vec<String> = summary
+                        .failures_of(kind)
+                        .map(|f| f.reason.clone())
+                        .collect();
+                    reason_rows = table::count_rows(&reasons);
+                    let sort = self.failure_table.sort;
+                    reason_rows.sort_by(|a, b| table::compare(sort, a, b));
+                    open_reason = self
+                        .failure_table
+                        .expanded_row
+                        .and_then(|row| reason_rows.get(row))
+                        .map(|(reason, _)| reason.clone());
+                }
+                let mut failure_table = Some(&mut self.failure_table);
+                self.failure_kind_buttons
+                    .resize_with(failure_counts.len(), Default::default);
+                for ((kind, count), button) in failure_counts
+                    .into_iter()
+                    .zip(self.failure_kind_buttons.iter_mut())
+                {
+                    let shown = expanded_failures == Some(kind);
+                    error_view = error_view.push(
+                        Row::new()
+                            .align_items(iced::Align::Center)
+                            .spacing(10)
+                            .push(palette.status_text(
+                                Status::Failure,
+                                format!("{}: {}", kind.label(), count),
+                            ))
+                            .push(
+                                Button::new(button, Text::new(if shown { "Hide" } else { "Show" }))
+                                    .on_press(Message::ToggleFailures(kind)),
+                            ),
+                    );
+                    if let (true, Some(table_state)) = (shown, failure_table.take()) {
+                        error_view = error_view.push(table::view(
+                            table_state,
+                            self.thousands_separator,
+                            "Message",
+                            std::mem::take(&mut reason_rows),
+                            Message::SortFailuresByName,
+                            Message::SortFailuresByCount,
+                            Message::ExpandFailureRow,
+                        ));
+                        if let Some(reason) = &open_reason {
+                            error_view = summary
+                                .failures_of(kind)
+                                .filter(|f| &f.reason == reason)
+                                .fold(error_view, |v, f| {
+                                    v.push(palette.status_text(Status::Failure, f.message.clone()))
+                                });
+                        }
+                    }
+                }
+                let failed = summary.fail_messages.len() as u32 + summary.spilled_failures;
+                let mut content = Column::new()
                     .spacing(5)
-                    .push(Text::new("Finished"))
-                    .push(Text::new(&format!(
+                    .push(match &self.crash_message {
+                        Some(e) => palette.status_text(
+                            Status::Failure,
+                            format!("The importer crashed, partial results below: {}", e),
+                        ),
+                        None if self.cancelled => palette.status_text(
+                            Status::Warning,
+                            "Import cancelled, partial results below",
+                        ),
+                        None if self.options.dry_run => {
+                            palette.status_text(Status::Success, "Dry run — nothing was written")
+                        }
+                        None => palette.status_text(Status::Success, "Finished"),
+                    })
+                    .push(Text::new(format!(
+                        "Score files processed: {} of {}",
+                        summary.files_processed, summary.files_found
+                    )))
+                    .push(Text::new(format!("Scores found: {}", summary.scores_found)))
+                    .push(Text::new(format!(
                         "Scores Imported: {}",
                         summary.scores_imported
                     )))
-                    .push(Text::new(&format!(
-                        "Failed Imports: {}",
-                        summary.fail_messages.len()
+                    .push(bucket_row(
+                        &mut self.new_charts_button,
+                        "New charts",
+                        &summary.new_charts,
+                        expanded,
+                        InsertKind::NewChart,
+                    ))
+                    .push(bucket_row(
+                        &mut self.improvements_button,
+                        "Improvements",
+                        &summary.improvements,
+                        expanded,
+                        InsertKind::Improvement,
+                    ))
+                    .push(bucket_row(
+                        &mut self.additional_plays_button,
+                        "Additional plays",
+                        &summary.additional_plays,
+                        expanded,
+                        InsertKind::AdditionalPlay,
+                    ))
+                    .push(Text::new(format!(
+                        "Already in the database, skipped: {}",
+                        summary.scores_skipped
+                    )))
+                    .push(Text::new(format!(
+                        "Chart file absent, matched via database: {}",
+                        summary.charts_matched_via_db
                     )))
+                    .push(if failed > 0 {
+                        palette.status_text(Status::Failure, format!("Failed Imports: {}", failed))
+                    } else {
+                        Text::new("Failed Imports: 0")
+                    });
+
+                if summary.files_unmodified > 0 {
+                    content = content.push(Text::new(format!(
+                        "Score files not modified since the cutoff: {}",
+                        summary.files_unmodified
+                    )));
+                }
+                if summary.scores_upgraded > 0 {
+                    content = content.push(Text::new(format!(
+                        "Earlier imports given a better badge: {}",
+                        summary.scores_upgraded
+                    )));
+                }
+                if summary.unindexed_charts > 0 {
+                    content = content.push(palette.status_text(
+                        Status::Warning,
+                        format!(
+                            "{} charts are not in USC's song list yet. Start USC and let the song scan finish, \
+                            their scores show up once the charts are indexed. If they never appear, check that \
+                            USC's songs folder setting points at the folder containing them.",
+                            summary.unindexed_charts
+                        ),
+                    ));
+                } else if summary.scores_imported > 0 {
+                    content = content.push(Text::new(
+                        "Restart USC if it was running during the import to see the new scores",
+                    ));
+                }
+                let mut policy_skips = summary.skipped.clone();
+                if let Some(n) = policy_skips.remove(&importer::SkipRule::NonstandardSettings) {
+                    content = content.push(Text::new(format!(
+                        "Skipped: {} (unsupported options)",
+                        table::format_count(n.into(), self.thousands_separator)
+                    )));
+                }
+                if !policy_skips.is_empty() {
+                    content = content.push(Text::new(format!(
+                        "Skipped by policy: {}",
+                        format_skips(&policy_skips, self.thousands_separator)
+                    )));
+                }
+                if summary.legacy_converted > 0 {
+                    content = content.push(palette.status_text(
+                        Status::Warning,
+                        format!("Converted from legacy scale: {}", summary.legacy_converted),
+                    ));
+                }
+                if summary.scores_with_nonstandard_options > 0 {
+                    content = content.push(palette.status_text(
+                        Status::Warning,
+                        format!(
+                            "Scores with options USC can't store, imported as plain plays: {}",
+                            summary.scores_with_nonstandard_options
+                        ),
+                    ));
+                }
+                if summary.scores_with_extra_fields > 0 {
+                    content = content.push(palette.status_text(
+                        Status::Warning,
+                        format!(
+                            "Scores with fields unknown to this version, KSM may have a newer score format: {}",
+                            summary.scores_with_extra_fields
+                        ),
+                    ));
+                }
+                if summary.timestamps_backdated > 0 {
+                    content = content.push(Text::new(format!(
+                        "Score files backdated: {}",
+                        summary.timestamps_backdated
+                    )));
+                }
+                if summary.timestamps_adjusted > 0 {
+                    content = content.push(palette.status_text(
+                        Status::Warning,
+                        format!(
+                            "Timestamps adjusted for unusual file dates: {}",
+                            summary.timestamps_adjusted
+                        ),
+                    ));
+                }
+                if let Some(path) = &summary.spill_path {
+                    content = content.push(Text::new(format!(
+                        "Full failure list written to \"{}\"",
+                        path.to_str().unwrap_or_default()
+                    )));
+                }
+                if let Some(path) = &summary.backup_path {
+                    content = content.push(Text::new(format!(
+                        "Backup saved to \"{}\"",
+                        path.to_str().unwrap_or_default()
+                    )));
+                }
+
+                if let Some((before, after)) = summary.optimized_size {
+                    content = content.push(Text::new(format!(
+                        "Database optimized: {} -> {}",
+                        importer_funcs::format_size(before),
+                        importer_funcs::format_size(after)
+                    )));
+                }
+                if let Some(e) = &summary.optimize_error {
+                    content = content.push(palette.status_text(
+                        Status::Failure,
+                        format!(
+                            "Database optimization failed, imported scores are unaffected: {}",
+                            e
+                        ),
+                    ));
+                }
+
+                content = content.push(Text::new(format!(
+                    "Time spent: {}",
+                    report::format_timings(&summary.timings)
+                )));
+                if let Some(preflight) = self.preflight.as_ref().filter(|p| p.junk_files > 0) {
+                    content = content.push(Text::new(format!(
+                        "Backup and hidden score files ignored: {}",
+                        preflight.junk_files
+                    )));
+                }
+                if let Some(preflight) = self.preflight.as_ref().filter(|p| p.duplicate_charts > 0)
+                {
+                    content = content.push(palette.status_text(
+                        Status::Warning,
+                        format!(
+                            "{} charts are present multiple times in your library",
+                            preflight.duplicate_charts
+                        ),
+                    ));
+                }
+
+                if let Some(preflight) = self
+                    .preflight
+                    .as_ref()
+                    .filter(|p| !p.case_variants.is_empty())
+                {
+                    let example = preflight.case_variants[0]
+                        .iter()
+                        .map(|p| p.to_string_lossy())
+                        .collect::<Vec<_>>()
+                        .join(" and ");
+                    content = content.push(palette.status_text(
+                        Status::Warning,
+                        format!(
+                            "{} song folders or charts differ only by letter case, for example {}",
+                            preflight.case_variants.len(),
+                            example
+                        ),
+                    ));
+                }
+
+                if let Some(kind) = expanded {
+                    let charts = match kind {
+                        InsertKind::NewChart => &summary.new_charts,
+                        InsertKind::Improvement => &summary.improvements,
+                        InsertKind::AdditionalPlay => &summary.additional_plays,
+                    };
+                    content = content.push(
+                        Scrollable::new(&mut self.bucket_scroll)
+                            .height(Length::FillPortion(1))
+                            .push(table::view(
+                                &mut self.bucket_table,
+                                self.thousands_separator,
+                                "Chart",
+                                table::count_rows(charts),
+                                Message::SortBucketByName,
+                                Message::SortBucketByCount,
+                                Message::ExpandBucketRow,
+                            )),
+                    );
+                }
+
+                content
                     .push(iced::Space::with_height(Length::Units(10)))
-                    .push(Text::new("Errors:"))
-                    .push(error_view)
+                    .push(Text::new(if summary.warnings.is_empty() {
+                        "Errors:"
+                    } else {
+                        "Warnings and errors:"
+                    }))
+                    .push(error_view.height(Length::FillPortion(1)))
+                    .push(
+                        Row::new()
+                            .spacing(10)
+                            .push(
+                                Button::new(&mut self.save_report_button, Text::new("Save report"))
+                                    .on_press(Message::SaveReport),
+                            )
+                            .push({
+                                // A dry run exports the scores it would have imported.
+                                let export = Button::new(
+                                    &mut self.export_button,
+                                    Text::new("Export for stat site upload"),
+                                );
+                                if summary.imported_scores.is_empty() {
+                                    export
+                                } else {
+                                    export.on_press(Message::ExportScores)
+                                }
+                            })
+                            .push({
+                                let verify = Button::new(
+                                    &mut self.verify_backup_button,
+                                    Text::new("Verify backup"),
+                                );
+                                if summary.backup_path.is_some() {
+                                    verify.on_press(Message::VerifyBackup)
+                                } else {
+                                    verify
+                                }
+                            })
+                            .push({
+                                let copy = Button::new(
+                                    &mut self.copy_errors_button,
+                                    Text::new("Copy errors"),
+                                );
+                                if summary.fail_messages.is_empty() {
+                                    copy
+                                } else {
+                                    copy.on_press(Message::CopyErrors)
+                                }
+                            })
+                            .push(
+                                Button::new(&mut self.start_over_button, Text::new("Start over"))
+                                    .on_press(Message::StartOver),
+                            )
+                            .push(Text::new(if self.copied_at.is_some() {
+                                "Copied!"
+                            } else {
+                                ""
+                            })),
+                    )
             }
         };
 
@@ -266,3 +2162,231 @@ impl Application for State {
             .into()
     }
 }
+
+impl State {
+    // The options as Start would apply them, for checking while they're being edited. A date that
+    // doesn't parse yet is only reported on Start.
+    fn pending_options(&self) -> importer::ImportOptions {
+        let mut options = self.options.clone();
+        options.policy.timestamps = match importer_funcs::parse_date(&self.backdate_before) {
+            Some(cutoff) if self.backdate => importer_funcs::TimestampPolicy::Before(cutoff),
+            _ => importer_funcs::TimestampPolicy::Preserve,
+        };
+        options
+    }
+
+    fn import_running(&self) -> bool {
+        matches!(
+            self.progress,
+            Some(
+                Progress::Started
+                    | Progress::Advanced(..)
+                    | Progress::Locked
+                    | Progress::Optimizing
+            )
+        )
+    }
+
+    fn write_dirty_shutdown(&self) {
+        let db = self
+            .db_path
+            .as_ref()
+            .map(|p| p.to_string_lossy().to_string())
+            .unwrap_or_default();
+        if let Some(path) = config::dirty_shutdown_path() {
+            let _ = std::fs::write(path, db);
+        }
+    }
+
+    fn load_db_users(&mut self) {
+        self.db_users = self
+            .db_path
+            .as_ref()
+            .filter(|p| p.is_file())
+            .and_then(|p| importer::db_users(p).ok())
+            .unwrap_or_default();
+    }
+
+    fn record_run(&mut self, summary: &Summary, crashed: bool) {
+        if let (Some(ksm_path), Some(db_path)) = (&self.ksm_path, &self.db_path) {
+            let run = history::Run::new(&self.label, ksm_path, db_path, summary, crashed);
+            self.report_header = Some(report::Header {
+                error: self.crash_message.clone(),
+                ..run.header()
+            });
+            if self.options.dry_run {
+                return;
+            }
+            if let Err(e) = history::record(&run, summary) {
+                eprintln!("Failed to record import history: {:?}", e);
+            }
+        }
+    }
+
+    fn pick_db(&mut self) {
+        self.create_db = false;
+        self.detected_dbs.clear();
+        self.db_path = rfd::FileDialog::new()
+            .add_filter("Database", &["db"])
+            .pick_file()
+    }
+
+    fn prefill(&mut self, forwarded: Vec<instance::Forwarded>) {
+        // Never swap paths under a running import.
+        if self.progress.is_some() {
+            return;
+        }
+        for path in forwarded {
+            match path {
+                instance::Forwarded::Ksm(p) => self.ksm_path = Some(p),
+                instance::Forwarded::Db(p) => {
+                    self.create_db = false;
+                    self.db_path = Some(p)
+                }
+            }
+        }
+    }
+}
+
+fn detected_list<'a>(
+    paths: &[PathBuf],
+    buttons: &'a mut [button::State],
+    pick: fn(usize) -> Message,
+) -> Column<'a, Message> {
+    paths.iter().zip(buttons.iter_mut()).enumerate().fold(
+        Column::new().spacing(5),
+        |list, (i, (path, state))| {
+            list.push(
+                Button::new(
+                    state,
+                    Text::new(format!("Use {}", path.to_str().unwrap_or_default())),
+                )
+                .on_press(pick(i)),
+            )
+        },
+    )
+}
+
+fn bucket_row<'a>(
+    button_state: &'a mut button::State,
+    label: &str,
+    charts: &[String],
+    expanded: Option<InsertKind>,
+    kind: InsertKind,
+) -> Row<'a, Message> {
+    Row::new()
+        .align_items(iced::Align::Center)
+        .spacing(10)
+        .push(Text::new(format!("{}: {}", label, charts.len())))
+        .push(
+            Button::new(
+                button_state,
+                Text::new(if expanded == Some(kind) {
+                    "Hide"
+                } else {
+                    "Show"
+                }),
+            )
+            .on_press(Message::ToggleBucket(kind)),
+        )
+}
+
+fn intro_panel(show_again: bool, locale: i18n::Locale) -> Column<'static, Message> {
+    let text = |key| i18n::text(locale, key);
+    Column::new()
+        .spacing(10)
+        .max_width(600)
+        .push(Text::new(text(i18n::Key::IntroReads)))
+        .push(Text::new(text(i18n::Key::IntroWrites)))
+        .push(Text::new(text(i18n::Key::IntroPaths)))
+        .push(Checkbox::new(
+            !show_again,
+            text(i18n::Key::DontShowAgain),
+            Message::HideIntro,
+        ))
+}
+
+// Shows a dialog when USC holds maps.db, nothing should be written to it then.
+fn warn_if_locked(db_path: &std::path::Path) -> bool {
+    let locked = importer::db_locked(db_path);
+    if locked {
+        rfd::MessageDialog::new()
+            .set_title("maps.db is locked")
+            .set_description("USC is using maps.db. Close USC and try again.")
+            .set_level(rfd::MessageLevel::Warning)
+            .set_buttons(rfd::MessageButtons::Ok)
+            .show();
+    }
+    locked
+}
+
+fn format_skips(skipped: &BTreeMap<importer::SkipRule, u32>, separator: char) -> String {
+    let total: u32 = skipped.values().sum();
+    let rules: Vec<String> = skipped
+        .iter()
+        .map(|(rule, n)| {
+            format!(
+                "{}: {}",
+                rule.label(),
+                table::format_count((*n).into(), separator)
+            )
+        })
+        .collect();
+    format!(
+        "{} ({})",
+        table::format_count(total.into(), separator),
+        rules.join(", ")
+    )
+}
+
+// A value that doesn't parse ends the run, the same as an import that couldn't start.
+fn parsed_arg<T>(name: &str, parse: fn(&str) -> Option<T>) -> Option<T> {
+    let value = arg_value(name)?;
+    let value = value.to_string_lossy();
+    match parse(&value) {
+        Some(parsed) => Some(parsed),
+        None => {
+            eprintln!("Error: invalid value for {}: \"{}\"", name, value);
+            std::process::exit(cli::EXIT_ERRORED)
+        }
+    }
+}
+
+fn arg_value(name: &str) -> Option<PathBuf> {
+    let mut args = std::env::args_os();
+    args.find(|a| a == name)?;
+    args.next().map(PathBuf::from)
+}
+
+fn report_dirty_shutdown() {
+    let path = match config::dirty_shutdown_path() {
+        Some(path) => path,
+        None => return,
+    };
+    if let Ok(db) = std::fs::read_to_string(&path) {
+        let _ = std::fs::remove_file(&path);
+        rfd::MessageDialog::new()
+            .set_title("Previous import was interrupted")
+            .set_description(&format!(
+                "The app was closed before the last import into \"{}\" could stop. \
+                Score files finished before then were kept. The one being imported was never \
+                committed and SQLite takes it back the next time maps.db is opened, all of it \
+                for an all or nothing import. Import again to add the rest.",
+                db
+            ))
+            .set_level(rfd::MessageLevel::Warning)
+            .set_buttons(rfd::MessageButtons::Ok)
+            .show();
+    }
+}
+
+fn save_config(config: &config::Config) {
+    if let Err(e) = config::save(config) {
+        rfd::MessageDialog::new()
+            .set_title("Failed to save settings")
+            .set_description(&format!("{:?}", e))
+            .set_level(rfd::MessageLevel::Error)
+            .set_buttons(rfd::MessageButtons::Ok)
+            .show();
+    }
+}
@@ -0,0 +1,168 @@
+use crate::{checksum, importer::Timings, importer_funcs, Summary};
+use anyhow::Result;
+use serde::Serialize;
+use std::fmt::Write;
+use std::path::{Path, PathBuf};
+
+// Which run a report belongs to, as recorded in the history. Everything that changes between two
+// runs over the same files goes in the header, the rest of a report can be diffed.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct Header {
+    pub label: String,
+    pub finished: i64,
+    // Why the run stopped, for one that errored out.
+    pub error: Option<String>,
+    // The zip the scores were read from, for a run that didn't import a KSM folder.
+    pub archive: Option<PathBuf>,
+}
+
+#[derive(Serialize)]
+struct JsonReport<'a> {
+    label: &'a str,
+    finished: i64,
+    error: Option<&'a str>,
+    archive: Option<&'a Path>,
+    timings: &'a Timings,
+    #[serde(flatten)]
+    summary: &'a Summary,
+}
+
+// A .json path gets the serialized summary, anything else a plain text report. Either comes with
+// a .sha256 companion.
+pub fn write(summary: &Summary, header: Option<&Header>, path: &Path) -> Result<()> {
+    let contents = if path
+        .extension()
+        .is_some_and(|e| e.eq_ignore_ascii_case("json"))
+    {
+        json(summary, header)?
+    } else {
+        text(summary, header)?
+    };
+    std::fs::write(path, contents)?;
+    checksum::write_companion(path)?;
+    Ok(())
+}
+
+pub fn json(summary: &Summary, header: Option<&Header>) -> Result<String> {
+    Ok(match header {
+        Some(header) => serde_json::to_string_pretty(&JsonReport {
+            label: &header.label,
+            finished: header.finished,
+            error: header.error.as_deref(),
+            archive: header.archive.as_deref(),
+            timings: &summary.timings,
+            summary,
+        })?,
+        None => serde_json::to_string_pretty(summary)?,
+    })
+}
+
+// "4.2s: scanning 0.1s, reading 1.0s, ..." with the phases that took any time.
+pub fn format_timings(timings: &Timings) -> String {
+    let phases: Vec<String> = timings
+        .phases()
+        .iter()
+        .filter(|(_, time)| !time.is_zero())
+        .map(|(phase, time)| format!("{} {:.1}s", phase, time.as_secs_f64()))
+        .collect();
+    format!("{:.1}s: {}", timings.total.as_secs_f64(), phases.join(", "))
+}
+
+// Reports are saved as "ksm2usc-report-<label>.txt", the label is already safe in a file name.
+pub fn file_name(header: Option<&Header>) -> String {
+    match header.filter(|h| !h.label.is_empty()) {
+        Some(header) => format!("ksm2usc-report-{}.txt", header.label),
+        None => "ksm2usc-report.txt".to_string(),
+    }
+}
+
+fn text(summary: &Summary, header: Option<&Header>) -> Result<String> {
+    let mut report = String::new();
+    if let Some(header) = header {
+        writeln!(report, "Run: {}", header.label)?;
+        if let Some(archive) = &header.archive {
+            writeln!(
+                report,
+                "Read from the archive \"{}\"",
+                archive.to_str().unwrap_or_default()
+            )?;
+        }
+        writeln!(
+            report,
+            "Finished: {} UTC",
+            importer_funcs::format_date_time(header.finished)
+        )?;
+        if let Some(error) = &header.error {
+            writeln!(report, "Stopped by an error: {}", error)?;
+        }
+        writeln!(report, "Time spent: {}", format_timings(&summary.timings))?;
+        report.push('\n');
+    }
+    writeln!(
+        report,
+        "Score files processed: {} of {}",
+        summary.files_processed, summary.files_found
+    )?;
+    if summary.files_unmodified > 0 {
+        writeln!(
+            report,
+            "Score files not modified since the cutoff: {}",
+            summary.files_unmodified
+        )?;
+    }
+    writeln!(report, "Scores found: {}", summary.scores_found)?;
+    writeln!(report, "Scores imported: {}", summary.scores_imported)?;
+    writeln!(
+        report,
+        "Already in the database: {}",
+        summary.scores_skipped
+    )?;
+    if summary.scores_upgraded > 0 {
+        writeln!(
+            report,
+            "Earlier imports given a better badge: {}",
+            summary.scores_upgraded
+        )?;
+    }
+    writeln!(report, "New charts: {}", summary.new_charts.len())?;
+    writeln!(report, "Improvements: {}", summary.improvements.len())?;
+    writeln!(
+        report,
+        "Additional plays: {}",
+        summary.additional_plays.len()
+    )?;
+    if summary.scores_with_nonstandard_options > 0 {
+        writeln!(
+            report,
+            "Scores with options USC can't store, imported as plain plays: {}",
+            summary.scores_with_nonstandard_options
+        )?;
+    }
+    for (rule, count) in &summary.skipped {
+        writeln!(report, "Skipped for {}: {}", rule.label(), count)?;
+    }
+    writeln!(
+        report,
+        "Failed imports: {}",
+        summary.fail_messages.len() as u32 + summary.spilled_failures
+    )?;
+    if let Some(path) = &summary.backup_path {
+        writeln!(
+            report,
+            "Backup saved to \"{}\"",
+            path.to_str().unwrap_or_default()
+        )?;
+    }
+    report.push('\n');
+    for warning in &summary.warnings {
+        writeln!(report, "Warning: {}", warning)?;
+    }
+    for failure in &summary.fail_messages {
+        writeln!(report, "{}", failure.message)?;
+    }
+    // Low memory runs kept their failures out of the summary, they're only in the spill file.
+    if let Some(spill) = &summary.spill_path {
+        report.push_str(&std::fs::read_to_string(spill)?);
+    }
+    Ok(report)
+}
@@ -0,0 +1,678 @@
+use crate::importer::{GaugeType, KsmScore};
+use crate::InsertedRow;
+use anyhow::{ensure, Context, Result};
+use rusqlite::{types::Value, Connection};
+use std::path::Path;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ScoreField {
+    Score,
+    Crit,
+    Near,
+    Miss,
+    Gauge,
+    GaugeType,
+    Mirror,
+    Random,
+    Combo,
+    // Version 18's gameflags, the gauge, mirror and random bits of one play.
+    GameFlags,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum MetaField {
+    Timestamp,
+    ChartHash,
+    UserName,
+    UserId,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum Constant {
+    Int(i64),
+    Text(&'static str),
+    Bool(bool),
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum Source {
+    FromScore(ScoreField),
+    FromMeta(MetaField),
+    Constant(Constant),
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct Column {
+    pub name: &'static str,
+    pub source: Source,
+}
+
+pub struct Meta<'a> {
+    pub timestamp: i64,
+    pub chart_hash: &'a str,
+    pub user_name: &'a str,
+    pub user_id: i64,
+}
+
+// USC writes user ids as numbers. Local scores have always been written with user id 0, which is
+// what an empty id means.
+pub fn user_id(id: &str) -> Result<i64> {
+    if id.is_empty() {
+        return Ok(0);
+    }
+    id.parse()
+        .with_context(|| format!("The user id \"{}\" isn't a whole number", id))
+}
+
+const fn score(name: &'static str, field: ScoreField) -> Column {
+    Column {
+        name,
+        source: Source::FromScore(field),
+    }
+}
+
+const fn meta(name: &'static str, field: MetaField) -> Column {
+    Column {
+        name,
+        source: Source::FromMeta(field),
+    }
+}
+
+const fn constant(name: &'static str, value: Constant) -> Column {
+    Column {
+        name,
+        source: Source::Constant(value),
+    }
+}
+
+// Before 19 the gauge, mirror and random settings shared one gameflags column with USC's
+// GameFlags bits, the duplicate checks compare the column against the same bits. There was no
+// permissive gauge yet, those plays are skipped.
+pub const VERSION_18: &[Column] = &[
+    score("score", ScoreField::Score),
+    score("crit", ScoreField::Crit),
+    score("near", ScoreField::Near),
+    score("miss", ScoreField::Miss),
+    score("gauge", ScoreField::Gauge),
+    score("gameflags", ScoreField::GameFlags),
+    constant("replay", Constant::Text("")),
+    meta("timestamp", MetaField::Timestamp),
+    meta("chart_hash", MetaField::ChartHash),
+    meta("user_name", MetaField::UserName),
+    meta("user_id", MetaField::UserId),
+    constant("local_score", Constant::Bool(true)),
+    constant("window_perfect", Constant::Int(46)),
+    constant("window_good", Constant::Int(92)),
+    constant("window_hold", Constant::Int(138)),
+    constant("window_miss", Constant::Int(250)),
+    constant("window_slam", Constant::Int(84)),
+];
+
+pub const VERSION_19: &[Column] = &[
+    score("score", ScoreField::Score),
+    score("crit", ScoreField::Crit),
+    score("near", ScoreField::Near),
+    score("miss", ScoreField::Miss),
+    score("gauge", ScoreField::Gauge),
+    constant("auto_flags", Constant::Int(0)),
+    constant("replay", Constant::Text("")),
+    meta("timestamp", MetaField::Timestamp),
+    meta("chart_hash", MetaField::ChartHash),
+    meta("user_name", MetaField::UserName),
+    meta("user_id", MetaField::UserId),
+    constant("local_score", Constant::Bool(true)),
+    constant("window_perfect", Constant::Int(46)),
+    constant("window_good", Constant::Int(92)),
+    constant("window_hold", Constant::Int(138)),
+    constant("window_miss", Constant::Int(250)),
+    constant("window_slam", Constant::Int(84)),
+    score("gauge_type", ScoreField::GaugeType),
+    constant("gauge_opt", Constant::Int(0)),
+    score("mirror", ScoreField::Mirror),
+    score("random", ScoreField::Random),
+];
+
+// The early, late and combo columns are already in version 19, its import has always left them
+// empty. From 20 on they're filled: KSM keeps no early/late split, and the longest chain is only
+// known for a full combo, it's every note then.
+pub const VERSION_20: &[Column] = &[
+    score("score", ScoreField::Score),
+    score("crit", ScoreField::Crit),
+    score("near", ScoreField::Near),
+    constant("early", Constant::Int(0)),
+    constant("late", Constant::Int(0)),
+    score("combo", ScoreField::Combo),
+    score("miss", ScoreField::Miss),
+    score("gauge", ScoreField::Gauge),
+    constant("auto_flags", Constant::Int(0)),
+    constant("replay", Constant::Text("")),
+    meta("timestamp", MetaField::Timestamp),
+    meta("chart_hash", MetaField::ChartHash),
+    meta("user_name", MetaField::UserName),
+    meta("user_id", MetaField::UserId),
+    constant("local_score", Constant::Bool(true)),
+    constant("window_perfect", Constant::Int(46)),
+    constant("window_good", Constant::Int(92)),
+    constant("window_hold", Constant::Int(138)),
+    constant("window_miss", Constant::Int(250)),
+    constant("window_slam", Constant::Int(84)),
+    score("gauge_type", ScoreField::GaugeType),
+    constant("gauge_opt", Constant::Int(0)),
+    score("mirror", ScoreField::Mirror),
+    score("random", ScoreField::Random),
+];
+
+// Version 21 is written like 20, check_columns stops the import up front when its Scores table
+// needs a column none of these fill.
+pub const VERSION_21: &[Column] = VERSION_20;
+
+const FLAG_HARD: i64 = 1;
+const FLAG_MIRROR: i64 = 1 << 1;
+const FLAG_RANDOM: i64 = 1 << 2;
+
+pub fn game_flags(ksm: &KsmScore) -> i64 {
+    [
+        (ksm.gauge_type == GaugeType::Hard, FLAG_HARD),
+        (ksm.mirror, FLAG_MIRROR),
+        (ksm.random, FLAG_RANDOM),
+    ]
+    .iter()
+    .filter(|(set, _)| *set)
+    .fold(0, |flags, (_, flag)| flags | flag)
+}
+
+pub fn has_game_flags(columns: &[Column]) -> bool {
+    columns
+        .iter()
+        .any(|c| matches!(c.source, Source::FromScore(ScoreField::GameFlags)))
+}
+
+fn gauge_column(columns: &[Column]) -> &'static str {
+    columns
+        .iter()
+        .find(|c| matches!(c.source, Source::FromScore(ScoreField::GaugeType)))
+        .map_or("gauge_type", |c| c.name)
+}
+
+// The condition and its value that find the rows played on the gauge of `ksm`.
+pub fn same_gauge(columns: &[Column], ksm: &KsmScore) -> (String, i64) {
+    if has_game_flags(columns) {
+        return (
+            format!("gameflags & {} = ?", FLAG_HARD),
+            game_flags(ksm) & FLAG_HARD,
+        );
+    }
+    (
+        format!("{} = ?", gauge_column(columns)),
+        ksm.gauge_type.value(),
+    )
+}
+
+// Like same_gauge, a gameflags row also has to have the mirror and random bits of `ksm`.
+pub fn same_settings(columns: &[Column], ksm: &KsmScore) -> (String, i64) {
+    if has_game_flags(columns) {
+        return ("gameflags = ?".to_string(), game_flags(ksm));
+    }
+    same_gauge(columns, ksm)
+}
+
+pub fn insert_sql(columns: &[Column]) -> String {
+    let names: Vec<&str> = columns.iter().map(|c| c.name).collect();
+    let placeholders = vec!["?"; columns.len()];
+    format!(
+        "INSERT INTO Scores({}) VALUES({})",
+        names.join(","),
+        placeholders.join(",")
+    )
+}
+
+fn score_value(field: ScoreField, ksm: &KsmScore) -> Value {
+    match field {
+        ScoreField::Score => Value::Integer(ksm.score.into()),
+        ScoreField::Crit => Value::Integer(ksm.crit.into()),
+        ScoreField::Near => Value::Integer(ksm.near.into()),
+        ScoreField::Miss => Value::Integer(ksm.miss.into()),
+        ScoreField::Gauge => Value::Real(ksm.gauge),
+        ScoreField::GaugeType => Value::Integer(ksm.gauge_type.value()),
+        ScoreField::Mirror => Value::Integer(ksm.mirror.into()),
+        ScoreField::Random => Value::Integer(ksm.random.into()),
+        ScoreField::Combo if ksm.miss == 0 => Value::Integer((ksm.crit + ksm.near).into()),
+        ScoreField::Combo => Value::Integer(0),
+        ScoreField::GameFlags => Value::Integer(game_flags(ksm)),
+    }
+}
+
+pub fn bind(columns: &[Column], ksm: &KsmScore, meta: &Meta) -> Vec<Value> {
+    columns
+        .iter()
+        .map(|c| match c.source {
+            Source::FromScore(field) => score_value(field, ksm),
+            Source::FromMeta(field) => match field {
+                MetaField::Timestamp => Value::Integer(meta.timestamp),
+                MetaField::ChartHash => Value::Text(meta.chart_hash.to_string()),
+                MetaField::UserName => Value::Text(meta.user_name.to_string()),
+                MetaField::UserId => Value::Integer(meta.user_id),
+            },
+            Source::Constant(value) => match value {
+                Constant::Int(i) => Value::Integer(i),
+                Constant::Text(t) => Value::Text(t.to_string()),
+                Constant::Bool(b) => Value::Integer(b.into()),
+            },
+        })
+        .collect()
+}
+
+// Everything a play fills in besides its score, which is what a better badge changes.
+fn result_fields(columns: &[Column]) -> impl Iterator<Item = (&'static str, ScoreField)> + '_ {
+    columns.iter().filter_map(|c| match c.source {
+        Source::FromScore(ScoreField::Score) => None,
+        Source::FromScore(field) => Some((c.name, field)),
+        _ => None,
+    })
+}
+
+// Rewrites the result of one row, only while it still holds the chart, score and timestamp it
+// was written with. Binds with bind_upgrade.
+pub fn upgrade_sql(columns: &[Column]) -> String {
+    let set: Vec<String> = result_fields(columns)
+        .map(|(name, _)| format!("{} = ?", name))
+        .collect();
+    format!(
+        "UPDATE Scores SET {} WHERE rowid = ? AND chart_hash = ? AND score = ? AND timestamp = ?",
+        set.join(", ")
+    )
+}
+
+pub fn bind_upgrade(columns: &[Column], ksm: &KsmScore, row: &InsertedRow) -> Vec<Value> {
+    result_fields(columns)
+        .map(|(_, field)| score_value(field, ksm))
+        .chain(vec![
+            Value::Integer(row.rowid),
+            Value::Text(row.chart_hash.clone()),
+            Value::Integer(row.score.into()),
+            Value::Integer(row.timestamp),
+        ])
+        .collect()
+}
+
+fn scores_columns(db: &Connection, filter: &str) -> Result<Vec<String>> {
+    let mut stmt = db.prepare(&format!(
+        "SELECT name FROM pragma_table_info('Scores') {}",
+        filter
+    ))?;
+    let names = stmt
+        .query_map([], |r| r.get::<_, String>(0))?
+        .collect::<rusqlite::Result<Vec<_>>>()?;
+    Ok(names)
+}
+
+// Columns the Scores table needs on insert that none of `columns` fill, these would fail every
+// score of a newer USC version.
+pub fn unfilled_columns(db: &Connection, columns: &[Column]) -> Result<Vec<String>> {
+    let required = scores_columns(db, "WHERE \"notnull\" AND dflt_value IS NULL AND NOT pk")?;
+    Ok(required
+        .into_iter()
+        .filter(|name| !columns.iter().any(|c| c.name == name))
+        .collect())
+}
+
+fn known_column(name: &str) -> Option<Column> {
+    NEWEST_COLUMNS
+        .iter()
+        .chain(VERSION_18)
+        .find(|c| c.name == name)
+        .copied()
+}
+
+// For a version no importer knows, the known columns its Scores table has. Anything else is
+// left to its default.
+pub fn detect_columns(db: &Connection) -> Result<Vec<Column>> {
+    let existing = scores_columns(db, "")?;
+    let columns: Vec<Column> = existing.iter().filter_map(|n| known_column(n)).collect();
+    ensure!(
+        ["score", "chart_hash", "timestamp"]
+            .iter()
+            .all(|name| columns.iter().any(|c| c.name == *name)),
+        "Scores has no score, chart_hash or timestamp column to write to"
+    );
+    Ok(columns)
+}
+
+// What detect_columns makes of a Scores table, shown before an import into an unknown version
+// and kept in its report.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct Audit {
+    pub written: Vec<&'static str>,
+    // Columns this tool doesn't know, they get their defaults.
+    pub defaulted: Vec<String>,
+    // Columns of the newest known version the table has no place for, what they hold is lost.
+    pub dropped: Vec<&'static str>,
+}
+
+impl Audit {
+    pub fn lines(&self) -> Vec<String> {
+        let mut lines = vec![format!(
+            "Writes the Scores columns it recognizes: {}",
+            self.written.join(", ")
+        )];
+        if !self.defaulted.is_empty() {
+            lines.push(format!(
+                "Leaves unknown columns to their defaults: {}",
+                self.defaulted.join(", ")
+            ));
+        }
+        if !self.dropped.is_empty() {
+            lines.push(format!(
+                "Has no column for, so doesn't import: {}",
+                self.dropped.join(", ")
+            ));
+        }
+        lines
+    }
+}
+
+pub fn audit(existing: &[String]) -> Audit {
+    let written: Vec<Column> = existing.iter().filter_map(|n| known_column(n)).collect();
+    // The settings of a version 18 gameflags column still count as written.
+    let has_place = |column: &Column| {
+        written.iter().any(|w| match (w.source, column.source) {
+            (
+                Source::FromScore(ScoreField::GameFlags),
+                Source::FromScore(ScoreField::GaugeType | ScoreField::Mirror | ScoreField::Random),
+            ) => true,
+            (Source::FromScore(a), Source::FromScore(b)) => a == b,
+            _ => w.name == column.name,
+        })
+    };
+    Audit {
+        written: written.iter().map(|c| c.name).collect(),
+        defaulted: existing
+            .iter()
+            .filter(|n| known_column(n).is_none())
+            .cloned()
+            .collect(),
+        dropped: NEWEST_COLUMNS
+            .iter()
+            .filter(|c| !has_place(c))
+            .map(|c| c.name)
+            .collect(),
+    }
+}
+
+pub fn audit_db(db: &Connection) -> Result<Audit> {
+    Ok(audit(&scores_columns(db, "")?))
+}
+
+// Columns of `columns` the Scores table doesn't have.
+pub fn missing_columns(db: &Connection, columns: &[Column]) -> Result<Vec<&'static str>> {
+    let existing = scores_columns(db, "")?;
+    Ok(columns
+        .iter()
+        .map(|c| c.name)
+        .filter(|name| !existing.iter().any(|e| e == name))
+        .collect())
+}
+
+// What the newest version the importer knows writes, unknown versions are compared against it.
+pub const NEWEST_COLUMNS: &[Column] = VERSION_21;
+pub const NEWEST_VERSION: u32 = 19;
+pub(crate) const NEWEST_SCHEMA: &str = include_str!("maps_v19.sql");
+
+pub fn create_database(path: &Path) -> Result<()> {
+    ensure!(!path.exists(), "Database already exists: {:?}", path);
+    let db = Connection::open(path)?;
+    if let Err(e) = db.execute_batch(NEWEST_SCHEMA) {
+        drop(db);
+        let _ = std::fs::remove_file(path);
+        return Err(e.into());
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::memory_db;
+
+    fn hard_clear() -> KsmScore {
+        KsmScore {
+            score: 9_876_543,
+            crit: 980,
+            near: 15,
+            miss: 5,
+            gauge: 0.625,
+            badge: 3,
+            gauge_type: GaugeType::Hard,
+            mirror: false,
+            random: false,
+            nonstandard: false,
+            extra: Vec::new(),
+        }
+    }
+
+    const META: Meta = Meta {
+        timestamp: 1_600_000_000,
+        chart_hash: "abc",
+        user_name: "",
+        user_id: 0,
+    };
+
+    #[test]
+    fn version_19_insert_matches_the_original() {
+        assert_eq!(
+            insert_sql(VERSION_19),
+            "INSERT INTO Scores(score,crit,near,miss,gauge,auto_flags,replay,timestamp,\
+            chart_hash,user_name,user_id,local_score,window_perfect,window_good,window_hold,\
+            window_miss,window_slam,gauge_type,gauge_opt,mirror,random) \
+            VALUES(?,?,?,?,?,?,?,?,?,?,?,?,?,?,?,?,?,?,?,?,?)"
+        );
+        use Value::{Integer as I, Real, Text};
+        assert_eq!(
+            bind(VERSION_19, &hard_clear(), &META),
+            vec![
+                I(9_876_543),
+                I(980),
+                I(15),
+                I(5),
+                Real(0.625),
+                I(0),
+                Text(String::new()),
+                I(1_600_000_000),
+                Text("abc".to_string()),
+                Text(String::new()),
+                I(0),
+                I(1),
+                I(46),
+                I(92),
+                I(138),
+                I(250),
+                I(84),
+                I(1),
+                I(0),
+                I(0),
+                I(0),
+            ]
+        );
+    }
+
+    #[test]
+    fn version_18_insert() {
+        assert_eq!(
+            insert_sql(VERSION_18),
+            "INSERT INTO Scores(score,crit,near,miss,gauge,gameflags,replay,timestamp,\
+            chart_hash,user_name,user_id,local_score,window_perfect,window_good,window_hold,\
+            window_miss,window_slam) VALUES(?,?,?,?,?,?,?,?,?,?,?,?,?,?,?,?,?)"
+        );
+        let values = bind(VERSION_18, &hard_clear(), &META);
+        assert_eq!(values.len(), VERSION_18.len());
+        assert_eq!(values[5], Value::Integer(1));
+        let mir_ran = KsmScore {
+            gauge_type: GaugeType::Normal,
+            mirror: true,
+            random: true,
+            ..hard_clear()
+        };
+        assert_eq!(bind(VERSION_18, &mir_ran, &META)[5], Value::Integer(6));
+    }
+
+    #[test]
+    fn game_flags_use_the_usc_bits() {
+        let flags = |gauge_type, mirror, random| {
+            game_flags(&KsmScore {
+                gauge_type,
+                mirror,
+                random,
+                ..hard_clear()
+            })
+        };
+        assert_eq!(flags(GaugeType::Normal, false, false), 0);
+        assert_eq!(flags(GaugeType::Hard, false, false), 1);
+        assert_eq!(flags(GaugeType::Normal, true, false), 2);
+        assert_eq!(flags(GaugeType::Hard, false, true), 5);
+        assert_eq!(flags(GaugeType::Hard, true, true), 7);
+    }
+
+    #[test]
+    fn user_ids_are_whole_numbers() {
+        assert_eq!(user_id("").unwrap(), 0);
+        assert_eq!(user_id("42").unwrap(), 42);
+        assert!(user_id("abc").unwrap_err().to_string().contains("\"abc\""));
+        assert!(user_id("4.2").is_err());
+    }
+
+    #[test]
+    fn bound_values_land_in_their_columns() {
+        let db = memory_db();
+        db.execute(
+            &insert_sql(VERSION_19),
+            rusqlite::params_from_iter(bind(VERSION_19, &hard_clear(), &META)),
+        )
+        .unwrap();
+        let row: (i64, f64, i64, String) = db
+            .query_row(
+                "SELECT score, gauge, gauge_type, chart_hash FROM Scores",
+                [],
+                |r| Ok((r.get(0)?, r.get(1)?, r.get(2)?, r.get(3)?)),
+            )
+            .unwrap();
+        assert_eq!(row, (9_876_543, 0.625, 1, "abc".to_string()));
+    }
+
+    #[test]
+    fn bundled_schema_is_the_newest_version() {
+        let db = memory_db();
+        let version: u32 = db
+            .query_row("SELECT version FROM Database", [], |r| r.get(0))
+            .unwrap();
+        assert_eq!(version, NEWEST_VERSION);
+        assert!(missing_columns(&db, VERSION_19).unwrap().is_empty());
+    }
+
+    // Name, type, not null and primary key of every column.
+    type Layout = Vec<(String, String, bool, bool)>;
+
+    fn table_layouts(db: &Connection) -> Vec<(String, Layout)> {
+        let tables: Vec<String> = db
+            .prepare("SELECT name FROM sqlite_master WHERE type = 'table' ORDER BY name")
+            .unwrap()
+            .query_map([], |r| r.get(0))
+            .unwrap()
+            .map(|r| r.unwrap())
+            .collect();
+        tables
+            .into_iter()
+            .map(|table| {
+                let columns = db
+                    .prepare(&format!(
+                        "SELECT name, type, \"notnull\", pk FROM pragma_table_info('{}')",
+                        table
+                    ))
+                    .unwrap()
+                    .query_map([], |r| {
+                        Ok((r.get(0)?, r.get(1)?, r.get(2)?, r.get::<_, i64>(3)? > 0))
+                    })
+                    .unwrap()
+                    .map(|r| r.unwrap())
+                    .collect();
+                (table, columns)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn bundled_schema_matches_what_usc_creates() {
+        let usc = Connection::open_in_memory().unwrap();
+        usc.execute_batch(include_str!("../tests/fixtures/maps_v19_schema.sql"))
+            .unwrap();
+        assert_eq!(table_layouts(&memory_db()), table_layouts(&usc));
+    }
+
+    fn columns(names: &str) -> Vec<String> {
+        names.split(',').map(str::to_string).collect()
+    }
+
+    #[test]
+    fn the_bundled_schema_has_a_place_for_every_column() {
+        let audit = audit_db(&memory_db()).unwrap();
+        assert_eq!(audit.written.len(), NEWEST_COLUMNS.len());
+        assert!(audit.defaulted.is_empty());
+        assert!(audit.dropped.is_empty());
+        assert_eq!(audit.lines().len(), 1);
+    }
+
+    #[test]
+    fn a_future_schema_lists_new_and_dropped_columns() {
+        let existing = columns(
+            "score,crit,near,miss,gauge,auto_flags,replay,timestamp,chart_hash,user_name,\
+            user_id,local_score,window_perfect,window_good,window_hold,window_miss,window_slam,\
+            gauge_type,gauge_opt,gauge_graph,hit_stats",
+        );
+        let audit = audit(&existing);
+        assert_eq!(audit.defaulted, columns("gauge_graph,hit_stats"));
+        assert_eq!(
+            audit.dropped,
+            vec!["early", "late", "combo", "mirror", "random"]
+        );
+        assert!(!audit.written.contains(&"gauge_graph"));
+        let lines = audit.lines();
+        assert_eq!(lines.len(), 3);
+        assert_eq!(
+            lines[2],
+            "Has no column for, so doesn't import: early, late, combo, mirror, random"
+        );
+    }
+
+    #[test]
+    fn a_gameflags_column_keeps_the_gauge_type() {
+        let audit = audit(&columns("score,gauge,gameflags,timestamp,chart_hash"));
+        assert_eq!(
+            audit.written,
+            vec!["score", "gauge", "gameflags", "timestamp", "chart_hash"]
+        );
+        assert!(!audit.dropped.contains(&"gauge_type"));
+        assert!(!audit.dropped.contains(&"mirror"));
+        assert!(!audit.dropped.contains(&"random"));
+        assert!(audit.dropped.contains(&"crit"));
+        assert!(audit.defaulted.is_empty());
+        assert_eq!(audit.lines().len(), 2);
+    }
+
+    #[test]
+    fn version_20_insert() {
+        assert_eq!(
+            insert_sql(VERSION_20),
+            "INSERT INTO Scores(score,crit,near,early,late,combo,miss,gauge,auto_flags,replay,\
+            timestamp,chart_hash,user_name,user_id,local_score,window_perfect,window_good,\
+            window_hold,window_miss,window_slam,gauge_type,gauge_opt,mirror,random) \
+            VALUES(?,?,?,?,?,?,?,?,?,?,?,?,?,?,?,?,?,?,?,?,?,?,?,?)"
+        );
+        let values = bind(VERSION_20, &hard_clear(), &META);
+        assert_eq!(values.len(), VERSION_20.len());
+        assert_eq!(values[3..6], vec![Value::Integer(0); 3][..]);
+        let full_combo = KsmScore {
+            miss: 0,
+            ..hard_clear()
+        };
+        assert_eq!(bind(VERSION_20, &full_combo, &META)[5], Value::Integer(995));
+    }
+}
@@ -0,0 +1,329 @@
+use crate::importer::{self, ScoreFileKind};
+use crate::importer_funcs;
+use crate::watchdog;
+use anyhow::{bail, Result};
+use std::collections::BTreeMap;
+use std::io::{self, Read};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::AtomicBool;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use zip::result::ZipError;
+
+pub struct ScoreFiles {
+    pub files: Vec<PathBuf>,
+    pub junk_files: u32,
+}
+
+pub trait ScoreSource {
+    fn score_files(&self, warnings: &mut Vec<String>) -> Result<ScoreFiles>;
+    fn read_score(&self, path: &Path) -> Result<Vec<u8>>;
+    fn modified(&self, path: &Path) -> Result<SystemTime>;
+    fn size(&self, path: &Path) -> Result<u64>;
+}
+
+pub trait ChartSource {
+    fn chart_exists(&self, path: &Path) -> bool;
+    fn read_chart(&self, path: &Path) -> Result<Vec<u8>>;
+    // Other charts whose pack, song or file name differ from path only by letter case.
+    fn case_variants(&self, path: &Path) -> Vec<PathBuf>;
+}
+
+pub trait Source: ScoreSource + ChartSource + std::fmt::Debug + Send + Sync {}
+
+impl<T: ScoreSource + ChartSource + std::fmt::Debug + Send + Sync> Source for T {}
+
+#[derive(Debug)]
+pub struct FsSource {
+    pub ksm_path: PathBuf,
+    pub cancel: Arc<AtomicBool>,
+}
+
+impl FsSource {
+    // A share that stopped answering fails the call instead of hanging the import.
+    fn run<T, F>(&self, what: &str, path: &Path, f: F) -> Result<T>
+    where
+        T: Send + 'static,
+        F: FnOnce(PathBuf) -> Result<T> + Send + 'static,
+    {
+        let owned = path.to_path_buf();
+        watchdog::run_with_timeout(
+            watchdog::STALL_TIMEOUT,
+            &self.cancel,
+            &format!("{} \"{}\"", what, path.to_str().unwrap_or_default()),
+            move || f(owned),
+        )
+    }
+}
+
+impl ScoreSource for FsSource {
+    fn score_files(&self, warnings: &mut Vec<String>) -> Result<ScoreFiles> {
+        importer::enumerate_ksm_score_files(&self.ksm_path, &self.cancel, warnings)
+    }
+
+    fn read_score(&self, path: &Path) -> Result<Vec<u8>> {
+        watchdog::read_file(path, &self.cancel)
+    }
+
+    fn modified(&self, path: &Path) -> Result<SystemTime> {
+        self.run("reading the date of", path, |path| {
+            Ok(std::fs::metadata(path)?.modified()?)
+        })
+    }
+
+    fn size(&self, path: &Path) -> Result<u64> {
+        self.run("reading the size of", path, |path| {
+            Ok(std::fs::metadata(path)?.len())
+        })
+    }
+}
+
+impl ChartSource for FsSource {
+    fn chart_exists(&self, path: &Path) -> bool {
+        self.run("looking for", path, |path| Ok(path.exists()))
+            .unwrap_or(false)
+    }
+
+    fn read_chart(&self, path: &Path) -> Result<Vec<u8>> {
+        watchdog::read_file(path, &self.cancel)
+    }
+
+    fn case_variants(&self, path: &Path) -> Vec<PathBuf> {
+        self.run("listing the folders of", path, |path| {
+            Ok(case_variants_on_disk(&path))
+        })
+        .unwrap_or_default()
+    }
+}
+
+fn case_variants_on_disk(path: &Path) -> Vec<PathBuf> {
+    let names: Vec<_> = path.iter().collect();
+    if names.len() < 3 {
+        return Vec::new();
+    }
+    let (base, names) = names.split_at(names.len() - 3);
+    let base: PathBuf = base.iter().collect();
+    names
+        .iter()
+        .fold(vec![base], |dirs, name| {
+            let name = name.to_string_lossy().to_lowercase();
+            dirs.iter()
+                .filter_map(|dir| std::fs::read_dir(dir).ok())
+                .flatten()
+                .filter_map(|e| e.ok())
+                .filter(|e| e.file_name().to_string_lossy().to_lowercase() == name)
+                .map(|e| e.path())
+                .collect()
+        })
+        .into_iter()
+        .filter(|p| p != path)
+        .collect()
+}
+
+// Holds a KSM folder in memory, keyed by paths relative to the KSM root such as
+// "score/<profile>/<pack>/<song>/<difficulty>.ksc" and "songs/<pack>/<song>/<difficulty>.ksh".
+#[derive(Debug, Default)]
+pub struct MemorySource {
+    files: BTreeMap<PathBuf, (Vec<u8>, SystemTime)>,
+}
+
+impl MemorySource {
+    // Entries map as if the archive was extracted over a KSM folder, whether it holds the whole
+    // KSM folder, the score folder or only the profile folders inside it. No charts ship with a
+    // score archive, so those are matched through the Charts table. Only score files and charts
+    // are read, a zip of a whole KSM folder also holds the audio of every song.
+    pub fn from_zip(path: &Path) -> Result<Self> {
+        let mut archive = zip::ZipArchive::new(std::fs::File::open(path)?)?;
+        let mut source = Self::default();
+        for i in 0..archive.len() {
+            let mut entry = match archive.by_index(i) {
+                Err(ZipError::UnsupportedArchive(ZipError::PASSWORD_REQUIRED)) => bail!(
+                    "\"{}\" is password protected, extract it and pick the folder instead",
+                    path.to_str().unwrap_or_default()
+                ),
+                entry => entry?,
+            };
+            let name = match entry.enclosed_name() {
+                Some(name) if !entry.is_dir() => archive_path(name),
+                _ => continue,
+            };
+            if !is_imported(&name) {
+                continue;
+            }
+            let modified = zip_time(entry.last_modified());
+            let mut contents = Vec::new();
+            entry.read_to_end(&mut contents)?;
+            source.insert(name, contents, modified);
+        }
+        Ok(source)
+    }
+
+    pub fn insert(&mut self, path: impl Into<PathBuf>, contents: Vec<u8>, modified: SystemTime) {
+        self.files.insert(path.into(), (contents, modified));
+    }
+
+    fn get(&self, path: &Path) -> Result<&(Vec<u8>, SystemTime)> {
+        self.files.get(path).ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("{:?} is not in the source", path),
+            )
+            .into()
+        })
+    }
+}
+
+pub fn is_archive(path: &Path) -> bool {
+    path.is_file()
+        && path
+            .extension()
+            .is_some_and(|e| e.eq_ignore_ascii_case("zip"))
+}
+
+fn archive_path(name: &Path) -> PathBuf {
+    // Shadow entries keep their folder so they can't land on the files they describe.
+    if name.iter().any(|c| c == importer::MACOS_SHADOW_DIR) {
+        return Path::new("score").join(name);
+    }
+    match name.iter().position(|c| c == "score" || c == "songs") {
+        Some(root) => name.iter().skip(root).collect(),
+        None => Path::new("score").join(name),
+    }
+}
+
+fn is_imported(path: &Path) -> bool {
+    (path.starts_with("score") && importer::classify_score_file(path).is_some())
+        || (path.starts_with("songs")
+            && path
+                .extension()
+                .is_some_and(|e| e.eq_ignore_ascii_case("ksh")))
+}
+
+// Zip timestamps carry no time zone, they are taken as UTC.
+fn zip_time(time: zip::DateTime) -> SystemTime {
+    let date = format!("{}-{}-{}", time.year(), time.month(), time.day());
+    let secs = importer_funcs::parse_date(&date).unwrap_or_default()
+        + i64::from(time.hour()) * 3600
+        + i64::from(time.minute()) * 60
+        + i64::from(time.second());
+    UNIX_EPOCH + Duration::from_secs(secs.max(0) as u64)
+}
+
+impl ScoreSource for MemorySource {
+    fn score_files(&self, _warnings: &mut Vec<String>) -> Result<ScoreFiles> {
+        let mut junk_files = 0;
+        let files = self
+            .files
+            .keys()
+            .filter(|p| p.starts_with("score"))
+            .filter(|p| match importer::classify_score_file(p) {
+                Some(ScoreFileKind::Score) => true,
+                Some(ScoreFileKind::Junk) => {
+                    junk_files += 1;
+                    false
+                }
+                None => false,
+            })
+            .cloned()
+            .collect();
+        Ok(ScoreFiles { files, junk_files })
+    }
+
+    fn read_score(&self, path: &Path) -> Result<Vec<u8>> {
+        Ok(self.get(path)?.0.clone())
+    }
+
+    fn modified(&self, path: &Path) -> Result<SystemTime> {
+        Ok(self.get(path)?.1)
+    }
+
+    fn size(&self, path: &Path) -> Result<u64> {
+        Ok(self.get(path)?.0.len() as u64)
+    }
+}
+
+impl ChartSource for MemorySource {
+    fn chart_exists(&self, path: &Path) -> bool {
+        self.files.contains_key(path)
+    }
+
+    fn read_chart(&self, path: &Path) -> Result<Vec<u8>> {
+        Ok(self.get(path)?.0.clone())
+    }
+
+    fn case_variants(&self, path: &Path) -> Vec<PathBuf> {
+        let lower = path.to_string_lossy().to_lowercase();
+        self.files
+            .keys()
+            .filter(|p| *p != path && p.to_string_lossy().to_lowercase() == lower)
+            .cloned()
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn fs_source_goes_through_the_watchdog() {
+        let ksm = tempfile::tempdir().unwrap();
+        let chart = ksm.path().join("songs/pack/song/ex.ksh");
+        let variant = ksm.path().join("songs/Pack/Song/ex.ksh");
+        for path in [&chart, &variant] {
+            std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+            std::fs::write(path, b"chart").unwrap();
+        }
+        let source = FsSource {
+            ksm_path: ksm.path().to_path_buf(),
+            cancel: Arc::default(),
+        };
+        assert!(source.chart_exists(&chart));
+        assert!(!source.chart_exists(&ksm.path().join("songs/pack/song/mxm.ksh")));
+        assert_eq!(source.read_chart(&chart).unwrap(), b"chart");
+        assert_eq!(source.size(&chart).unwrap(), 5);
+        assert!(source.modified(&chart).is_ok());
+        // Only a case-sensitive filesystem can hold both.
+        if source.chart_exists(&variant)
+            && std::fs::read_dir(ksm.path().join("songs")).unwrap().count() == 2
+        {
+            assert_eq!(source.case_variants(&chart), vec![variant]);
+        }
+    }
+
+    #[test]
+    fn a_zip_only_loads_scores_and_charts() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("KSM.zip");
+        let mut zip = zip::ZipWriter::new(std::fs::File::create(&path).unwrap());
+        for name in [
+            "KSM/score/PLAYER/pack/song/ex.ksc",
+            "KSM/songs/pack/song/ex.ksh",
+            "KSM/songs/pack/song/song.ogg",
+            "KSM/songs/pack/song/jacket.png",
+            "KSM/imgs/title.png",
+        ]
+        .iter()
+        {
+            zip.start_file(*name, zip::write::FileOptions::default())
+                .unwrap();
+            io::Write::write_all(&mut zip, name.as_bytes()).unwrap();
+        }
+        zip.finish().unwrap();
+
+        let source = MemorySource::from_zip(&path).unwrap();
+        assert_eq!(
+            source.files.keys().collect::<Vec<_>>(),
+            vec![
+                Path::new("score/PLAYER/pack/song/ex.ksc"),
+                Path::new("songs/pack/song/ex.ksh")
+            ]
+        );
+        assert_eq!(
+            source
+                .read_chart(Path::new("songs/pack/song/ex.ksh"))
+                .unwrap(),
+            b"KSM/songs/pack/song/ex.ksh"
+        );
+    }
+}
@@ -0,0 +1,59 @@
+use iced::{Color, Text};
+
+pub const MIN_SCALE: f64 = 0.75;
+pub const MAX_SCALE: f64 = 2.0;
+pub const SCALE_STEP: f64 = 0.25;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Status {
+    Success,
+    Failure,
+    Warning,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct Palette {
+    pub high_contrast: bool,
+}
+
+impl Palette {
+    pub fn color(&self, status: Status) -> Color {
+        // The high contrast set keeps blue/vermillion/purple apart for red-green colorblindness.
+        match (self.high_contrast, status) {
+            (false, Status::Success) => Color::from_rgb(0.1, 0.55, 0.1),
+            (false, Status::Failure) => Color::from_rgb(0.8, 0.1, 0.1),
+            (false, Status::Warning) => Color::from_rgb(0.75, 0.45, 0.0),
+            (true, Status::Success) => Color::from_rgb(0.0, 0.35, 0.6),
+            (true, Status::Failure) => Color::from_rgb(0.75, 0.25, 0.0),
+            (true, Status::Warning) => Color::from_rgb(0.45, 0.2, 0.5),
+        }
+    }
+
+    fn glyph(&self, status: Status) -> Option<&'static str> {
+        if !self.high_contrast {
+            return None;
+        }
+        Some(match status {
+            Status::Success => "✓",
+            Status::Failure => "✗",
+            Status::Warning => "⚠",
+        })
+    }
+
+    pub fn status_text(&self, status: Status, label: impl Into<String>) -> Text {
+        let label = label.into();
+        let label = match self.glyph(status) {
+            Some(glyph) => format!("{} {}", glyph, label),
+            None => label,
+        };
+        Text::new(label).color(self.color(status))
+    }
+}
+
+pub fn clamp_scale(scale: f64) -> f64 {
+    if scale.is_finite() {
+        scale.clamp(MIN_SCALE, MAX_SCALE)
+    } else {
+        1.0
+    }
+}
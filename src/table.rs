@@ -0,0 +1,214 @@
+use iced::{button, Button, Column, Length, Row, Text};
+use std::cmp::Ordering;
+
+const MAX_TITLE_CHARS: usize = 48;
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum SortOrder {
+    #[default]
+    CountDesc,
+    CountAsc,
+    Name,
+}
+
+#[derive(Debug, Default)]
+pub struct TableState {
+    pub sort: SortOrder,
+    pub expanded_row: Option<usize>,
+    name_header: button::State,
+    count_header: button::State,
+    rows: Vec<button::State>,
+}
+
+impl TableState {
+    pub fn toggle_count_sort(&mut self) {
+        self.sort = match self.sort {
+            SortOrder::CountDesc => SortOrder::CountAsc,
+            _ => SortOrder::CountDesc,
+        };
+        self.expanded_row = None;
+    }
+
+    pub fn sort_by_name(&mut self) {
+        self.sort = SortOrder::Name;
+        self.expanded_row = None;
+    }
+
+    pub fn toggle_row(&mut self, row: usize) {
+        self.expanded_row = if self.expanded_row == Some(row) {
+            None
+        } else {
+            Some(row)
+        };
+    }
+}
+
+pub fn compare(sort: SortOrder, a: &(String, u32), b: &(String, u32)) -> Ordering {
+    match sort {
+        SortOrder::CountDesc => b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)),
+        SortOrder::CountAsc => a.1.cmp(&b.1).then_with(|| a.0.cmp(&b.0)),
+        SortOrder::Name => a.0.cmp(&b.0).then_with(|| b.1.cmp(&a.1)),
+    }
+}
+
+pub fn count_rows(names: &[String]) -> Vec<(String, u32)> {
+    let mut counts = std::collections::BTreeMap::new();
+    for name in names {
+        *counts.entry(name.clone()).or_insert(0) += 1;
+    }
+    counts.into_iter().collect()
+}
+
+// The digit group separator for a locale like "de_DE.UTF-8", a comma unless its language is
+// known to group differently.
+pub fn thousands_separator(locale: &str) -> char {
+    let language = locale
+        .split(['_', '-', '.'])
+        .next()
+        .unwrap_or_default()
+        .to_ascii_lowercase();
+    match language.as_str() {
+        "de" | "nl" | "it" | "es" | "pt" | "da" | "id" | "tr" | "el" => '.',
+        "fr" | "ru" | "pl" | "cs" | "sk" | "sv" | "fi" | "nb" | "no" | "uk" | "hu" => '\u{a0}',
+        _ => ',',
+    }
+}
+
+pub fn format_count(n: u64, separator: char) -> String {
+    let digits = n.to_string();
+    let mut out = String::with_capacity(digits.len() + digits.len() / 3);
+    for (i, c) in digits.chars().enumerate() {
+        if i > 0 && (digits.len() - i).is_multiple_of(3) {
+            out.push(separator);
+        }
+        out.push(c);
+    }
+    out
+}
+
+pub fn truncate(s: &str, max_chars: usize) -> String {
+    if s.chars().count() <= max_chars {
+        s.to_string()
+    } else {
+        let mut out: String = s.chars().take(max_chars.saturating_sub(1)).collect();
+        out.push('…');
+        out
+    }
+}
+
+pub fn view<'a, Message: Clone + 'a>(
+    state: &'a mut TableState,
+    separator: char,
+    name_label: &str,
+    mut rows: Vec<(String, u32)>,
+    on_sort_name: Message,
+    on_sort_count: Message,
+    on_expand: impl Fn(usize) -> Message,
+) -> Column<'a, Message> {
+    let sort = state.sort;
+    rows.sort_by(|a, b| compare(sort, a, b));
+    state.rows.resize_with(rows.len(), Default::default);
+
+    let count_label = match sort {
+        SortOrder::CountDesc => "Count ▼",
+        SortOrder::CountAsc => "Count ▲",
+        SortOrder::Name => "Count",
+    };
+    let header = Row::new()
+        .spacing(10)
+        .push(
+            Button::new(&mut state.name_header, Text::new(name_label))
+                .on_press(on_sort_name)
+                .width(Length::FillPortion(4)),
+        )
+        .push(
+            Button::new(
+                &mut state.count_header,
+                Text::new(count_label).horizontal_alignment(iced::HorizontalAlignment::Right),
+            )
+            .on_press(on_sort_count)
+            .width(Length::FillPortion(1)),
+        );
+
+    let expanded = state.expanded_row;
+    rows.into_iter()
+        .zip(state.rows.iter_mut())
+        .enumerate()
+        .fold(
+            Column::new().spacing(2).push(header),
+            |table, (i, ((name, count), button))| {
+                let title = if expanded == Some(i) {
+                    name
+                } else {
+                    truncate(&name, MAX_TITLE_CHARS)
+                };
+                table.push(
+                    Button::new(
+                        button,
+                        Row::new()
+                            .spacing(10)
+                            .push(Text::new(title).width(Length::FillPortion(4)))
+                            .push(
+                                Text::new(format_count(count.into(), separator))
+                                    .width(Length::FillPortion(1))
+                                    .horizontal_alignment(iced::HorizontalAlignment::Right),
+                            ),
+                    )
+                    .on_press(on_expand(i))
+                    .width(Length::Fill),
+                )
+            },
+        )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rows() -> Vec<(String, u32)> {
+        vec![
+            ("b".to_string(), 2),
+            ("a".to_string(), 2),
+            ("c".to_string(), 5),
+            ("d".to_string(), 1),
+        ]
+    }
+
+    fn sorted(sort: SortOrder) -> Vec<String> {
+        let mut rows = rows();
+        rows.sort_by(|a, b| compare(sort, a, b));
+        rows.into_iter().map(|(name, _)| name).collect()
+    }
+
+    #[test]
+    fn count_sorts_break_ties_by_name() {
+        assert_eq!(sorted(SortOrder::CountDesc), ["c", "a", "b", "d"]);
+        assert_eq!(sorted(SortOrder::CountAsc), ["d", "a", "b", "c"]);
+    }
+
+    #[test]
+    fn name_sort_is_alphabetical() {
+        assert_eq!(sorted(SortOrder::Name), ["a", "b", "c", "d"]);
+        let mut same_name = [("a".to_string(), 1), ("a".to_string(), 3)];
+        same_name.sort_by(|a, b| compare(SortOrder::Name, a, b));
+        assert_eq!(same_name[0].1, 3);
+    }
+
+    #[test]
+    fn counts_group_by_thousands() {
+        assert_eq!(format_count(0, ','), "0");
+        assert_eq!(format_count(999, ','), "999");
+        assert_eq!(format_count(1000, ','), "1,000");
+        assert_eq!(format_count(1_234_567, '.'), "1.234.567");
+        assert_eq!(format_count(12_345, '\u{a0}'), "12\u{a0}345");
+    }
+
+    #[test]
+    fn separators_follow_the_language() {
+        assert_eq!(thousands_separator("en_US.UTF-8"), ',');
+        assert_eq!(thousands_separator("ja_JP.UTF-8"), ',');
+        assert_eq!(thousands_separator("de_DE.UTF-8"), '.');
+        assert_eq!(thousands_separator("fr-FR"), '\u{a0}');
+        assert_eq!(thousands_separator(""), ',');
+    }
+}
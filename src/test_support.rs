@@ -0,0 +1,168 @@
+// Fixtures for the unit tests: databases built from the bundled schema and KSM folders held in
+// memory.
+use crate::{
+    importer::{self, ImportOptions, Progress},
+    schema,
+    source::MemorySource,
+    Summary,
+};
+use iced_futures::futures::{self, StreamExt};
+use rusqlite::Connection;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+// Modification time of every fixture file unless given otherwise.
+pub const MODIFIED: i64 = 1_600_000_000;
+
+pub fn modified_at(secs: i64) -> SystemTime {
+    UNIX_EPOCH + Duration::from_secs(secs as u64)
+}
+
+pub fn memory_db() -> Connection {
+    let db = Connection::open_in_memory().unwrap();
+    db.execute_batch(schema::NEWEST_SCHEMA).unwrap();
+    db
+}
+
+// The importer opens maps.db by path, so runs get a fresh file in a directory removed on drop.
+pub struct TempDb {
+    _dir: tempfile::TempDir,
+    pub path: PathBuf,
+}
+
+impl TempDb {
+    pub fn new() -> Self {
+        Self::with(|_| {})
+    }
+
+    pub fn with(setup: impl FnOnce(&Connection)) -> Self {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("maps.db");
+        schema::create_database(&path).unwrap();
+        setup(&Connection::open(&path).unwrap());
+        Self { _dir: dir, path }
+    }
+
+    pub fn open(&self) -> Connection {
+        Connection::open(&self.path).unwrap()
+    }
+
+    pub fn count(&self, sql: &str) -> i64 {
+        self.open().query_row(sql, [], |r| r.get(0)).unwrap()
+    }
+
+    pub fn scores(&self) -> i64 {
+        self.count("SELECT COUNT(*) FROM Scores")
+    }
+}
+
+// A KSM folder with one profile. Songs are "<pack>/<song>" and difficulties file stems, every
+// score file gets a chart next to it unless added with score_only.
+#[derive(Default)]
+pub struct Fixture {
+    pub source: MemorySource,
+}
+
+impl Fixture {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn score_path(song: &str, difficulty: &str) -> PathBuf {
+        Path::new("score/player")
+            .join(song)
+            .join(format!("{}.ksc", difficulty))
+    }
+
+    pub fn chart_path(song: &str, difficulty: &str) -> PathBuf {
+        Path::new("songs")
+            .join(song)
+            .join(format!("{}.ksh", difficulty))
+    }
+
+    pub fn score(self, song: &str, difficulty: &str, lines: &[&str]) -> Self {
+        let chart = format!("title={}\nlevel=15\n--\n", song);
+        self.chart(song, difficulty, &chart)
+            .score_only(song, difficulty, lines)
+    }
+
+    pub fn score_only(mut self, song: &str, difficulty: &str, lines: &[&str]) -> Self {
+        let contents = lines.iter().map(|l| format!("{}\n", l)).collect::<String>();
+        self.source.insert(
+            Self::score_path(song, difficulty),
+            contents.into_bytes(),
+            modified_at(MODIFIED),
+        );
+        self
+    }
+
+    pub fn chart(mut self, song: &str, difficulty: &str, contents: &str) -> Self {
+        self.source.insert(
+            Self::chart_path(song, difficulty),
+            contents.as_bytes().to_vec(),
+            modified_at(MODIFIED),
+        );
+        self
+    }
+
+    pub fn file(mut self, path: &str, contents: &[u8]) -> Self {
+        self.source
+            .insert(path, contents.to_vec(), modified_at(MODIFIED));
+        self
+    }
+}
+
+// A line KSM writes for a plain clear on the normal gauge.
+pub fn line(score: u32) -> String {
+    format!("normal,normal,normal,on,on,on={},2,0,87.5,1,2", score)
+}
+
+// Backups would land next to the temporary database, tests that check them turn them back on.
+pub fn options() -> ImportOptions {
+    ImportOptions {
+        skip_backup: true,
+        ..Default::default()
+    }
+}
+
+pub fn run(fixture: Fixture, db: &TempDb, options: ImportOptions) -> Vec<Progress> {
+    run_source(Arc::new(fixture.source), &db.path, options)
+}
+
+pub fn run_source(
+    source: Arc<dyn crate::source::Source>,
+    db: &Path,
+    options: ImportOptions,
+) -> Vec<Progress> {
+    run_watching(source, db, options, |_| {})
+}
+
+// Hands every update to `watch` as it arrives, so a test can answer a pause the way the UI does.
+pub fn run_watching(
+    source: Arc<dyn crate::source::Source>,
+    db: &Path,
+    options: ImportOptions,
+    mut watch: impl FnMut(&Progress),
+) -> Vec<Progress> {
+    let subscription = importer::import_source(Path::new("KSM"), source, db, options, 0).unwrap();
+    subscription
+        .recipes()
+        .into_iter()
+        .flat_map(|recipe| {
+            let stream = recipe.stream(Box::pin(futures::stream::empty()));
+            futures::executor::block_on(stream.inspect(|p| watch(p)).collect::<Vec<_>>())
+        })
+        .collect()
+}
+
+pub fn finished(progress: &[Progress]) -> Summary {
+    match progress.last() {
+        Some(Progress::Finished(summary)) => summary.clone(),
+        other => panic!("The import didn't finish: {:?}", other),
+    }
+}
+
+pub fn import(fixture: Fixture, db: &TempDb, options: ImportOptions) -> Summary {
+    finished(&run(fixture, db, options))
+}
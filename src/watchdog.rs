@@ -0,0 +1,179 @@
+use anyhow::{anyhow, Result};
+use lazy_static::lazy_static;
+use std::{
+    panic::{self, AssertUnwindSafe},
+    path::Path,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        mpsc, Mutex, PoisonError,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+pub const STALL_TIMEOUT: Duration = Duration::from_secs(30);
+// How often a wait checks whether the import was cancelled.
+const CANCEL_POLL: Duration = Duration::from_millis(100);
+
+type Job = Box<dyn FnOnce() + Send>;
+
+// Every filesystem call runs on one worker thread. A call that stalls on a dead share or is
+// cancelled leaves the worker behind, the next call gets a new one.
+lazy_static! {
+    static ref WORKER: Mutex<mpsc::Sender<Job>> = Mutex::new(spawn_worker());
+}
+
+fn spawn_worker() -> mpsc::Sender<Job> {
+    let (tx, rx) = mpsc::channel::<Job>();
+    // A panicking job drops its sender, its caller sees that as an error.
+    thread::spawn(move || {
+        rx.into_iter().for_each(|job| {
+            let _ = panic::catch_unwind(AssertUnwindSafe(job));
+        })
+    });
+    tx
+}
+
+fn submit(job: Job) {
+    let mut worker = WORKER.lock().unwrap_or_else(PoisonError::into_inner);
+    if let Err(mpsc::SendError(job)) = worker.send(job) {
+        *worker = spawn_worker();
+        let _ = worker.send(job);
+    }
+}
+
+fn abandon_worker() {
+    *WORKER.lock().unwrap_or_else(PoisonError::into_inner) = spawn_worker();
+}
+
+// Waits for the next item as long as `stall` allows, giving up early once `cancel` is set.
+fn recv<T>(rx: &mpsc::Receiver<T>, stall: Duration, cancel: &AtomicBool) -> WaitResult<T> {
+    let started = Instant::now();
+    loop {
+        if cancel.load(Ordering::Relaxed) {
+            abandon_worker();
+            return WaitResult::Cancelled;
+        }
+        let left = stall.saturating_sub(started.elapsed());
+        if left.is_zero() {
+            abandon_worker();
+            return WaitResult::TimedOut;
+        }
+        match rx.recv_timeout(left.min(CANCEL_POLL)) {
+            Ok(item) => return WaitResult::Item(item),
+            Err(mpsc::RecvTimeoutError::Timeout) => {}
+            Err(mpsc::RecvTimeoutError::Disconnected) => return WaitResult::Done,
+        }
+    }
+}
+
+enum WaitResult<T> {
+    Item(T),
+    Done,
+    TimedOut,
+    Cancelled,
+}
+
+pub fn run_with_timeout<T, F>(timeout: Duration, cancel: &AtomicBool, what: &str, f: F) -> Result<T>
+where
+    T: Send + 'static,
+    F: FnOnce() -> Result<T> + Send + 'static,
+{
+    let (tx, rx) = mpsc::channel();
+    submit(Box::new(move || {
+        let _ = tx.send(f());
+    }));
+
+    match recv(&rx, timeout, cancel) {
+        WaitResult::Item(res) => res,
+        WaitResult::TimedOut => Err(anyhow!("Timed out after {} s: {}", timeout.as_secs(), what)),
+        WaitResult::Cancelled => Err(anyhow!("Cancelled while {}", what)),
+        WaitResult::Done => Err(anyhow!("Worker thread stopped unexpectedly: {}", what)),
+    }
+}
+
+pub fn read_file(path: &Path, cancel: &AtomicBool) -> Result<Vec<u8>> {
+    let owned = path.to_path_buf();
+    run_with_timeout(
+        STALL_TIMEOUT,
+        cancel,
+        &format!("reading \"{}\"", path.to_str().unwrap_or_default()),
+        move || Ok(std::fs::read(owned)?),
+    )
+}
+
+// Runs `produce` on the worker and collects what it sends, failing once nothing arrived for
+// `stall`.
+pub fn collect_with_timeout<T, F>(
+    stall: Duration,
+    cancel: &AtomicBool,
+    what: &str,
+    produce: F,
+) -> Result<Vec<T>>
+where
+    T: Send + 'static,
+    F: FnOnce(mpsc::Sender<T>) + Send + 'static,
+{
+    let (tx, rx) = mpsc::channel();
+    submit(Box::new(move || produce(tx)));
+    let mut items = Vec::new();
+    loop {
+        match recv(&rx, stall, cancel) {
+            WaitResult::Item(item) => items.push(item),
+            WaitResult::Done => return Ok(items),
+            WaitResult::Cancelled => return Err(anyhow!("Cancelled while {}", what)),
+            WaitResult::TimedOut => {
+                return Err(anyhow!(
+                    "Stalled for {} s after {} entries: {}",
+                    stall.as_secs(),
+                    items.len(),
+                    what
+                ))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn a_stalled_call_times_out_and_the_next_one_runs() {
+        let cancel = AtomicBool::new(false);
+        let stalled = run_with_timeout(Duration::from_millis(50), &cancel, "stalling", || {
+            thread::sleep(Duration::from_secs(5));
+            Ok(())
+        });
+        assert!(stalled.unwrap_err().to_string().contains("Timed out"));
+        assert_eq!(
+            run_with_timeout(STALL_TIMEOUT, &cancel, "test", || Ok(1)).unwrap(),
+            1
+        );
+    }
+
+    #[test]
+    fn cancelling_stops_the_wait() {
+        let cancel = AtomicBool::new(true);
+        let started = Instant::now();
+        let cancelled = run_with_timeout(STALL_TIMEOUT, &cancel, "stalling", || {
+            thread::sleep(Duration::from_secs(5));
+            Ok(())
+        });
+        assert!(cancelled.unwrap_err().to_string().contains("Cancelled"));
+        assert!(started.elapsed() < Duration::from_secs(1));
+    }
+
+    #[test]
+    fn a_panicking_call_doesnt_take_the_next_one_down() {
+        let cancel = AtomicBool::new(false);
+        let panicked: Result<()> = run_with_timeout(STALL_TIMEOUT, &cancel, "panicking", || {
+            panic!("worker exploded")
+        });
+        assert!(panicked.is_err());
+        assert_eq!(
+            run_with_timeout(STALL_TIMEOUT, &cancel, "test", || Ok(2)).unwrap(),
+            2
+        );
+    }
+}
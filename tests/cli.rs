@@ -0,0 +1,80 @@
+// Runs the built binary the way a script would, with its config folder in a temporary directory.
+use ksm2usc_score_import::schema;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Output};
+
+fn write(path: &Path, contents: &str) {
+    std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+    std::fs::write(path, contents).unwrap();
+}
+
+// A KSM folder with three scores on one level 15 chart, and an empty maps.db.
+fn setup(dir: &Path) -> (PathBuf, PathBuf) {
+    let ksm = dir.join("KSM");
+    write(
+        &ksm.join("songs/pack/song/ex.ksh"),
+        "title=song\nlevel=15\n--\n",
+    );
+    let scores: String = [9_000_000, 9_100_000, 9_200_000]
+        .iter()
+        .map(|s| format!("normal,normal,normal,on,on,on={},2,0,87.5,1,2\n", s))
+        .collect();
+    write(&ksm.join("score/PLAYER/pack/song/ex.ksc"), &scores);
+    let db = dir.join("usc/maps.db");
+    std::fs::create_dir_all(db.parent().unwrap()).unwrap();
+    schema::create_database(&db).unwrap();
+    (ksm, db)
+}
+
+fn run(dir: &Path, args: &[&str]) -> Output {
+    let (ksm, db) = setup(dir);
+    Command::new(env!("CARGO_BIN_EXE_ksm2usc-score-import"))
+        .arg("--ksm")
+        .arg(&ksm)
+        .arg("--db")
+        .arg(&db)
+        .args(["--json", "--no-backup"])
+        .args(args)
+        .env("HOME", dir)
+        .env("XDG_CONFIG_HOME", dir.join("config"))
+        .output()
+        .unwrap()
+}
+
+fn json_summary(output: &Output) -> serde_json::Value {
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert_eq!(output.status.code(), Some(0), "{}", stdout);
+    serde_json::from_str(&stdout)
+        .unwrap_or_else(|e| panic!("stdout isn't JSON ({}):\n{}", e, stdout))
+}
+
+#[test]
+fn json_output_is_only_the_summary() {
+    let dir = tempfile::tempdir().unwrap();
+    // Every score after the first on the chart takes its hash from the cache.
+    let summary = json_summary(&run(dir.path(), &[]));
+    assert_eq!(summary["scores_imported"], 3, "{}", summary);
+}
+
+#[test]
+fn filters_are_read_from_the_command_line() {
+    let dir = tempfile::tempdir().unwrap();
+    let summary = json_summary(&run(dir.path(), &["--min-score", "9,100,000"]));
+    assert_eq!(summary["scores_imported"], 2, "{}", summary);
+
+    let dir = tempfile::tempdir().unwrap();
+    let summary = json_summary(&run(dir.path(), &["--levels", "16+"]));
+    assert_eq!(summary["scores_imported"], 0, "{}", summary);
+
+    for (option, value) in [("--min-score", "9.1m"), ("--levels", "18-15")].iter() {
+        let dir = tempfile::tempdir().unwrap();
+        let output = run(dir.path(), &[option, value]);
+        assert_eq!(output.status.code(), Some(2), "{} {}", option, value);
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        assert!(
+            stderr.contains(&format!("invalid value for {}", option)),
+            "{}",
+            stderr
+        );
+    }
+}
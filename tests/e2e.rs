@@ -0,0 +1,340 @@
+// Every folder in tests/scenarios is imported from scratch and compared with what it expects:
+//
+//   ksm/          the KSM folder, copied to a temporary directory. Every file is dated
+//                 MODIFIED unless listed in mtimes.txt as "<path relative to ksm> <seconds>".
+//   setup.sql     optional, run on a fresh version 19 maps.db before the import. "{ksm}" is
+//                 replaced by the copied KSM folder.
+//   options.txt   optional, one import option per line, see apply_option.
+//   scores.txt    the expected Scores rows.
+//   report.txt    the expected text report, with the KSM folder written as "{ksm}".
+//   report.json   the expected JSON report, written the same way.
+//   export.json   the expected score dump for stat sites, a dry run has to give the same one.
+//
+// The folder is imported twice and both runs have to write byte for byte the same reports, that's
+// what lets two runs be compared with diff.
+//
+// Every scenario also runs with the KSM folder read into a MemorySource, and from a zip of it
+// inside a "KSM" folder. Those runs have to write the same Scores rows and the same report with
+// paths relative to the KSM folder. Zips keep dates to 2 seconds, mtimes.txt sticks to even ones.
+//
+// Running with BLESS=1 writes the actual results over the expected ones, which is how a new
+// scenario gets its expectations.
+use ksm2usc_score_import::{
+    export, importer, importer_funcs, report, schema, source::MemorySource, Summary,
+};
+use rusqlite::Connection;
+use std::fs::File;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, UNIX_EPOCH};
+
+const MODIFIED: u64 = 1_600_000_000;
+
+fn copy_tree(from: &Path, to: &Path) {
+    for entry in walkdir::WalkDir::new(from) {
+        let entry = entry.unwrap();
+        let target = to.join(entry.path().strip_prefix(from).unwrap());
+        if entry.file_type().is_dir() {
+            std::fs::create_dir_all(&target).unwrap();
+        } else {
+            std::fs::copy(entry.path(), &target).unwrap();
+        }
+    }
+}
+
+fn set_modified(path: &Path, secs: u64) {
+    File::options()
+        .write(true)
+        .open(path)
+        .and_then(|f| f.set_modified(UNIX_EPOCH + Duration::from_secs(secs)))
+        .unwrap();
+}
+
+fn date_files(ksm: &Path, scenario: &Path) {
+    walkdir::WalkDir::new(ksm)
+        .into_iter()
+        .filter_map(|e| e.ok())
+        .filter(|e| e.file_type().is_file())
+        .for_each(|e| set_modified(e.path(), MODIFIED));
+    let mtimes = std::fs::read_to_string(scenario.join("mtimes.txt")).unwrap_or_default();
+    for line in mtimes.lines().filter(|l| !l.trim().is_empty()) {
+        let (path, secs) = line.rsplit_once(' ').unwrap();
+        set_modified(&ksm.join(path), secs.parse().unwrap());
+    }
+}
+
+fn apply_option(options: &mut importer::ImportOptions, option: &str) {
+    let (name, value) = option.split_once('=').unwrap_or((option, ""));
+    match name {
+        "safe_mode" => options.safe_mode = true,
+        "best_only" => options.policy.best_only = true,
+        "clears_only" => options.policy.clears_only = true,
+        "skip_legacy" => options.policy.skip_legacy = true,
+        "nonstandard_options" => options.policy.nonstandard_options = true,
+        "improvements_only" => options.improvements_only = true,
+        "allow_duplicates" => options.skip_duplicates = false,
+        "dry_run" => options.dry_run = true,
+        "all_or_nothing" => options.all_or_nothing = true,
+        "user_name" => options.user_name = value.to_string(),
+        "user_id" => options.user_id = value.to_string(),
+        _ => panic!("Unknown scenario option \"{}\"", option),
+    }
+}
+
+fn scores_table(db: &Path) -> String {
+    let db = Connection::open(db).unwrap();
+    let mut stmt = db
+        .prepare(
+            "SELECT chart_hash, score, crit, near, miss, gauge, gauge_type, mirror, random, \
+            timestamp, user_name, user_id FROM Scores ORDER BY rowid",
+        )
+        .unwrap();
+    let rows = stmt
+        .query_map([], |r| {
+            let fields: Vec<String> = (0..12)
+                .map(|i| match r.get_ref(i).unwrap() {
+                    rusqlite::types::ValueRef::Null => "NULL".to_string(),
+                    rusqlite::types::ValueRef::Integer(i) => i.to_string(),
+                    rusqlite::types::ValueRef::Real(f) => f.to_string(),
+                    rusqlite::types::ValueRef::Text(t) => String::from_utf8_lossy(t).to_string(),
+                    rusqlite::types::ValueRef::Blob(_) => "BLOB".to_string(),
+                })
+                .collect();
+            Ok(fields.join("|"))
+        })
+        .unwrap();
+    rows.map(|r| r.unwrap() + "\n").collect()
+}
+
+fn check(scenario: &Path, name: &str, actual: &str, failures: &mut Vec<String>) {
+    let path = scenario.join(name);
+    if std::env::var_os("BLESS").is_some() {
+        std::fs::write(&path, actual).unwrap();
+        return;
+    }
+    let expected = std::fs::read_to_string(&path)
+        .unwrap_or_default()
+        .replace("\r\n", "\n");
+    if expected != actual {
+        failures.push(format!(
+            "{} differs\n--- expected\n{}--- actual\n{}",
+            path.display(),
+            expected,
+            actual
+        ));
+    }
+}
+
+fn zip_tree(ksm: &Path, path: &Path) {
+    let mut zip = zip::ZipWriter::new(File::create(path).unwrap());
+    for entry in walkdir::WalkDir::new(ksm)
+        .into_iter()
+        .filter_map(|e| e.ok())
+    {
+        if !entry.file_type().is_file() {
+            continue;
+        }
+        let modified = entry.metadata().unwrap().modified().unwrap();
+        let secs = modified.duration_since(UNIX_EPOCH).unwrap().as_secs() as i64;
+        // "YYYY-MM-DD HH:MM:SS" in UTC, which is how zip dates are read back.
+        let fields: Vec<u16> = importer_funcs::format_date_time(secs)
+            .split(&['-', ' ', ':'][..])
+            .map(|f| f.parse().unwrap())
+            .collect();
+        let time = zip::DateTime::from_date_and_time(
+            fields[0],
+            fields[1] as u8,
+            fields[2] as u8,
+            fields[3] as u8,
+            fields[4] as u8,
+            fields[5] as u8,
+        )
+        .unwrap();
+        let name = Path::new("KSM").join(entry.path().strip_prefix(ksm).unwrap());
+        let options = zip::write::FileOptions::default().last_modified_time(time);
+        zip.start_file(name.to_str().unwrap().replace('\\', "/"), options)
+            .unwrap();
+        std::io::Write::write_all(&mut zip, &std::fs::read(entry.path()).unwrap()).unwrap();
+    }
+    zip.finish().unwrap();
+}
+
+fn read_into_memory(ksm: &Path) -> MemorySource {
+    let mut source = MemorySource::default();
+    walkdir::WalkDir::new(ksm)
+        .into_iter()
+        .filter_map(|e| e.ok())
+        .filter(|e| e.file_type().is_file())
+        .for_each(|e| {
+            source.insert(
+                e.path().strip_prefix(ksm).unwrap(),
+                std::fs::read(e.path()).unwrap(),
+                e.metadata().unwrap().modified().unwrap(),
+            )
+        });
+    source
+}
+
+// Imports into a fresh maps.db next to the KSM folder, returning the Scores rows and the report.
+fn import(
+    scenario: &Path,
+    dir: &Path,
+    ksm: &Path,
+    run: impl FnOnce(&Path, importer::ImportOptions) -> Summary,
+) -> (String, Report) {
+    let db = dir.join("maps.db");
+    let _ = std::fs::remove_file(&db);
+    schema::create_database(&db).unwrap();
+    let ksm_placeholder = ksm.to_str().unwrap();
+    if let Ok(setup) = std::fs::read_to_string(scenario.join("setup.sql")) {
+        Connection::open(&db)
+            .unwrap()
+            .execute_batch(&setup.replace("{ksm}", ksm_placeholder))
+            .unwrap();
+    }
+
+    let mut options = importer::ImportOptions {
+        skip_backup: true,
+        ..Default::default()
+    };
+    std::fs::read_to_string(scenario.join("options.txt"))
+        .unwrap_or_default()
+        .lines()
+        .map(str::trim)
+        .filter(|l| !l.is_empty())
+        .for_each(|o| apply_option(&mut options, o));
+    let summary = run(&db, options);
+
+    // JSON escapes the backslashes of Windows paths.
+    let report = |name: &str, backslash: &str| {
+        let path = dir.join(name);
+        report::write(&summary, None, &path).unwrap();
+        std::fs::read_to_string(&path)
+            .unwrap()
+            .replace(&ksm_placeholder.replace('\\', backslash), "{ksm}")
+            .replace(backslash, "/")
+    };
+    let reports = Report {
+        text: report("report.txt", "\\"),
+        json: report("report.json", "\\\\"),
+        export: export::json(&summary).unwrap() + "\n",
+    };
+    (scores_table(&db), reports)
+}
+
+#[derive(PartialEq)]
+struct Report {
+    text: String,
+    json: String,
+    export: String,
+}
+
+impl Report {
+    fn relative(&self) -> Self {
+        Self {
+            text: self.text.replace("{ksm}/", ""),
+            json: self.json.replace("{ksm}/", ""),
+            export: self.export.clone(),
+        }
+    }
+}
+
+fn run_scenario(scenario: &Path) -> Vec<String> {
+    let dir = tempfile::tempdir().unwrap();
+    let ksm = dir.path().join("KSM");
+    copy_tree(&scenario.join("ksm"), &ksm);
+    date_files(&ksm, scenario);
+    let from_folder =
+        |db: &Path, options| importer::import_scores(&ksm, db, options, |_| {}).unwrap();
+    let (scores, report) = import(scenario, dir.path(), &ksm, from_folder);
+
+    let mut failures = Vec::new();
+    check(scenario, "scores.txt", &scores, &mut failures);
+    check(scenario, "report.txt", &report.text, &mut failures);
+    check(scenario, "report.json", &report.json, &mut failures);
+    check(scenario, "export.json", &report.export, &mut failures);
+
+    let (_, dry_run) = import(scenario, dir.path(), &ksm, |db, mut options| {
+        options.dry_run = true;
+        from_folder(db, options)
+    });
+    if dry_run.export != report.export {
+        failures.push(format!(
+            "{}: the export of a dry run differs\n--- imported\n{}--- dry run\n{}",
+            scenario.display(),
+            report.export,
+            dry_run.export
+        ));
+    }
+
+    let (_, again) = import(scenario, dir.path(), &ksm, from_folder);
+    for (name, first, second) in [
+        ("text", &report.text, &again.text),
+        ("JSON", &report.json, &again.json),
+    ]
+    .iter()
+    {
+        if first != second {
+            failures.push(format!(
+                "{}: the {} report of a second run differs\n--- first\n{}--- second\n{}",
+                scenario.display(),
+                name,
+                first,
+                second
+            ));
+        }
+    }
+
+    let source = Arc::new(read_into_memory(&ksm));
+    let (memory_scores, memory_report) = import(scenario, dir.path(), &ksm, |db, options| {
+        importer::import_from(source, db, options, |_| {}).unwrap()
+    });
+    let archive = dir.path().join("KSM.zip");
+    zip_tree(&ksm, &archive);
+    let (zip_scores, zip_report) = import(scenario, dir.path(), &ksm, |db, options| {
+        importer::import_scores(&archive, db, options, |_| {}).unwrap()
+    });
+
+    let relative = report.relative();
+    for (source, other_scores, other_report) in [
+        ("in-memory", &memory_scores, &memory_report),
+        ("zip", &zip_scores, &zip_report),
+    ]
+    .iter()
+    {
+        for (name, expected, actual) in [
+            ("Scores", &scores, *other_scores),
+            ("text report", &relative.text, &other_report.text),
+            ("JSON report", &relative.json, &other_report.json),
+        ]
+        .iter()
+        {
+            if expected != actual {
+                failures.push(format!(
+                    "{}: the {} {} differs\n--- from the folder\n{}--- from the {}\n{}",
+                    scenario.display(),
+                    source,
+                    name,
+                    expected,
+                    source,
+                    actual
+                ));
+            }
+        }
+    }
+    failures
+}
+
+#[test]
+fn scenarios() {
+    let root = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("tests/scenarios");
+    let mut scenarios: Vec<PathBuf> = std::fs::read_dir(&root)
+        .unwrap()
+        .map(|e| e.unwrap().path())
+        .filter(|p| p.is_dir())
+        .collect();
+    scenarios.sort();
+    assert!(!scenarios.is_empty(), "No scenarios in {}", root.display());
+    let failures: Vec<String> = scenarios.iter().flat_map(|s| run_scenario(s)).collect();
+    assert!(failures.is_empty(), "{}", failures.join("\n"));
+}
@@ -0,0 +1,7 @@
+-- The tables of a maps.db USC created at version 19, as `sqlite3 maps.db .schema` lists them.
+CREATE TABLE Database(version INTEGER);
+CREATE TABLE Folders(path TEXT,rowid INTEGER PRIMARY KEY);
+CREATE TABLE Charts(folderid INTEGER,title TEXT,artist TEXT,title_translit TEXT,artist_translit TEXT,jacket_path TEXT,effector TEXT,illustrator TEXT,diff_name TEXT,diff_shortname TEXT,path TEXT,bpm TEXT,diff_index INTEGER,level INTEGER,hash TEXT,preview_file TEXT,preview_offset INTEGER,preview_length INTEGER,lwt INTEGER,custom_offset INTEGER,FOREIGN KEY(folderid) REFERENCES Folders(rowid));
+CREATE TABLE Scores(score INTEGER,crit INTEGER,near INTEGER,early INTEGER,late INTEGER,combo INTEGER,miss INTEGER,gauge REAL,auto_flags INTEGER,replay TEXT,timestamp INTEGER,chart_hash TEXT,user_name TEXT,user_id TEXT,local_score INTEGER,window_perfect INTEGER,window_good INTEGER,window_hold INTEGER,window_miss INTEGER,window_slam INTEGER,gauge_type INTEGER,gauge_opt INTEGER,mirror INTEGER,random INTEGER);
+CREATE TABLE Collections(collection TEXT,folderid INTEGER,UNIQUE(collection,folderid),FOREIGN KEY(folderid) REFERENCES Folders(rowid));
+CREATE TABLE PracticeSetups(chart_id INTEGER,setup_title TEXT,loop_success INTEGER,loop_fail INTEGER,range_begin INTEGER,range_end INTEGER,fail_cond_type INTEGER,fail_cond_value INTEGER,playback_speed REAL,inc_speed_on_success INTEGER,inc_speed REAL,inc_streak INTEGER,dec_speed_on_fail INTEGER,dec_speed REAL,min_playback_speed REAL,max_rewind INTEGER,max_rewind_measure INTEGER,FOREIGN KEY(chart_id) REFERENCES Charts(rowid));
@@ -0,0 +1,4 @@
+INSERT INTO Charts(title, path, level, hash) VALUES('jp', '{ksm}/songs/pack/jp/mxm.ksh', 18, '1e292d6aaec314f026490eca7a66c0c48954d8f3');
+INSERT INTO Charts(title, path, level, hash) VALUES('gone', '{ksm}/songs/pack/gone/ex.ksh', 16, '0123456789abcdef0123456789abcdef01234567');
+INSERT INTO Scores(score, crit, near, miss, gauge, auto_flags, replay, timestamp, chart_hash, user_name, user_id, local_score, gauge_type, gauge_opt, mirror, random)
+    VALUES(9000000, 900, 150, 25, 0.75, 0, '', 1600000000, '1e292d6aaec314f026490eca7a66c0c48954d8f3', '', 0, 1, 0, 0, 0, 0);